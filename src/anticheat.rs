@@ -0,0 +1,132 @@
+//! 反作弊辅助结构
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// 按 (玩家, 动作) 记录最近一次被接受的时间，用于动作限频
+#[derive(Debug, Default)]
+pub struct ActionCooldowns {
+    last_used: HashMap<(Uuid, String), Instant>,
+}
+
+impl ActionCooldowns {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 尝试使用一次动作
+    ///
+    /// 距上次被接受的同名动作不足 `cooldown` 时返回 false（不刷新记录），
+    /// 否则记录本次使用时间并返回 true。
+    pub fn try_use(&mut self, uuid: Uuid, action: &str, cooldown: Duration, now: Instant) -> bool {
+        let key = (uuid, action.to_string());
+        if let Some(&last) = self.last_used.get(&key) {
+            if now.saturating_duration_since(last) < cooldown {
+                return false;
+            }
+        }
+        self.last_used.insert(key, now);
+        true
+    }
+
+    /// 是否还记录着该玩家的动作使用时间
+    pub fn contains(&self, uuid: &Uuid) -> bool {
+        self.last_used.keys().any(|(owner, _)| owner == uuid)
+    }
+
+    /// 清除玩家所有动作的使用记录
+    pub fn remove(&mut self, uuid: &Uuid) {
+        self.last_used.retain(|(owner, _), _| owner != uuid);
+    }
+}
+
+/// 记录玩家加入时间和加入后的更新次数，用于首次移动的宽限期
+///
+/// 刚注册的玩家客户端时钟与服务器尚未对齐，前几次移动容易被误判，
+/// 宽限期内跳过移动校验。
+#[derive(Debug, Default)]
+pub struct SettlingTracker {
+    joins: HashMap<Uuid, (Instant, u32)>,
+}
+
+impl SettlingTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 玩家加入（注册或恢复），重新开始宽限期
+    pub fn join(&mut self, uuid: Uuid, now: Instant) {
+        self.joins.insert(uuid, (now, 0));
+    }
+
+    /// 记录一次更新，并返回该更新是否仍处于宽限期
+    ///
+    /// 加入后的前 `max_updates` 次更新，或加入后 `period` 时间内的更新都算宽限期。
+    /// 两个条件都过去后清除该玩家的记录。
+    pub fn observe_update(
+        &mut self,
+        uuid: Uuid,
+        now: Instant,
+        max_updates: u32,
+        period: Duration,
+    ) -> bool {
+        let Some((joined_at, updates)) = self.joins.get_mut(&uuid) else {
+            return false;
+        };
+        *updates += 1;
+        let settling =
+            *updates <= max_updates || now.saturating_duration_since(*joined_at) < period;
+        if !settling {
+            self.joins.remove(&uuid);
+        }
+        settling
+    }
+
+    /// 该玩家是否还有加入记录
+    pub fn contains(&self, uuid: &Uuid) -> bool {
+        self.joins.contains_key(uuid)
+    }
+
+    /// 清除玩家的加入记录（加入后一直没有更新的玩家不会自行清除）
+    pub fn remove(&mut self, uuid: &Uuid) {
+        self.joins.remove(uuid);
+    }
+}
+
+/// 学习每个玩家的典型速度，用于移动能力差异很大的游戏（冲刺、坐骑、载具）
+///
+/// 预热期内记录合法更新的实际速度；样本数达到预热次数后，
+/// 以样本分位数作为该玩家的速度基线。
+#[derive(Debug, Default)]
+pub struct PlayerSpeedProfile {
+    samples: HashMap<Uuid, Vec<f64>>,
+}
+
+impl PlayerSpeedProfile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 记录一次合法更新的速度（米/秒）；样本已满 `warmup` 个时不再记录
+    pub fn record(&mut self, uuid: Uuid, speed: f64, warmup: usize) {
+        let samples = self.samples.entry(uuid).or_default();
+        if samples.len() < warmup {
+            samples.push(speed);
+        }
+    }
+
+    /// 预热完成后返回样本的 `percentile`（0.0 ~ 1.0）分位数，预热未完成时返回 None
+    pub fn baseline(&self, uuid: &Uuid, warmup: usize, percentile: f64) -> Option<f64> {
+        let samples = self.samples.get(uuid).filter(|s| !s.is_empty() && s.len() >= warmup)?;
+        let mut sorted = samples.clone();
+        sorted.sort_by(f64::total_cmp);
+        let rank = (percentile.clamp(0.0, 1.0) * (sorted.len() - 1) as f64).round() as usize;
+        Some(sorted[rank])
+    }
+
+    /// 清除玩家的样本（重新预热）
+    pub fn remove(&mut self, uuid: &Uuid) {
+        self.samples.remove(uuid);
+    }
+}
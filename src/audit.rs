@@ -0,0 +1,96 @@
+//! 纠正审计日志
+//!
+//! 每次纠正追加一行 JSON 到单独的文件，供作弊调查使用，与普通日志输出无关。
+//! 写文件在单独的线程中进行，处理更新的线程只负责把记录放进通道。
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+use uuid::Uuid;
+
+/// 一条纠正记录
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// 记录时间（Unix 毫秒）
+    #[serde(with = "crate::ts_millis::required")]
+    pub ts: u64,
+    pub uuid: Uuid,
+    pub username: String,
+    /// 纠正原因（如 "invalid_movement"）
+    pub reason: String,
+    /// 客户端上报的位置
+    pub claimed: (Option<f64>, Option<f64>, Option<f64>),
+    /// 纠正后的位置
+    pub corrected: (Option<f64>, Option<f64>, Option<f64>),
+    /// 与上一次状态的时间差（毫秒，任一侧缺少时间戳时省略）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dt_ms: Option<u128>,
+}
+
+/// JSON lines 审计日志写入器
+///
+/// 丢弃时关闭通道并等待写线程把剩余记录写完。
+pub struct AuditLog {
+    tx: Option<Sender<AuditRecord>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl AuditLog {
+    /// 以追加方式打开 `path`（不存在时创建）
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_with_rfc3339(path, false)
+    }
+
+    /// 同 `open`，`rfc3339` 为 true 时记录时间写成 RFC 3339 字符串（见 `config.rfc3339_timestamps`）
+    pub fn open_with_rfc3339(path: impl AsRef<Path>, rfc3339: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (tx, rx) = mpsc::channel::<AuditRecord>();
+        let writer = thread::spawn(move || {
+            let mut out = BufWriter::new(file);
+            while let Ok(record) = rx.recv() {
+                let mut write = |record: &AuditRecord| -> io::Result<()> {
+                    crate::ts_millis::with_rfc3339(rfc3339, || serde_json::to_writer(&mut out, record))?;
+                    out.write_all(b"\n")
+                };
+                let mut result = write(&record);
+                // 把已排队的记录一并写出后再刷新
+                for record in rx.try_iter() {
+                    result = result.and(write(&record));
+                }
+                if let Err(e) = result.and_then(|_| out.flush()) {
+                    eprintln!("写入审计日志失败: {}", e);
+                }
+            }
+        });
+        Ok(AuditLog {
+            tx: Some(tx),
+            writer: Some(writer),
+        })
+    }
+
+    /// 追加一条记录（不阻塞）
+    pub fn record(&self, record: AuditRecord) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(record);
+        }
+    }
+}
+
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuditLog")
+    }
+}
@@ -0,0 +1,76 @@
+//! 注册前的身份验证
+//!
+//! 对接账号系统的服务器实现 `Authenticator`，校验注册请求携带的 `token`；
+//! 默认的 `NoAuth` 不做任何校验。
+
+use std::fmt;
+
+/// 验证通过后账号系统确认的身份
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthClaims {
+    /// 玩家使用的用户名（可以与请求中的不同，例如账号系统规范化后的名字）
+    pub username: String,
+}
+
+/// 验证失败的原因（原样返回给客户端）
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthError(pub String);
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "authentication failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// 注册验证器
+///
+/// 在持有服务器状态锁时同步调用，实现中不要做耗时操作。
+pub trait Authenticator: Send + Sync {
+    /// 校验 `username` 出示的 `token`（请求未携带时为空字符串）
+    fn verify(&self, username: &str, token: &str) -> Result<AuthClaims, AuthError>;
+}
+
+impl fmt::Debug for dyn Authenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Authenticator")
+    }
+}
+
+/// 不做验证（默认）：任何人都可以用任何用户名注册
+pub struct NoAuth;
+
+impl Authenticator for NoAuth {
+    fn verify(&self, username: &str, _token: &str) -> Result<AuthClaims, AuthError> {
+        Ok(AuthClaims { username: username.to_string() })
+    }
+}
+
+/// 所有客户端共享同一个密钥：`token` 必须与之相同
+pub struct SharedSecretAuth {
+    secret: String,
+}
+
+impl SharedSecretAuth {
+    pub fn new(secret: impl Into<String>) -> Self {
+        SharedSecretAuth { secret: secret.into() }
+    }
+}
+
+impl Authenticator for SharedSecretAuth {
+    fn verify(&self, username: &str, token: &str) -> Result<AuthClaims, AuthError> {
+        if token.is_empty() {
+            return Err(AuthError("token required".to_string()));
+        }
+        if !constant_time_eq(token.as_bytes(), self.secret.as_bytes()) {
+            return Err(AuthError("invalid token".to_string()));
+        }
+        Ok(AuthClaims { username: username.to_string() })
+    }
+}
+
+/// 比较耗时与内容无关，避免通过响应时间逐字节猜出密钥
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,146 @@
+//! 出站故障注入（需要启用 `chaos` feature）
+//!
+//! 按配置的概率丢弃出站数据包，或延迟一段随机时间再发送。随机数由固定种子
+//! 生成，同样的配置和发送顺序得到同样的结果，便于复现。
+
+use crate::config::ChaosConfig;
+use crate::transport::ClientConn;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// 出站故障注入器
+#[derive(Debug)]
+pub struct Chaos {
+    config: ChaosConfig,
+    rng: Mutex<StdRng>,
+}
+
+impl Chaos {
+    pub fn new(config: &ChaosConfig) -> Self {
+        Chaos {
+            config: config.clone(),
+            rng: Mutex::new(StdRng::seed_from_u64(config.seed)),
+        }
+    }
+
+    /// 决定下一个数据包的命运：`None` 表示丢弃，否则为发送前的延迟
+    pub fn decide(&self) -> Option<Duration> {
+        let mut rng = self.rng.lock().unwrap();
+        if rng.gen_bool(self.config.drop_probability.clamp(0.0, 1.0)) {
+            return None;
+        }
+        let (min, max) = (self.config.min_latency, self.config.max_latency);
+        if max <= min {
+            return Some(min);
+        }
+        Some(rng.gen_range(min..=max))
+    }
+}
+
+/// 一条被延迟的消息；同一时刻到期的按入队顺序发送
+#[derive(Debug)]
+struct Delayed {
+    due: Instant,
+    seq: u64,
+    conn: ClientConn,
+    payload: Vec<u8>,
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        (self.due, self.seq) == (other.due, other.seq)
+    }
+}
+
+impl Eq for Delayed {}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delayed {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.due, self.seq).cmp(&(other.due, other.seq))
+    }
+}
+
+/// 等待发送的延迟消息，由一个线程按到期时间取出（而不是每条消息一个线程）
+#[derive(Debug, Default)]
+pub struct DelayQueue {
+    pending: Mutex<Pending>,
+    ready: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    heap: BinaryHeap<Reverse<Delayed>>,
+    next_seq: u64,
+    closed: bool,
+}
+
+impl DelayQueue {
+    pub fn new() -> Self {
+        DelayQueue::default()
+    }
+
+    /// 放入一条 `delay` 之后发送的消息
+    pub fn push(&self, delay: Duration, conn: ClientConn, payload: Vec<u8>) {
+        let mut pending = self.pending.lock().unwrap();
+        let seq = pending.next_seq;
+        pending.heap.push(Reverse(Delayed {
+            due: Instant::now() + delay,
+            seq,
+            conn,
+            payload,
+        }));
+        pending.next_seq += 1;
+        self.ready.notify_one();
+    }
+
+    /// 阻塞直到有消息到期，取出所有已到期的消息；`close` 之后返回 None
+    pub fn wait_due(&self) -> Option<Vec<(ClientConn, Vec<u8>)>> {
+        let mut pending = self.pending.lock().unwrap();
+        loop {
+            if pending.closed {
+                return None;
+            }
+            let now = Instant::now();
+            let next_due = pending.heap.peek().map(|Reverse(d)| d.due);
+            match next_due {
+                Some(due) if due <= now => break,
+                Some(due) => pending = self.ready.wait_timeout(pending, due - now).unwrap().0,
+                None => pending = self.ready.wait(pending).unwrap(),
+            }
+        }
+        let now = Instant::now();
+        let mut due = Vec::new();
+        while pending.heap.peek().is_some_and(|Reverse(d)| d.due <= now) {
+            let Reverse(d) = pending.heap.pop().unwrap();
+            due.push((d.conn, d.payload));
+        }
+        Some(due)
+    }
+
+    /// 唤醒并结束 `wait_due`，尚未到期的消息被丢弃
+    pub fn close(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.closed = true;
+        pending.heap.clear();
+        self.ready.notify_all();
+    }
+
+    /// 尚未发送的消息数
+    pub fn len(&self) -> usize {
+        self.pending.lock().unwrap().heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,236 @@
+//! 出站消息的编码格式
+//!
+//! 所有发往客户端的 `ServerMessage` 都经由 `Codec` 编码，线上格式由
+//! `ServerConfig::wire_format` 统一决定；客户端发来的数据包也由同一个 `Codec` 解码。
+
+use crate::protocol::ServerMessage;
+use std::fmt;
+
+/// 编解码失败
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodecError(pub String);
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "codec error: {}", self.0)
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// 入站数据包解码失败
+#[derive(Debug, Clone, PartialEq)]
+pub enum InboundError {
+    /// 文本格式的数据包不是合法的 UTF-8
+    InvalidUtf8,
+    /// 数据包无法解析
+    Malformed(String),
+}
+
+/// 严格按 UTF-8 JSON 解码入站数据包
+pub fn decode_json_inbound(payload: &[u8]) -> Result<serde_json::Value, InboundError> {
+    let text = std::str::from_utf8(payload).map_err(|_| InboundError::InvalidUtf8)?;
+    serde_json::from_str(text).map_err(|e| InboundError::Malformed(e.to_string()))
+}
+
+/// 入站数据包的可读转储（排查客户端问题用）
+///
+/// 可打印的 ASCII 原样输出，其余字节转义为 `\xNN`；只转储前 `limit` 个字节，
+/// 截断时在末尾注明省略的字节数。
+pub fn dump_payload(payload: &[u8], limit: usize) -> String {
+    let shown = &payload[..payload.len().min(limit)];
+    let mut dump: String = shown.iter().flat_map(|&b| std::ascii::escape_default(b)).map(char::from).collect();
+    if payload.len() > shown.len() {
+        dump.push_str(&format!("...(+{} bytes)", payload.len() - shown.len()));
+    }
+    dump
+}
+
+/// 消息编解码器
+pub trait Codec: Send + Sync {
+    fn encode(&self, msg: &ServerMessage) -> Vec<u8>;
+    fn decode(&self, bytes: &[u8]) -> Result<ServerMessage, CodecError>;
+
+    /// 解码客户端发来的数据包（默认严格 UTF-8 JSON，不做有损替换）
+    fn decode_inbound(&self, payload: &[u8]) -> Result<serde_json::Value, InboundError> {
+        decode_json_inbound(payload)
+    }
+}
+
+impl<C: Codec + ?Sized> Codec for &C {
+    fn encode(&self, msg: &ServerMessage) -> Vec<u8> {
+        (**self).encode(msg)
+    }
+
+    fn decode_inbound(&self, payload: &[u8]) -> Result<serde_json::Value, InboundError> {
+        (**self).decode_inbound(payload)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<ServerMessage, CodecError> {
+        (**self).decode(bytes)
+    }
+}
+
+/// 紧凑 JSON（默认，与旧版线上格式一致）
+pub struct CompactJson;
+
+impl Codec for CompactJson {
+    fn encode(&self, msg: &ServerMessage) -> Vec<u8> {
+        serde_json::to_vec(msg).expect("ServerMessage is always serializable")
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<ServerMessage, CodecError> {
+        serde_json::from_slice(bytes).map_err(|e| CodecError(e.to_string()))
+    }
+}
+
+/// 带缩进的 JSON（便于调试）
+pub struct PrettyJson;
+
+impl Codec for PrettyJson {
+    fn encode(&self, msg: &ServerMessage) -> Vec<u8> {
+        serde_json::to_vec_pretty(msg).expect("ServerMessage is always serializable")
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<ServerMessage, CodecError> {
+        serde_json::from_slice(bytes).map_err(|e| CodecError(e.to_string()))
+    }
+}
+
+/// MessagePack（需要启用 `msgpack` feature；字段按名字编码）
+#[cfg(feature = "msgpack")]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    fn encode(&self, msg: &ServerMessage) -> Vec<u8> {
+        rmp_serde::to_vec_named(msg).expect("ServerMessage is always serializable")
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<ServerMessage, CodecError> {
+        rmp_serde::from_slice(bytes).map_err(|e| CodecError(e.to_string()))
+    }
+
+    /// 以 MessagePack map 开头的数据包按二进制解码（不检查 UTF-8），其余仍按 JSON 解码
+    fn decode_inbound(&self, payload: &[u8]) -> Result<serde_json::Value, InboundError> {
+        match payload.first() {
+            Some(0x80..=0x8f | 0xde | 0xdf) => {
+                rmp_serde::from_slice(payload).map_err(|e| InboundError::Malformed(e.to_string()))
+            }
+            _ => decode_json_inbound(payload),
+        }
+    }
+}
+
+/// 线上编码格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    #[default]
+    CompactJson,
+    PrettyJson,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+}
+
+impl WireFormat {
+    /// 对应的编解码器
+    pub fn codec(self) -> &'static dyn Codec {
+        match self {
+            WireFormat::CompactJson => &CompactJson,
+            WireFormat::PrettyJson => &PrettyJson,
+            #[cfg(feature = "msgpack")]
+            WireFormat::MessagePack => &MessagePack,
+        }
+    }
+}
+
+/// 压缩消息的首字节标记（JSON 和 MessagePack 编码的消息都不会以该字节开头）
+pub const COMPRESSED_MARKER: u8 = 0x01;
+
+/// 对编码后超过 `threshold` 字节的世界广播做 deflate 压缩，并加上 `COMPRESSED_MARKER`
+///
+/// 其他消息原样发送。需要启用 `compression` feature。
+#[cfg(feature = "compression")]
+pub struct Compressed<C> {
+    inner: C,
+    threshold: usize,
+}
+
+#[cfg(feature = "compression")]
+impl<C: Codec> Compressed<C> {
+    pub fn new(inner: C, threshold: usize) -> Self {
+        Compressed { inner, threshold }
+    }
+}
+
+#[cfg(feature = "compression")]
+impl<C: Codec> Codec for Compressed<C> {
+    fn encode(&self, msg: &ServerMessage) -> Vec<u8> {
+        let bytes = self.inner.encode(msg);
+        if matches!(msg, ServerMessage::World { .. }) && bytes.len() > self.threshold {
+            compress(&bytes)
+        } else {
+            bytes
+        }
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<ServerMessage, CodecError> {
+        self.inner.decode(&decompress(bytes)?)
+    }
+
+    fn decode_inbound(&self, payload: &[u8]) -> Result<serde_json::Value, InboundError> {
+        self.inner.decode_inbound(payload)
+    }
+}
+
+/// 编码时把时间戳输出为 RFC 3339 字符串（便于阅读日志、对接使用 ISO 时间的系统），其余交给 `inner`
+pub struct Rfc3339Timestamps<C> {
+    inner: C,
+}
+
+impl<C: Codec> Rfc3339Timestamps<C> {
+    pub fn new(inner: C) -> Self {
+        Rfc3339Timestamps { inner }
+    }
+}
+
+impl<C: Codec> Codec for Rfc3339Timestamps<C> {
+    fn encode(&self, msg: &ServerMessage) -> Vec<u8> {
+        crate::ts_millis::with_rfc3339(true, || self.inner.encode(msg))
+    }
+
+    // 解码两种格式都接受，无需切换
+    fn decode(&self, bytes: &[u8]) -> Result<ServerMessage, CodecError> {
+        self.inner.decode(bytes)
+    }
+
+    fn decode_inbound(&self, payload: &[u8]) -> Result<serde_json::Value, InboundError> {
+        self.inner.decode_inbound(payload)
+    }
+}
+
+/// 压缩并加上标记
+#[cfg(feature = "compression")]
+pub fn compress(payload: &[u8]) -> Vec<u8> {
+    use flate2::write::DeflateEncoder;
+    use std::io::Write;
+    let mut encoder = DeflateEncoder::new(vec![COMPRESSED_MARKER], flate2::Compression::default());
+    encoder.write_all(payload).expect("writing to a Vec cannot fail");
+    encoder.finish().expect("writing to a Vec cannot fail")
+}
+
+/// 带标记的数据解压，其余原样返回
+#[cfg(feature = "compression")]
+pub fn decompress(bytes: &[u8]) -> Result<std::borrow::Cow<'_, [u8]>, CodecError> {
+    use std::io::Read;
+    match bytes.split_first() {
+        Some((&COMPRESSED_MARKER, rest)) => {
+            let mut out = Vec::new();
+            flate2::read::DeflateDecoder::new(rest)
+                .read_to_end(&mut out)
+                .map_err(|e| CodecError(e.to_string()))?;
+            Ok(out.into())
+        }
+        _ => Ok(bytes.into()),
+    }
+}
@@ -0,0 +1,446 @@
+//! 服务器配置
+
+use crate::auth::{Authenticator, NoAuth};
+use crate::codec::{Codec, Rfc3339Timestamps, WireFormat};
+use crate::i18n::MessageCatalog;
+use crate::protocol::BroadcastProjection;
+use crate::server::ONLINE_TIMEOUT_SECS;
+use crate::transport::Transport;
+use crate::{CorrectionStrategy, PhysicsMode, PlayerOrder, SuffixStrategy, DEFAULT_MAX_NAME_SUFFIX, DEFAULT_MOVEMENT_TOLERANCE};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// 服务器可调参数
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// 监听的传输（默认只有 UDP 127.0.0.1:8888；UDP 最多一个）
+    pub transports: Vec<Transport>,
+    /// 动作冷却时间（动作名 -> 同一玩家两次使用之间的最小间隔）
+    ///
+    /// 未列出的动作不做限制。
+    pub action_cooldowns: HashMap<String, Duration>,
+    /// 广播中坐标保留的小数位数（None 表示不做舍入）
+    pub coord_precision: Option<u8>,
+    /// 加入后跳过移动校验的更新次数
+    pub settle_updates: u32,
+    /// 加入后跳过移动校验的时长
+    pub settle_period: Duration,
+    /// 名字冲突时建议名的数字后缀上限（不含）
+    pub max_name_suffix: u32,
+    /// 名字冲突时建议名的后缀策略
+    pub name_suffix_strategy: SuffixStrategy,
+    /// 注册时用户名已被占用的处理方式
+    pub username_policy: UsernamePolicy,
+    /// 管理消息（如 teleport）需要携带的密钥；None 表示禁用管理消息
+    pub admin_secret: Option<String>,
+    /// 累计违规达到该次数后隔离玩家（None 表示只纠正、不隔离）
+    ///
+    /// 被隔离的玩家不再收到纠正，公开广播中的位置冻结在最后一次合法的位置，
+    /// 上报的位置只记录在影子状态中供管理员审查。
+    pub quarantine_after: Option<u32>,
+    /// 纠正审计日志文件（JSON lines，追加写入；None 表示不记录）
+    pub audit_log_path: Option<String>,
+    /// 受信任的客户端（机器人、回放、服务器驱动的实体）：更新不做移动校验，直接作为权威状态
+    pub trusted_clients: HashSet<Uuid>,
+    /// 发往客户端的消息编码格式
+    pub wire_format: WireFormat,
+    /// 发出的消息、保存的世界快照和审计日志中的时间戳使用 RFC 3339 字符串而不是毫秒数（内存中仍是毫秒数）
+    pub rfc3339_timestamps: bool,
+    /// 压缩较大的世界广播（需要启用 `compression` feature）
+    pub compress_broadcasts: bool,
+    /// 编码后超过该字节数的广播才压缩
+    pub compress_threshold_bytes: usize,
+    /// 更新在抖动缓冲中停留的时长（ZERO 表示不缓冲，按到达顺序处理）
+    pub jitter_window: Duration,
+    /// 每个玩家最多缓冲的更新数
+    pub jitter_depth: usize,
+    /// 更新合并周期：非 ZERO 时每个周期内每个玩家只应用 ts 最新的一条更新，
+    /// 并在周期结束时统一广播一次（启用后不再经过抖动缓冲）
+    pub coalesce_interval: Duration,
+    /// 固定频率的模拟周期（每秒次数）：启用后数据包只把更新放入输入队列，
+    /// 由 `ServerState::tick` 统一校验、应用、推进物理并广播（不再经过抖动缓冲和更新合并）；
+    /// None 表示逐包处理
+    pub tick_hz: Option<u32>,
+    /// 面向玩家的提示文本
+    pub messages: MessageCatalog,
+    /// 注册（含恢复会话）前校验请求中的 `token`；默认不校验
+    pub authenticator: Arc<dyn Authenticator>,
+    /// 出站故障注入（仅在启用 `chaos` feature 时生效）
+    pub chaos: ChaosConfig,
+    /// 服务器名称（局域网发现时返回）
+    pub server_name: String,
+    /// 是否响应 `discover` 消息
+    ///
+    /// 要收到广播到 255.255.255.255 的发现包，UDP 需要绑定在 0.0.0.0 上。
+    pub discovery: bool,
+    /// 宣称的玩家上限（仅用于发现信息）
+    pub max_players: Option<u32>,
+    /// 同一来源 IP 上最多绑定的玩家数，超过后拒绝新注册（None 表示不限制）
+    pub max_registrations_per_ip: Option<usize>,
+    /// 房间人数上限和空房间的回收
+    pub rooms: RoomConfig,
+    /// 超过此时长没有活动的玩家视为离线
+    pub online_timeout: Duration,
+    /// 超过此时长既没有 ping 也没有更新的连接视为已断开（None 表示只看 `online_timeout`）
+    pub keepalive_timeout: Option<Duration>,
+    /// 离线前多久发送一次 `InactivityWarning`（None 表示不提醒）
+    pub inactivity_warning: Option<Duration>,
+    /// 离线超过此时长的玩家从内存中移除（仍保留在身份存储中，可以恢复）；None 表示永不移除
+    pub evict_after: Option<Duration>,
+    /// 同一连接在此时长内以相同用户名重复注册时返回已有的注册（ZERO 表示不去重）
+    pub duplicate_register_window: Duration,
+    /// 玩家离线后为其保留用户名的时长，到期后其他玩家可以注册该名字（None 表示保留到玩家被移除）
+    ///
+    /// 原玩家在保留期内恢复会话即可取回名字；保留期过后名字被占用时改用建议名。
+    pub name_reservation: Option<Duration>,
+    /// 在线玩家从新地址恢复会话（换网/NAT 重映射）时是否必须出示 resume_token
+    pub require_resume_token: bool,
+    /// 恢复会话时客户端声明的位置与服务器记录的位置最多相距多远仍会被采用（米）
+    ///
+    /// 超出时忽略客户端的位置；服务器没有记录位置时直接采用。
+    pub max_resume_distance: f64,
+    /// 从身份存储恢复、但没有保存过位置的玩家的出生点（None 表示位置留空，直到客户端上报）
+    pub default_spawn: Option<(f64, f64, f64)>,
+    /// 注册时声明的协议版本低于此值的客户端被拒绝
+    pub min_protocol_version: u32,
+    /// 广播中玩家的输出顺序（None 表示不排序，顺序不确定）
+    pub stable_broadcast_order: Option<PlayerOrder>,
+    /// 广播中每个玩家输出的字段（None 表示完整状态）
+    pub broadcast_projection: Option<BroadcastProjection>,
+    /// 广播是否包含接收者自己控制的玩家（false 时客户端只用本地预测和纠正消息维护自己的位置）
+    pub include_self_in_broadcast: bool,
+    /// 注册时未声明 `mtu` 的客户端使用的广播分片上限（None 表示不拆分）
+    pub default_mtu: Option<usize>,
+    /// 世界状态文件（启动时加载、定期保存；None 表示只保存在内存中）
+    pub world_path: Option<String>,
+    /// UDP 传输绑定的 socket 数，每个 socket 一个接收线程
+    ///
+    /// 启用 `reuseport` feature 时用 SO_REUSEPORT 让内核在多个 socket 间分流。
+    pub udp_sockets: usize,
+    /// 处理数据包的工作线程数（固定大小，流量突增时排队而不是新建线程）
+    pub max_workers: usize,
+    /// 等待工作线程处理的数据包上限，队列满时丢弃新到的数据包
+    pub ingest_capacity: usize,
+    /// TCP 和 WebSocket 合计同时打开的连接数上限（每个连接一个线程），超出时新连接直接关闭
+    pub max_connections: usize,
+    /// UDP 接收的读超时：没有数据时接收线程最多阻塞这么久再检查停机标志
+    pub udp_recv_timeout: Duration,
+    /// TCP 连接的写超时：对端停止读取时发送最多阻塞这么久，超时后断开该连接
+    pub tcp_write_timeout: Duration,
+    /// 停机通知中建议客户端等待多久再重连
+    pub shutdown_reconnect_after: Duration,
+    /// 被踢下线的客户端收到的重连等待时间（`kicked` 中的 `retry_after_secs`）
+    pub kick_retry_after: Duration,
+    /// 停机时发送通知的最长等待时间（不可达的客户端不会拖住停机）
+    pub shutdown_notice_timeout: Duration,
+    /// 健康检查 HTTP 监听地址：`GET /health` 返回 ok，`GET /metrics` 返回 Prometheus 指标（None 表示不监听）
+    pub health_addr: Option<SocketAddr>,
+    /// 每个客户端发送队列的容量（None 表示不排队，直接发送）
+    ///
+    /// 队列满时优先丢弃最早的世界广播，回复和纠正等消息不会被丢弃。
+    pub send_queue_capacity: Option<usize>,
+    /// 发送队列中的广播只保留最新一轮：新广播入队时丢弃尚未发送的旧广播（纠正等可靠消息不受影响）
+    pub drop_stale_broadcasts: bool,
+    /// 单个数据包的最大字节数（UDP 接收缓冲区大小，也是所有传输上消息的处理上限）
+    pub max_recv_bytes: usize,
+    /// 数据包无法解析时，debug 日志中转储的最大字节数（二进制程序用 `RUST_LOG=debug` 开启）
+    pub packet_dump_bytes: usize,
+    /// 位置由客户端上报还是由服务器模拟
+    pub physics_mode: PhysicsMode,
+    /// 服务器模拟的固定步长（仅 `ServerAuthoritative` 模式）
+    pub physics_step: Duration,
+    /// 每个玩家保留的位置历史时长（按客户端 ts）
+    pub history_window: Duration,
+    /// 允许的动作名（None 表示不限制）
+    pub allowed_actions: Option<HashSet<String>>,
+    /// 动作不在 `allowed_actions` 中时的处理方式
+    pub action_policy: ActionPolicy,
+    /// 更新被拒绝时把该玩家最后的权威状态（`player_info`）连同错误一起发回给来源客户端
+    pub rebroadcast_authoritative_on_reject: bool,
+    /// 被接受的非 idle 动作立即以 `player_action` 通知其他在线客户端
+    pub broadcast_actions: bool,
+    /// 世界广播中附带各玩家的平滑往返时延（`rtt_ms`）
+    pub broadcast_rtt: bool,
+    /// 按注册/ping 时上报的 `client_ts` 估计客户端时钟偏差，并把更新的 ts 换算到服务器时间线
+    pub correct_clock_skew: bool,
+    /// 移动校验参数
+    pub movement: MovementConfig,
+    /// 玩家碰撞半径：设置后每次应用更新都把重叠的在线玩家推开到 `2 * radius`（None 表示不处理碰撞）
+    pub collision_radius: Option<f64>,
+    /// 玩家附加数据（`extra`）序列化后的最大字节数
+    pub max_extra_bytes: usize,
+    /// 世界状态落盘间隔（世界未修改时跳过写入）
+    pub save_interval: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            transports: vec![Transport::Udp(SocketAddr::from(([127, 0, 0, 1], 8888)))],
+            action_cooldowns: HashMap::new(),
+            coord_precision: None,
+            settle_updates: 0,
+            settle_period: Duration::ZERO,
+            max_name_suffix: DEFAULT_MAX_NAME_SUFFIX,
+            name_suffix_strategy: SuffixStrategy::default(),
+            username_policy: UsernamePolicy::default(),
+            admin_secret: None,
+            quarantine_after: None,
+            audit_log_path: None,
+            trusted_clients: HashSet::new(),
+            wire_format: WireFormat::default(),
+            rfc3339_timestamps: false,
+            compress_broadcasts: false,
+            compress_threshold_bytes: 512,
+            jitter_window: Duration::ZERO,
+            jitter_depth: 4,
+            coalesce_interval: Duration::ZERO,
+            tick_hz: None,
+            messages: MessageCatalog::default(),
+            authenticator: Arc::new(NoAuth),
+            chaos: ChaosConfig::default(),
+            server_name: "backend-demo".to_string(),
+            discovery: false,
+            max_players: None,
+            max_registrations_per_ip: None,
+            rooms: RoomConfig::default(),
+            online_timeout: Duration::from_secs(ONLINE_TIMEOUT_SECS),
+            keepalive_timeout: None,
+            inactivity_warning: None,
+            evict_after: None,
+            duplicate_register_window: Duration::from_secs(2),
+            require_resume_token: false,
+            name_reservation: None,
+            max_resume_distance: 10.0,
+            default_spawn: None,
+            min_protocol_version: 1,
+            stable_broadcast_order: None,
+            broadcast_projection: None,
+            include_self_in_broadcast: true,
+            default_mtu: None,
+            world_path: Some("world_state.json".to_string()),
+            udp_sockets: 1,
+            max_workers: 4,
+            ingest_capacity: 1024,
+            max_connections: 256,
+            udp_recv_timeout: Duration::from_millis(100),
+            tcp_write_timeout: Duration::from_secs(1),
+            shutdown_reconnect_after: Duration::from_secs(5),
+            kick_retry_after: Duration::from_secs(60),
+            shutdown_notice_timeout: Duration::from_millis(500),
+            health_addr: None,
+            send_queue_capacity: None,
+            drop_stale_broadcasts: false,
+            max_recv_bytes: 2048,
+            packet_dump_bytes: 256,
+            physics_mode: PhysicsMode::default(),
+            physics_step: Duration::from_millis(50),
+            history_window: Duration::from_secs(1),
+            allowed_actions: None,
+            action_policy: ActionPolicy::default(),
+            rebroadcast_authoritative_on_reject: false,
+            broadcast_actions: false,
+            broadcast_rtt: false,
+            correct_clock_skew: false,
+            movement: MovementConfig::default(),
+            collision_radius: None,
+            max_extra_bytes: 256,
+            save_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// 发往客户端的消息使用的编解码器（按 `wire_format`，启用时压缩较大的广播）
+    pub fn codec(&self) -> Box<dyn Codec> {
+        let wire = self.wire_format.codec();
+        #[cfg(feature = "compression")]
+        if self.compress_broadcasts {
+            let threshold = self.compress_threshold_bytes;
+            return match self.rfc3339_timestamps {
+                true => Box::new(crate::codec::Compressed::new(Rfc3339Timestamps::new(wire), threshold)),
+                false => Box::new(crate::codec::Compressed::new(wire, threshold)),
+            };
+        }
+        match self.rfc3339_timestamps {
+            true => Box::new(Rfc3339Timestamps::new(wire)),
+            false => Box::new(wire),
+        }
+    }
+
+    /// 模拟周期的间隔（未启用 `tick_hz` 或为 0 时返回 None）
+    pub fn tick_interval(&self) -> Option<Duration> {
+        self.tick_hz.filter(|hz| *hz > 0).map(|hz| Duration::from_secs(1) / hz)
+    }
+}
+
+/// 注册的用户名已被占用时如何处理（先注册的玩家总是保留名字）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UsernamePolicy {
+    /// 拒绝并建议一个可用的名字（默认）
+    #[default]
+    Suggest,
+    /// 直接以建议名注册，`registered` 中的 `username` 为实际分配的名字
+    AutoRename,
+    /// 只拒绝，不给出建议
+    Reject,
+}
+
+/// 不在白名单中的动作如何处理
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ActionPolicy {
+    /// 清空动作，其余字段照常更新（默认）
+    #[default]
+    Clear,
+    /// 拒绝整个更新
+    Reject,
+}
+
+/// 移动校验参数
+#[derive(Debug, Clone)]
+pub struct MovementConfig {
+    /// 基础容差（米）
+    pub tolerance: f64,
+    /// 每秒 RTT 额外放宽的容差（米/秒），0 表示不随延迟放宽
+    pub rtt_factor: f64,
+    /// 参与计算的 RTT 上限（客户端可以故意推迟回显 `echo_ts` 抬高测得的 RTT，防止借此换取无限容差）
+    pub max_rtt: Duration,
+    /// 违规移动的纠正方式
+    pub correction: CorrectionStrategy,
+    /// 是否要求实际位移方向与上报速度方向一致
+    pub require_velocity_consistency: bool,
+    /// 位移方向与速度方向允许的最大夹角（度）
+    pub max_velocity_angle: f64,
+    /// X 轴最大速度（米/秒），None 表示不单独限制
+    pub max_speed_x: Option<f64>,
+    /// Y 轴（竖直方向）最大速度（米/秒），例如不能飞行的游戏只允许跳跃的速度
+    pub max_speed_y: Option<f64>,
+    /// Z 轴最大速度（米/秒）
+    pub max_speed_z: Option<f64>,
+    /// 可疑移动先冻结观察的时长：期间的后续更新回到合理轨迹则放行，
+    /// 超过时长仍不合理才纠正（None 表示立即纠正）
+    pub suspect_hold: Option<Duration>,
+    /// 位置网格间距：设置后位置必须对齐网格点，否则纠正到最近的网格点（None 表示不限制）
+    pub grid: Option<f64>,
+    /// 判断是否对齐网格时允许的误差
+    pub grid_epsilon: f64,
+    /// 连续多少次时间戳不前进（dt <= 0）仍然放行；超过后视为篡改时间戳，拉回上一个合法位置
+    ///
+    /// None 表示一律放行（时钟抖动时 dt 可能为 0）。
+    pub non_positive_dt_allowance: Option<u32>,
+    /// 单个更新允许的最大位移（米），与时间戳和速度无关；超过时直接拒绝该位移（默认不限制）
+    ///
+    /// 在速度校验之前执行，用于立即拦截明显的瞬移。
+    pub max_delta_per_update: f64,
+    /// 按玩家学习的速度基线（None 表示不启用）
+    pub speed_profile: Option<SpeedProfileConfig>,
+    /// 合法的重生点：带 `respawn` 动作、且新位置距某个重生点（或 `default_spawn`）不超过
+    /// `spawn_radius` 的更新不做移动校验
+    pub spawn_points: Vec<(f64, f64, f64)>,
+    /// 重生位置与重生点之间允许的距离（米）
+    pub spawn_radius: f64,
+}
+
+impl Default for MovementConfig {
+    fn default() -> Self {
+        MovementConfig {
+            tolerance: DEFAULT_MOVEMENT_TOLERANCE,
+            rtt_factor: 0.0,
+            max_rtt: Duration::from_secs(1),
+            correction: CorrectionStrategy::default(),
+            require_velocity_consistency: false,
+            max_velocity_angle: 90.0,
+            max_speed_x: None,
+            max_speed_y: None,
+            max_speed_z: None,
+            suspect_hold: None,
+            grid: None,
+            grid_epsilon: 1e-6,
+            non_positive_dt_allowance: None,
+            max_delta_per_update: f64::INFINITY,
+            speed_profile: None,
+            spawn_points: Vec::new(),
+            spawn_radius: 1.0,
+        }
+    }
+}
+
+impl MovementConfig {
+    /// 各轴的速度上限（X、Y、Z）
+    pub fn axis_caps(&self) -> [Option<f64>; 3] {
+        [self.max_speed_x, self.max_speed_y, self.max_speed_z]
+    }
+
+    /// 把上报的速度按各轴上限截断
+    pub fn clamp_velocity(&self, (vx, vy, vz): (f64, f64, f64)) -> (f64, f64, f64) {
+        let clamp = |v: f64, cap: Option<f64>| cap.map_or(v, |cap| v.clamp(-cap, cap));
+        (clamp(vx, self.max_speed_x), clamp(vy, self.max_speed_y), clamp(vz, self.max_speed_z))
+    }
+
+    /// `tolerance + rtt_factor * rtt`（RTT 未知时按 0 计算）
+    pub fn effective_tolerance(&self, rtt: Option<Duration>) -> f64 {
+        let rtt = rtt.unwrap_or(Duration::ZERO).min(self.max_rtt);
+        self.tolerance + self.rtt_factor * rtt.as_secs_f64()
+    }
+}
+
+/// 房间参数（注册时的 `room` 字段）
+#[derive(Debug, Clone)]
+pub struct RoomConfig {
+    /// 每个房间最多同时在线的玩家数（None 表示不限制）
+    pub max_players: Option<usize>,
+    /// 房间内没有在线玩家且超过该时长没有活动后被回收
+    pub idle_ttl: Duration,
+}
+
+impl Default for RoomConfig {
+    fn default() -> Self {
+        RoomConfig {
+            max_players: None,
+            idle_ttl: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// 速度基线参数：预热期内的合法更新决定玩家的速度上限，预热后超出上限的移动被纠正
+#[derive(Debug, Clone, Copy)]
+pub struct SpeedProfileConfig {
+    /// 建立基线所需的合法更新次数
+    pub warmup_updates: usize,
+    /// 基线取预热样本的分位数（0.0 ~ 1.0）
+    pub percentile: f64,
+    /// 在基线之上额外允许的比例（0.5 表示基线的 1.5 倍）
+    pub margin: f64,
+}
+
+impl Default for SpeedProfileConfig {
+    fn default() -> Self {
+        SpeedProfileConfig {
+            warmup_updates: 20,
+            percentile: 0.95,
+            margin: 0.5,
+        }
+    }
+}
+
+/// 出站故障注入参数（测试客户端重连/补偿逻辑用）
+#[derive(Debug, Clone, Default)]
+pub struct ChaosConfig {
+    /// 丢弃出站数据包的概率（0.0 ~ 1.0）
+    pub drop_probability: f64,
+    /// 未丢弃的数据包在 `min_latency..=max_latency` 内随机延迟后发送
+    pub min_latency: Duration,
+    pub max_latency: Duration,
+    /// 随机数种子
+    pub seed: u64,
+}
+
+impl ChaosConfig {
+    /// 是否会影响任何数据包
+    pub fn is_enabled(&self) -> bool {
+        self.drop_probability > 0.0 || !self.max_latency.is_zero()
+    }
+}
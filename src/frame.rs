@@ -0,0 +1,94 @@
+//! 可选的数据包帧格式（CRC32 校验）
+//!
+//! 帧布局：`[MAGIC][CRC32 大端 4 字节][payload]`
+//!
+//! MAGIC 字节不可能出现在 UTF-8 JSON 的开头，所以不带帧头的纯 JSON
+//! 客户端依旧可以直接发送，`decode` 会原样放行。
+
+use std::fmt;
+
+/// 帧头魔数（0xC5 不是合法的 UTF-8 起始字节）
+pub const MAGIC: u8 = 0xC5;
+
+/// 帧头长度：魔数 1 字节 + CRC32 4 字节
+pub const HEADER_LEN: usize = 5;
+
+/// 帧解码错误
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// 有魔数但长度不足以容纳帧头
+    Truncated,
+    /// 校验和不匹配（数据包已损坏）
+    ChecksumMismatch { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Truncated => write!(f, "frame shorter than header"),
+            FrameError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "crc mismatch (expected {:08x}, got {:08x})",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+const CRC_TABLE: [u32; 256] = build_crc_table();
+
+const fn build_crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+/// 计算 CRC32（IEEE 802.3 多项式，与 zlib 的 crc32 一致）
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &b in data {
+        crc = CRC_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc ^ 0xFFFF_FFFF
+}
+
+/// 为 payload 加上帧头
+pub fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LEN + payload.len());
+    out.push(MAGIC);
+    out.extend_from_slice(&crc32(payload).to_be_bytes());
+    out.extend_from_slice(payload);
+    out
+}
+
+/// 解析数据包
+///
+/// - 不以 MAGIC 开头：视为纯 JSON，原样返回
+/// - 以 MAGIC 开头：校验 CRC32，通过则返回去掉帧头的 payload
+pub fn decode(bytes: &[u8]) -> Result<&[u8], FrameError> {
+    if bytes.first() != Some(&MAGIC) {
+        return Ok(bytes);
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err(FrameError::Truncated);
+    }
+    let expected = u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
+    let payload = &bytes[HEADER_LEN..];
+    let actual = crc32(payload);
+    if expected != actual {
+        return Err(FrameError::ChecksumMismatch { expected, actual });
+    }
+    Ok(payload)
+}
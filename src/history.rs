@@ -0,0 +1,65 @@
+//! 每个玩家最近一段时间的位置历史（用于延迟补偿的命中判定）
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use uuid::Uuid;
+
+type Position = (f64, f64, f64);
+
+/// 按客户端 ts 记录的位置样本，只保留最新样本之前 `window` 内的部分
+#[derive(Debug)]
+pub struct StateHistory {
+    window_ms: u128,
+    samples: HashMap<Uuid, VecDeque<(u128, Position)>>,
+}
+
+impl StateHistory {
+    pub fn new(window: Duration) -> Self {
+        StateHistory {
+            window_ms: window.as_millis(),
+            samples: HashMap::new(),
+        }
+    }
+
+    /// 记录一个样本；ts 不晚于最新样本的（乱序/重复）样本被忽略
+    pub fn record(&mut self, uuid: Uuid, ts: u128, pos: Position) {
+        let samples = self.samples.entry(uuid).or_default();
+        if samples.back().is_some_and(|&(last, _)| ts <= last) {
+            return;
+        }
+        samples.push_back((ts, pos));
+        // 保留窗口边界之前的最后一个样本，使窗口起点附近仍可插值
+        let oldest = ts.saturating_sub(self.window_ms);
+        while samples.get(1).is_some_and(|&(t, _)| t <= oldest) {
+            samples.pop_front();
+        }
+    }
+
+    /// 玩家在 `ts` 时刻的位置（在相邻样本间线性插值）
+    ///
+    /// `ts` 早于窗口或超出已记录的范围时返回 None。
+    pub fn position_at(&self, uuid: &Uuid, ts: u128) -> Option<Position> {
+        let samples = self.samples.get(uuid)?;
+        let &(newest, _) = samples.back()?;
+        if ts < newest.saturating_sub(self.window_ms) {
+            return None;
+        }
+        let after = samples.iter().position(|&(t, _)| t >= ts)?;
+        let (t1, p1) = samples[after];
+        if t1 == ts {
+            return Some(p1);
+        }
+        let (t0, p0) = *samples.get(after.checked_sub(1)?)?;
+        let f = (ts - t0) as f64 / (t1 - t0) as f64;
+        Some((
+            p0.0 + (p1.0 - p0.0) * f,
+            p0.1 + (p1.1 - p0.1) * f,
+            p0.2 + (p1.2 - p0.2) * f,
+        ))
+    }
+
+    /// 丢弃玩家的全部历史
+    pub fn remove(&mut self, uuid: &Uuid) {
+        self.samples.remove(uuid);
+    }
+}
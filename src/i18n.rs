@@ -0,0 +1,95 @@
+//! 面向玩家的提示文本（消息目录）
+//!
+//! 文本按 locale 查找，回退链为：完整 locale（如 "zh-TW"）→ 语言部分（"zh"）
+//! → "en"。文本中的 `{name}` 占位符在渲染时替换。
+
+use std::collections::HashMap;
+
+/// 默认语言
+pub const DEFAULT_LOCALE: &str = "en";
+
+/// 提示文本的键
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKey {
+    /// 不活动离线；占位符 `{timeout}`
+    Offline,
+    /// 用户名冲突；占位符 `{suggested}`
+    NameConflict,
+    /// 用户名已被占用（不给出建议）；占位符 `{username}`
+    NameTaken,
+    /// 移动未通过校验
+    InvalidMovement,
+    /// 上一次纠正尚未确认
+    UnacknowledgedCorrection,
+}
+
+/// 消息目录：locale -> 键 -> 文本
+#[derive(Debug, Clone)]
+pub struct MessageCatalog {
+    texts: HashMap<String, HashMap<MessageKey, String>>,
+}
+
+impl MessageCatalog {
+    /// 空目录（所有查找都回退到键名）
+    pub fn empty() -> Self {
+        MessageCatalog {
+            texts: HashMap::new(),
+        }
+    }
+
+    /// 添加或覆盖一条文本
+    pub fn insert(&mut self, locale: &str, key: MessageKey, text: impl Into<String>) {
+        self.texts
+            .entry(locale.to_lowercase())
+            .or_default()
+            .insert(key, text.into());
+    }
+
+    fn lookup(&self, locale: &str, key: MessageKey) -> Option<&str> {
+        let locale = locale.to_lowercase();
+        let language = locale.split(['-', '_']).next().unwrap_or("");
+        let chain = [locale.as_str(), language, DEFAULT_LOCALE];
+        chain
+            .iter()
+            .find_map(|l| self.texts.get(*l).and_then(|t| t.get(&key)))
+            .map(|s| s.as_str())
+    }
+
+    /// 渲染一条文本，`vars` 中的 `(名字, 值)` 替换对应的 `{名字}`
+    pub fn render(&self, locale: &str, key: MessageKey, vars: &[(&str, &str)]) -> String {
+        let Some(template) = self.lookup(locale, key) else {
+            return format!("{:?}", key);
+        };
+        let mut text = template.to_string();
+        for (name, value) in vars {
+            text = text.replace(&format!("{{{}}}", name), value);
+        }
+        text
+    }
+}
+
+impl Default for MessageCatalog {
+    /// 内置的英文和中文文本
+    fn default() -> Self {
+        let mut catalog = MessageCatalog::empty();
+        catalog.insert(
+            "en",
+            MessageKey::Offline,
+            "No activity for {timeout} seconds, going offline. Rejoin with same UUID to resume.",
+        );
+        catalog.insert("en", MessageKey::NameConflict, "Username is taken, try \"{suggested}\".");
+        catalog.insert("en", MessageKey::NameTaken, "Username \"{username}\" is taken.");
+        catalog.insert("en", MessageKey::InvalidMovement, "Movement rejected, position corrected by the server.");
+        catalog.insert(
+            "en",
+            MessageKey::UnacknowledgedCorrection,
+            "Previous correction was not acknowledged, position reset.",
+        );
+        catalog.insert("zh", MessageKey::Offline, "{timeout} 秒内没有活动，已离线。使用相同 UUID 重新加入即可恢复。");
+        catalog.insert("zh", MessageKey::NameConflict, "用户名已被占用，可以使用 \"{suggested}\"。");
+        catalog.insert("zh", MessageKey::NameTaken, "用户名 \"{username}\" 已被占用。");
+        catalog.insert("zh", MessageKey::InvalidMovement, "移动无效，位置已由服务器纠正。");
+        catalog.insert("zh", MessageKey::UnacknowledgedCorrection, "上一次纠正尚未确认，位置已重置。");
+        catalog
+    }
+}
@@ -0,0 +1,49 @@
+//! UUID 分配
+//!
+//! 新玩家的 UUID 由 `UuidGenerator` 生成，测试中可以换成可复现的实现。
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt;
+use uuid::Uuid;
+
+/// UUID 生成器
+pub trait UuidGenerator: Send {
+    fn next_uuid(&mut self) -> Uuid;
+}
+
+impl fmt::Debug for dyn UuidGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("UuidGenerator")
+    }
+}
+
+/// 随机 v4 UUID（默认）
+pub struct V4Generator;
+
+impl UuidGenerator for V4Generator {
+    fn next_uuid(&mut self) -> Uuid {
+        Uuid::new_v4()
+    }
+}
+
+/// 由固定种子生成的 v4 UUID 序列（测试用）
+///
+/// 同一种子的两个生成器产生完全相同的序列。
+pub struct SeededGenerator {
+    rng: StdRng,
+}
+
+impl SeededGenerator {
+    pub fn new(seed: u64) -> Self {
+        SeededGenerator {
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl UuidGenerator for SeededGenerator {
+    fn next_uuid(&mut self) -> Uuid {
+        uuid::Builder::from_random_bytes(self.rng.gen()).into_uuid()
+    }
+}
@@ -0,0 +1,73 @@
+//! 按客户端时间戳重排更新的抖动缓冲
+//!
+//! UDP 可能乱序到达，直接按到达顺序处理会让基于 ts 的 dt 计算产生误纠正。
+//! 每个玩家的更新先在缓冲中停留一个短窗口，再按 ts 顺序交给处理逻辑。
+
+use crate::protocol::PlayerUpdate;
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+/// 可以按客户端时间戳排序的更新
+pub trait Timestamped {
+    fn ts(&self) -> Option<u128>;
+}
+
+impl Timestamped for PlayerUpdate {
+    fn ts(&self) -> Option<u128> {
+        self.ts
+    }
+}
+
+impl Timestamped for Value {
+    fn ts(&self) -> Option<u128> {
+        self.get("ts").and_then(|x| x.as_u64()).map(u128::from)
+    }
+}
+
+/// 单个玩家的抖动缓冲
+#[derive(Debug)]
+pub struct JitterBuffer<T> {
+    window: Duration,
+    depth: usize,
+    /// (ts, 到达时间, 更新)，按 ts 升序
+    pending: Vec<(u128, Instant, T)>,
+}
+
+impl<T: Timestamped> JitterBuffer<T> {
+    /// `window`：每个更新最多停留的时长；`depth`：最多缓冲的更新数
+    pub fn new(window: Duration, depth: usize) -> Self {
+        JitterBuffer {
+            window,
+            depth: depth.max(1),
+            pending: Vec::new(),
+        }
+    }
+
+    /// 放入一个刚到达的更新（没有 ts 的更新排在最前）
+    pub fn push(&mut self, update: T, now: Instant) {
+        let ts = update.ts().unwrap_or(0);
+        let pos = self.pending.partition_point(|(t, _, _)| *t <= ts);
+        self.pending.insert(pos, (ts, now, update));
+    }
+
+    /// 取出可以处理的更新（按 ts 升序）
+    ///
+    /// 从 ts 最小的开始，依次取出已停留满窗口的更新；超过深度时强制取出。
+    /// 尚未到期的更新会挡住其后 ts 更大的更新，以保证输出有序。
+    pub fn drain_ready(&mut self, now: Instant) -> Vec<T> {
+        let mut ready = 0;
+        for (i, (_, arrived, _)) in self.pending.iter().enumerate() {
+            let overflow = self.pending.len() - i > self.depth;
+            if overflow || now.saturating_duration_since(*arrived) >= self.window {
+                ready = i + 1;
+            } else {
+                break;
+            }
+        }
+        self.pending.drain(..ready).map(|(_, _, u)| u).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
@@ -1,10 +1,152 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs;
 use std::path::Path;
 use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+pub mod anticheat;
+pub mod audit;
+pub mod auth;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod codec;
+pub mod config;
+pub mod frame;
+pub mod history;
+pub mod i18n;
+pub mod ids;
+pub mod jitter;
+pub mod metrics;
+pub mod observer;
+pub mod pool;
+pub mod protocol;
+pub mod rtt;
+pub mod runtime;
+pub mod server;
+pub mod store;
+pub mod sweep;
+pub mod transport;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+/// 时间戳在线上按 u64 编码
+///
+/// serde 的内部标签枚举（`ServerMessage`）在反序列化时会先缓冲内容，
+/// 而缓冲不支持 u128；毫秒时间戳用 u64 足够。
+///
+/// 在 `with_rfc3339(true, ..)` 内序列化时输出 RFC 3339 字符串（UTC，毫秒精度）；
+/// 反序列化两种格式都接受，内存中始终是毫秒数。
+pub(crate) mod ts_millis {
+    use chrono::{DateTime, SecondsFormat};
+    use serde::de::{self, Visitor};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::cell::Cell;
+    use std::fmt;
+
+    thread_local! {
+        static RFC3339: Cell<bool> = const { Cell::new(false) };
+    }
+
+    /// 在 `f` 执行期间（当前线程）按 `enabled` 选择时间戳的输出格式
+    pub fn with_rfc3339<R>(enabled: bool, f: impl FnOnce() -> R) -> R {
+        struct Restore(bool);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                RFC3339.with(|c| c.set(self.0));
+            }
+        }
+        let _restore = Restore(RFC3339.with(|c| c.replace(enabled)));
+        f()
+    }
+
+    /// Unix 毫秒时间戳
+    struct Millis(u64);
+
+    impl Serialize for Millis {
+        fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            let formatted = RFC3339
+                .with(|c| c.get())
+                .then(|| i64::try_from(self.0).ok().and_then(DateTime::from_timestamp_millis))
+                .flatten();
+            match formatted {
+                Some(t) => s.serialize_str(&t.to_rfc3339_opts(SecondsFormat::Millis, true)),
+                None => s.serialize_u64(self.0),
+            }
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Millis {
+        fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+            struct MillisVisitor;
+
+            impl Visitor<'_> for MillisVisitor {
+                type Value = Millis;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    f.write_str("millis since epoch or an RFC 3339 timestamp")
+                }
+
+                fn visit_u64<E: de::Error>(self, v: u64) -> Result<Millis, E> {
+                    Ok(Millis(v))
+                }
+
+                fn visit_i64<E: de::Error>(self, v: i64) -> Result<Millis, E> {
+                    u64::try_from(v).map(Millis).map_err(|_| E::custom("timestamp before the epoch"))
+                }
+
+                fn visit_str<E: de::Error>(self, v: &str) -> Result<Millis, E> {
+                    let t = DateTime::parse_from_rfc3339(v).map_err(E::custom)?;
+                    self.visit_i64(t.timestamp_millis())
+                }
+            }
+
+            d.deserialize_any(MillisVisitor)
+        }
+    }
+
+    pub fn serialize<S: Serializer>(ts: &Option<u128>, s: S) -> Result<S::Ok, S::Error> {
+        ts.map(|v| Millis(u64::try_from(v).unwrap_or(u64::MAX))).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u128>, D::Error> {
+        Ok(Option::<Millis>::deserialize(d)?.map(|m| u128::from(m.0)))
+    }
+
+    /// 解析客户端原样带回的时间戳（毫秒数或 RFC 3339 字符串）
+    pub fn from_value(v: &serde_json::Value) -> Option<u64> {
+        Millis::deserialize(v).ok().map(|m| m.0)
+    }
+
+    /// 必填的 u64 时间戳
+    pub mod required {
+        use super::Millis;
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        pub fn serialize<S: Serializer>(ts: &u64, s: S) -> Result<S::Ok, S::Error> {
+            Millis(*ts).serialize(s)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+            Ok(Millis::deserialize(d)?.0)
+        }
+    }
+
+    /// 可选的 u64 时间戳
+    pub mod optional {
+        use super::Millis;
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        pub fn serialize<S: Serializer>(ts: &Option<u64>, s: S) -> Result<S::Ok, S::Error> {
+            ts.map(Millis).serialize(s)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+            Ok(Option::<Millis>::deserialize(d)?.map(|m| m.0))
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct PlayerState {
     pub uuid: Uuid,
     pub username: String,
@@ -13,6 +155,7 @@ pub struct PlayerState {
     pub y: Option<f64>,
     pub z: Option<f64>,
     // timestamp provided by client (millis since epoch)
+    #[serde(default, with = "ts_millis")]
     pub ts: Option<u128>,
     // rotation (Euler)
     pub rx: Option<f64>,
@@ -24,43 +167,419 @@ pub struct PlayerState {
     pub vz: Option<f64>,
     // optional action field for future use
     pub action: Option<String>,
+    /// 游戏自定义的附加数据（队伍、皮肤、分数等），原样广播
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extra: Option<serde_json::Value>,
+}
+
+impl PlayerState {
+    /// 创建一个只有身份信息、其余字段均为空的玩家状态
+    pub fn new(uuid: Uuid, username: impl Into<String>) -> Self {
+        PlayerState {
+            uuid,
+            username: username.into(),
+            x: None,
+            y: None,
+            z: None,
+            ts: None,
+            rx: None,
+            ry: None,
+            rz: None,
+            vx: None,
+            vy: None,
+            vz: None,
+            action: None,
+            extra: None,
+        }
+    }
+
+    /// 设置位置
+    pub fn with_position(mut self, x: f64, y: f64, z: f64) -> Self {
+        self.x = Some(x);
+        self.y = Some(y);
+        self.z = Some(z);
+        self
+    }
+
+    /// 设置旋转（欧拉角）
+    pub fn with_rotation(mut self, rx: f64, ry: f64, rz: f64) -> Self {
+        self.rx = Some(rx);
+        self.ry = Some(ry);
+        self.rz = Some(rz);
+        self
+    }
+
+    /// 设置速度
+    pub fn with_velocity(mut self, vx: f64, vy: f64, vz: f64) -> Self {
+        self.vx = Some(vx);
+        self.vy = Some(vy);
+        self.vz = Some(vz);
+        self
+    }
+
+    /// 设置时间戳（毫秒）
+    pub fn with_ts(mut self, ts: u128) -> Self {
+        self.ts = Some(ts);
+        self
+    }
+
+    /// 设置动作
+    pub fn with_action(mut self, action: impl Into<String>) -> Self {
+        self.action = Some(action.into());
+        self
+    }
+
+    /// 设置附加数据
+    pub fn with_extra(mut self, extra: serde_json::Value) -> Self {
+        self.extra = Some(extra);
+        self
+    }
+
+    /// 合并一次更新中出现的字段（缺失的字段保持原值），返回状态是否发生了变化
+    pub fn apply_update(&mut self, update: &protocol::PlayerUpdate) -> bool {
+        fn merge<T: Clone + PartialEq>(field: &mut Option<T>, incoming: &Option<T>) -> bool {
+            match incoming {
+                Some(v) if field.as_ref() != Some(v) => {
+                    *field = Some(v.clone());
+                    true
+                }
+                _ => false,
+            }
+        }
+        // 用 `|` 而不是 `||`，保证每个字段都会被合并
+        merge(&mut self.x, &update.x)
+            | merge(&mut self.y, &update.y)
+            | merge(&mut self.z, &update.z)
+            | merge(&mut self.ts, &update.ts)
+            | merge(&mut self.rx, &update.rx)
+            | merge(&mut self.ry, &update.ry)
+            | merge(&mut self.rz, &update.rz)
+            | merge(&mut self.vx, &update.vx)
+            | merge(&mut self.vy, &update.vy)
+            | merge(&mut self.vz, &update.vz)
+            | merge(&mut self.action, &update.action)
+            | merge(&mut self.extra, &update.extra)
+    }
+}
+
+/// 将玩家的位置/旋转/速度四舍五入到 `precision` 位小数
+///
+/// 只用于序列化到网络上的副本，不应作用于服务器保存的权威状态。
+pub fn round_player(p: &PlayerState, precision: u8) -> PlayerState {
+    let factor = 10f64.powi(precision as i32);
+    let round = |v: Option<f64>| v.map(|v| (v * factor).round() / factor);
+    PlayerState {
+        x: round(p.x),
+        y: round(p.y),
+        z: round(p.z),
+        rx: round(p.rx),
+        ry: round(p.ry),
+        rz: round(p.rz),
+        vx: round(p.vx),
+        vy: round(p.vy),
+        vz: round(p.vz),
+        ..p.clone()
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct WorldState {
     pub players: HashMap<Uuid, PlayerState>,
+    /// 玩家以外的实体（投射物、可拾取物品）
+    #[serde(default)]
+    pub entities: HashMap<Uuid, Entity>,
+}
+
+impl WorldState {
+    /// 按给定顺序排列的所有玩家
+    pub fn to_sorted_vec(&self, order: PlayerOrder) -> Vec<&PlayerState> {
+        let mut players: Vec<&PlayerState> = self.players.values().collect();
+        sort_players(&mut players, order);
+        players
+    }
+
+    /// 移除在 `now_ms`（服务器毫秒时间）之前到期的实体，返回被移除的实体
+    pub fn expire_entities(&mut self, now_ms: u64) -> Vec<Uuid> {
+        let expired: Vec<Uuid> = self
+            .entities
+            .iter()
+            .filter(|(_, e)| e.expires_at().is_some_and(|t| t <= now_ms))
+            .map(|(uuid, _)| *uuid)
+            .collect();
+        for uuid in &expired {
+            self.entities.remove(uuid);
+        }
+        expired
+    }
+
+    /// 最早到期的实体的到期时间
+    pub fn next_entity_expiry(&self) -> Option<u64> {
+        self.entities.values().filter_map(Entity::expires_at).min()
+    }
+
+    /// 保存世界状态到文件（带 `version` 字段）
+    pub fn save_to_file(&self, path: &str) -> std::io::Result<()> {
+        save_versioned(self, path)
+    }
+
+    /// 从文件加载世界状态，旧版本的格式升级到当前版本；文件不存在时返回空世界
+    pub fn load_from_file(path: &str) -> std::io::Result<Self> {
+        if !Path::new(path).exists() {
+            return Ok(WorldState { players: HashMap::new(), entities: HashMap::new() });
+        }
+        let (version, value) = read_versioned(&fs::read_to_string(path)?, "world state")?;
+        Self::migrate(version, value)
+    }
+
+    /// 把 `version` 版本的世界状态升级到当前格式
+    ///
+    /// v1 与当前格式只差 `version` 字段。
+    pub fn migrate(version: u32, value: serde_json::Value) -> std::io::Result<Self> {
+        match version {
+            1 | SNAPSHOT_VERSION => serde_json::from_value(value).map_err(invalid_data),
+            v => Err(unsupported_version("world state", v)),
+        }
+    }
+}
+
+/// 由服务器生成的非玩家实体：不做移动校验，可以设置到期时间（服务器毫秒时间）
+///
+/// 玩家仍单独保存在 `WorldState::players` 中。
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Entity {
+    /// 投射物：从 `spawned_at` 时的 `position` 按 `velocity` 匀速飞行，客户端自行外推
+    Projectile {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        owner: Option<Uuid>,
+        position: (f64, f64, f64),
+        velocity: (f64, f64, f64),
+        #[serde(with = "ts_millis::required")]
+        spawned_at: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none", with = "ts_millis::optional")]
+        expires_at: Option<u64>,
+    },
+    /// 可拾取物品
+    Pickup {
+        item: String,
+        position: (f64, f64, f64),
+        #[serde(default, skip_serializing_if = "Option::is_none", with = "ts_millis::optional")]
+        expires_at: Option<u64>,
+    },
+}
+
+impl Entity {
+    /// 到期时间（None 表示一直存在）
+    pub fn expires_at(&self) -> Option<u64> {
+        match self {
+            Entity::Projectile { expires_at, .. } | Entity::Pickup { expires_at, .. } => *expires_at,
+        }
+    }
+}
+
+/// 玩家列表的排序方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PlayerOrder {
+    #[default]
+    ByUuid,
+    /// 按用户名，同名时按 UUID
+    ByUsername,
+}
+
+/// 按 `order` 原地排序玩家列表
+pub fn sort_players(players: &mut [&PlayerState], order: PlayerOrder) {
+    match order {
+        PlayerOrder::ByUuid => players.sort_by_key(|p| p.uuid),
+        PlayerOrder::ByUsername => players.sort_by(|a, b| (&a.username, a.uuid).cmp(&(&b.username, b.uuid))),
+    }
+}
+
+/// 位置由谁决定
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PhysicsMode {
+    /// 客户端上报位置，服务器只做校验（默认）
+    #[default]
+    ClientAuthoritative,
+    /// 服务器按固定步长根据速度积分位置，忽略客户端上报的位置
+    ServerAuthoritative,
+}
+
+/// 按速度把一个玩家的位置推进 `dt`
+///
+/// 没有速度的玩家不动；有速度但还没有位置的玩家从原点开始。
+pub fn step_player(p: &mut PlayerState, dt: std::time::Duration) {
+    if p.vx.is_none() && p.vy.is_none() && p.vz.is_none() {
+        return;
+    }
+    let secs = dt.as_secs_f64();
+    p.x = Some(p.x.unwrap_or(0.0) + p.vx.unwrap_or(0.0) * secs);
+    p.y = Some(p.y.unwrap_or(0.0) + p.vy.unwrap_or(0.0) * secs);
+    p.z = Some(p.z.unwrap_or(0.0) + p.vz.unwrap_or(0.0) * secs);
+}
+
+/// 把世界中所有玩家推进 `dt`
+pub fn step(world: &mut WorldState, dt: std::time::Duration) {
+    for player in world.players.values_mut() {
+        step_player(player, dt);
+    }
+}
+
+/// 把重叠的玩家沿中心连线推开到恰好 `2 * radius` 的距离，两人各移动一半，
+/// 返回被移动过的玩家（按 uuid 排序）
+///
+/// 按 uuid 顺序逐对处理；位置完全重合时沿 X 轴分开（uuid 较小的一方向 -X）。
+/// 没有完整位置的玩家不参与。
+pub fn resolve_collisions(world: &mut WorldState, radius: f64) -> Vec<Uuid> {
+    let min_dist = 2.0 * radius;
+    let mut uuids: Vec<Uuid> = world
+        .players
+        .values()
+        .filter(|p| p.x.is_some() && p.y.is_some() && p.z.is_some())
+        .map(|p| p.uuid)
+        .collect();
+    uuids.sort();
+    let pos = |p: &PlayerState| (p.x.unwrap_or(0.0), p.y.unwrap_or(0.0), p.z.unwrap_or(0.0));
+    let mut moved = BTreeSet::new();
+    for i in 0..uuids.len() {
+        for j in i + 1..uuids.len() {
+            let a = pos(&world.players[&uuids[i]]);
+            let b = pos(&world.players[&uuids[j]]);
+            let (dx, dy, dz) = (b.0 - a.0, b.1 - a.1, b.2 - a.2);
+            let dist = (dx * dx + dy * dy + dz * dz).sqrt();
+            if dist >= min_dist {
+                continue;
+            }
+            let (nx, ny, nz) = if dist > 0.0 { (dx / dist, dy / dist, dz / dist) } else { (1.0, 0.0, 0.0) };
+            let push = (min_dist - dist) / 2.0;
+            let shift = |p: &mut PlayerState, sign: f64| {
+                p.x = p.x.map(|v| v + sign * nx * push);
+                p.y = p.y.map(|v| v + sign * ny * push);
+                p.z = p.z.map(|v| v + sign * nz * push);
+            };
+            world.players.entry(uuids[i]).and_modify(|p| shift(p, -1.0));
+            world.players.entry(uuids[j]).and_modify(|p| shift(p, 1.0));
+            moved.insert(uuids[i]);
+            moved.insert(uuids[j]);
+        }
+    }
+    moved.into_iter().collect()
+}
+
+/// 兴趣区域过滤：位置在 `center` 的 `radius` 范围内（含边界）的玩家
+///
+/// 没有完整位置的玩家不在任何区域内。
+pub fn players_within(
+    players: &HashMap<Uuid, PlayerState>,
+    (cx, cy, cz): (f64, f64, f64),
+    radius: f64,
+) -> HashMap<Uuid, PlayerState> {
+    players
+        .iter()
+        .filter(|(_, p)| match (p.x, p.y, p.z) {
+            (Some(x), Some(y), Some(z)) => {
+                let (dx, dy, dz) = (x - cx, y - cy, z - cz);
+                dx * dx + dy * dy + dz * dz <= radius * radius
+            }
+            _ => false,
+        })
+        .map(|(k, v)| (*k, v.clone()))
+        .collect()
+}
+
+/// 持久化文件（世界状态、UUID 存储）当前的格式版本
+///
+/// 文件顶层的 `version` 字段记录写入时的版本，没有该字段的文件视为 v1。
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+#[derive(Serialize)]
+struct Versioned<'a, T> {
+    version: u32,
+    #[serde(flatten)]
+    data: &'a T,
+}
+
+fn save_versioned<T: Serialize>(data: &T, path: &str) -> std::io::Result<()> {
+    let json = serde_json::to_string_pretty(&Versioned { version: SNAPSHOT_VERSION, data }).map_err(invalid_data)?;
+    fs::write(path, json)
+}
+
+/// 解析持久化文件，返回其版本和去掉 `version` 字段后的内容；比当前版本新的文件直接报错
+fn read_versioned(content: &str, what: &str) -> std::io::Result<(u32, serde_json::Value)> {
+    let mut value: serde_json::Value = serde_json::from_str(content).map_err(invalid_data)?;
+    let version = match value.as_object_mut().and_then(|obj| obj.remove("version")) {
+        None => 1,
+        Some(v) => v
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| invalid_data(format!("{} version is not a non-negative integer", what)))?,
+    };
+    if version > SNAPSHOT_VERSION {
+        return Err(unsupported_version(what, version));
+    }
+    Ok((version, value))
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
+
+/// 文件版本比本程序新（或未知）：不能当作空数据处理，否则保存时会覆盖掉它
+fn unsupported_version(what: &str, version: u32) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("{} version {} is not supported (current version {})", what, version, SNAPSHOT_VERSION),
+    )
 }
 
 /// UUID 持久化存储结构
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct UuidStorage {
     /// 记录所有见过的 UUID 及其对应的用户名
     pub uuids: HashMap<Uuid, String>,
+    /// 客户端自带的 ID -> UUID
+    #[serde(default)]
+    pub client_ids: HashMap<String, Uuid>,
+    /// 玩家被移出内存时的最后位置
+    #[serde(default)]
+    pub positions: HashMap<Uuid, (f64, f64, f64)>,
 }
 
 impl UuidStorage {
-    /// 从文件加载 UUID 存储
+    /// 从文件加载 UUID 存储，旧版本的格式升级到当前版本
+    ///
+    /// 无法解析的文件从空存储开始；版本比当前新的文件返回 `Unsupported` 错误。
     pub fn load_from_file(path: &str) -> std::io::Result<Self> {
-        if Path::new(path).exists() {
-            let content = fs::read_to_string(path)?;
-            match serde_json::from_str(&content) {
-                Ok(storage) => Ok(storage),
-                Err(_) => Ok(UuidStorage {
-                    uuids: HashMap::new(),
-                }),
+        if !Path::new(path).exists() {
+            return Ok(UuidStorage::default());
+        }
+        let content = fs::read_to_string(path)?;
+        match read_versioned(&content, "uuid storage") {
+            Ok((version, value)) => Ok(Self::migrate(version, value).unwrap_or_default()),
+            Err(e) if e.kind() == std::io::ErrorKind::Unsupported => Err(e),
+            Err(_) => Ok(UuidStorage::default()),
+        }
+    }
+
+    /// 把 `version` 版本的 UUID 存储升级到当前格式
+    ///
+    /// v1 只有 `uuids`（UUID -> 用户名），其余字段取默认值。
+    pub fn migrate(version: u32, value: serde_json::Value) -> std::io::Result<Self> {
+        match version {
+            1 => {
+                let uuids = value.get("uuids").cloned().unwrap_or_default();
+                Ok(UuidStorage {
+                    uuids: serde_json::from_value(uuids).map_err(invalid_data)?,
+                    ..UuidStorage::default()
+                })
             }
-        } else {
-            Ok(UuidStorage {
-                uuids: HashMap::new(),
-            })
+            SNAPSHOT_VERSION => serde_json::from_value(value).map_err(invalid_data),
+            v => Err(unsupported_version("uuid storage", v)),
         }
     }
 
-    /// 保存 UUID 存储到文件
+    /// 保存 UUID 存储到文件（带 `version` 字段）
     pub fn save_to_file(&self, path: &str) -> std::io::Result<()> {
-        let json = serde_json::to_string_pretty(&self)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-        fs::write(path, json)
+        save_versioned(self, path)
     }
 
     /// 添加或更新 UUID
@@ -79,20 +598,125 @@ impl UuidStorage {
     }
 }
 
+/// 当前墙钟时间（毫秒，Unix 纪元起）
+///
+/// 所有下发给客户端的服务器时间戳（`server_ts`）都取自这里。
+pub fn now_millis() -> u64 {
+    chrono::Utc::now().timestamp_millis().max(0) as u64
+}
+
+/// 默认的用户名后缀上限（不含）
+pub const DEFAULT_MAX_NAME_SUFFIX: u32 = 10000;
+
+/// 生成候选用户名后缀的策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SuffixStrategy {
+    /// 依次尝试 "base_1", "base_2", ...（默认）
+    #[default]
+    Sequential,
+    /// 在后缀范围内随机尝试，多次碰撞后退回顺序查找
+    RandomInRange,
+    /// 追加一个短随机串，如 "base_3f9a1c"，与后缀范围无关
+    RandomToken,
+}
+
+/// 随机策略的最大尝试次数
+const RANDOM_NAME_ATTEMPTS: usize = 32;
+
 /// 生成唯一的用户名（当请求的名字已被占用时）
 /// 
 /// 算法：依次尝试 "base_1", "base_2", ... "base_9999"，直到找到未被占用的名字
 /// 如果全部用尽，使用 "base_fallback" 作为最后的备选
 pub fn generate_unique_name(world: &HashMap<Uuid, PlayerState>, base: &str) -> String {
-    for i in 1..10000 {
+    generate_unique_name_with(world, base, DEFAULT_MAX_NAME_SUFFIX, SuffixStrategy::Sequential)
+}
+
+/// 按指定策略生成唯一用户名；数字后缀取值范围为 `1..max_suffix`
+pub fn generate_unique_name_with(
+    world: &HashMap<Uuid, PlayerState>,
+    base: &str,
+    max_suffix: u32,
+    strategy: SuffixStrategy,
+) -> String {
+    let taken: HashSet<&str> = world.values().map(|p| p.username.as_str()).collect();
+    generate_unique_name_by(|name| taken.contains(name), base, max_suffix, strategy)
+}
+
+/// 同 `generate_unique_name_with`，名字是否被占用由 `is_taken` 判定
+pub fn generate_unique_name_by(
+    is_taken: impl Fn(&str) -> bool,
+    base: &str,
+    max_suffix: u32,
+    strategy: SuffixStrategy,
+) -> String {
+    use rand::Rng;
+
+    let mut rng = rand::thread_rng();
+    match strategy {
+        SuffixStrategy::Sequential => {}
+        SuffixStrategy::RandomInRange => {
+            if max_suffix > 1 {
+                for _ in 0..RANDOM_NAME_ATTEMPTS {
+                    let candidate = format!("{}_{}", base, rng.gen_range(1..max_suffix));
+                    if !is_taken(&candidate) {
+                        return candidate;
+                    }
+                }
+            }
+        }
+        SuffixStrategy::RandomToken => {
+            for _ in 0..RANDOM_NAME_ATTEMPTS {
+                let candidate = format!("{}_{:06x}", base, rng.gen::<u32>() & 0xff_ffff);
+                if !is_taken(&candidate) {
+                    return candidate;
+                }
+            }
+        }
+    }
+
+    for i in 1..max_suffix {
         let candidate = format!("{}_{}", base, i);
-        if !world.values().any(|p| p.username == candidate) {
+        if !is_taken(&candidate) {
             return candidate;
         }
     }
     format!("{}_fallback", base)
 }
 
+/// 纠正 nonce 的取值范围：JavaScript 的 Number 只能精确表示 2^53 以内的整数，
+/// 更大的值经浏览器客户端回显后会变，永远无法确认
+pub const MAX_CORRECTION_NONCE: u64 = (1 << 53) - 1;
+
+/// 为玩家发放一个新的纠正 nonce（不超过 `MAX_CORRECTION_NONCE`），并记为待确认
+///
+/// 客户端必须在后续 update 中通过 `ack` 字段回显该 nonce，
+/// 服务器才会继续信任它上报的移动。
+pub fn issue_correction_nonce(pending: &mut HashMap<Uuid, u64>, uuid: Uuid) -> u64 {
+    let nonce = rand::random::<u64>() & MAX_CORRECTION_NONCE;
+    pending.insert(uuid, nonce);
+    nonce
+}
+
+/// 检查一次 update 是否确认了待处理的纠正
+///
+/// - 没有待确认的纠正：返回 true
+/// - `ack` 与待确认的 nonce 一致：清除记录并返回 true
+/// - 否则返回 false（调用方应再次纠正）
+pub fn acknowledges_correction(
+    pending: &mut HashMap<Uuid, u64>,
+    uuid: &Uuid,
+    ack: Option<u64>,
+) -> bool {
+    match pending.get(uuid) {
+        None => true,
+        Some(&nonce) if ack == Some(nonce) => {
+            pending.remove(uuid);
+            true
+        }
+        Some(_) => false,
+    }
+}
+
 /// 位置验证结果
 #[derive(Debug, Clone)]
 pub struct MovementValidation {
@@ -121,6 +745,7 @@ pub struct MovementValidation {
 /// 返回：
 /// - 若验证通过：is_valid=true，无纠正坐标
 /// - 若检测到违规：is_valid=false，包含纠正后的坐标
+#[allow(clippy::too_many_arguments)]
 pub fn validate_movement(
     prev_x: f64,
     prev_y: f64,
@@ -134,15 +759,153 @@ pub fn validate_movement(
     vy: f64,
     vz: f64,
 ) -> MovementValidation {
-    const TOLERANCE: f64 = 0.5; // 米
+    validate_movement_with_tolerance(
+        DEFAULT_MOVEMENT_TOLERANCE,
+        prev_x,
+        prev_y,
+        prev_z,
+        prev_ts,
+        new_x,
+        new_y,
+        new_z,
+        new_ts,
+        vx,
+        vy,
+        vz,
+    )
+}
+
+/// 检测到违规移动后如何给出纠正位置
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CorrectionStrategy {
+    /// 直接拉回期望位置（默认）
+    #[default]
+    Snap,
+    /// 从上报位置向期望位置移动一个比例（0.0 ~ 1.0，1.0 等同于 Snap）
+    Nudge(f64),
+    /// 保留上报的移动方向，把位移长度截断到允许的最大距离（期望位移 + 容差）
+    ClampVelocity,
+}
+
+/// 按策略计算纠正后的位置
+///
+/// `expected` 是 `validate_movement` 给出的期望位置，`tolerance` 与校验时使用的容差相同。
+pub fn apply_correction(
+    strategy: CorrectionStrategy,
+    tolerance: f64,
+    prev: (f64, f64, f64),
+    actual: (f64, f64, f64),
+    expected: (f64, f64, f64),
+) -> (f64, f64, f64) {
+    match strategy {
+        CorrectionStrategy::Snap => expected,
+        CorrectionStrategy::Nudge(factor) => {
+            let f = factor.clamp(0.0, 1.0);
+            (
+                actual.0 + (expected.0 - actual.0) * f,
+                actual.1 + (expected.1 - actual.1) * f,
+                actual.2 + (expected.2 - actual.2) * f,
+            )
+        }
+        CorrectionStrategy::ClampVelocity => {
+            let dist = |a: (f64, f64, f64), b: (f64, f64, f64)| {
+                ((b.0 - a.0).powi(2) + (b.1 - a.1).powi(2) + (b.2 - a.2).powi(2)).sqrt()
+            };
+            let actual_dist = dist(prev, actual);
+            if actual_dist == 0.0 {
+                return prev;
+            }
+            let scale = ((dist(prev, expected) + tolerance) / actual_dist).min(1.0);
+            (
+                prev.0 + (actual.0 - prev.0) * scale,
+                prev.1 + (actual.1 - prev.1) * scale,
+                prev.2 + (actual.2 - prev.2) * scale,
+            )
+        }
+    }
+}
+
+/// 把位置对齐到间距为 `grid` 的最近网格点（`grid` 不为正时原样返回）
+pub fn snap_to_grid(pos: (f64, f64, f64), grid: f64) -> (f64, f64, f64) {
+    if grid <= 0.0 {
+        return pos;
+    }
+    let snap = |v: f64| (v / grid).round() * grid;
+    (snap(pos.0), snap(pos.1), snap(pos.2))
+}
+
+/// 位置的每个轴是否都在最近网格点的 `epsilon` 范围内
+pub fn on_grid(pos: (f64, f64, f64), grid: f64, epsilon: f64) -> bool {
+    let snapped = snap_to_grid(pos, grid);
+    (pos.0 - snapped.0).abs() <= epsilon && (pos.1 - snapped.1).abs() <= epsilon && (pos.2 - snapped.2).abs() <= epsilon
+}
+
+/// 实际位移方向与上报速度方向是否一致
+///
+/// 位移不超过 `tolerance`（原地抖动）时视为一致；否则速度不能为零，
+/// 且两个向量的夹角不能超过 `max_angle_deg`（90 度即只检查点积符号）。
+pub fn velocity_consistent(
+    max_angle_deg: f64,
+    tolerance: f64,
+    delta: (f64, f64, f64),
+    velocity: (f64, f64, f64),
+) -> bool {
+    let len = |v: (f64, f64, f64)| (v.0 * v.0 + v.1 * v.1 + v.2 * v.2).sqrt();
+    let dist = len(delta);
+    if dist <= tolerance {
+        return true;
+    }
+    let speed = len(velocity);
+    if speed == 0.0 {
+        return false;
+    }
+    let cos = (delta.0 * velocity.0 + delta.1 * velocity.1 + delta.2 * velocity.2) / (dist * speed);
+    cos >= max_angle_deg.to_radians().cos() - 1e-9
+}
+
+/// 按轴限速：某轴的位移超过 `cap * dt + tolerance` 时把该轴截断到 `cap * dt`
+///
+/// 返回截断后的位置；所有轴都未超限（或都未设上限）时返回 None。
+pub fn clamp_axes(
+    caps: [Option<f64>; 3],
+    tolerance: f64,
+    dt: f64,
+    prev: (f64, f64, f64),
+    actual: (f64, f64, f64),
+) -> Option<(f64, f64, f64)> {
+    let clamp = |cap: Option<f64>, prev: f64, actual: f64| match cap {
+        Some(cap) if (actual - prev).abs() > cap * dt + tolerance => (prev + (actual - prev).signum() * cap * dt, true),
+        _ => (actual, false),
+    };
+    let (x, cx) = clamp(caps[0], prev.0, actual.0);
+    let (y, cy) = clamp(caps[1], prev.1, actual.1);
+    let (z, cz) = clamp(caps[2], prev.2, actual.2);
+    (cx || cy || cz).then_some((x, y, z))
+}
+
+/// 默认的移动容差（米）
+pub const DEFAULT_MOVEMENT_TOLERANCE: f64 = 0.5;
+
+/// 与 `validate_movement` 相同，但使用给定的容差（米）
+#[allow(clippy::too_many_arguments)]
+pub fn validate_movement_with_tolerance(
+    tolerance: f64,
+    prev_x: f64,
+    prev_y: f64,
+    prev_z: f64,
+    prev_ts: u128,
+    new_x: f64,
+    new_y: f64,
+    new_z: f64,
+    new_ts: u128,
+    vx: f64,
+    vy: f64,
+    vz: f64,
+) -> MovementValidation {
     const MAX_DT_MS: u128 = 60000; // 60秒
 
     // 计算时间差
-    let dt_ms = if new_ts > prev_ts {
-        new_ts - prev_ts
-    } else {
-        0
-    };
+    let dt_ms = new_ts.saturating_sub(prev_ts);
 
     // 时间差必须在合理范围内
     if dt_ms == 0 || dt_ms >= MAX_DT_MS {
@@ -169,7 +932,7 @@ pub fn validate_movement(
     let actual_dist = (dx * dx + dy * dy + dz * dz).sqrt();
 
     // 检查是否违规
-    if actual_dist > expect_dist + TOLERANCE {
+    if actual_dist > expect_dist + tolerance {
         // 纠正为期望位置
         let corrected_x = prev_x + expect_dx;
         let corrected_y = prev_y + expect_dy;
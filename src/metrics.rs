@@ -0,0 +1,301 @@
+//! 运行计数器与 Prometheus 文本格式导出
+//!
+//! 计数器保存在 `ServerState` 中（随状态锁保护），由运行时在处理每个数据包后更新；
+//! 启用 `health_addr` 时可以通过 `GET /metrics` 抓取。
+
+use crate::protocol::ServerMessage;
+use crate::server::{HandlerError, Outgoing};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// 延迟直方图各桶的上界（微秒），最后还有一个 +Inf 桶
+pub const LATENCY_BUCKETS_US: [u64; 10] = [50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 50_000, 100_000];
+
+/// 固定分桶的延迟直方图，计数器为原子量，可以在不持有状态锁时记录
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    /// 每个桶的样本数（不累计），下标与 `LATENCY_BUCKETS_US` 对应，最后一个为 +Inf
+    buckets: [AtomicU64; LATENCY_BUCKETS_US.len() + 1],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        LatencyHistogram::default()
+    }
+
+    /// 记录一个样本
+    pub fn record(&self, elapsed: Duration) {
+        let us = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        let bucket = LATENCY_BUCKETS_US.iter().position(|&bound| us <= bound).unwrap_or(LATENCY_BUCKETS_US.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+    }
+
+    /// 样本总数
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// 所有样本之和
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_us.load(Ordering::Relaxed))
+    }
+
+    /// 每个桶的样本数（不累计），最后一个为 +Inf 桶
+    pub fn bucket_counts(&self) -> Vec<u64> {
+        self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect()
+    }
+
+    /// 分位数 `q`（0.0 ~ 1.0）所在桶的上界；没有样本时为 None，落在 +Inf 桶时为 `Duration::MAX`
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let counts = self.bucket_counts();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(LATENCY_BUCKETS_US.get(i).map_or(Duration::MAX, |&us| Duration::from_micros(us)));
+            }
+        }
+        Some(Duration::MAX)
+    }
+}
+
+impl Clone for LatencyHistogram {
+    fn clone(&self) -> Self {
+        let copy = LatencyHistogram::new();
+        for (dst, src) in copy.buckets.iter().zip(&self.buckets) {
+            dst.store(src.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        copy.count.store(self.count(), Ordering::Relaxed);
+        copy.sum_us.store(self.sum_us.load(Ordering::Relaxed), Ordering::Relaxed);
+        copy
+    }
+}
+
+impl PartialEq for LatencyHistogram {
+    fn eq(&self, other: &Self) -> bool {
+        self.bucket_counts() == other.bucket_counts() && self.sum() == other.sum()
+    }
+}
+
+/// 忙碌的工作线程数及其峰值；克隆后共享同一组计数，工作线程不持有状态锁即可更新
+#[derive(Debug, Clone, Default)]
+pub struct WorkerGauge {
+    active: Arc<AtomicUsize>,
+    peak: Arc<AtomicUsize>,
+}
+
+impl WorkerGauge {
+    pub fn new() -> Self {
+        WorkerGauge::default()
+    }
+
+    /// 一个工作线程开始处理任务
+    pub fn begin(&self) {
+        let active = self.active.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak.fetch_max(active, Ordering::Relaxed);
+    }
+
+    /// 一个工作线程处理完任务
+    pub fn end(&self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// 当前忙碌的工作线程数
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// 自启动以来同时忙碌的最大工作线程数
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+}
+
+impl PartialEq for WorkerGauge {
+    fn eq(&self, other: &Self) -> bool {
+        self.active() == other.active() && self.peak() == other.peak()
+    }
+}
+
+/// 接收线程与工作线程之间队列的深度、峰值和溢出丢弃数；克隆后共享同一组计数
+#[derive(Debug, Clone, Default)]
+pub struct QueueGauge {
+    depth: Arc<AtomicUsize>,
+    peak: Arc<AtomicUsize>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl QueueGauge {
+    pub fn new() -> Self {
+        QueueGauge::default()
+    }
+
+    /// 一个数据包准备入队（随后调用 `accepted` 或 `drop_newest`）
+    pub fn push(&self) {
+        self.depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 数据包已进入队列
+    pub fn accepted(&self) {
+        self.peak.fetch_max(self.depth(), Ordering::Relaxed);
+    }
+
+    /// 一个数据包被工作线程取走
+    pub fn pop(&self) {
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// 队列已满，刚入队的数据包被丢弃
+    pub fn drop_newest(&self) {
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 当前排队的数据包数
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    /// 自启动以来的最大排队数
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    /// 因队列已满被丢弃的数据包数
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl PartialEq for QueueGauge {
+    fn eq(&self, other: &Self) -> bool {
+        self.depth() == other.depth() && self.peak() == other.peak() && self.dropped() == other.dropped()
+    }
+}
+
+/// 可在线程间共享的累计计数；克隆后共享同一个计数
+#[derive(Debug, Clone, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn new() -> Self {
+        Counter::default()
+    }
+
+    /// 计数加一，返回加一后的值
+    pub fn inc(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl PartialEq for Counter {
+    fn eq(&self, other: &Self) -> bool {
+        self.get() == other.get()
+    }
+}
+
+/// 服务器运行计数器（自启动以来累计）
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    /// 处理过的消息数
+    pub messages: u64,
+    /// 被拒绝（回复错误）的消息数
+    pub errors: u64,
+    /// 发出的纠正数
+    pub corrections: u64,
+    /// 发出的世界广播数（按接收方计）
+    pub broadcasts: u64,
+    /// `handle_message` 的执行时间
+    pub handler_latency: LatencyHistogram,
+    /// 数据包到达后等待处理的时间（排队等待工作线程和状态锁）
+    pub queue_wait: LatencyHistogram,
+    /// 处理数据包的工作线程（见 `config.max_workers`）
+    pub workers: WorkerGauge,
+    /// 等待工作线程处理的数据包队列（见 `config.ingest_capacity`）
+    pub ingest: QueueGauge,
+    /// 因 CRC 校验失败而丢弃的数据包数
+    pub frames_dropped: Counter,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// 记录一次 `handle_message` 的结果
+    pub fn record(&mut self, result: &Result<Outgoing, HandlerError>) {
+        self.messages += 1;
+        match result {
+            Ok(out) => {
+                for (_, msg) in out {
+                    match msg {
+                        ServerMessage::Correction { .. } => self.corrections += 1,
+                        ServerMessage::World { .. } => self.broadcasts += 1,
+                        // 附带了其他回复的错误（如被拒绝的更新附上权威状态）
+                        ServerMessage::Error { .. } => self.errors += 1,
+                        _ => {}
+                    }
+                }
+            }
+            Err(_) => self.errors += 1,
+        }
+    }
+}
+
+/// 按 Prometheus 文本格式（0.0.4）输出计数器，`online` 为当前在线玩家数
+pub fn render_prometheus(metrics: &Metrics, online: usize) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        let _ = writeln!(out, "{} {}", name, value);
+    };
+    metric("game_messages_total", "counter", "Messages handled by the server.", metrics.messages);
+    metric("game_message_errors_total", "counter", "Messages rejected with an error reply.", metrics.errors);
+    metric("game_corrections_total", "counter", "Movement corrections sent to clients.", metrics.corrections);
+    metric("game_broadcasts_total", "counter", "World broadcasts sent, counted per recipient.", metrics.broadcasts);
+    metric("game_players_online", "gauge", "Players currently online.", online as u64);
+    metric("game_workers_active", "gauge", "Worker threads currently handling a packet.", metrics.workers.active() as u64);
+    metric("game_ingest_queue_depth", "gauge", "Packets waiting for a worker thread.", metrics.ingest.depth() as u64);
+    metric("game_ingest_queue_peak", "gauge", "Most packets ever waiting for a worker thread.", metrics.ingest.peak() as u64);
+    metric("game_ingest_dropped_total", "counter", "Packets dropped because the ingest queue was full.", metrics.ingest.dropped());
+    metric("game_frames_dropped_total", "counter", "Frames dropped because of a checksum mismatch.", metrics.frames_dropped.get());
+    histogram(&mut out, "game_handler_seconds", "Time spent in the message handler.", &metrics.handler_latency);
+    histogram(&mut out, "game_queue_wait_seconds", "Time a packet waited before being handled.", &metrics.queue_wait);
+    out
+}
+
+/// 按 Prometheus 直方图格式输出（累计的 `_bucket`、`_sum`、`_count`）
+fn histogram(out: &mut String, name: &str, help: &str, hist: &LatencyHistogram) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    let mut cumulative = 0;
+    for (i, count) in hist.bucket_counts().into_iter().enumerate() {
+        cumulative += count;
+        match LATENCY_BUCKETS_US.get(i) {
+            Some(&us) => {
+                let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, us as f64 / 1e6, cumulative);
+            }
+            None => {
+                let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative);
+            }
+        }
+    }
+    let _ = writeln!(out, "{}_sum {}", name, hist.sum().as_secs_f64());
+    let _ = writeln!(out, "{}_count {}", name, hist.count());
+}
@@ -0,0 +1,59 @@
+//! 服务器事件回调
+//!
+//! 把服务器嵌入更大的程序时，实现 `ServerObserver` 即可感知玩家加入、移动、
+//! 违规和离开，无需修改 main.rs。所有回调都有空的默认实现。
+
+use crate::transport::ClientConn;
+use crate::PlayerState;
+use std::fmt;
+use uuid::Uuid;
+
+/// 服务器事件观察者
+///
+/// 回调在持有服务器状态锁时同步调用，实现中不要做耗时操作。
+pub trait ServerObserver: Send + Sync {
+    /// 玩家注册或恢复会话
+    fn on_join(&self, _uuid: Uuid, _username: &str, _conn: ClientConn, _resumed: bool) {}
+    /// 玩家状态已更新（纠正后的最终状态）
+    fn on_update(&self, _player: &PlayerState) {}
+    /// 玩家的移动未通过校验
+    fn on_violation(&self, _uuid: Uuid, _reason: &str) {}
+    /// 玩家离线
+    fn on_leave(&self, _uuid: Uuid, _username: &str, _reason: &str) {}
+}
+
+impl fmt::Debug for dyn ServerObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ServerObserver")
+    }
+}
+
+/// 不做任何事的观察者
+pub struct NoopObserver;
+
+impl ServerObserver for NoopObserver {}
+
+/// 内置的日志输出
+pub struct LoggingObserver;
+
+impl ServerObserver for LoggingObserver {
+    fn on_join(&self, _uuid: Uuid, username: &str, conn: ClientConn, resumed: bool) {
+        if resumed {
+            println!("{} resumed from {}", username, conn);
+        } else {
+            println!("{} registered from {}", username, conn);
+        }
+    }
+
+    fn on_update(&self, player: &PlayerState) {
+        println!("Received update for {}", player.username);
+    }
+
+    fn on_violation(&self, uuid: Uuid, reason: &str) {
+        println!("Movement violation by {}: {}", uuid, reason);
+    }
+
+    fn on_leave(&self, _uuid: Uuid, username: &str, reason: &str) {
+        println!("Notified {} of offline status ({})", username, reason);
+    }
+}
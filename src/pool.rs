@@ -0,0 +1,71 @@
+//! 处理数据包的固定大小工作线程池
+//!
+//! 接收线程只负责收包，处理交给池中的线程，线程总数在启动时确定。
+//! 两者之间是一个有界队列：流量突增时先排队，队列满了丢弃最新的数据包并计数。
+
+use crate::metrics::{QueueGauge, WorkerGauge};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// 固定数量的工作线程；池被丢弃后线程处理完已排队的任务再退出
+pub struct WorkerPool {
+    sender: SyncSender<Job>,
+    workers: usize,
+    queue: QueueGauge,
+}
+
+impl WorkerPool {
+    /// 启动 `workers` 个线程（至少 1 个），队列最多容纳 `capacity` 个任务（至少 1 个）
+    ///
+    /// 忙碌的线程数记入 `gauge`，队列深度和丢弃数记入 `queue`。
+    pub fn new(workers: usize, capacity: usize, gauge: WorkerGauge, queue: QueueGauge) -> Self {
+        let workers = workers.max(1);
+        let (sender, receiver) = mpsc::sync_channel::<Job>(capacity.max(1));
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..workers {
+            let receiver = receiver.clone();
+            let gauge = gauge.clone();
+            let queue = queue.clone();
+            thread::spawn(move || run_worker(&receiver, &gauge, &queue));
+        }
+        WorkerPool { sender, workers, queue }
+    }
+
+    /// 池中的线程数
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    /// 把任务放入队列，由空闲的工作线程执行；队列已满时丢弃该任务并返回 false
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) -> bool {
+        // 先计入深度：工作线程可能在 try_send 返回前就取走任务
+        self.queue.push();
+        match self.sender.try_send(Box::new(job)) {
+            Ok(()) => {
+                self.queue.accepted();
+                true
+            }
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.queue.drop_newest();
+                false
+            }
+        }
+    }
+}
+
+fn run_worker(receiver: &Mutex<Receiver<Job>>, gauge: &WorkerGauge, queue: &QueueGauge) {
+    loop {
+        // 只在取任务时持有锁，执行期间其他线程可以继续取
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        queue.pop();
+        gauge.begin();
+        job();
+        gauge.end();
+    }
+}
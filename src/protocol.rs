@@ -0,0 +1,566 @@
+//! 客户端/服务器之间的消息类型
+
+use crate::{Entity, PlayerState};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::ops::Deref;
+use uuid::Uuid;
+
+/// 服务器实现的协议版本
+///
+/// - 1：初始版本
+/// - 2：纠正和世界广播携带输入序号 `seq`
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// 开始携带输入序号 `seq` 的协议版本
+pub const SEQ_PROTOCOL_VERSION: u32 = 2;
+
+/// 客户端发送的状态更新（`"type": "update"`）
+///
+/// 除 `uuid` 外的字段都是可选的：缺失的字段反序列化为 `None`，
+/// 表示本次更新不涉及该字段。
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct PlayerUpdate {
+    pub uuid: Uuid,
+    #[serde(default)]
+    pub x: Option<f64>,
+    #[serde(default)]
+    pub y: Option<f64>,
+    #[serde(default)]
+    pub z: Option<f64>,
+    #[serde(default)]
+    pub ts: Option<u128>,
+    #[serde(default)]
+    pub rx: Option<f64>,
+    #[serde(default)]
+    pub ry: Option<f64>,
+    #[serde(default)]
+    pub rz: Option<f64>,
+    #[serde(default)]
+    pub vx: Option<f64>,
+    #[serde(default)]
+    pub vy: Option<f64>,
+    #[serde(default)]
+    pub vz: Option<f64>,
+    #[serde(default)]
+    pub action: Option<String>,
+    /// 替换玩家的附加数据（整体替换，不做字段级合并）
+    #[serde(default)]
+    pub extra: Option<serde_json::Value>,
+}
+
+impl PlayerUpdate {
+    /// 从原始 JSON 中取出更新字段；类型不对的字段按缺失处理
+    pub fn from_value(uuid: Uuid, val: &serde_json::Value) -> Self {
+        let f64_field = |name: &str| val.get(name).and_then(|x| x.as_f64());
+        PlayerUpdate {
+            uuid,
+            x: f64_field("x"),
+            y: f64_field("y"),
+            z: f64_field("z"),
+            ts: val.get("ts").and_then(|x| x.as_u64()).map(u128::from),
+            rx: f64_field("rx"),
+            ry: f64_field("ry"),
+            rz: f64_field("rz"),
+            vx: f64_field("vx"),
+            vy: f64_field("vy"),
+            vz: f64_field("vz"),
+            action: val.get("action").and_then(|x| x.as_str()).map(|s| s.to_string()),
+            extra: val.get("extra").filter(|x| !x.is_null()).cloned(),
+        }
+    }
+}
+
+/// 字段存在但类型不符
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: &'static str,
+    /// 期望的类型（回复给客户端）
+    pub expected: &'static str,
+}
+
+/// 注册 / 恢复请求（`"type": "register"`）
+///
+/// 所有字段都是可选的，缺失或为 null 视为未提供；出现但类型不符时报错，
+/// 而不是悄悄当作未提供。
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RegisterRequest {
+    pub uuid: Option<Uuid>,
+    pub username: Option<String>,
+    pub locale: Option<String>,
+    pub resume_token: Option<String>,
+    /// 游戏自己的账号 ID：服务器把它一一映射到内部 UUID，重连时可代替 `uuid`
+    pub client_id: Option<String>,
+    /// 客户端能接收的单个数据包上限（字节）
+    pub mtu: Option<usize>,
+    /// 恢复会话时客户端最后已知的位置（三个坐标都提供时才生效）
+    pub position: Option<(f64, f64, f64)>,
+    /// 客户端实现的协议版本（None 表示未声明，按当前版本对待）
+    pub protocol_version: Option<u32>,
+    /// 要加入的房间（None 表示不加入或沿用之前的房间）
+    pub room: Option<String>,
+    /// 交给 `Authenticator` 校验的凭证（账号系统签发）
+    pub token: Option<String>,
+}
+
+impl RegisterRequest {
+    pub fn from_value(val: &serde_json::Value) -> Result<Self, FieldError> {
+        let string = |field: &'static str| -> Result<Option<String>, FieldError> {
+            match val.get(field) {
+                None | Some(serde_json::Value::Null) => Ok(None),
+                Some(v) => v
+                    .as_str()
+                    .map(|s| Some(s.to_string()))
+                    .ok_or(FieldError { field, expected: "string" }),
+            }
+        };
+        let uuid = match val.get("uuid") {
+            None | Some(serde_json::Value::Null) => None,
+            Some(v) => Some(
+                v.as_str()
+                    .and_then(|s| Uuid::parse_str(s).ok())
+                    .ok_or(FieldError { field: "uuid", expected: "uuid string" })?,
+            ),
+        };
+        let mtu = match val.get("mtu") {
+            None | Some(serde_json::Value::Null) => None,
+            Some(v) => Some(
+                v.as_u64()
+                    .filter(|&m| m > 0)
+                    .ok_or(FieldError { field: "mtu", expected: "positive integer" })? as usize,
+            ),
+        };
+        let number = |field: &'static str| -> Result<Option<f64>, FieldError> {
+            match val.get(field) {
+                None | Some(serde_json::Value::Null) => Ok(None),
+                Some(v) => v.as_f64().map(Some).ok_or(FieldError { field, expected: "number" }),
+            }
+        };
+        let position = match (number("x")?, number("y")?, number("z")?) {
+            (Some(x), Some(y), Some(z)) => Some((x, y, z)),
+            _ => None,
+        };
+        let protocol_version = match val.get("protocol_version") {
+            None | Some(serde_json::Value::Null) => None,
+            Some(v) => Some(
+                v.as_u64()
+                    .and_then(|n| u32::try_from(n).ok())
+                    .ok_or(FieldError { field: "protocol_version", expected: "non-negative integer" })?,
+            ),
+        };
+        Ok(RegisterRequest {
+            uuid,
+            username: string("username")?,
+            locale: string("locale")?,
+            resume_token: string("resume_token")?,
+            client_id: string("client_id")?,
+            mtu,
+            position,
+            protocol_version,
+            room: string("room")?,
+            token: string("token")?,
+        })
+    }
+}
+
+/// `batch_update` 中单个实体的处理结果
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BatchEntryResult {
+    /// 该项的 UUID（缺失或无法解析时为 None）
+    pub uuid: Option<Uuid>,
+    /// 被拒绝时的错误代码（与 `error` 消息的代码相同）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// 是否因移动校验失败而单独发送了纠正
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub corrected: bool,
+}
+
+/// 纠正消息中携带的权威状态
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CorrectedState {
+    pub uuid: Uuid,
+    pub username: String,
+    pub x: Option<f64>,
+    pub y: Option<f64>,
+    pub z: Option<f64>,
+    pub vx: Option<f64>,
+    pub vy: Option<f64>,
+    pub vz: Option<f64>,
+    #[serde(default, with = "crate::ts_millis")]
+    pub ts: Option<u128>,
+}
+
+/// 广播中每个玩家输出哪些字段；uuid 始终作为键输出，不再重复放进值里
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BroadcastProjection {
+    pub username: bool,
+    /// x / y / z
+    pub position: bool,
+    /// rx / ry / rz
+    pub rotation: bool,
+    /// vx / vy / vz
+    pub velocity: bool,
+    pub ts: bool,
+    pub action: bool,
+    pub extra: bool,
+}
+
+impl BroadcastProjection {
+    /// 只输出用户名、位置和旋转
+    pub fn position_only() -> Self {
+        BroadcastProjection {
+            username: true,
+            position: true,
+            rotation: true,
+            velocity: false,
+            ts: false,
+            action: false,
+            extra: false,
+        }
+    }
+
+    /// 线上字段名是否被选中
+    pub fn includes(&self, field: &str) -> bool {
+        match field {
+            "username" => self.username,
+            "x" | "y" | "z" => self.position,
+            "rx" | "ry" | "rz" => self.rotation,
+            "vx" | "vy" | "vz" => self.velocity,
+            "ts" => self.ts,
+            "action" => self.action,
+            "extra" => self.extra,
+            _ => false,
+        }
+    }
+
+    /// 按选择的字段裁剪玩家状态
+    pub fn project(&self, player: &PlayerState) -> serde_json::Map<String, serde_json::Value> {
+        let mut fields = match serde_json::to_value(player) {
+            Ok(serde_json::Value::Object(fields)) => fields,
+            _ => serde_json::Map::new(),
+        };
+        fields.retain(|k, _| self.includes(k));
+        fields
+    }
+}
+
+/// `World` 消息中的玩家集合
+///
+/// 线上格式始终是以 uuid 为键的对象；用 `ordered` 构造时按给定顺序输出，
+/// 否则按 HashMap 的（不确定的）顺序输出。设置了投影时每个玩家只输出选中的字段。
+#[derive(Debug, Clone, Default)]
+pub struct Players {
+    map: HashMap<Uuid, PlayerState>,
+    order: Option<Vec<Uuid>>,
+    projection: Option<BroadcastProjection>,
+}
+
+impl Players {
+    /// 按 `players` 的顺序输出
+    pub fn ordered(players: Vec<PlayerState>) -> Self {
+        let order = players.iter().map(|p| p.uuid).collect();
+        let map = players.into_iter().map(|p| (p.uuid, p)).collect();
+        Players {
+            map,
+            order: Some(order),
+            projection: None,
+        }
+    }
+
+    /// 输出时按 `projection` 裁剪字段（None 表示输出完整状态）
+    pub fn with_projection(mut self, projection: Option<BroadcastProjection>) -> Self {
+        self.projection = projection;
+        self
+    }
+
+    pub fn into_map(self) -> HashMap<Uuid, PlayerState> {
+        self.map
+    }
+}
+
+impl From<HashMap<Uuid, PlayerState>> for Players {
+    fn from(map: HashMap<Uuid, PlayerState>) -> Self {
+        Players { map, order: None, projection: None }
+    }
+}
+
+impl Deref for Players {
+    type Target = HashMap<Uuid, PlayerState>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.map
+    }
+}
+
+/// 只比较内容，不比较输出顺序
+impl PartialEq for Players {
+    fn eq(&self, other: &Self) -> bool {
+        self.map == other.map
+    }
+}
+
+impl Serialize for Players {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let order = match &self.order {
+            Some(order) => order.clone(),
+            None if self.projection.is_none() => return self.map.serialize(s),
+            None => self.map.keys().copied().collect(),
+        };
+        let mut map = s.serialize_map(Some(order.len()))?;
+        for uuid in &order {
+            let player = &self.map[uuid];
+            match &self.projection {
+                Some(projection) => map.serialize_entry(uuid, &projection.project(player))?,
+                None => map.serialize_entry(uuid, player)?,
+            }
+        }
+        map.end()
+    }
+}
+
+/// 投影后缺失的 uuid 从键补回，缺失的用户名视为空
+impl<'de> Deserialize<'de> for Players {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let raw = HashMap::<Uuid, serde_json::Value>::deserialize(d)?;
+        let mut map = HashMap::with_capacity(raw.len());
+        for (uuid, mut value) in raw {
+            if let serde_json::Value::Object(fields) = &mut value {
+                fields.entry("uuid").or_insert_with(|| serde_json::json!(uuid));
+                fields.entry("username").or_insert_with(|| serde_json::json!(""));
+            }
+            let player = serde_json::from_value(value).map_err(serde::de::Error::custom)?;
+            map.insert(uuid, player);
+        }
+        Ok(Players::from(map))
+    }
+}
+
+/// 服务器发送给客户端的消息
+///
+/// 序列化时以 `action` 字段区分类型，与旧版 `json!` 拼出的格式保持一致。
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// 注册或恢复成功
+    Registered {
+        uuid: Uuid,
+        username: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        state: Option<PlayerState>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        resumed: bool,
+        /// 从新地址恢复会话时出示的凭证
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        resume_token: Option<String>,
+        /// 服务器时间（毫秒）
+        #[serde(default, with = "crate::ts_millis::required")]
+        server_ts: u64,
+        /// 服务器的协议版本
+        #[serde(default)]
+        protocol_version: u32,
+    },
+    /// `batch_update` 的逐项结果（与请求中的 `updates` 顺序一致）
+    BatchResult { results: Vec<BatchEntryResult> },
+    /// 客户端声明的协议版本过旧，注册被拒绝
+    IncompatibleVersion {
+        /// 服务器的协议版本
+        server: u32,
+        /// 服务器仍支持的最低版本
+        min_supported: u32,
+    },
+    /// whoami 查询结果
+    Identity {
+        uuid: Uuid,
+        username: String,
+        online: bool,
+        /// 仅存在于持久化存储中（不在内存世界里）
+        from_storage: bool,
+    },
+    /// `get` 查询结果
+    PlayerInfo {
+        player: PlayerState,
+        online: bool,
+        /// 服务器记录的平滑往返时延（毫秒）；还没有样本时省略
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rtt_ms: Option<u64>,
+    },
+    /// 提供的 UUID 不存在
+    UuidNotFound { uuid: Uuid, message: String },
+    /// 来源 IP 上绑定的玩家已达上限，新注册被拒绝
+    TooManyFromAddress { limit: usize },
+    /// 要加入的房间在线人数已满
+    RoomFull { room: String, limit: usize },
+    /// 注册请求未通过 `Authenticator` 校验
+    AuthFailed { reason: String },
+    /// 新建账号时缺少用户名
+    UsernameRequired { message: String },
+    /// 用户名已被占用
+    NameConflict {
+        suggested: String,
+        /// 按客户端 locale 渲染的提示
+        #[serde(default)]
+        message: String,
+    },
+    /// 用户名已被占用，且服务器不提供建议名（`UsernamePolicy::Reject`）
+    NameTaken {
+        username: String,
+        /// 按客户端 locale 渲染的提示
+        #[serde(default)]
+        message: String,
+    },
+    /// 位置纠正（反作弊）
+    Correction {
+        reason: String,
+        nonce: u64,
+        corrected: CorrectedState,
+        /// 按客户端 locale 渲染的提示
+        #[serde(default)]
+        message: String,
+        /// 触发本次纠正的输入序号（客户端重放此后尚未确认的输入）
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    /// 不活动离线通知
+    Offline {
+        reason: String,
+        uuid: Uuid,
+        message: String,
+        /// 客户端应在多少秒后重连（不活动离线为 0，随时可以重连）
+        #[serde(default)]
+        retry_after_secs: u64,
+    },
+    /// 即将因不活动离线；客户端可以提示用户或自动发送 ping
+    InactivityWarning { seconds_remaining: u64 },
+    /// 被管理员踢下线；客户端应在 `retry_after_secs` 秒后再重连
+    Kicked {
+        uuid: Uuid,
+        reason: String,
+        retry_after_secs: u64,
+    },
+    /// 广播给其他在线客户端：该玩家已离线，应从视图中移除
+    PlayerOffline { uuid: Uuid },
+    /// 其他玩家刚执行了一个被接受的动作（`ts` 为客户端时间，未上报时为服务器时间）
+    PlayerAction {
+        uuid: Uuid,
+        /// 线上字段名为 `name`（`action` 已用作消息类型标签）
+        #[serde(rename = "name")]
+        action: String,
+        #[serde(with = "crate::ts_millis::required")]
+        ts: u64,
+    },
+    /// 服务器即将停机；客户端应在 `retry_after_secs` 秒后开始带退避重连
+    ServerShutdown { reason: String, retry_after_secs: u64 },
+    /// 世界状态广播（仅在线玩家）
+    World {
+        players: Players,
+        /// 服务器时间（毫秒），用于客户端估计时钟偏差
+        #[serde(default, with = "crate::ts_millis::required")]
+        server_ts: u64,
+        /// 按客户端 MTU 拆分时的 `(序号, 总数)`；未拆分时省略
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        chunk: Option<(u32, u32)>,
+        /// 服务器已处理的该接收方最后一个输入序号；未上报过序号时省略
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        /// 玩家以外的实体（拆分时只放在第一个分片中）；没有时省略
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        entities: HashMap<Uuid, Entity>,
+        /// 各玩家的平滑往返时延（毫秒，仅在开启 `broadcast_rtt` 时，放在第一个分片中）；没有时省略
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        rtt_ms: HashMap<Uuid, u64>,
+    },
+    /// `ping` 的回复
+    Pong {
+        /// 原样返回客户端发送的时间戳
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_ts: Option<u64>,
+        /// 服务器时间（毫秒）；客户端在下一次 ping / update 中以 `echo_ts` 带回即可让服务器测量 RTT
+        #[serde(with = "crate::ts_millis::required")]
+        server_ts: u64,
+    },
+    /// 局域网发现（`"type": "discover"`）的回复
+    ServerInfo {
+        name: String,
+        version: String,
+        player_count: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_players: Option<u32>,
+    },
+    /// `config` 查询结果：客户端做预测需要与服务器一致的参数（不含密钥、文件路径等）
+    ServerConfigInfo {
+        /// 移动校验的基础容差（米）
+        tolerance: f64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_speed_x: Option<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_speed_y: Option<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_speed_z: Option<f64>,
+        /// 位置网格间距
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        grid: Option<f64>,
+        /// 广播坐标保留的小数位数
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        coord_precision: Option<u8>,
+        /// 服务器是否按速度积分位置（忽略客户端上报的位置）
+        server_authoritative: bool,
+        /// 服务器物理步长（毫秒）
+        tick_ms: u64,
+        online_timeout_secs: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keepalive_timeout_secs: Option<u64>,
+        protocol_version: u32,
+        min_protocol_version: u32,
+    },
+    /// 管理员查询（或解除）玩家的隔离状态
+    Quarantine {
+        uuid: Uuid,
+        quarantined: bool,
+        /// 累计违规次数
+        violations: u32,
+        /// 隔离期间玩家最后一次上报的状态
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        claimed: Option<PlayerState>,
+    },
+    /// 管理员修改了玩家的受信任状态
+    TrustChanged { uuid: Uuid, trusted: bool },
+    /// 管理员重置了世界，客户端需要重新注册
+    WorldReset {
+        /// 持久化的身份记录是否也被清空（为 true 时旧 UUID 不能再恢复）
+        storage_cleared: bool,
+    },
+    /// 管理员导入了世界（回复给管理员）
+    WorldImported {
+        /// 导入的玩家数
+        players: usize,
+        /// `replace` 模式下被移除的原有玩家数
+        removed: usize,
+    },
+    /// 消息缺少 `type` 或类型未知；附上服务器支持的类型
+    UnknownType {
+        /// 收到的类型（缺少 `type` 字段时省略）
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        received: Option<String>,
+        supported: Vec<String>,
+    },
+    /// 数据包超过 `max_recv_bytes`（或可能被截断），未处理
+    PayloadTooLarge { size: usize, limit: usize },
+    /// 请求中的字段类型不符
+    MalformedRequest { field: String, expected: String },
+    /// 请求处理失败
+    Error { error: String, message: String },
+}
+
+impl ServerMessage {
+    /// 拥塞时能否丢弃（世界广播会被下一次广播取代）
+    pub fn is_droppable(&self) -> bool {
+        matches!(self, ServerMessage::World { .. })
+    }
+
+    /// 是否是一轮世界广播的第一条（未拆分，或拆分后的第 0 片）
+    pub fn starts_broadcast(&self) -> bool {
+        matches!(self, ServerMessage::World { chunk: None | Some((0, _)), .. })
+    }
+}
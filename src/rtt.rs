@@ -0,0 +1,72 @@
+//! 服务器测得的往返时延（RTT）
+//!
+//! 服务器在 `pong` 中带上 `server_ts`，客户端在下一次 ping / update 中以 `echo_ts` 原样带回。
+//! 只接受确实发给该玩家且尚未回显过的时间戳，客户端无法凭空报告更小的 RTT。
+//! 样本按 RFC 6298 的方式平滑：`srtt = 7/8 * srtt + 1/8 * sample`。
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// 每个玩家最多保留的未回显时间戳（更早的视为丢失）
+const MAX_OUTSTANDING: usize = 8;
+
+/// 每个玩家的平滑 RTT
+#[derive(Debug, Default)]
+pub struct RttTracker {
+    /// uuid -> 已发出、尚未回显的服务器时间戳（毫秒），按发送顺序
+    sent: HashMap<Uuid, VecDeque<u64>>,
+    smoothed: HashMap<Uuid, Duration>,
+}
+
+impl RttTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 记录发给 `uuid` 的服务器时间戳（毫秒）
+    pub fn on_send(&mut self, uuid: Uuid, ts: u64) {
+        let sent = self.sent.entry(uuid).or_default();
+        if sent.len() == MAX_OUTSTANDING {
+            sent.pop_front();
+        }
+        sent.push_back(ts);
+    }
+
+    /// 客户端在 `now`（毫秒）回显了 `echoed_ts`，返回更新后的平滑 RTT
+    ///
+    /// 不是发给该玩家的、已经回显过的或已被挤出的时间戳返回 None，不计入样本。
+    /// 比它更早发出的时间戳视为丢失一并丢弃。
+    pub fn on_recv(&mut self, uuid: Uuid, echoed_ts: u64, now: u64) -> Option<Duration> {
+        let sent = self.sent.get_mut(&uuid)?;
+        let pos = sent.iter().position(|ts| *ts == echoed_ts)?;
+        sent.drain(..=pos);
+        Some(self.record(uuid, Duration::from_millis(now.saturating_sub(echoed_ts))))
+    }
+
+    /// 直接计入一个 RTT 样本，返回更新后的平滑 RTT
+    pub fn record(&mut self, uuid: Uuid, sample: Duration) -> Duration {
+        let srtt = match self.smoothed.get(&uuid) {
+            Some(prev) => *prev * 7 / 8 + sample / 8,
+            None => sample,
+        };
+        self.smoothed.insert(uuid, srtt);
+        srtt
+    }
+
+    /// 玩家当前的平滑 RTT（还没有样本时为 None）
+    pub fn get(&self, uuid: &Uuid) -> Option<Duration> {
+        self.smoothed.get(uuid).copied()
+    }
+
+    /// 清除玩家的所有记录
+    pub fn remove(&mut self, uuid: &Uuid) {
+        self.sent.remove(uuid);
+        self.smoothed.remove(uuid);
+    }
+
+    pub fn clear(&mut self) {
+        self.sent.clear();
+        self.smoothed.clear();
+    }
+}
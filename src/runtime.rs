@@ -0,0 +1,648 @@
+//! 网络运行时：传输收发、后台扫描和持久化
+//!
+//! 消息处理逻辑在 `server` 模块中，这里只负责把各传输上的数据包交给
+//! `handle_message` 并发送结果。嵌入方调用 `run_server` 即可启动完整服务器。
+
+use crate::audit::AuditLog;
+use crate::codec::Codec;
+use crate::config::ServerConfig;
+use crate::metrics::{render_prometheus, Metrics};
+use crate::observer::ServerObserver;
+use crate::pool::WorkerPool;
+use crate::protocol::ServerMessage;
+use crate::server::{handle_message_instrumented, HandlerError, ServerState};
+use crate::sweep::{next_deadline_delay, Clock, SweepSignal, SystemClock};
+use crate::transport::{
+    bind_udp_sockets, read_frame, ClientConn, ConnectionLimit, Delivery, Outbound, Transport, MAX_TCP_FRAME_LEN,
+};
+use crate::store::IdentityStore;
+use crate::{frame, now_millis, PhysicsMode, WorldState};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+// 扫描线程单次休眠上限
+const SWEEP_MAX_INTERVAL_SECS: u64 = 5;
+
+/// 编码并发送一批消息
+fn send_all(outbound: &Arc<Outbound>, codec: &dyn Codec, out: &[(ClientConn, ServerMessage)]) {
+    for (conn, msg) in out {
+        let delivery = if msg.starts_broadcast() {
+            Delivery::Latest
+        } else if msg.is_droppable() {
+            Delivery::Droppable
+        } else {
+            Delivery::Reliable
+        };
+        let _ = outbound.deliver_as(conn, &codec.encode(msg), delivery);
+    }
+}
+
+/// 处理一个数据包并发送处理结果（各传输共用）
+fn dispatch(state: &Mutex<ServerState>, outbound: &Arc<Outbound>, signal: &SweepSignal, src: ClientConn, payload: &[u8]) {
+    dispatch_since(state, outbound, signal, src, payload, Instant::now());
+}
+
+/// 与 `dispatch` 相同，`arrived` 为数据包被接收的时刻（排队等待工作线程的时间计入 queue_wait）
+fn dispatch_since(
+    state: &Mutex<ServerState>,
+    outbound: &Arc<Outbound>,
+    signal: &SweepSignal,
+    src: ClientConn,
+    payload: &[u8],
+    arrived: Instant,
+) {
+    let mut st = state.lock().unwrap();
+    let now = Instant::now();
+    st.metrics.queue_wait.record(now - arrived);
+    let result = handle_message_instrumented(&mut st, src, payload, now);
+    let codec = st.config.codec();
+    // 发送前释放状态锁：套接字 I/O 不应串行化其他工作线程
+    drop(st);
+    let out = match result {
+        Ok(out) => {
+            signal.notify();
+            out
+        }
+        Err(e) => {
+            // 只要能确定来源，就回复错误而不是静默丢弃
+            eprintln!("Rejected message from {}: {}", src, e);
+            vec![(src, e.to_reply())]
+        }
+    };
+    send_all(outbound, &*codec, &out);
+}
+
+/// TCP 监听：每个连接一个线程，按长度前缀读取消息；连接数达到 `limit` 时新连接直接关闭
+fn run_tcp(
+    listener: TcpListener,
+    state: Arc<Mutex<ServerState>>,
+    outbound: Arc<Outbound>,
+    signal: Arc<SweepSignal>,
+    limit: ConnectionLimit,
+    shutdown: Arc<AtomicBool>,
+) {
+    let write_timeout = state.lock().unwrap().config.tcp_write_timeout;
+    for stream in listener.incoming() {
+        if shutdown.load(Ordering::Relaxed) {
+            break;
+        }
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("tcp accept error: {}", e);
+                continue;
+            }
+        };
+        let Some(slot) = limit.try_acquire() else {
+            eprintln!(
+                "Rejected TCP connection from {:?}: connection limit reached ({})",
+                stream.peer_addr().ok(),
+                limit.limit()
+            );
+            continue;
+        };
+        // 不读取的对端不能让发送方无限期阻塞
+        if let Err(e) = stream.set_write_timeout(Some(write_timeout.max(Duration::from_millis(1)))) {
+            eprintln!("tcp setup failed for {:?}: {}", stream.peer_addr().ok(), e);
+            continue;
+        }
+        let state = state.clone();
+        let outbound = outbound.clone();
+        let signal = signal.clone();
+        thread::spawn(move || {
+            serve_tcp_conn(stream, &state, &outbound, &signal);
+            drop(slot);
+        });
+    }
+}
+
+fn serve_tcp_conn(mut stream: TcpStream, state: &Mutex<ServerState>, outbound: &Arc<Outbound>, signal: &SweepSignal) {
+    let peer = match stream.peer_addr() {
+        Ok(p) => p,
+        Err(_) => return,
+    };
+    match stream.try_clone() {
+        Ok(writer) => outbound.add_tcp(peer, writer),
+        Err(e) => {
+            eprintln!("tcp clone failed for {}: {}", peer, e);
+            return;
+        }
+    }
+    println!("TCP client connected: {}", peer);
+    loop {
+        match read_frame(&mut stream, MAX_TCP_FRAME_LEN) {
+            Ok(payload) => dispatch(state, outbound, signal, ClientConn::Tcp(peer), &payload),
+            Err(e) => {
+                if e.kind() != io::ErrorKind::UnexpectedEof {
+                    eprintln!("tcp read error from {}: {}", peer, e);
+                }
+                break;
+            }
+        }
+    }
+    outbound.remove_tcp(&peer);
+    println!("TCP client disconnected: {}", peer);
+}
+
+/// UDP 接收循环
+fn run_udp(
+    socket: UdpSocket,
+    state: Arc<Mutex<ServerState>>,
+    outbound: Arc<Outbound>,
+    signal: Arc<SweepSignal>,
+    pool: Arc<WorkerPool>,
+    shutdown: Arc<AtomicBool>,
+) {
+    let (max_recv_bytes, codec, frames_dropped) = {
+        let st = state.lock().unwrap();
+        (st.config.max_recv_bytes, st.config.codec(), st.metrics.frames_dropped.clone())
+    };
+    // 多留 1 字节：读满整个缓冲区说明数据包可能被截断
+    let mut buf = vec![0u8; max_recv_bytes + 1];
+    while !shutdown.load(Ordering::Relaxed) {
+        match socket.recv_from(&mut buf) {
+            Ok((n, src)) => {
+                if n == buf.len() {
+                    eprintln!(
+                        "Dropped datagram from {}: possibly truncated, increase max_recv_bytes (currently {})",
+                        src, max_recv_bytes
+                    );
+                    let reply = HandlerError::PayloadTooLarge {
+                        size: n,
+                        limit: max_recv_bytes,
+                    }
+                    .to_reply();
+                    send_all(&outbound, &*codec, &[(ClientConn::Udp(src), reply)]);
+                    continue;
+                }
+                let payload = match frame::decode(&buf[..n]) {
+                    Ok(payload) => payload.to_vec(),
+                    Err(e) => {
+                        let total = frames_dropped.inc();
+                        eprintln!("Dropped corrupted frame from {}: {} (total dropped: {})", src, e, total);
+                        continue;
+                    }
+                };
+
+                let state_clone = state.clone();
+                let outbound_clone = outbound.clone();
+                let signal_clone = signal.clone();
+
+                let arrived = Instant::now();
+                let accepted = pool.execute(move || {
+                    dispatch_since(&state_clone, &outbound_clone, &signal_clone, ClientConn::Udp(src), &payload, arrived);
+                });
+                if !accepted {
+                    eprintln!("Dropped datagram from {}: ingest queue full", src);
+                }
+            }
+            // 读超时（Unix 上报告为 WouldBlock）：回到循环开头检查停机标志
+            Err(ref e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+            Err(e) => {
+                eprintln!("recv error: {}", e);
+            }
+        }
+    }
+}
+
+/// 扫描线程：通知超时玩家、定期保存、广播世界状态
+fn run_sweep(
+    state: Arc<Mutex<ServerState>>,
+    outbound: Arc<Outbound>,
+    signal: Arc<SweepSignal>,
+    clock: Arc<dyn Clock>,
+    shutdown: Arc<AtomicBool>,
+) {
+    // 已发送过离线通知的玩家（避免重复通知）
+    let mut notified: HashSet<Uuid> = HashSet::new();
+    let mut last_save = clock.now();
+    let (codec, save_interval, world_path) = {
+        let st = state.lock().unwrap();
+        (st.config.codec(), st.config.save_interval, st.config.world_path.clone())
+    };
+    while !shutdown.load(Ordering::Relaxed) {
+        let now = clock.now();
+        let to_notify;
+        let delay;
+
+        {
+            let mut st = state.lock().unwrap();
+            // 通知刚刚离线的玩家，移除长时间离线的玩家
+            to_notify = st.prune_offline(&mut notified, now);
+            let max_interval = Duration::from_secs(SWEEP_MAX_INTERVAL_SECS);
+            let entity_delay = st
+                .world
+                .next_entity_expiry()
+                .map(|t| Duration::from_millis(t.saturating_sub(now_millis())).min(max_interval));
+            delay = next_deadline_delay(&st.offline_deadlines(), &notified, now, max_interval)
+                // 还有等待移除的离线玩家时不能无限期阻塞
+                .or_else(|| (st.config.evict_after.is_some() && !st.last_seen.is_empty()).then_some(max_interval))
+                // 还有会到期的实体时按最早的到期时间醒来
+                .map(|d| entity_delay.map_or(d, |e| d.min(e)))
+                .or(entity_delay)
+                // 还有待发送的不活动提醒时按最早的提醒时刻醒来
+                .map(|d| st.next_warning_delay(now).map_or(d, |w| d.min(w)));
+        }
+
+        // 发送离线通知
+        send_all(&outbound, &*codec, &to_notify);
+
+        // 定期保存世界状态到磁盘（仅在有修改时写入）；即将进入空闲等待时也保存一次
+        if delay.is_none() || now.duration_since(last_save) >= save_interval {
+            last_save = now;
+            let mut st = state.lock().unwrap();
+            if let Some(path) = &world_path {
+                match st.checkpoint(path) {
+                    Ok(true) => println!("已保存世界状态（{} 玩家）", st.world.players.len()),
+                    Ok(false) => {}
+                    Err(e) => eprintln!("保存世界状态失败: {}", e),
+                }
+            }
+            if let Err(e) = st.storage.flush() {
+                eprintln!("保存 UUID 存储失败: {}", e);
+            }
+        }
+
+        // 广播世界状态（仅在线玩家）
+        let out = state.lock().unwrap().broadcast(now);
+        send_all(&outbound, &*codec, &out);
+
+        match delay {
+            Some(d) => thread::sleep(d),
+            // 没有在线玩家：阻塞直到下一次注册/更新
+            None => signal.wait(),
+        }
+    }
+}
+
+/// 健康检查监听：极简 HTTP/1.0，只支持 `GET /health` 和 `GET /metrics`
+fn run_health(listener: TcpListener, state: Arc<Mutex<ServerState>>, shutdown: Arc<AtomicBool>) {
+    for stream in listener.incoming() {
+        if shutdown.load(Ordering::Relaxed) {
+            break;
+        }
+        let Ok(mut stream) = stream else { continue };
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(2)));
+        let mut request_line = String::new();
+        if BufReader::new(&stream).read_line(&mut request_line).is_err() {
+            continue;
+        }
+        let path = request_line.split_whitespace().nth(1).unwrap_or("");
+        let (status, content_type, body) = match path {
+            "/health" => ("200 OK", "text/plain", "ok\n".to_string()),
+            "/metrics" => {
+                let st = state.lock().unwrap();
+                let online = st.online_players(Instant::now()).len();
+                ("200 OK", "text/plain; version=0.0.4", render_prometheus(&st.metrics, online))
+            }
+            _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        };
+        let _ = write!(
+            stream,
+            "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        );
+    }
+}
+
+/// 运行中的服务器
+///
+/// 由 `start_server` 返回；`stop` 让所有接收循环退出并等待它们结束。
+pub struct ServerHandle {
+    udp_addr: Option<SocketAddr>,
+    /// 阻塞在 accept 上的监听地址（停止时连接一次以唤醒）
+    listener_addrs: Vec<SocketAddr>,
+    shutdown: Arc<AtomicBool>,
+    signal: Arc<SweepSignal>,
+    handles: Vec<JoinHandle<()>>,
+    /// 出站发送线程（见 `Outbound::start_drain`），停止时关闭发送器后等待它们退出
+    drain_handles: Vec<JoinHandle<()>>,
+    state: Arc<Mutex<ServerState>>,
+    outbound: Arc<Outbound>,
+}
+
+impl ServerHandle {
+    /// UDP 传输实际绑定的地址（配置端口为 0 时由系统分配）
+    pub fn udp_addr(&self) -> Option<SocketAddr> {
+        self.udp_addr
+    }
+
+    /// 当前运行计数器的快照
+    pub fn metrics(&self) -> Metrics {
+        self.state.lock().unwrap().metrics.clone()
+    }
+
+    /// 请求停止并等待所有接收循环退出（见 `stop_with_reason`）
+    pub fn stop(self) {
+        self.stop_with_reason("shutdown");
+    }
+
+    /// 通知所有客户端服务器即将停机，停止所有接收循环，最后把状态落盘
+    ///
+    /// 通知只尽力发送，最多等待 `shutdown_notice_timeout`。接收循环结束后关闭出站发送器并等待
+    /// 发送线程退出；周期性的后台线程（扫描、物理步进等）在下一次醒来时自行退出。
+    pub fn stop_with_reason(mut self, reason: &str) {
+        let (notices, codec, timeout) = {
+            let st = self.state.lock().unwrap();
+            (st.shutdown_notice(reason), st.config.codec(), st.config.shutdown_notice_timeout)
+        };
+        let (done_tx, done_rx) = mpsc::channel();
+        let outbound = self.outbound.clone();
+        thread::spawn(move || {
+            for (conn, msg) in &notices {
+                // 绕过发送队列直接发出，停机后队列不会再被清空
+                let _ = outbound.send(conn, &codec.encode(msg));
+            }
+            let _ = done_tx.send(());
+        });
+        let _ = done_rx.recv_timeout(timeout);
+
+        self.shutdown.store(true, Ordering::Relaxed);
+        self.signal.notify();
+        for addr in &self.listener_addrs {
+            let _ = TcpStream::connect(addr);
+        }
+        let state = self.state.clone();
+        let outbound = self.outbound.clone();
+        let drain_handles = std::mem::take(&mut self.drain_handles);
+        self.join();
+        outbound.close();
+        for handle in drain_handles {
+            let _ = handle.join();
+        }
+
+        let mut st = state.lock().unwrap();
+        if let Some(path) = st.config.world_path.clone() {
+            if let Err(e) = st.checkpoint(&path) {
+                eprintln!("保存世界状态失败: {}", e);
+            }
+        }
+        if let Err(e) = st.storage.flush() {
+            eprintln!("保存 UUID 存储失败: {}", e);
+        }
+    }
+
+    /// 阻塞直到所有接收循环结束
+    pub fn join(self) {
+        for handle in self.handles {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// 按配置绑定所有传输并运行服务器（阻塞直到所有监听结束）
+pub fn run_server(
+    config: ServerConfig,
+    storage: Box<dyn IdentityStore>,
+    observer: Arc<dyn ServerObserver>,
+) -> io::Result<()> {
+    start_server(config, storage, observer)?.join();
+    Ok(())
+}
+
+/// 按配置绑定所有传输，在后台线程中运行服务器
+pub fn start_server(
+    config: ServerConfig,
+    storage: Box<dyn IdentityStore>,
+    observer: Arc<dyn ServerObserver>,
+) -> io::Result<ServerHandle> {
+    // 先绑定所有传输，绑定失败直接退出
+    let mut udp_sockets: Vec<UdpSocket> = Vec::new();
+    let mut tcp_listeners: Vec<TcpListener> = Vec::new();
+    #[cfg(feature = "websocket")]
+    let mut ws_listeners: Vec<TcpListener> = Vec::new();
+    for transport in &config.transports {
+        match *transport {
+            Transport::Udp(addr) => {
+                if !udp_sockets.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "only one UDP transport is supported"));
+                }
+                udp_sockets = bind_udp_sockets(addr, config.udp_sockets)?;
+                // 阻塞接收直到有数据或超时，超时后检查停机标志
+                let timeout = config.udp_recv_timeout.max(Duration::from_millis(1));
+                for socket in &udp_sockets {
+                    socket.set_read_timeout(Some(timeout))?;
+                }
+                println!("Rust UDP server listening on {} ({} sockets)...", addr, udp_sockets.len());
+            }
+            Transport::Tcp(addr) => {
+                tcp_listeners.push(TcpListener::bind(addr)?);
+                println!("TCP listener on {}...", addr);
+            }
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(addr) => {
+                ws_listeners.push(TcpListener::bind(addr)?);
+                println!("WebSocket listener on {}...", addr);
+            }
+            #[cfg(not(feature = "websocket"))]
+            Transport::WebSocket(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "built without the `websocket` feature"));
+            }
+        }
+    }
+    #[cfg(not(feature = "compression"))]
+    if config.compress_broadcasts {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "built without the `compression` feature"));
+    }
+    let health_listener = config.health_addr.map(TcpListener::bind).transpose()?;
+    if let Some(addr) = config.health_addr {
+        println!("Health/metrics listener on http://{}...", addr);
+    }
+    let mut outbound = Outbound::new(udp_sockets.first().map(|s| s.try_clone()).transpose()?);
+    #[cfg(feature = "chaos")]
+    if config.chaos.is_enabled() {
+        println!("Chaos mode: {:?}", config.chaos);
+        outbound = outbound.with_chaos(crate::chaos::Chaos::new(&config.chaos));
+    }
+    if let Some(capacity) = config.send_queue_capacity {
+        outbound = outbound.with_send_queue(capacity, config.drop_stale_broadcasts);
+    }
+    let outbound = Arc::new(outbound);
+    let drain_handles = outbound.start_drain();
+
+    // 从磁盘加载历史世界状态
+    let loaded_world = match &config.world_path {
+        Some(path) => match WorldState::load_from_file(path) {
+            Ok(world) => world,
+            // 更新版本写入的文件：拒绝启动，而不是用新世界把它覆盖掉
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => return Err(e),
+            Err(e) => {
+                println!("未能加载历史数据（{}），使用新世界", e);
+                WorldState { players: HashMap::new(), entities: HashMap::new() }
+            }
+        },
+        None => WorldState { players: HashMap::new(), entities: HashMap::new() },
+    };
+    println!("加载了 {} 个历史玩家", loaded_world.players.len());
+
+    let udp_addr = udp_sockets.first().map(|s| s.local_addr()).transpose()?;
+    let mut listener_addrs = tcp_listeners.iter().map(|l| l.local_addr()).collect::<io::Result<Vec<_>>>()?;
+    #[cfg(feature = "websocket")]
+    for listener in &ws_listeners {
+        listener_addrs.push(listener.local_addr()?);
+    }
+    if let Some(listener) = &health_listener {
+        listener_addrs.push(listener.local_addr()?);
+    }
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    let audit = config
+        .audit_log_path
+        .as_ref()
+        .map(|path| AuditLog::open_with_rfc3339(path, config.rfc3339_timestamps))
+        .transpose()?;
+
+    // 从加载的世界重建 username_map
+    let mut state = ServerState::new(loaded_world, storage)
+        .with_config(config)
+        .with_observer(observer);
+    if let Some(audit) = audit {
+        state = state.with_audit_log(audit);
+    }
+    let state = Arc::new(Mutex::new(state));
+
+    // 扫描线程的唤醒信号：注册/更新时唤醒空闲中的扫描线程
+    let sweep_signal = Arc::new(SweepSignal::new());
+
+    // background cleanup: mark players offline and save world periodically
+    {
+        let state = state.clone();
+        let outbound = outbound.clone();
+        let signal = sweep_signal.clone();
+        let shutdown = shutdown.clone();
+        thread::spawn(move || run_sweep(state, outbound, signal, Arc::new(SystemClock), shutdown));
+    }
+
+    // 模拟周期：统一处理排队的输入、物理和广播（取代下面单独的物理线程）
+    let (tick_interval, physics_mode, physics_step) = {
+        let st = state.lock().unwrap();
+        (st.config.tick_interval(), st.config.physics_mode, st.config.physics_step)
+    };
+    if let Some(interval) = tick_interval {
+        let state = state.clone();
+        let outbound = outbound.clone();
+        let shutdown = shutdown.clone();
+        thread::spawn(move || while !shutdown.load(Ordering::Relaxed) {
+            thread::sleep(interval);
+            let (out, codec) = {
+                let mut st = state.lock().unwrap();
+                (st.tick(interval, Instant::now()), st.config.codec())
+            };
+            send_all(&outbound, &*codec, &out);
+        });
+    }
+
+    // 服务器权威模式：按固定步长推进并广播
+    if tick_interval.is_none() && physics_mode == PhysicsMode::ServerAuthoritative && !physics_step.is_zero() {
+        let state = state.clone();
+        let outbound = outbound.clone();
+        let shutdown = shutdown.clone();
+        thread::spawn(move || while !shutdown.load(Ordering::Relaxed) {
+            thread::sleep(physics_step);
+            let (out, codec) = {
+                let mut st = state.lock().unwrap();
+                let now = Instant::now();
+                st.step_physics(physics_step, now);
+                (st.broadcast(now), st.config.codec())
+            };
+            send_all(&outbound, &*codec, &out);
+        });
+    }
+
+    // 抖动缓冲：周期性处理停留满窗口的更新
+    let jitter_window = state.lock().unwrap().config.jitter_window;
+    if !jitter_window.is_zero() {
+        let state = state.clone();
+        let outbound = outbound.clone();
+        let shutdown = shutdown.clone();
+        thread::spawn(move || while !shutdown.load(Ordering::Relaxed) {
+            thread::sleep(jitter_window / 2);
+            let (out, codec) = {
+                let mut st = state.lock().unwrap();
+                (st.flush_jitter(Instant::now()), st.config.codec())
+            };
+            send_all(&outbound, &*codec, &out);
+        });
+    }
+
+    // 更新合并：每个周期应用一次最新更新并广播
+    let coalesce_interval = state.lock().unwrap().config.coalesce_interval;
+    if !coalesce_interval.is_zero() {
+        let state = state.clone();
+        let outbound = outbound.clone();
+        let shutdown = shutdown.clone();
+        thread::spawn(move || while !shutdown.load(Ordering::Relaxed) {
+            thread::sleep(coalesce_interval);
+            let (out, codec) = {
+                let mut st = state.lock().unwrap();
+                (st.flush_coalesced(Instant::now()), st.config.codec())
+            };
+            send_all(&outbound, &*codec, &out);
+        });
+    }
+
+    let mut handles = Vec::new();
+    if let Some(listener) = health_listener {
+        let state = state.clone();
+        let shutdown = shutdown.clone();
+        handles.push(thread::spawn(move || run_health(listener, state, shutdown)));
+    }
+    // TCP 和 WebSocket 共用一个连接数上限
+    let connections = ConnectionLimit::new(state.lock().unwrap().config.max_connections);
+    for listener in tcp_listeners {
+        let state = state.clone();
+        let outbound = outbound.clone();
+        let signal = sweep_signal.clone();
+        let limit = connections.clone();
+        let shutdown = shutdown.clone();
+        handles.push(thread::spawn(move || run_tcp(listener, state, outbound, signal, limit, shutdown)));
+    }
+    #[cfg(feature = "websocket")]
+    for listener in ws_listeners {
+        let state = state.clone();
+        let outbound_cb = outbound.clone();
+        let signal = sweep_signal.clone();
+        let on_message: crate::websocket::OnMessage =
+            Arc::new(move |src, payload| dispatch(&state, &outbound_cb, &signal, src, payload));
+        let outbound = outbound.clone();
+        let limit = connections.clone();
+        let shutdown = shutdown.clone();
+        handles.push(thread::spawn(move || {
+            crate::websocket::run_listener(listener, outbound, on_message, limit, shutdown)
+        }));
+    }
+
+    // 所有 UDP socket 共用一个工作线程池
+    let pool = {
+        let st = state.lock().unwrap();
+        let (workers, capacity) = (st.config.max_workers, st.config.ingest_capacity);
+        Arc::new(WorkerPool::new(workers, capacity, st.metrics.workers.clone(), st.metrics.ingest.clone()))
+    };
+    for socket in udp_sockets {
+        let state = state.clone();
+        let outbound = outbound.clone();
+        let signal = sweep_signal.clone();
+        let pool = pool.clone();
+        let shutdown = shutdown.clone();
+        handles.push(thread::spawn(move || run_udp(socket, state, outbound, signal, pool, shutdown)));
+    }
+    Ok(ServerHandle {
+        udp_addr,
+        listener_addrs,
+        shutdown,
+        signal: sweep_signal,
+        handles,
+        drain_handles,
+        state,
+        outbound,
+    })
+}
@@ -0,0 +1,2528 @@
+//! 服务器核心：状态与消息处理
+//!
+//! `handle_message` 不做任何网络 IO，只返回需要发送的 `(地址, 消息)` 列表，
+//! 由 main.rs 中的适配层负责序列化和发送。
+
+use crate::anticheat::{ActionCooldowns, PlayerSpeedProfile, SettlingTracker};
+use crate::audit::{AuditLog, AuditRecord};
+use crate::codec::{dump_payload, InboundError};
+use crate::config::{ActionPolicy, ServerConfig, UsernamePolicy};
+use crate::history::StateHistory;
+use crate::i18n::{MessageKey, DEFAULT_LOCALE};
+use crate::ids::{UuidGenerator, V4Generator};
+use crate::jitter::JitterBuffer;
+use crate::metrics::Metrics;
+use crate::observer::{NoopObserver, ServerObserver};
+use crate::protocol::{
+    BatchEntryResult, CorrectedState, FieldError, PlayerUpdate, Players, RegisterRequest, ServerMessage, PROTOCOL_VERSION,
+    SEQ_PROTOCOL_VERSION,
+};
+use crate::store::{IdentityStore, PlayerRecord};
+use crate::rtt::RttTracker;
+use crate::sweep::collect_past_deadline;
+use crate::transport::ClientConn;
+use crate::{
+    acknowledges_correction, apply_correction, clamp_axes, generate_unique_name_by, issue_correction_nonce, now_millis, on_grid, players_within, resolve_collisions, round_player, snap_to_grid,
+    sort_players, step_player, validate_movement_with_tolerance, velocity_consistent, Entity, PhysicsMode, PlayerState, WorldState,
+};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// 默认的在线超时时间
+pub const ONLINE_TIMEOUT_SECS: u64 = 60;
+
+/// 待发送的消息列表
+pub type Outgoing = Vec<(ClientConn, ServerMessage)>;
+
+/// 已注册客户端的连接信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub conn: ClientConn,
+    /// 客户端声明的单个数据包上限（字节），广播按此拆分
+    pub mtu: Option<usize>,
+    /// 注册时协商的协议版本（None 表示客户端未声明，按当前版本对待）
+    pub protocol_version: Option<u32>,
+}
+
+impl ClientInfo {
+    pub fn new(conn: ClientConn) -> Self {
+        ClientInfo { conn, mtu: None, protocol_version: None }
+    }
+
+    pub fn with_mtu(mut self, mtu: Option<usize>) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    pub fn with_protocol_version(mut self, version: Option<u32>) -> Self {
+        self.protocol_version = version;
+        self
+    }
+
+    /// 客户端是否理解 `seq` 字段
+    pub fn supports_seq(&self) -> bool {
+        self.protocol_version.is_none_or(|v| v >= SEQ_PROTOCOL_VERSION)
+    }
+}
+
+/// 观战者关注的位置
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpectatorFocus {
+    /// 跟随某个玩家的当前位置
+    Follow(Uuid),
+    /// 固定坐标
+    Center((f64, f64, f64)),
+}
+
+/// 观战者的视野：只接收 `focus` 周围 `radius` 范围内的在线玩家
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpectatorView {
+    pub focus: SpectatorFocus,
+    pub radius: f64,
+}
+
+/// 房间：成员和最后一次活动的时间（见 `config.rooms`）
+#[derive(Debug, Clone, PartialEq)]
+pub struct Room {
+    pub members: HashSet<Uuid>,
+    pub last_activity: Instant,
+}
+
+/// 服务器的全部内存状态
+#[derive(Debug)]
+pub struct ServerState {
+    pub config: ServerConfig,
+    pub world: WorldState,
+    /// uuid -> 客户端连接
+    pub clients: HashMap<Uuid, ClientInfo>,
+    /// 来源 IP -> 绑定在该 IP 上的玩家数（随 `clients` 维护）
+    pub per_ip_count: HashMap<IpAddr, usize>,
+    /// username -> uuid：当前被占用的用户名（内存中且未释放名字的玩家）；名字冲突和建议名都以此为准
+    pub username_map: HashMap<String, Uuid>,
+    /// 客户端自带 ID -> uuid（与身份存储同步持久化）
+    pub client_id_map: HashMap<String, Uuid>,
+    /// 离线玩家仍保留的用户名 -> (玩家, 保留截止时间)（见 `config.name_reservation`）
+    pub reservations: HashMap<String, (Uuid, Instant)>,
+    /// uuid -> 最后活动时间（注册/更新，用于不活动检测）
+    pub last_seen: HashMap<Uuid, Instant>,
+    /// uuid -> 最后一次 ping 的时间（用于连接保活检测）
+    pub last_ping: HashMap<Uuid, Instant>,
+    /// uuid -> 尚未被客户端确认的纠正 nonce
+    pub pending_correction: HashMap<Uuid, u64>,
+    /// 所有见过的 UUID（持久化）
+    pub storage: Box<dyn IdentityStore>,
+    /// 动作限频记录
+    pub action_cooldowns: ActionCooldowns,
+    /// 加入时间（首次移动宽限期）
+    pub settling: SettlingTracker,
+    /// 每个玩家学习到的速度基线（见 `movement.speed_profile`）
+    pub speed_profiles: PlayerSpeedProfile,
+    /// 刚被管理员传送、下一次更新跳过移动校验的玩家
+    pub teleported: HashSet<Uuid>,
+    /// uuid -> 暂缓纠正的可疑移动的首次检测时间（见 `movement.suspect_hold`）
+    pub suspects: HashMap<Uuid, Instant>,
+    /// uuid -> 连续时间戳不前进的更新次数（见 `movement.non_positive_dt_allowance`）
+    pub bad_dt: HashMap<Uuid, u32>,
+    /// uuid -> 已发送过不活动提醒的离线时刻（玩家重新活动后离线时刻改变，会再次提醒）
+    pub warned: HashMap<Uuid, Instant>,
+    /// uuid -> 累计移动违规次数
+    pub violations: HashMap<Uuid, u32>,
+    /// 被隔离的玩家（见 `config.quarantine_after`）
+    pub quarantined: HashSet<Uuid>,
+    /// 被隔离玩家上报的状态（影子状态，只对管理员可见）
+    pub shadow: HashMap<Uuid, PlayerState>,
+    /// 更新不做移动校验的受信任玩家（初始值来自 `config.trusted_clients`）
+    pub trusted: HashSet<Uuid>,
+    /// 事件回调
+    pub observer: Arc<dyn ServerObserver>,
+    /// 每个玩家尚未处理的更新（仅在启用 `jitter_window` 时使用）
+    pub jitter: HashMap<Uuid, JitterBuffer<Value>>,
+    /// 本周期内每个玩家合并后的更新（仅在启用 `coalesce_interval` 时使用）
+    pub coalesced: HashMap<Uuid, Value>,
+    /// 等待下一个模拟周期处理的更新，按到达顺序（仅在启用 `tick_hz` 时使用）
+    pub inputs: Vec<(Uuid, Value)>,
+    /// 玩家注册时声明的 locale（未声明时使用默认语言）
+    pub locales: HashMap<Uuid, String>,
+    /// uuid -> 会话恢复凭证（仅保存在内存中）
+    pub resume_tokens: HashMap<Uuid, String>,
+    /// 新玩家 UUID 的来源
+    pub uuid_generator: Box<dyn UuidGenerator>,
+    /// 最近的位置历史（延迟补偿）
+    pub history: StateHistory,
+    /// uuid -> 客户端时钟相对服务器时钟的偏差（毫秒，客户端减服务器；见 `config.correct_clock_skew`）
+    pub clock_skew: HashMap<Uuid, i128>,
+    /// 每个玩家的平滑往返时延（服务器通过 `echo_ts` 测得，或客户端在 ping 中上报）
+    pub rtt: RttTracker,
+    /// 世界状态自上次落盘以来是否被修改
+    pub world_dirty: bool,
+    /// uuid -> 最后处理的输入序号（客户端在更新中上报的 `seq`）
+    pub last_seq: HashMap<Uuid, u64>,
+    /// 运行计数器
+    pub metrics: Metrics,
+    /// 纠正审计日志（未配置时为 None）
+    pub audit: Option<AuditLog>,
+    /// 连接 -> (最近在该连接上注册/恢复的 uuid, 注册时间)
+    pub registered_by_conn: HashMap<ClientConn, (Uuid, Instant)>,
+    /// 观战连接 -> 视野（观战者不是玩家，只接收过滤后的广播）
+    pub spectators: HashMap<ClientConn, SpectatorView>,
+    /// 房间名 -> 房间（没有在线成员且空闲超过 `config.rooms.idle_ttl` 后回收）
+    pub rooms: HashMap<String, Room>,
+    /// uuid -> 所在房间名
+    pub player_rooms: HashMap<Uuid, String>,
+}
+
+impl ServerState {
+    /// 从（可能从磁盘加载的）世界状态和 UUID 存储构建，并重建 username_map
+    pub fn new(world: WorldState, mut storage: Box<dyn IdentityStore>) -> Self {
+        let username_map = world
+            .players
+            .iter()
+            .map(|(uuid, p)| (p.username.clone(), *uuid))
+            .collect();
+        for (uuid, p) in world.players.iter() {
+            storage.put(PlayerRecord {
+                uuid: *uuid,
+                username: p.username.clone(),
+            });
+        }
+        let client_id_map = storage.client_ids();
+        ServerState {
+            config: ServerConfig::default(),
+            world,
+            clients: HashMap::new(),
+            per_ip_count: HashMap::new(),
+            username_map,
+            client_id_map,
+            reservations: HashMap::new(),
+            last_seen: HashMap::new(),
+            last_ping: HashMap::new(),
+            pending_correction: HashMap::new(),
+            storage,
+            action_cooldowns: ActionCooldowns::new(),
+            settling: SettlingTracker::new(),
+            speed_profiles: PlayerSpeedProfile::new(),
+            teleported: HashSet::new(),
+            suspects: HashMap::new(),
+            bad_dt: HashMap::new(),
+            warned: HashMap::new(),
+            violations: HashMap::new(),
+            quarantined: HashSet::new(),
+            shadow: HashMap::new(),
+            trusted: HashSet::new(),
+            observer: Arc::new(NoopObserver),
+            jitter: HashMap::new(),
+            coalesced: HashMap::new(),
+            inputs: Vec::new(),
+            locales: HashMap::new(),
+            resume_tokens: HashMap::new(),
+            uuid_generator: Box::new(V4Generator),
+            history: StateHistory::new(ServerConfig::default().history_window),
+            clock_skew: HashMap::new(),
+            rtt: RttTracker::new(),
+            world_dirty: false,
+            last_seq: HashMap::new(),
+            metrics: Metrics::new(),
+            audit: None,
+            registered_by_conn: HashMap::new(),
+            spectators: HashMap::new(),
+            rooms: HashMap::new(),
+            player_rooms: HashMap::new(),
+        }
+    }
+
+    /// 替换配置
+    pub fn with_config(mut self, config: ServerConfig) -> Self {
+        self.history = StateHistory::new(config.history_window);
+        self.trusted.extend(config.trusted_clients.iter().copied());
+        self.config = config;
+        self
+    }
+
+    /// 动作是否在白名单中（未配置白名单时总是 true）
+    pub fn action_allowed(&self, action: &str) -> bool {
+        self.config
+            .allowed_actions
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(action))
+    }
+
+    /// 玩家在客户端时间 `ts` 时的位置（来自位置历史）
+    pub fn position_at(&self, uuid: &Uuid, ts: u128) -> Option<(f64, f64, f64)> {
+        self.history.position_at(uuid, ts)
+    }
+
+    /// 替换 UUID 生成器
+    pub fn with_uuid_generator(mut self, generator: Box<dyn UuidGenerator>) -> Self {
+        self.uuid_generator = generator;
+        self
+    }
+
+    /// 把每次纠正写入审计日志
+    pub fn with_audit_log(mut self, audit: AuditLog) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// 设置事件回调
+    pub fn with_observer(mut self, observer: Arc<dyn ServerObserver>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// 判断玩家是否在线（见 `offline_deadline`；按需计算，不依赖扫描线程）
+    ///
+    /// 广播、在线计数和离线扫描都以此为准。
+    pub fn is_online(&self, uuid: &Uuid, now: Instant) -> bool {
+        self.offline_deadline(uuid).is_some_and(|d| now < d)
+    }
+
+    /// 玩家将被视为离线的时刻，以及原因（"inactivity" 或 "keepalive"）和对应的超时
+    ///
+    /// 超过 `online_timeout` 没有注册/更新即不活动离线；启用 `keepalive_timeout` 时，
+    /// 超过该时长既没有 ping 也没有更新则视为连接已断开，更早离线。
+    fn offline_deadline_with_reason(&self, uuid: &Uuid) -> Option<(Instant, &'static str, Duration)> {
+        let &seen = self.last_seen.get(uuid)?;
+        let inactive = (seen + self.config.online_timeout, "inactivity", self.config.online_timeout);
+        let Some(keepalive) = self.config.keepalive_timeout else {
+            return Some(inactive);
+        };
+        let dead = (self.last_alive(uuid)? + keepalive, "keepalive", keepalive);
+        Some(if dead.0 < inactive.0 { dead } else { inactive })
+    }
+
+    /// 玩家最后一次 ping 或注册/更新的时刻
+    fn last_alive(&self, uuid: &Uuid) -> Option<Instant> {
+        let &seen = self.last_seen.get(uuid)?;
+        Some(self.last_ping.get(uuid).map_or(seen, |&p| p.max(seen)))
+    }
+
+    /// 玩家将被视为离线的时刻（从未活动过的玩家为 None）
+    pub fn offline_deadline(&self, uuid: &Uuid) -> Option<Instant> {
+        self.offline_deadline_with_reason(uuid).map(|(d, _, _)| d)
+    }
+
+    /// 所有被跟踪玩家的离线时刻
+    pub fn offline_deadlines(&self) -> HashMap<Uuid, Instant> {
+        self.last_seen
+            .keys()
+            .filter_map(|uuid| self.offline_deadline(uuid).map(|d| (*uuid, d)))
+            .collect()
+    }
+
+    /// 所有在线玩家的状态
+    pub fn online_players(&self, now: Instant) -> HashMap<Uuid, PlayerState> {
+        self.world
+            .players
+            .iter()
+            .filter(|(uuid, _)| self.is_online(uuid, now))
+            .map(|(k, v)| (*k, v.clone()))
+            .collect()
+    }
+
+    /// 发送到网络上的世界快照（在线玩家，按配置舍入坐标）
+    pub fn snapshot(&self, now: Instant) -> HashMap<Uuid, PlayerState> {
+        let players = self.online_players(now);
+        match self.config.coord_precision {
+            Some(precision) => players
+                .into_iter()
+                .map(|(k, p)| (k, round_player(&p, precision)))
+                .collect(),
+            None => players,
+        }
+    }
+
+    /// 玩家当前绑定的连接
+    pub fn conn_of(&self, uuid: &Uuid) -> Option<ClientConn> {
+        self.clients.get(uuid).map(|c| c.conn)
+    }
+
+    /// 把玩家绑定到连接（同时维护 `per_ip_count`），返回之前绑定的连接信息
+    pub fn bind_client(&mut self, uuid: Uuid, client: ClientInfo) -> Option<ClientInfo> {
+        *self.per_ip_count.entry(client.conn.addr().ip()).or_insert(0) += 1;
+        let previous = self.clients.insert(uuid, client);
+        if let Some(previous) = previous {
+            self.release_ip(previous.conn.addr().ip());
+        }
+        previous
+    }
+
+    /// 解除玩家与连接的绑定（同时维护 `per_ip_count`）
+    pub fn unbind_client(&mut self, uuid: &Uuid) -> Option<ClientInfo> {
+        let client = self.clients.remove(uuid)?;
+        self.release_ip(client.conn.addr().ip());
+        Some(client)
+    }
+
+    fn release_ip(&mut self, ip: IpAddr) {
+        if let Some(count) = self.per_ip_count.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_ip_count.remove(&ip);
+            }
+        }
+    }
+
+    /// 发给控制 `own` 这些玩家的连接的快照：`include_self_in_broadcast` 关闭时去掉它们自己
+    fn players_for<'a>(&self, players: &'a HashMap<Uuid, PlayerState>, own: &[Uuid]) -> Cow<'a, HashMap<Uuid, PlayerState>> {
+        if self.config.include_self_in_broadcast {
+            return Cow::Borrowed(players);
+        }
+        let mut others = players.clone();
+        for uuid in own {
+            others.remove(uuid);
+        }
+        Cow::Owned(others)
+    }
+
+    /// 生成一个非玩家实体，返回它的 UUID
+    pub fn spawn_entity(&mut self, entity: Entity) -> Uuid {
+        let mut uuid = self.uuid_generator.next_uuid();
+        while self.world.players.contains_key(&uuid) || self.world.entities.contains_key(&uuid) {
+            uuid = self.uuid_generator.next_uuid();
+        }
+        self.world.entities.insert(uuid, entity);
+        self.world_dirty = true;
+        uuid
+    }
+
+    /// 移除到期的实体（`now_ms` 为服务器毫秒时间），之后的广播中不再包含它们
+    pub fn expire_entities(&mut self, now_ms: u64) -> Vec<Uuid> {
+        let expired = self.world.expire_entities(now_ms);
+        if !expired.is_empty() {
+            self.world_dirty = true;
+        }
+        expired
+    }
+
+    /// 房间内除 `except` 以外的在线玩家数
+    pub fn room_occupancy(&self, room: &str, except: Option<&Uuid>, now: Instant) -> usize {
+        self.rooms.get(room).map_or(0, |r| {
+            r.members
+                .iter()
+                .filter(|uuid| Some(*uuid) != except && self.is_online(uuid, now))
+                .count()
+        })
+    }
+
+    /// 把玩家加入房间（房间不存在时创建），并离开之前所在的房间
+    pub fn join_room(&mut self, uuid: Uuid, room: &str, now: Instant) {
+        self.leave_room(&uuid);
+        let entry = self.rooms.entry(room.to_string()).or_insert_with(|| Room {
+            members: HashSet::new(),
+            last_activity: now,
+        });
+        entry.members.insert(uuid);
+        entry.last_activity = now;
+        self.player_rooms.insert(uuid, room.to_string());
+    }
+
+    /// 玩家离开所在的房间；房间本身留到回收时才删除
+    pub fn leave_room(&mut self, uuid: &Uuid) {
+        if let Some(name) = self.player_rooms.remove(uuid) {
+            if let Some(room) = self.rooms.get_mut(&name) {
+                room.members.remove(uuid);
+            }
+        }
+    }
+
+    fn touch_room(&mut self, uuid: &Uuid, now: Instant) {
+        if let Some(room) = self.player_rooms.get(uuid).and_then(|name| self.rooms.get_mut(name)) {
+            room.last_activity = now;
+        }
+    }
+
+    /// 回收没有在线成员且空闲超过 `idle_ttl` 的房间，返回被回收的房间名
+    ///
+    /// 仍在内存中的离线成员随之离开房间，重新上线时需要重新加入。
+    pub fn reap_rooms(&mut self, now: Instant) -> Vec<String> {
+        let ttl = self.config.rooms.idle_ttl;
+        let reaped: Vec<String> = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| {
+                now.saturating_duration_since(room.last_activity) >= ttl
+                    && !room.members.iter().any(|uuid| self.is_online(uuid, now))
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &reaped {
+            if let Some(room) = self.rooms.remove(name) {
+                for uuid in room.members {
+                    self.player_rooms.remove(&uuid);
+                }
+            }
+        }
+        reaped
+    }
+
+    /// 向所有客户端广播世界状态（仅在线玩家），按各客户端的 MTU 拆分
+    ///
+    /// 控制多个实体的连接只收到一份，`seq` 取其中最大的输入序号。
+    pub fn broadcast(&self, now: Instant) -> Outgoing {
+        let players = self.snapshot(now);
+        let server_ts = now_millis();
+        let mut recipients: HashMap<ClientConn, (ClientInfo, Option<u64>, Vec<Uuid>)> = HashMap::new();
+        for (uuid, client) in &self.clients {
+            let seq = self.last_seq.get(uuid).copied().filter(|_| client.supports_seq());
+            let entry = recipients.entry(client.conn).or_insert((*client, seq, Vec::new()));
+            entry.1 = entry.1.max(seq);
+            entry.2.push(*uuid);
+        }
+        let mut out = Vec::new();
+        for (client, last_seq, own) in recipients.into_values() {
+            let mtu = client.mtu.or(self.config.default_mtu);
+            for mut msg in self.world_messages(&self.players_for(&players, &own), server_ts, mtu) {
+                if let ServerMessage::World { seq, .. } = &mut msg {
+                    *seq = last_seq;
+                }
+                out.push((client.conn, msg));
+            }
+        }
+        for (conn, view) in &self.spectators {
+            for msg in self.spectator_messages(view, &players, server_ts) {
+                out.push((*conn, msg));
+            }
+        }
+        out
+    }
+
+    /// 观战者视野内的世界快照（跟随的玩家不在线或没有位置时为空）
+    fn spectator_messages(
+        &self,
+        view: &SpectatorView,
+        players: &HashMap<Uuid, PlayerState>,
+        server_ts: u64,
+    ) -> Vec<ServerMessage> {
+        let center = match view.focus {
+            SpectatorFocus::Center(center) => Some(center),
+            SpectatorFocus::Follow(uuid) => players.get(&uuid).and_then(|p| match (p.x, p.y, p.z) {
+                (Some(x), Some(y), Some(z)) => Some((x, y, z)),
+                _ => None,
+            }),
+        };
+        let Some(center) = center else {
+            return Vec::new();
+        };
+        self.world_messages(&players_within(players, center, view.radius), server_ts, self.config.default_mtu)
+    }
+
+    /// 发给每个已连接客户端（含观战者）的停机通知
+    pub fn shutdown_notice(&self, reason: &str) -> Outgoing {
+        let conns: HashSet<ClientConn> = self
+            .clients
+            .values()
+            .map(|c| c.conn)
+            .chain(self.spectators.keys().copied())
+            .collect();
+        let msg = ServerMessage::ServerShutdown {
+            reason: reason.to_string(),
+            retry_after_secs: self.config.shutdown_reconnect_after.as_secs(),
+        };
+        conns.into_iter().map(|conn| (conn, msg.clone())).collect()
+    }
+
+    /// 把世界快照拆成编码后（按估算）不超过 `mtu` 字节的若干条 `World` 消息
+    ///
+    /// 单个玩家超过上限时单独成一片；`mtu` 为 None 或一片即可容纳时不拆分。
+    /// 配置了 `stable_broadcast_order` 时每片内的玩家按该顺序输出。
+    pub fn world_messages(
+        &self,
+        players: &HashMap<Uuid, PlayerState>,
+        server_ts: u64,
+        mtu: Option<usize>,
+    ) -> Vec<ServerMessage> {
+        let stable = self.config.stable_broadcast_order;
+        let projection = self.config.broadcast_projection;
+        let whole = |group: Vec<PlayerState>, chunk| ServerMessage::World {
+            players: match stable {
+                Some(_) => Players::ordered(group),
+                None => Players::from(group.into_iter().map(|p| (p.uuid, p)).collect::<HashMap<_, _>>()),
+            }
+            .with_projection(projection),
+            server_ts,
+            chunk,
+            seq: None,
+            entities: HashMap::new(),
+            rtt_ms: HashMap::new(),
+        };
+        let mut messages = self.player_messages(players, mtu, whole);
+        if let Some(ServerMessage::World { entities, rtt_ms, .. }) = messages.first_mut() {
+            entities.clone_from(&self.world.entities);
+            if self.config.broadcast_rtt {
+                rtt_ms.extend(
+                    players
+                        .keys()
+                        .filter_map(|uuid| self.rtt.get(uuid).map(|rtt| (*uuid, rtt.as_millis() as u64))),
+                );
+            }
+        }
+        messages
+    }
+
+    fn player_messages(
+        &self,
+        players: &HashMap<Uuid, PlayerState>,
+        mtu: Option<usize>,
+        whole: impl Fn(Vec<PlayerState>, Option<(u32, u32)>) -> ServerMessage,
+    ) -> Vec<ServerMessage> {
+        let stable = self.config.stable_broadcast_order;
+        let Some(mtu) = mtu else {
+            let group = match stable {
+                Some(order) => {
+                    let mut sorted: Vec<&PlayerState> = players.values().collect();
+                    sort_players(&mut sorted, order);
+                    sorted.into_iter().cloned().collect()
+                }
+                None => players.values().cloned().collect(),
+            };
+            return vec![whole(group, None)];
+        };
+        let codec = self.config.wire_format.codec();
+        // 分片头（不含玩家）的大小，按最长的分片序号估算
+        let overhead = codec.encode(&whole(Vec::new(), Some((u32::MAX, u32::MAX)))).len();
+
+        // 拆分时总是排序，保证同一快照的分片结果稳定
+        let mut sorted: Vec<&PlayerState> = players.values().collect();
+        sort_players(&mut sorted, stable.unwrap_or_default());
+        let mut groups: Vec<Vec<PlayerState>> = Vec::new();
+        let mut current = Vec::new();
+        let mut size = overhead;
+        for player in sorted {
+            // 多出的 1 字节留给条目之间的分隔符
+            let entry = codec.encode(&whole(vec![player.clone()], None)).len().saturating_sub(overhead) + 1;
+            if !current.is_empty() && size + entry > mtu {
+                groups.push(std::mem::take(&mut current));
+                size = overhead;
+            }
+            current.push(player.clone());
+            size += entry;
+        }
+        if groups.is_empty() {
+            return vec![whole(current, None)];
+        }
+        groups.push(current);
+        let total = groups.len() as u32;
+        groups
+            .into_iter()
+            .enumerate()
+            .map(|(i, group)| whole(group, Some((i as u32, total))))
+            .collect()
+    }
+
+    /// 服务器权威模式下把所有在线玩家推进 `dt`
+    pub fn step_physics(&mut self, dt: Duration, now: Instant) {
+        let online: Vec<Uuid> = self
+            .world
+            .players
+            .keys()
+            .filter(|uuid| self.is_online(uuid, now))
+            .copied()
+            .collect();
+        for uuid in online {
+            if let Some(player) = self.world.players.get_mut(&uuid) {
+                step_player(player, dt);
+                self.world_dirty = true;
+            }
+        }
+    }
+
+    /// 世界状态有修改时写入 `path` 并清除修改标记，返回是否实际写入
+    pub fn checkpoint(&mut self, path: &str) -> io::Result<bool> {
+        if !self.world_dirty {
+            return Ok(false);
+        }
+        crate::ts_millis::with_rfc3339(self.config.rfc3339_timestamps, || self.world.save_to_file(path))?;
+        self.world_dirty = false;
+        Ok(true)
+    }
+
+    /// 局域网发现信息
+    pub fn server_info(&self, now: Instant) -> ServerMessage {
+        ServerMessage::ServerInfo {
+            name: self.config.server_name.clone(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            player_count: self.online_players(now).len(),
+            max_players: self.config.max_players,
+        }
+    }
+
+    /// 客户端可见的服务器参数（`config` 查询）
+    pub fn config_info(&self) -> ServerMessage {
+        let config = &self.config;
+        ServerMessage::ServerConfigInfo {
+            tolerance: config.movement.tolerance,
+            max_speed_x: config.movement.max_speed_x,
+            max_speed_y: config.movement.max_speed_y,
+            max_speed_z: config.movement.max_speed_z,
+            grid: config.movement.grid,
+            coord_precision: config.coord_precision,
+            server_authoritative: config.physics_mode == PhysicsMode::ServerAuthoritative,
+            tick_ms: config.tick_interval().unwrap_or(config.physics_step).as_millis() as u64,
+            online_timeout_secs: config.online_timeout.as_secs(),
+            keepalive_timeout_secs: config.keepalive_timeout.map(|t| t.as_secs()),
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: config.min_protocol_version,
+        }
+    }
+
+    /// 按玩家的 locale 渲染提示文本
+    pub fn message_for(&self, uuid: &Uuid, key: MessageKey, vars: &[(&str, &str)]) -> String {
+        let locale = self.locales.get(uuid).map(|s| s.as_str()).unwrap_or(DEFAULT_LOCALE);
+        self.config.messages.render(locale, key, vars)
+    }
+
+    /// 找出刚刚超时的玩家，生成离线通知（`notified` 记录已通知过的玩家）
+    ///
+    /// 除了发给该玩家本人的 `Offline`，还向其余在线客户端广播 `PlayerOffline`。
+    pub fn expire_inactive(&self, notified: &mut HashSet<Uuid>, now: Instant) -> Outgoing {
+        let mut out = Vec::new();
+        let online_conns = self.online_conns(now);
+        for uuid in collect_past_deadline(&self.offline_deadlines(), notified, now) {
+            let Some(player) = self.world.players.get(&uuid) else {
+                continue;
+            };
+            // 已解除绑定的玩家由 `mark_offline` 处理过（离线已经广播）
+            let Some(own) = self.conn_of(&uuid) else {
+                continue;
+            };
+            let (_, reason, timeout) = self
+                .offline_deadline_with_reason(&uuid)
+                .unwrap_or((now, "inactivity", self.config.online_timeout));
+            self.observer.on_leave(uuid, &player.username, reason);
+            out.push((
+                own,
+                ServerMessage::Offline {
+                    reason: reason.to_string(),
+                    uuid,
+                    message: self.message_for(
+                        &uuid,
+                        MessageKey::Offline,
+                        &[("timeout", &timeout.as_secs().to_string())],
+                    ),
+                    retry_after_secs: 0,
+                },
+            ));
+            for conn in online_conns.iter().filter(|c| **c != own) {
+                out.push((*conn, ServerMessage::PlayerOffline { uuid }));
+            }
+        }
+        out
+    }
+
+    /// 向距离离线不足 `inactivity_warning` 的在线玩家发送提醒，每段不活动期只提醒一次
+    pub fn warn_inactive(&mut self, now: Instant) -> Outgoing {
+        let Some(lead) = self.config.inactivity_warning else {
+            return Vec::new();
+        };
+        let deadlines = self.offline_deadlines();
+        self.warned.retain(|uuid, at| deadlines.get(uuid) == Some(at));
+        let mut due: Vec<(Instant, Uuid)> = deadlines
+            .iter()
+            .filter(|(uuid, &d)| now < d && now + lead >= d && !self.warned.contains_key(uuid))
+            .map(|(uuid, &d)| (d, *uuid))
+            .collect();
+        due.sort();
+        let mut out = Vec::new();
+        for (deadline, uuid) in due {
+            let Some(own) = self.conn_of(&uuid) else {
+                continue;
+            };
+            self.warned.insert(uuid, deadline);
+            let remaining = deadline - now;
+            let seconds_remaining = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+            out.push((own, ServerMessage::InactivityWarning { seconds_remaining }));
+        }
+        out
+    }
+
+    /// 距离下一次需要发送不活动提醒还有多久（没有待提醒的玩家时返回 None）
+    pub fn next_warning_delay(&self, now: Instant) -> Option<Duration> {
+        let lead = self.config.inactivity_warning?;
+        self.offline_deadlines()
+            .iter()
+            .filter(|(uuid, &d)| now < d && self.warned.get(uuid) != Some(&d) && self.conn_of(uuid).is_some())
+            .map(|(_, &d)| d.checked_sub(lead).map_or(Duration::ZERO, |w| w.saturating_duration_since(now)))
+            .min()
+    }
+
+    /// 控制着在线玩家的连接
+    pub fn online_conns(&self, now: Instant) -> HashSet<ClientConn> {
+        self.clients
+            .iter()
+            .filter(|(uuid, _)| self.is_online(uuid, now))
+            .map(|(_, client)| client.conn)
+            .collect()
+    }
+
+    /// 为已被占用的 `base` 生成建议名
+    ///
+    /// 以 `username_map` 为准（在线玩家和仍保留名字的离线玩家），与注册时的冲突检查一致。
+    pub fn suggest_name(&self, base: &str) -> String {
+        generate_unique_name_by(
+            |name| self.username_map.contains_key(name),
+            base,
+            self.config.max_name_suffix,
+            self.config.name_suffix_strategy,
+        )
+    }
+
+    /// 扫描线程每轮在一次加锁内完成的离线处理：提醒即将超时的玩家、通知刚超时的玩家、释放到期的名字保留、
+    /// 移除长时间离线的玩家，返回离线通知
+    ///
+    /// 候选玩家的判定和处理都基于此刻的 `last_seen`，在此之前重新活动过的玩家不会被标记离线。
+    pub fn prune_offline(&mut self, notified: &mut HashSet<Uuid>, now: Instant) -> Outgoing {
+        let mut out = self.warn_inactive(now);
+        out.extend(self.expire_inactive(notified, now));
+        self.release_names(now);
+        for uuid in self.evict_offline(now) {
+            notified.remove(&uuid);
+        }
+        self.reap_rooms(now);
+        self.expire_entities(now_millis());
+        out
+    }
+
+    /// 按 `name_reservation` 维护离线玩家的用户名保留：刚离线的玩家开始保留，
+    /// 重新上线的玩家取消保留，保留到期的用户名释放给其他玩家
+    pub fn release_names(&mut self, now: Instant) {
+        let Some(ttl) = self.config.name_reservation else {
+            return;
+        };
+        let back_online: Vec<String> = self
+            .reservations
+            .iter()
+            .filter(|(_, (uuid, _))| self.is_online(uuid, now))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in back_online {
+            self.reservations.remove(&name);
+        }
+        for (name, uuid) in &self.username_map {
+            if self.reservations.contains_key(name) || !self.last_seen.contains_key(uuid) || self.is_online(uuid, now) {
+                continue;
+            }
+            if let Some(offline_at) = self.offline_deadline(uuid) {
+                self.reservations.insert(name.clone(), (*uuid, offline_at + ttl));
+            }
+        }
+        let expired: Vec<(String, Uuid)> = self
+            .reservations
+            .iter()
+            .filter(|(_, (_, until))| *until <= now)
+            .map(|(name, (uuid, _))| (name.clone(), *uuid))
+            .collect();
+        for (name, uuid) in expired {
+            self.reservations.remove(&name);
+            if self.username_map.get(&name) == Some(&uuid) {
+                self.username_map.remove(&name);
+                println!("Released reserved name {} of offline player {}", name, uuid);
+            }
+        }
+    }
+
+    /// 从内存中移除离线超过 `evict_after` 的玩家，返回被移除的 uuid
+    ///
+    /// 身份存储中的记录保留，玩家之后仍可用原 UUID 恢复。从未活动过的玩家
+    /// （例如从磁盘加载、尚未重新加入的）不会被移除。
+    pub fn evict_offline(&mut self, now: Instant) -> Vec<Uuid> {
+        let Some(evict_after) = self.config.evict_after else {
+            return Vec::new();
+        };
+        let threshold = self.config.online_timeout + evict_after;
+        let evicted: Vec<Uuid> = self
+            .last_seen
+            .iter()
+            .filter(|(_, &t)| now.saturating_duration_since(t) >= threshold)
+            .map(|(uuid, _)| *uuid)
+            .collect();
+        for uuid in &evicted {
+            if let Some(player) = self.remove_player(uuid) {
+                println!("Evicted long-offline player {} ({})", player.username, uuid);
+            }
+        }
+        evicted
+    }
+
+    /// 把玩家标记为离线：解除连接绑定，并向其余在线客户端广播 `PlayerOffline`
+    ///
+    /// 幂等：玩家不存在、已经离线或已经解除绑定时什么也不做，返回空列表，
+    /// 与扫描线程的超时离线同时发生也只会广播一次。玩家仍留在世界中，之后照常被移除或恢复会话。
+    pub fn mark_offline(&mut self, uuid: &Uuid, reason: &str, now: Instant) -> Outgoing {
+        let Some(username) = self.world.players.get(uuid).map(|p| p.username.clone()) else {
+            return Vec::new();
+        };
+        let was_online = self.is_online(uuid, now);
+        let Some(client) = self.unbind_client(uuid) else {
+            return Vec::new();
+        };
+        if self.registered_by_conn.get(&client.conn).is_some_and(|(u, _)| u == uuid) {
+            self.registered_by_conn.remove(&client.conn);
+        }
+        // 超时时间回拨到此刻：立即离线，移除计时照常从这里开始
+        match now.checked_sub(self.config.online_timeout) {
+            Some(at) => {
+                self.last_seen.insert(*uuid, at);
+            }
+            None => {
+                self.last_seen.remove(uuid);
+            }
+        }
+        self.last_ping.remove(uuid);
+        if !was_online {
+            // 已经超时离线，扫描线程已经广播过
+            return Vec::new();
+        }
+        self.observer.on_leave(*uuid, &username, reason);
+        self.online_conns(now)
+            .into_iter()
+            .filter(|conn| *conn != client.conn)
+            .map(|conn| (conn, ServerMessage::PlayerOffline { uuid: *uuid }))
+            .collect()
+    }
+
+    /// 把玩家从内存中移除（保存最后位置、释放用户名和所有按玩家记录的状态），返回被移除的玩家
+    ///
+    /// 容忍缺失的记录：对已移除的玩家再次调用只会返回 None。
+    pub fn remove_player(&mut self, uuid: &Uuid) -> Option<PlayerState> {
+        let player = self.world.players.remove(uuid);
+        if let Some(player) = &player {
+            if let (Some(x), Some(y), Some(z)) = (player.x, player.y, player.z) {
+                self.storage.put_position(*uuid, (x, y, z));
+            }
+            if self.username_map.get(&player.username) == Some(uuid) {
+                self.username_map.remove(&player.username);
+                self.reservations.remove(&player.username);
+            }
+            self.world_dirty = true;
+        }
+        if let Some(client) = self.unbind_client(uuid) {
+            if self.registered_by_conn.get(&client.conn).is_some_and(|(u, _)| u == uuid) {
+                self.registered_by_conn.remove(&client.conn);
+            }
+        }
+        self.last_seen.remove(uuid);
+        self.last_ping.remove(uuid);
+        self.warned.remove(uuid);
+        self.reset_movement_state(uuid);
+        self.locales.remove(uuid);
+        self.resume_tokens.remove(uuid);
+        self.leave_room(uuid);
+        player
+    }
+
+    /// 清除玩家的移动校验、纠正确认、排队输入和 RTT 等状态（位置被外部替换后这些状态不再成立）
+    pub fn reset_movement_state(&mut self, uuid: &Uuid) {
+        self.pending_correction.remove(uuid);
+        self.teleported.remove(uuid);
+        self.suspects.remove(uuid);
+        self.bad_dt.remove(uuid);
+        self.action_cooldowns.remove(uuid);
+        self.settling.remove(uuid);
+        self.quarantined.remove(uuid);
+        self.speed_profiles.remove(uuid);
+        self.jitter.remove(uuid);
+        self.coalesced.remove(uuid);
+        self.inputs.retain(|(owner, _)| owner != uuid);
+        self.rtt.remove(uuid);
+        self.clock_skew.remove(uuid);
+        self.last_seq.remove(uuid);
+        self.history.remove(uuid);
+    }
+
+    /// 处理所有抖动缓冲中已到期的更新
+    pub fn flush_jitter(&mut self, now: Instant) -> Outgoing {
+        let mut ready = Vec::new();
+        for (uuid, buffer) in self.jitter.iter_mut() {
+            ready.extend(buffer.drain_ready(now).into_iter().map(|v| (*uuid, v)));
+        }
+        self.jitter.retain(|_, buffer| !buffer.is_empty());
+
+        let mut out = Vec::new();
+        for (uuid, val) in ready {
+            if let Some(src) = self.conn_of(&uuid) {
+                out.extend(apply_update(self, src, uuid, &val, now));
+            }
+        }
+        out
+    }
+
+    /// 应用本周期合并后的更新，并广播一次世界状态
+    pub fn flush_coalesced(&mut self, now: Instant) -> Outgoing {
+        let pending: Vec<(Uuid, Value)> = self.coalesced.drain().collect();
+        let mut out = Vec::new();
+        for (uuid, val) in pending.iter() {
+            if let Some(src) = self.conn_of(uuid) {
+                out.extend(apply_update_fields(self, src, *uuid, val, now));
+            }
+        }
+        if !pending.is_empty() {
+            self.separate_players(now);
+            out.extend(self.broadcast(now));
+        }
+        out
+    }
+
+    /// 推进一个模拟周期：按到达顺序校验并应用排队的更新，服务器权威模式下再推进 `dt` 的物理，
+    /// 有变化时统一广播一次
+    ///
+    /// 只依赖传入的 `dt` 和 `now`，相同的输入和时间得到相同的结果。
+    pub fn tick(&mut self, dt: Duration, now: Instant) -> Outgoing {
+        let inputs = std::mem::take(&mut self.inputs);
+        let mut out = Vec::new();
+        for (uuid, val) in inputs.iter() {
+            if let Some(src) = self.conn_of(uuid) {
+                out.extend(apply_update_fields(self, src, *uuid, val, now));
+            }
+        }
+        let simulated = self.config.physics_mode == PhysicsMode::ServerAuthoritative;
+        if simulated {
+            self.step_physics(dt, now);
+        }
+        if !inputs.is_empty() || simulated {
+            self.separate_players(now);
+            out.extend(self.broadcast(now));
+        }
+        out
+    }
+
+    /// 按 `collision_radius` 推开重叠的在线玩家，返回被移动过的玩家（未配置时不做任何事）
+    pub fn separate_players(&mut self, now: Instant) -> Vec<Uuid> {
+        let Some(radius) = self.config.collision_radius else {
+            return Vec::new();
+        };
+        let mut online = WorldState { players: self.online_players(now), entities: HashMap::new() };
+        let moved = resolve_collisions(&mut online, radius);
+        for uuid in &moved {
+            if let Some(player) = online.players.remove(uuid) {
+                self.world.players.insert(*uuid, player);
+                self.world_dirty = true;
+            }
+        }
+        moved
+    }
+}
+
+/// 消息处理失败的原因
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandlerError {
+    /// 数据包不是合法的 UTF-8
+    InvalidUtf8,
+    /// 数据包不是合法的 JSON
+    MalformedJson(String),
+    /// 缺少 `type` 字段
+    MissingType,
+    /// 不支持的 `type`
+    UnknownType(String),
+    /// 必需字段缺失或格式错误
+    InvalidField(&'static str),
+    /// 字段存在但类型不符
+    Malformed(FieldError),
+    /// UUID 对应的玩家不存在
+    UnknownPlayer(Uuid),
+    /// 用户名对应的玩家不存在
+    UnknownUsername(String),
+    /// 来源连接与该 UUID 绑定的连接不一致（或尚未注册）
+    Unauthorized(Uuid),
+    /// 管理消息的密钥缺失或错误（或未配置管理密钥）
+    Forbidden,
+    /// 数据包超过 `max_recv_bytes`
+    PayloadTooLarge { size: usize, limit: usize },
+}
+
+impl HandlerError {
+    /// 错误代码（回复给客户端的 `error` 字段）
+    pub fn code(&self) -> &'static str {
+        match self {
+            HandlerError::InvalidUtf8 => "invalid_utf8",
+            HandlerError::MalformedJson(_) => "malformed_json",
+            HandlerError::MissingType => "missing_type",
+            HandlerError::UnknownType(_) => "unknown_type",
+            HandlerError::InvalidField(_) => "invalid_field",
+            HandlerError::Malformed(_) => "malformed_request",
+            HandlerError::UnknownPlayer(_) => "unknown_player",
+            HandlerError::UnknownUsername(_) => "unknown_username",
+            HandlerError::Unauthorized(_) => "unauthorized",
+            HandlerError::Forbidden => "forbidden",
+            HandlerError::PayloadTooLarge { .. } => "payload_too_large",
+        }
+    }
+
+    /// 转换为回复给来源地址的错误消息
+    pub fn to_reply(&self) -> ServerMessage {
+        let unknown_type = |received: Option<String>| ServerMessage::UnknownType {
+            received,
+            supported: MESSAGE_TYPES.iter().map(|t| t.to_string()).collect(),
+        };
+        match self {
+            HandlerError::PayloadTooLarge { size, limit } => ServerMessage::PayloadTooLarge {
+                size: *size,
+                limit: *limit,
+            },
+            HandlerError::MissingType => unknown_type(None),
+            HandlerError::UnknownType(t) => unknown_type(Some(t.clone())),
+            HandlerError::Malformed(e) => ServerMessage::MalformedRequest {
+                field: e.field.to_string(),
+                expected: e.expected.to_string(),
+            },
+            _ => ServerMessage::Error {
+                error: self.code().to_string(),
+                message: self.to_string(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandlerError::InvalidUtf8 => write!(f, "payload is not valid utf-8"),
+            HandlerError::MalformedJson(e) => write!(f, "payload is not valid json: {}", e),
+            HandlerError::MissingType => write!(f, "message has no \"type\" field"),
+            HandlerError::UnknownType(t) => write!(f, "unknown message type \"{}\"", t),
+            HandlerError::InvalidField(field) => {
+                write!(f, "field \"{}\" is missing or invalid", field)
+            }
+            HandlerError::Malformed(e) => write!(f, "field \"{}\" must be a {}", e.field, e.expected),
+            HandlerError::UnknownPlayer(uuid) => write!(f, "no player with uuid {}", uuid),
+            HandlerError::UnknownUsername(name) => write!(f, "no player named \"{}\"", name),
+            HandlerError::Unauthorized(uuid) => {
+                write!(f, "source address is not registered for {}", uuid)
+            }
+            HandlerError::Forbidden => write!(f, "admin secret is missing or wrong"),
+            HandlerError::PayloadTooLarge { size, limit } => {
+                write!(f, "payload of {} bytes exceeds limit {}", size, limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for HandlerError {}
+
+impl From<InboundError> for HandlerError {
+    fn from(e: InboundError) -> Self {
+        match e {
+            InboundError::InvalidUtf8 => HandlerError::InvalidUtf8,
+            InboundError::Malformed(e) => HandlerError::MalformedJson(e),
+        }
+    }
+}
+
+impl From<FieldError> for HandlerError {
+    fn from(e: FieldError) -> Self {
+        HandlerError::Malformed(e)
+    }
+}
+
+/// 表示“没有动作”的动作名，不会单独通知
+pub const IDLE_ACTION: &str = "idle";
+
+/// 表示“重生”的动作名：移到重生点的这一次更新不做移动校验（见 `MovementConfig::spawn_points`）
+pub const RESPAWN_ACTION: &str = "respawn";
+
+/// `handle_message` 支持的消息类型（`discover` 需要在配置中开启，不在此列）
+pub const MESSAGE_TYPES: &[&str] = &[
+    "register", "update", "batch_update", "whoami", "get", "ping", "teleport", "reset", "trust", "quarantine",
+    "spectate", "kick", "config", "import",
+];
+
+/// 与 `handle_message` 相同，并把结果和处理耗时记入 `state.metrics`
+pub fn handle_message_instrumented(
+    state: &mut ServerState,
+    src: ClientConn,
+    payload: &[u8],
+    now: Instant,
+) -> Result<Outgoing, HandlerError> {
+    let started = Instant::now();
+    let result = handle_message(state, src, payload, now);
+    state.metrics.handler_latency.record(started.elapsed());
+    state.metrics.record(&result);
+    result
+}
+
+/// 处理一个数据包，返回需要发送的消息
+pub fn handle_message(
+    state: &mut ServerState,
+    src: ClientConn,
+    payload: &[u8],
+    now: Instant,
+) -> Result<Outgoing, HandlerError> {
+    let limit = state.config.max_recv_bytes;
+    if payload.len() > limit {
+        return Err(HandlerError::PayloadTooLarge {
+            size: payload.len(),
+            limit,
+        });
+    }
+    let val = match state.config.wire_format.codec().decode_inbound(payload) {
+        Ok(val) => val,
+        Err(e) => {
+            let e = HandlerError::from(e);
+            // 转储只在 debug 级别输出，生产环境保持安静
+            log::debug!(
+                "unparseable packet from {} ({} bytes): {}: {}",
+                src,
+                payload.len(),
+                e,
+                dump_payload(payload, state.config.packet_dump_bytes)
+            );
+            return Err(e);
+        }
+    };
+    let t = val
+        .get("type")
+        .and_then(|x| x.as_str())
+        .ok_or(HandlerError::MissingType)?;
+
+    match t {
+        "register" => handle_register(state, src, &val, now),
+        "update" => handle_update(state, src, &val, now),
+        "batch_update" => handle_batch_update(state, src, &val, now),
+        "whoami" => handle_whoami(state, src, &val, now),
+        "get" => handle_get(state, src, &val, now),
+        "teleport" => handle_teleport(state, &val, now),
+        "reset" => handle_reset(state, &val),
+        "import" => handle_import(state, src, &val, now),
+        "trust" => handle_trust(state, src, &val),
+        "quarantine" => handle_quarantine(state, src, &val),
+        "spectate" => handle_spectate(state, src, &val, now),
+        "kick" => handle_kick(state, src, &val, now),
+        "config" => Ok(vec![(src, state.config_info())]),
+        "ping" => Ok(handle_ping(state, src, &val, now)),
+        "discover" if state.config.discovery => Ok(vec![(src, state.server_info(now))]),
+        other => Err(HandlerError::UnknownType(other.to_string())),
+    }
+}
+
+/// 回复 pong；已注册的客户端可以带上 `uuid`（用于保活）和上一个 pong 的 `echo_ts`
+///
+/// RTT 只由服务器按 `echo_ts` 测得：它决定移动校验的容差，客户端自报的值不可信。
+fn handle_ping(state: &mut ServerState, src: ClientConn, val: &Value, now: Instant) -> Outgoing {
+    let uuid = val
+        .get("uuid")
+        .and_then(|x| x.as_str())
+        .and_then(|s| Uuid::parse_str(s).ok());
+    let server_ts = now_millis();
+    if let Some(uuid) = uuid {
+        if state.conn_of(&uuid) == Some(src) {
+            state.last_ping.insert(uuid, now);
+            estimate_clock_skew(state, uuid, val);
+            measure_rtt(state, uuid, val);
+            state.rtt.on_send(uuid, server_ts);
+        }
+    }
+    vec![(
+        src,
+        ServerMessage::Pong {
+            client_ts: val.get("client_ts").and_then(|x| x.as_u64()),
+            server_ts,
+        },
+    )]
+}
+
+/// 客户端回显了之前 pong 中的 `server_ts`（`echo_ts`）时计入一个 RTT 样本
+fn measure_rtt(state: &mut ServerState, uuid: Uuid, val: &Value) {
+    if let Some(echoed) = val.get("echo_ts").and_then(crate::ts_millis::from_value) {
+        state.rtt.on_recv(uuid, echoed, now_millis());
+    }
+}
+
+/// 按消息中的 `client_ts` 估计客户端时钟偏差（忽略单程网络延迟；未开启 `correct_clock_skew` 时不记录）
+fn estimate_clock_skew(state: &mut ServerState, uuid: Uuid, val: &Value) {
+    if !state.config.correct_clock_skew {
+        return;
+    }
+    if let Some(client_ts) = val.get("client_ts").and_then(|x| x.as_u64()) {
+        state.clock_skew.insert(uuid, i128::from(client_ts) - i128::from(now_millis()));
+    }
+}
+
+/// 取出玩家的会话恢复凭证，没有则新发一个
+fn resume_token_for(state: &mut ServerState, uuid: Uuid) -> String {
+    state
+        .resume_tokens
+        .entry(uuid)
+        .or_insert_with(|| format!("{:016x}", rand::random::<u64>()))
+        .clone()
+}
+
+/// 恢复会话的玩家重新占用自己的用户名；名字已被其他玩家占用（保留期过后被注册）时改用建议名
+fn claim_name(state: &mut ServerState, uuid: Uuid, username: String) -> String {
+    state.reservations.remove(&username);
+    let username = match state.username_map.get(&username) {
+        Some(owner) if *owner != uuid => {
+            let renamed = state.suggest_name(&username);
+            state.storage.put(PlayerRecord {
+                uuid,
+                username: renamed.clone(),
+            });
+            renamed
+        }
+        _ => username,
+    };
+    state.username_map.insert(username.clone(), uuid);
+    username
+}
+
+/// 把只存在于身份存储中的玩家放回内存世界；用户名已被他人占用时改用建议名
+fn restore_from_storage(state: &mut ServerState, record: PlayerRecord) -> PlayerState {
+    let username = claim_name(state, record.uuid, record.username);
+    let mut player = PlayerState::new(record.uuid, username);
+    // 回到移出内存时的位置；没有保存过位置时放到默认出生点
+    if let Some((x, y, z)) = state.storage.last_position(&record.uuid).or(state.config.default_spawn) {
+        player = player.with_position(x, y, z);
+        player.ts = Some(u128::from(now_millis()));
+    }
+    state.world.players.insert(record.uuid, player.clone());
+    state.world_dirty = true;
+    player
+}
+
+/// 恢复会话时采用客户端声明的位置：与已记录的位置相距不超过 `max_resume_distance`
+/// （或服务器没有记录位置）时采用并返回 true，否则保留服务器的位置
+fn adopt_resume_position(state: &ServerState, player: &mut PlayerState, (x, y, z): (f64, f64, f64)) -> bool {
+    if !(x.is_finite() && y.is_finite() && z.is_finite()) {
+        return false;
+    }
+    if let (Some(px), Some(py), Some(pz)) = (player.x, player.y, player.z) {
+        let distance = ((x - px).powi(2) + (y - py).powi(2) + (z - pz).powi(2)).sqrt();
+        if distance > state.config.max_resume_distance {
+            println!(
+                "Ignored resume position of {}: {:.2}m from stored position",
+                player.username, distance
+            );
+            return false;
+        }
+    }
+    player.x = Some(x);
+    player.y = Some(y);
+    player.z = Some(z);
+    player.ts = Some(u128::from(now_millis()));
+    true
+}
+
+fn handle_register(
+    state: &mut ServerState,
+    src: ClientConn,
+    val: &Value,
+    now: Instant,
+) -> Result<Outgoing, HandlerError> {
+    let request = RegisterRequest::from_value(val)?;
+    // 已知的 client_id 等同于出示了它对应的 uuid；两者同时出示时必须一致
+    let mapped_uuid = request.client_id.as_ref().and_then(|id| state.client_id_map.get(id)).copied();
+    if let (Some(requested), Some(mapped)) = (request.uuid, mapped_uuid) {
+        if requested != mapped {
+            return Err(HandlerError::Unauthorized(requested));
+        }
+    }
+    let requested_uuid = request.uuid.or(mapped_uuid);
+    let uname_opt = request.username.as_deref();
+    let locale = request.locale.as_deref();
+    let mtu = request.mtu;
+    let protocol_version = request.protocol_version;
+    state.release_names(now);
+
+    // 协议版本过旧的客户端无法正确解析当前的消息，直接拒绝
+    if protocol_version.is_some_and(|v| v < state.config.min_protocol_version) {
+        return Ok(vec![(
+            src,
+            ServerMessage::IncompatibleVersion {
+                server: PROTOCOL_VERSION,
+                min_supported: state.config.min_protocol_version,
+            },
+        )]);
+    }
+
+    // Try to resume if provided uuid exists
+    if let Some(existing_uuid) = requested_uuid {
+        // 已从内存中移除的玩家：先只读出身份记录，所有检查通过后再放回世界
+        let (mut player, stored) = match state.world.players.get(&existing_uuid) {
+            Some(player) => (player.clone(), None),
+            None => match state.storage.get(&existing_uuid) {
+                Some(record) => (PlayerState::new(record.uuid, record.username.clone()), Some(record)),
+                None => {
+                    // UUID 不存在，无法恢复
+                    return Ok(vec![(
+                        src,
+                        ServerMessage::UuidNotFound {
+                            uuid: existing_uuid,
+                            message: "提供的 UUID 不存在，请提供用户名以创建新账号".to_string(),
+                        },
+                    )]);
+                }
+            },
+        };
+
+        if let Err(e) = state.config.authenticator.verify(&player.username, request.token.as_deref().unwrap_or("")) {
+            return Ok(vec![(src, ServerMessage::AuthFailed { reason: e.0 })]);
+        }
+
+        // 出示了凭证就必须正确（玩家被移出内存后凭证随之失效，无从校验）；
+        // 在线玩家换地址时可配置为必须出示
+        let token = request.resume_token.as_deref();
+        let expected_token = state.resume_tokens.get(&existing_uuid).map(|t| t.as_str());
+        let token_valid = token.is_some() && expected_token == token;
+        let token = token.filter(|_| expected_token.is_some());
+        let online = state.is_online(&existing_uuid, now);
+        let rebinding = online && state.conn_of(&existing_uuid).is_some_and(|c| c != src);
+        let token_required = rebinding && state.config.require_resume_token;
+        if !token_valid && (token.is_some() || token_required) {
+            return Err(HandlerError::Unauthorized(existing_uuid));
+        }
+        if let Some(reply) = room_full(state, request.room.as_deref(), Some(&existing_uuid), now) {
+            return Ok(vec![(src, reply)]);
+        }
+        // 恢复同样占用来源 IP 的名额（已绑定在同一 IP 上的玩家换端口不额外占用）
+        if let Some(limit) = state.config.max_registrations_per_ip {
+            let ip = src.addr().ip();
+            let same_ip = state.conn_of(&existing_uuid).is_some_and(|c| c.addr().ip() == ip);
+            if !same_ip && state.per_ip_count.get(&ip).copied().unwrap_or(0) >= limit {
+                return Ok(vec![(src, ServerMessage::TooManyFromAddress { limit })]);
+            }
+        }
+        if let Some(record) = stored {
+            player = restore_from_storage(state, record);
+        }
+
+        // 更新或添加到索引；旧地址随之不再收到广播
+        let username = claim_name(state, existing_uuid, player.username.clone());
+        if username != player.username {
+            player.username = username;
+            state.world.players.insert(existing_uuid, player.clone());
+            state.world_dirty = true;
+        }
+        let client = ClientInfo::new(src).with_mtu(mtu).with_protocol_version(protocol_version);
+        if let Some(previous) = state.bind_client(existing_uuid, client) {
+            state.registered_by_conn.remove(&previous.conn);
+        }
+        state.registered_by_conn.insert(src, (existing_uuid, now));
+        state.last_seen.insert(existing_uuid, now);
+        estimate_clock_skew(state, existing_uuid, val);
+        if let Some(room) = &request.room {
+            state.join_room(existing_uuid, room, now);
+        }
+        // 会话仍在线时（换网重绑）不重新进入宽限期
+        if !online {
+            state.settling.join(existing_uuid, now);
+        }
+        if let Some(locale) = locale {
+            state.locales.insert(existing_uuid, locale.to_string());
+        }
+        if let Some(claimed) = request.position {
+            if adopt_resume_position(state, &mut player, claimed) {
+                state.world.players.insert(existing_uuid, player.clone());
+                state.world_dirty = true;
+            }
+        }
+        state.observer.on_join(existing_uuid, &player.username, src, true);
+
+        let mut out = vec![(
+            src,
+            ServerMessage::Registered {
+                uuid: existing_uuid,
+                username: player.username.clone(),
+                state: Some(player),
+                resumed: true,
+                resume_token: Some(resume_token_for(state, existing_uuid)),
+                server_ts: now_millis(),
+                protocol_version: PROTOCOL_VERSION,
+            },
+        )];
+        // 紧跟 registered 之后单独给恢复的客户端发一份完整快照，
+        // 让它无需等待下一次广播即可填充视图
+        let own_mtu = mtu.or(state.config.default_mtu);
+        let snapshot = state.snapshot(now);
+        for msg in state.world_messages(&state.players_for(&snapshot, &[existing_uuid]), now_millis(), own_mtu) {
+            out.push((src, msg));
+        }
+        out.extend(state.broadcast(now).into_iter().filter(|(addr, _)| *addr != src));
+        return Ok(out);
+    }
+
+    // 如果没有提供用户名，无法创建新账号
+    let Some(uname) = uname_opt else {
+        return Ok(vec![(
+            src,
+            ServerMessage::UsernameRequired {
+                message: "请提供用户名以创建新账号".to_string(),
+            },
+        )]);
+    };
+    // 账号系统确认的用户名为准
+    let claims = match state.config.authenticator.verify(uname, request.token.as_deref().unwrap_or("")) {
+        Ok(claims) => claims,
+        Err(e) => return Ok(vec![(src, ServerMessage::AuthFailed { reason: e.0 })]),
+    };
+    let uname = claims.username.as_str();
+
+    // 同一连接刚刚以同名注册过（如 UDP 重传）：返回已有的注册而不是再建一个玩家
+    if let Some(&(uuid, at)) = state.registered_by_conn.get(&src) {
+        let window = state.config.duplicate_register_window;
+        let same_player = state.world.players.get(&uuid).filter(|p| p.username == uname);
+        if let Some(player) = same_player {
+            if now.saturating_duration_since(at) < window && state.conn_of(&uuid) == Some(src) {
+                let username = player.username.clone();
+                state.last_seen.insert(uuid, now);
+                return Ok(vec![(
+                    src,
+                    ServerMessage::Registered {
+                        uuid,
+                        username,
+                        state: None,
+                        resumed: false,
+                        resume_token: Some(resume_token_for(state, uuid)),
+                        server_ts: now_millis(),
+                        protocol_version: PROTOCOL_VERSION,
+                    },
+                )]);
+            }
+        }
+    }
+
+    // 同一 IP 上绑定的玩家过多（例如一台主机换着端口批量注册）
+    if let Some(limit) = state.config.max_registrations_per_ip {
+        if state.per_ip_count.get(&src.addr().ip()).copied().unwrap_or(0) >= limit {
+            return Ok(vec![(src, ServerMessage::TooManyFromAddress { limit })]);
+        }
+    }
+    if let Some(reply) = room_full(state, request.room.as_deref(), None, now) {
+        return Ok(vec![(src, reply)]);
+    }
+
+    // Check for active username conflict
+    let renamed;
+    let uname = if state.username_map.contains_key(uname) {
+        let locale = locale.unwrap_or(DEFAULT_LOCALE);
+        match state.config.username_policy {
+            UsernamePolicy::Suggest => {
+                let suggested = state.suggest_name(uname);
+                let message = state.config.messages.render(locale, MessageKey::NameConflict, &[("suggested", &suggested)]);
+                return Ok(vec![(src, ServerMessage::NameConflict { suggested, message })]);
+            }
+            UsernamePolicy::Reject => {
+                let message = state.config.messages.render(locale, MessageKey::NameTaken, &[("username", uname)]);
+                return Ok(vec![(src, ServerMessage::NameTaken { username: uname.to_string(), message })]);
+            }
+            UsernamePolicy::AutoRename => {
+                renamed = state.suggest_name(uname);
+                renamed.as_str()
+            }
+        }
+    } else {
+        uname
+    };
+
+    // allocate new uuid
+    let mut new_uuid = state.uuid_generator.next_uuid();
+    while state.world.players.contains_key(&new_uuid) {
+        new_uuid = state.uuid_generator.next_uuid();
+    }
+
+    state.username_map.insert(uname.to_string(), new_uuid);
+    if let Some(client_id) = &request.client_id {
+        state.client_id_map.insert(client_id.clone(), new_uuid);
+        state.storage.put_client_id(client_id.clone(), new_uuid);
+    }
+    state.storage.put(PlayerRecord {
+        uuid: new_uuid,
+        username: uname.to_string(),
+    });
+    state.bind_client(new_uuid, ClientInfo::new(src).with_mtu(mtu).with_protocol_version(protocol_version));
+    state.registered_by_conn.insert(src, (new_uuid, now));
+    state.last_seen.insert(new_uuid, now);
+    estimate_clock_skew(state, new_uuid, val);
+    if let Some(room) = &request.room {
+        state.join_room(new_uuid, room, now);
+    }
+    state.settling.join(new_uuid, now);
+    if let Some(locale) = locale {
+        state.locales.insert(new_uuid, locale.to_string());
+    }
+    state
+        .world
+        .players
+        .insert(new_uuid, PlayerState::new(new_uuid, uname));
+    state.world_dirty = true;
+    state.observer.on_join(new_uuid, uname, src, false);
+
+    let mut out = vec![(
+        src,
+        ServerMessage::Registered {
+            uuid: new_uuid,
+            username: uname.to_string(),
+            state: None,
+            resumed: false,
+            resume_token: Some(resume_token_for(state, new_uuid)),
+            server_ts: now_millis(),
+            protocol_version: PROTOCOL_VERSION,
+        },
+    )];
+    out.extend(state.broadcast(now));
+    Ok(out)
+}
+
+/// 房间在线人数已满时返回 `room_full`（`player` 自己已在房间中时不占名额）
+fn room_full(state: &ServerState, room: Option<&str>, player: Option<&Uuid>, now: Instant) -> Option<ServerMessage> {
+    let room = room?;
+    let limit = state.config.rooms.max_players?;
+    (state.room_occupancy(room, player, now) >= limit).then(|| ServerMessage::RoomFull {
+        room: room.to_string(),
+        limit,
+    })
+}
+
+/// 只读查询：UUID 是否仍然有效（不修改任何状态，也不重新绑定地址）
+fn handle_whoami(
+    state: &ServerState,
+    src: ClientConn,
+    val: &Value,
+    now: Instant,
+) -> Result<Outgoing, HandlerError> {
+    let uuid = val
+        .get("uuid")
+        .and_then(|x| x.as_str())
+        .and_then(|s| Uuid::parse_str(s).ok())
+        .ok_or(HandlerError::InvalidField("uuid"))?;
+
+    let reply = if let Some(player) = state.world.players.get(&uuid) {
+        ServerMessage::Identity {
+            uuid,
+            username: player.username.clone(),
+            online: state.is_online(&uuid, now),
+            from_storage: false,
+        }
+    } else if let Some(record) = state.storage.get(&uuid) {
+        ServerMessage::Identity {
+            uuid,
+            username: record.username,
+            online: false,
+            from_storage: true,
+        }
+    } else {
+        ServerMessage::UuidNotFound {
+            uuid,
+            message: "提供的 UUID 不存在".to_string(),
+        }
+    };
+    Ok(vec![(src, reply)])
+}
+
+/// 只读查询：按 uuid 或 username 取单个玩家的当前状态（不修改任何状态）
+fn handle_get(
+    state: &ServerState,
+    src: ClientConn,
+    val: &Value,
+    now: Instant,
+) -> Result<Outgoing, HandlerError> {
+    let uuid = if let Some(raw) = val.get("uuid") {
+        let uuid = raw
+            .as_str()
+            .and_then(|s| Uuid::parse_str(s).ok())
+            .ok_or(HandlerError::InvalidField("uuid"))?;
+        if !state.world.players.contains_key(&uuid) {
+            return Ok(vec![(
+                src,
+                ServerMessage::UuidNotFound {
+                    uuid,
+                    message: "提供的 UUID 不存在".to_string(),
+                },
+            )]);
+        }
+        uuid
+    } else {
+        let username = val
+            .get("username")
+            .and_then(|x| x.as_str())
+            .ok_or(HandlerError::InvalidField("username"))?;
+        *state
+            .username_map
+            .get(username)
+            .ok_or_else(|| HandlerError::UnknownUsername(username.to_string()))?
+    };
+
+    let player = state
+        .world
+        .players
+        .get(&uuid)
+        .ok_or(HandlerError::UnknownPlayer(uuid))?;
+    let player = match state.config.coord_precision {
+        Some(precision) => round_player(player, precision),
+        None => player.clone(),
+    };
+    let online = state.is_online(&uuid, now);
+    let rtt_ms = state.rtt.get(&uuid).map(|rtt| rtt.as_millis() as u64);
+    Ok(vec![(src, ServerMessage::PlayerInfo { player, online, rtt_ms })])
+}
+
+/// 校验管理消息携带的 `secret`
+fn check_admin(state: &ServerState, val: &Value) -> Result<(), HandlerError> {
+    let secret = val.get("secret").and_then(|x| x.as_str());
+    match (&state.config.admin_secret, secret) {
+        (Some(expected), Some(given)) if expected == given => Ok(()),
+        _ => Err(HandlerError::Forbidden),
+    }
+}
+
+/// 以观战者身份订阅广播：`follow` 指定跟随的玩家，或 `center` 给出固定坐标 `[x, y, z]`；
+/// `radius` 为视野半径。回复当前视野内的世界快照。
+fn handle_spectate(state: &mut ServerState, src: ClientConn, val: &Value, now: Instant) -> Result<Outgoing, HandlerError> {
+    let radius = val
+        .get("radius")
+        .and_then(|x| x.as_f64())
+        .filter(|r| r.is_finite() && *r > 0.0)
+        .ok_or(HandlerError::InvalidField("radius"))?;
+    let focus = match (val.get("follow"), val.get("center")) {
+        (Some(follow), _) => {
+            let uuid = follow
+                .as_str()
+                .and_then(|s| Uuid::parse_str(s).ok())
+                .ok_or(HandlerError::InvalidField("follow"))?;
+            if !state.world.players.contains_key(&uuid) {
+                return Err(HandlerError::UnknownPlayer(uuid));
+            }
+            SpectatorFocus::Follow(uuid)
+        }
+        (None, Some(center)) => match center.as_array().map(|a| a.iter().map(|v| v.as_f64()).collect::<Vec<_>>()).as_deref() {
+            Some([Some(x), Some(y), Some(z)]) => SpectatorFocus::Center((*x, *y, *z)),
+            _ => return Err(HandlerError::InvalidField("center")),
+        },
+        (None, None) => return Err(HandlerError::InvalidField("follow")),
+    };
+    let view = SpectatorView { focus, radius };
+    state.spectators.insert(src, view);
+    let players = state.snapshot(now);
+    Ok(state
+        .spectator_messages(&view, &players, now_millis())
+        .into_iter()
+        .map(|msg| (src, msg))
+        .collect())
+}
+
+/// 管理员踢人：解除玩家与连接的绑定并立即视为离线，通知被踢的客户端何时可以重连
+///
+/// 玩家留在世界中，之后可以照常恢复会话。
+fn handle_kick(state: &mut ServerState, src: ClientConn, val: &Value, now: Instant) -> Result<Outgoing, HandlerError> {
+    check_admin(state, val)?;
+    let uuid = val
+        .get("uuid")
+        .and_then(|x| x.as_str())
+        .and_then(|s| Uuid::parse_str(s).ok())
+        .ok_or(HandlerError::InvalidField("uuid"))?;
+    let reason = match val.get("reason") {
+        None => "kicked".to_string(),
+        Some(v) => v.as_str().ok_or(HandlerError::InvalidField("reason"))?.to_string(),
+    };
+    let username = state
+        .world
+        .players
+        .get(&uuid)
+        .map(|p| p.username.clone())
+        .ok_or(HandlerError::UnknownPlayer(uuid))?;
+    let notice = ServerMessage::Kicked {
+        uuid,
+        reason: reason.clone(),
+        retry_after_secs: state.config.kick_retry_after.as_secs(),
+    };
+
+    let mut out = vec![(src, notice.clone())];
+    if let Some(conn) = state.conn_of(&uuid).filter(|c| *c != src) {
+        out.push((conn, notice));
+    }
+    println!("Kicked {} ({}): {}", username, uuid, reason);
+    out.extend(state.mark_offline(&uuid, &reason, now).into_iter().filter(|(conn, _)| *conn != src));
+    Ok(out)
+}
+
+/// 管理员传送：直接设置目标玩家的位置，并豁免其下一次移动校验
+fn handle_teleport(state: &mut ServerState, val: &Value, now: Instant) -> Result<Outgoing, HandlerError> {
+    check_admin(state, val)?;
+    let uuid = val
+        .get("uuid")
+        .and_then(|x| x.as_str())
+        .and_then(|s| Uuid::parse_str(s).ok())
+        .ok_or(HandlerError::InvalidField("uuid"))?;
+    let x = val.get("x").and_then(|x| x.as_f64()).ok_or(HandlerError::InvalidField("x"))?;
+    let y = val.get("y").and_then(|x| x.as_f64()).ok_or(HandlerError::InvalidField("y"))?;
+    let z = val.get("z").and_then(|x| x.as_f64()).ok_or(HandlerError::InvalidField("z"))?;
+    let player = state
+        .world
+        .players
+        .get_mut(&uuid)
+        .ok_or(HandlerError::UnknownPlayer(uuid))?;
+
+    player.x = Some(x);
+    player.y = Some(y);
+    player.z = Some(z);
+    player.ts = Some(u128::from(now_millis()));
+    println!("Teleported {} to ({}, {}, {})", player.username, x, y, z);
+    state.teleported.insert(uuid);
+    state.world_dirty = true;
+
+    Ok(state.broadcast(now))
+}
+
+/// 管理员查询玩家的隔离状态和影子状态；`release` 为 true 时解除隔离并清零违规计数
+fn handle_quarantine(state: &mut ServerState, src: ClientConn, val: &Value) -> Result<Outgoing, HandlerError> {
+    check_admin(state, val)?;
+    let uuid = val
+        .get("uuid")
+        .and_then(|x| x.as_str())
+        .and_then(|s| Uuid::parse_str(s).ok())
+        .ok_or(HandlerError::InvalidField("uuid"))?;
+    let release = match val.get("release") {
+        None => false,
+        Some(v) => v.as_bool().ok_or(HandlerError::InvalidField("release"))?,
+    };
+    let reply = ServerMessage::Quarantine {
+        uuid,
+        quarantined: state.quarantined.contains(&uuid) && !release,
+        violations: if release { 0 } else { state.violations.get(&uuid).copied().unwrap_or(0) },
+        claimed: state.shadow.get(&uuid).cloned(),
+    };
+    if release && state.quarantined.remove(&uuid) {
+        println!("Released {} from quarantine", uuid);
+    }
+    if release {
+        state.violations.remove(&uuid);
+        state.shadow.remove(&uuid);
+        state.pending_correction.remove(&uuid);
+    }
+    Ok(vec![(src, reply)])
+}
+
+/// 管理员授予或撤销玩家的受信任状态（`trusted` 缺省为 true）
+fn handle_trust(state: &mut ServerState, src: ClientConn, val: &Value) -> Result<Outgoing, HandlerError> {
+    check_admin(state, val)?;
+    let uuid = val
+        .get("uuid")
+        .and_then(|x| x.as_str())
+        .and_then(|s| Uuid::parse_str(s).ok())
+        .ok_or(HandlerError::InvalidField("uuid"))?;
+    let trusted = match val.get("trusted") {
+        None => true,
+        Some(v) => v.as_bool().ok_or(HandlerError::InvalidField("trusted"))?,
+    };
+    if trusted {
+        state.trusted.insert(uuid);
+        // 受信任后不再要求确认之前的纠正
+        state.pending_correction.remove(&uuid);
+    } else {
+        state.trusted.remove(&uuid);
+    }
+    println!("{} {}", if trusted { "Trusted" } else { "Untrusted" }, uuid);
+    Ok(vec![(src, ServerMessage::TrustChanged { uuid, trusted })])
+}
+
+/// 管理员重置世界：移除所有玩家和会话状态，并通知所有已连接的客户端
+///
+/// 默认保留持久化的身份记录（旧 UUID 仍可恢复），`clear_storage` 为 true 时一并清空。
+fn handle_reset(state: &mut ServerState, val: &Value) -> Result<Outgoing, HandlerError> {
+    check_admin(state, val)?;
+    let clear_storage = match val.get("clear_storage") {
+        None => false,
+        Some(v) => v.as_bool().ok_or(HandlerError::InvalidField("clear_storage"))?,
+    };
+
+    let mut out: Outgoing = state
+        .clients
+        .values()
+        .map(|client| {
+            (
+                client.conn,
+                ServerMessage::WorldReset {
+                    storage_cleared: clear_storage,
+                },
+            )
+        })
+        .collect();
+    // 观战者也是已连接的客户端（已注册玩家的连接不重复通知）
+    for conn in state.spectators.keys() {
+        if !out.iter().any(|(dst, _)| dst == conn) {
+            out.push((*conn, ServerMessage::WorldReset { storage_cleared: clear_storage }));
+        }
+    }
+    for (uuid, player) in state.world.players.iter() {
+        state.observer.on_leave(*uuid, &player.username, "reset");
+    }
+    println!(
+        "World reset: removed {} players, {} clients{}",
+        state.world.players.len(),
+        state.clients.len(),
+        if clear_storage { ", storage cleared" } else { "" }
+    );
+
+    state.world.players.clear();
+    state.world.entities.clear();
+    state.world_dirty = true;
+    state.clients.clear();
+    state.per_ip_count.clear();
+    state.registered_by_conn.clear();
+    state.spectators.clear();
+    state.rooms.clear();
+    state.player_rooms.clear();
+    state.username_map.clear();
+    state.reservations.clear();
+    state.last_seen.clear();
+    state.last_ping.clear();
+    state.pending_correction.clear();
+    state.action_cooldowns = ActionCooldowns::new();
+    state.settling = SettlingTracker::new();
+    state.speed_profiles = PlayerSpeedProfile::new();
+    state.teleported.clear();
+    state.suspects.clear();
+    state.bad_dt.clear();
+    state.warned.clear();
+    state.violations.clear();
+    state.quarantined.clear();
+    state.shadow.clear();
+    state.jitter.clear();
+    state.coalesced.clear();
+    state.inputs.clear();
+    state.locales.clear();
+    state.resume_tokens.clear();
+    state.rtt.clear();
+    state.clock_skew.clear();
+    state.last_seq.clear();
+    state.history = StateHistory::new(state.config.history_window);
+    if clear_storage {
+        state.client_id_map.clear();
+        state.storage.clear();
+    }
+    Ok(out)
+}
+
+/// 管理员导入世界（场景搭建、测试）：`mode` 为 `merge`（默认）时覆盖同 UUID 的玩家、保留其余玩家，
+/// 为 `replace` 时先移除不在导入世界中的玩家（仍连接着的客户端收到 `kicked`）
+///
+/// 导入的玩家写入身份存储并视为刚刚活动过，其移动校验等状态重新开始，随后广播。
+/// `world` 无法解析、玩家的键与其 uuid 不一致或用户名与其他玩家冲突时整个导入被拒绝，
+/// 世界不做任何修改。
+fn handle_import(state: &mut ServerState, src: ClientConn, val: &Value, now: Instant) -> Result<Outgoing, HandlerError> {
+    check_admin(state, val)?;
+    let replace = match val.get("mode").map(|m| m.as_str()) {
+        None | Some(Some("merge")) => false,
+        Some(Some("replace")) => true,
+        Some(_) => return Err(HandlerError::InvalidField("mode")),
+    };
+    let world = val.get("world").ok_or(HandlerError::InvalidField("world"))?;
+    let world: WorldState = serde_json::from_value(world.clone()).map_err(|_| {
+        HandlerError::Malformed(FieldError {
+            field: "world",
+            expected: "world state object",
+        })
+    })?;
+
+    // 先检查键与 uuid 一致、用户名不冲突（导入的玩家之间，以及与导入后仍保留的玩家之间）
+    let mut names: HashMap<&str, Uuid> = HashMap::new();
+    for (uuid, player) in &world.players {
+        if player.uuid != *uuid {
+            return Err(HandlerError::Malformed(FieldError {
+                field: "world",
+                expected: "players keyed by their own uuid",
+            }));
+        }
+        let kept = state
+            .username_map
+            .get(&player.username)
+            .filter(|owner| *owner != uuid && !replace && !world.players.contains_key(owner));
+        if names.insert(&player.username, *uuid).is_some() || kept.is_some() {
+            return Err(HandlerError::Malformed(FieldError {
+                field: "world",
+                expected: "players with unique usernames",
+            }));
+        }
+    }
+
+    let removed: Vec<Uuid> = if replace {
+        state.world.players.keys().filter(|uuid| !world.players.contains_key(uuid)).copied().collect()
+    } else {
+        Vec::new()
+    };
+    let mut out = Vec::new();
+    for uuid in &removed {
+        let conn = state.conn_of(uuid);
+        let online = state.is_online(uuid, now);
+        let Some(player) = state.remove_player(uuid) else {
+            continue;
+        };
+        if let Some(conn) = conn {
+            out.push((
+                conn,
+                ServerMessage::Kicked {
+                    uuid: *uuid,
+                    reason: "world_replaced".to_string(),
+                    retry_after_secs: state.config.kick_retry_after.as_secs(),
+                },
+            ));
+        }
+        if online {
+            state.observer.on_leave(*uuid, &player.username, "world_replaced");
+        }
+    }
+    if replace {
+        state.world.entities.clear();
+    }
+    let imported = world.players.len();
+    for (uuid, player) in world.players {
+        if let Some(old) = state.world.players.get(&uuid) {
+            if state.username_map.get(&old.username) == Some(&uuid) {
+                state.username_map.remove(&old.username);
+            }
+        }
+        state.storage.put(PlayerRecord { uuid, username: player.username.clone() });
+        state.username_map.insert(player.username.clone(), uuid);
+        state.reservations.remove(&player.username);
+        state.last_seen.insert(uuid, now);
+        state.warned.remove(&uuid);
+        state.reset_movement_state(&uuid);
+        state.world.players.insert(uuid, player);
+    }
+    state.world.entities.extend(world.entities);
+    state.world_dirty = true;
+    println!("World imported: {} players, {} removed", imported, removed.len());
+
+    out.insert(0, (src, ServerMessage::WorldImported { players: imported, removed: removed.len() }));
+    out.extend(state.broadcast(now));
+    Ok(out)
+}
+
+/// 校验一条更新的身份、附加数据和动作，返回其 UUID
+fn check_update(state: &ServerState, src: ClientConn, val: &Value) -> Result<Uuid, HandlerError> {
+    let uuid = val
+        .get("uuid")
+        .and_then(|x| x.as_str())
+        .and_then(|s| Uuid::parse_str(s).ok())
+        .ok_or(HandlerError::InvalidField("uuid"))?;
+    if !state.world.players.contains_key(&uuid) {
+        return Err(HandlerError::UnknownPlayer(uuid));
+    }
+    // 只接受来自该玩家注册地址的更新，防止他人冒用广播中可见的 UUID
+    if state.conn_of(&uuid) != Some(src) {
+        return Err(HandlerError::Unauthorized(uuid));
+    }
+    // 附加数据会进入每一次广播，超过上限的更新整体拒绝
+    if let Some(extra) = val.get("extra").filter(|x| !x.is_null()) {
+        if extra.to_string().len() > state.config.max_extra_bytes {
+            return Err(HandlerError::InvalidField("extra"));
+        }
+    }
+    if state.config.action_policy == ActionPolicy::Reject {
+        let action = val.get("action").and_then(|x| x.as_str());
+        if action.is_some_and(|a| !state.action_allowed(a)) {
+            return Err(HandlerError::InvalidField("action"));
+        }
+    }
+    Ok(uuid)
+}
+
+/// 拒绝一条更新；开启 `rebroadcast_authoritative_on_reject` 且玩家存在时，
+/// 错误之后附上该玩家最后的权威状态，让客户端的视图回到与服务器一致
+fn reject_update(
+    state: &ServerState,
+    src: ClientConn,
+    val: &Value,
+    e: HandlerError,
+    now: Instant,
+) -> Result<Outgoing, HandlerError> {
+    let player = val
+        .get("uuid")
+        .and_then(|x| x.as_str())
+        .and_then(|s| Uuid::parse_str(s).ok())
+        .and_then(|uuid| state.world.players.get(&uuid));
+    match player {
+        Some(player) if state.config.rebroadcast_authoritative_on_reject => {
+            let player = match state.config.coord_precision {
+                Some(precision) => round_player(player, precision),
+                None => player.clone(),
+            };
+            let online = state.is_online(&player.uuid, now);
+            let rtt_ms = state.rtt.get(&player.uuid).map(|rtt| rtt.as_millis() as u64);
+            Ok(vec![(src, e.to_reply()), (src, ServerMessage::PlayerInfo { player, online, rtt_ms })])
+        }
+        _ => Err(e),
+    }
+}
+
+/// 把更新并入合并周期内该玩家待应用的更新
+///
+/// 较新的更新（按 ts；没有 ts 的按到达顺序）覆盖其中出现的字段，
+/// 与 `PlayerState::apply_update` 一样保留未出现的字段；较旧的更新直接丢弃
+fn coalesce_update(state: &mut ServerState, uuid: Uuid, val: &Value) {
+    let ts = |v: &Value| v.get("ts").and_then(|x| x.as_u64());
+    match state.coalesced.get_mut(&uuid) {
+        Some(pending) if ts(val) >= ts(pending) || ts(val).is_none() => {
+            if let (Some(pending), Some(fields)) = (pending.as_object_mut(), val.as_object()) {
+                pending.extend(fields.iter().map(|(k, v)| (k.clone(), v.clone())));
+            }
+        }
+        Some(_) => {}
+        None => {
+            state.coalesced.insert(uuid, val.clone());
+        }
+    }
+}
+
+fn handle_update(
+    state: &mut ServerState,
+    src: ClientConn,
+    val: &Value,
+    now: Instant,
+) -> Result<Outgoing, HandlerError> {
+    let uuid = match check_update(state, src, val) {
+        Ok(uuid) => uuid,
+        Err(e) => return reject_update(state, src, val, e, now),
+    };
+
+    // update last seen (标记为在线)
+    state.last_seen.insert(uuid, now);
+    measure_rtt(state, uuid, val);
+
+    if state.config.tick_hz.is_some() {
+        state.inputs.push((uuid, val.clone()));
+        return Ok(Vec::new());
+    }
+    if !state.config.coalesce_interval.is_zero() {
+        coalesce_update(state, uuid, val);
+        return Ok(Vec::new());
+    }
+    if state.config.jitter_window.is_zero() {
+        return Ok(apply_update(state, src, uuid, val, now));
+    }
+    let (window, depth) = (state.config.jitter_window, state.config.jitter_depth);
+    let buffer = state
+        .jitter
+        .entry(uuid)
+        .or_insert_with(|| JitterBuffer::new(window, depth));
+    buffer.push(val.clone(), now);
+    let ready = buffer.drain_ready(now);
+    let mut out = Vec::new();
+    for val in ready {
+        out.extend(apply_update(state, src, uuid, &val, now));
+    }
+    Ok(out)
+}
+
+/// 一次应用同一客户端控制的多个实体的更新（`updates` 数组，每项与 `update` 消息的字段相同）
+///
+/// 每个实体单独校验身份和移动，结果按顺序放在 `batch_result` 中返回；纠正照常单独发送，
+/// 全部应用后只广播一次。批量更新不经过抖动缓冲；启用 `tick_hz` 时同样只放入输入队列。
+fn handle_batch_update(
+    state: &mut ServerState,
+    src: ClientConn,
+    val: &Value,
+    now: Instant,
+) -> Result<Outgoing, HandlerError> {
+    let updates = val
+        .get("updates")
+        .and_then(|x| x.as_array())
+        .ok_or(HandlerError::InvalidField("updates"))?;
+    let ticking = state.config.tick_hz.is_some();
+    let coalescing = ticking || !state.config.coalesce_interval.is_zero();
+    let mut results = Vec::with_capacity(updates.len());
+    let mut corrections = Vec::new();
+    for entry in updates {
+        match check_update(state, src, entry) {
+            Ok(uuid) => {
+                state.last_seen.insert(uuid, now);
+                let mut corrected = false;
+                if ticking {
+                    state.inputs.push((uuid, entry.clone()));
+                } else if coalescing {
+                    coalesce_update(state, uuid, entry);
+                } else {
+                    let out = apply_update_fields(state, src, uuid, entry, now);
+                    corrected = out.iter().any(|(_, m)| matches!(m, ServerMessage::Correction { .. }));
+                    corrections.extend(out);
+                }
+                results.push(BatchEntryResult { uuid: Some(uuid), error: None, corrected });
+            }
+            Err(e) => results.push(BatchEntryResult {
+                uuid: entry.get("uuid").and_then(|x| x.as_str()).and_then(|s| Uuid::parse_str(s).ok()),
+                error: Some(e.code().to_string()),
+                corrected: false,
+            }),
+        }
+    }
+    let applied = results.iter().any(|r| r.error.is_none());
+    let mut out = vec![(src, ServerMessage::BatchResult { results })];
+    out.extend(corrections);
+    if applied && !coalescing {
+        state.separate_players(now);
+        out.extend(state.broadcast(now));
+    }
+    Ok(out)
+}
+
+/// 玩家的位置是否在某个重生点（`spawn_points` 或 `default_spawn`）的 `spawn_radius` 内
+fn at_spawn_point(config: &ServerConfig, player: &PlayerState) -> bool {
+    let (Some(x), Some(y), Some(z)) = (player.x, player.y, player.z) else {
+        return false;
+    };
+    let radius = config.movement.spawn_radius;
+    config
+        .movement
+        .spawn_points
+        .iter()
+        .chain(config.default_spawn.iter())
+        .any(|(sx, sy, sz)| ((x - sx).powi(2) + (y - sy).powi(2) + (z - sz).powi(2)).sqrt() <= radius)
+}
+
+/// 把一次（已通过身份校验的）更新应用到世界状态，并广播
+fn apply_update(state: &mut ServerState, src: ClientConn, uuid: Uuid, val: &Value, now: Instant) -> Outgoing {
+    let mut out = apply_update_fields(state, src, uuid, val, now);
+    state.separate_players(now);
+    // broadcast world (only online players)
+    out.extend(state.broadcast(now));
+    out
+}
+
+/// 把一次更新应用到世界状态，只返回发给该玩家的纠正和动作通知（不广播世界快照）
+fn apply_update_fields(
+    state: &mut ServerState,
+    src: ClientConn,
+    uuid: Uuid,
+    val: &Value,
+    now: Instant,
+) -> Outgoing {
+    let Some(existing) = state.world.players.get(&uuid).cloned() else {
+        return Vec::new();
+    };
+    state.last_seen.insert(uuid, now);
+    state.touch_room(&uuid, now);
+    // 输入序号只增不减（乱序到达的旧输入不回退）
+    let seq = val.get("seq").and_then(|x| x.as_u64());
+    if let Some(seq) = seq {
+        let last = state.last_seq.entry(uuid).or_insert(seq);
+        *last = (*last).max(seq);
+    }
+    let seq = seq.filter(|_| state.clients.get(&uuid).is_none_or(|c| c.supports_seq()));
+
+    // start from previous state and apply incoming fields；动作是一次性事件，不沿用上一次的值
+    let mut update = PlayerUpdate::from_value(uuid, val);
+    // 换算到服务器时间线，与服务器写入的 ts（传送、恢复位置等）可以直接相减
+    if let (Some(ts), Some(&skew)) = (update.ts, state.clock_skew.get(&uuid)) {
+        update.ts = Some((ts as i128 - skew).max(0) as u128);
+    }
+    let mut updated = existing.clone();
+    updated.action = None;
+    updated.apply_update(&update);
+
+    // 不在白名单中的动作不保存也不广播
+    if updated.action.as_deref().is_some_and(|a| !state.action_allowed(a)) {
+        eprintln!(
+            "Dropped disallowed action {:?} from {}",
+            updated.action.take().unwrap_or_default(),
+            updated.username
+        );
+    }
+
+    // 动作限频：冷却中的动作被丢弃，位置等其余字段照常更新
+    if let Some(action) = &updated.action {
+        if let Some(&cooldown) = state.config.action_cooldowns.get(action) {
+            if !state.action_cooldowns.try_use(uuid, action, cooldown, now) {
+                updated.action = None;
+            }
+        }
+    }
+
+    // 被接受的动作立即单独通知其他客户端，不依赖下一次快照（快照可能已被合并掉）
+    let mut out = Vec::new();
+    if state.config.broadcast_actions && !state.quarantined.contains(&uuid) {
+        if let Some(action) = updated.action.as_deref().filter(|a| !a.is_empty() && *a != IDLE_ACTION) {
+            let ts = updated.ts.map_or_else(now_millis, |t| t as u64);
+            for conn in state.online_conns(now).into_iter().filter(|c| *c != src) {
+                out.push((conn, ServerMessage::PlayerAction { uuid, action: action.to_string(), ts }));
+            }
+        }
+    }
+
+    // 服务器权威模式：忽略客户端上报的位置，只接受速度等输入，无需校验
+    if state.config.physics_mode == PhysicsMode::ServerAuthoritative {
+        updated.x = existing.x;
+        updated.y = existing.y;
+        updated.z = existing.z;
+        state.observer.on_update(&updated);
+        state.world.players.insert(uuid, updated);
+        state.world_dirty = true;
+        return out;
+    }
+
+    let settling = state.settling.observe_update(
+        uuid,
+        now,
+        state.config.settle_updates,
+        state.config.settle_period,
+    );
+
+    // 被传送后的第一次更新、重生到重生点的更新是合法的大跳跃，不做校验
+    let teleported = state.teleported.remove(&uuid);
+    let respawned = updated.action.as_deref() == Some(RESPAWN_ACTION) && at_spawn_point(&state.config, &updated);
+
+    // 粗过滤：单个更新的位移超过绝对上限，不看时间戳和速度
+    let coarse_jump = match (existing.x, existing.y, existing.z) {
+        (Some(px), Some(py), Some(pz)) => {
+            let (dx, dy, dz) = (updated.x.unwrap_or(px) - px, updated.y.unwrap_or(py) - py, updated.z.unwrap_or(pz) - pz);
+            (dx * dx + dy * dy + dz * dz).sqrt() > state.config.movement.max_delta_per_update
+        }
+        _ => false,
+    };
+
+    let ack = val.get("ack").and_then(|x| x.as_u64());
+    if state.trusted.contains(&uuid) {
+        // 受信任客户端的更新直接作为权威状态
+    } else if state.quarantined.contains(&uuid) {
+        // 被隔离：上报的状态只进影子状态，公开位置冻结
+        state.shadow.insert(uuid, updated.clone());
+        updated.x = existing.x;
+        updated.y = existing.y;
+        updated.z = existing.z;
+        updated.ts = existing.ts;
+        for v in [&mut updated.vx, &mut updated.vy, &mut updated.vz] {
+            if v.is_some() {
+                *v = Some(0.0);
+            }
+        }
+    } else if !acknowledges_correction(&mut state.pending_correction, &uuid, ack) {
+        // 上一次纠正尚未被确认：不信任本次移动，再次纠正到权威位置
+        let nonce = state.pending_correction[&uuid];
+        state.observer.on_violation(uuid, "unacknowledged_correction");
+        updated.x = existing.x;
+        updated.y = existing.y;
+        updated.z = existing.z;
+        updated.ts = existing.ts;
+        out.push((
+            src,
+            ServerMessage::Correction {
+                reason: "unacknowledged_correction".to_string(),
+                nonce,
+                corrected: CorrectedState {
+                    uuid,
+                    username: existing.username.clone(),
+                    x: existing.x,
+                    y: existing.y,
+                    z: existing.z,
+                    vx: updated.vx,
+                    vy: updated.vy,
+                    vz: updated.vz,
+                    ts: existing.ts,
+                },
+                message: state.message_for(&uuid, MessageKey::UnacknowledgedCorrection, &[]),
+                seq,
+            },
+        ));
+    } else if settling || teleported || respawned {
+        // 刚加入的宽限期内 / 传送或重生后跳过移动校验，但位置照常记录
+    } else if coarse_jump {
+        // 明显的瞬移：拒绝位移，拉回原位置，不再做速度校验
+        state.observer.on_violation(uuid, "max_delta");
+        let count = state.violations.entry(uuid).or_insert(0);
+        *count += 1;
+        if state.config.quarantine_after.is_some_and(|limit| *count >= limit) {
+            println!("Quarantined {} after {} violations", existing.username, count);
+            state.quarantined.insert(uuid);
+        }
+        updated.x = existing.x;
+        updated.y = existing.y;
+        updated.z = existing.z;
+        updated.ts = existing.ts;
+        let nonce = issue_correction_nonce(&mut state.pending_correction, uuid);
+        out.push((
+            src,
+            ServerMessage::Correction {
+                reason: "max_delta".to_string(),
+                nonce,
+                corrected: CorrectedState {
+                    uuid,
+                    username: existing.username.clone(),
+                    x: existing.x,
+                    y: existing.y,
+                    z: existing.z,
+                    vx: updated.vx,
+                    vy: updated.vy,
+                    vz: updated.vz,
+                    ts: existing.ts,
+                },
+                message: state.message_for(&uuid, MessageKey::InvalidMovement, &[]),
+                seq,
+            },
+        ));
+    } else if let (Some(prev_x), Some(prev_y), Some(prev_z), Some(prev_ts), Some(new_ts)) =
+        (existing.x, existing.y, existing.z, existing.ts, update.ts)
+    {
+        // 期望位移按各轴的速度上限计算
+        let (svx, svy, svz) = state.config.movement.clamp_velocity((
+            updated.vx.unwrap_or(0.0),
+            updated.vy.unwrap_or(0.0),
+            updated.vz.unwrap_or(0.0),
+        ));
+        let tolerance = state.config.movement.effective_tolerance(state.rtt.get(&uuid));
+        let actual = (
+            updated.x.unwrap_or(prev_x),
+            updated.y.unwrap_or(prev_y),
+            updated.z.unwrap_or(prev_z),
+        );
+        let result = validate_movement_with_tolerance(
+            tolerance,
+            prev_x,
+            prev_y,
+            prev_z,
+            prev_ts,
+            actual.0,
+            actual.1,
+            actual.2,
+            new_ts,
+            svx,
+            svy,
+            svz,
+        );
+        let mut violation = match (result.is_valid, result.corrected_x, result.corrected_y, result.corrected_z) {
+            (false, Some(ex), Some(ey), Some(ez)) => Some(("invalid_movement", (ex, ey, ez))),
+            _ => None,
+        };
+        let movement = &state.config.movement;
+        let dt = new_ts.saturating_sub(prev_ts) as f64 / 1000.0;
+        // 时间戳不前进时上面的校验一律通过：偶尔一次算时钟抖动，连续出现视为篡改时间戳
+        if new_ts <= prev_ts {
+            let count = state.bad_dt.entry(uuid).or_insert(0);
+            *count += 1;
+            if movement.non_positive_dt_allowance.is_some_and(|allowed| *count > allowed) {
+                violation = Some(("non_positive_dt", (prev_x, prev_y, prev_z)));
+            }
+        } else {
+            state.bad_dt.remove(&uuid);
+        }
+        if violation.is_none() && new_ts > prev_ts {
+            // 单独超出某轴上限时只截断该轴
+            if let Some(clamped) = clamp_axes(movement.axis_caps(), tolerance, dt, (prev_x, prev_y, prev_z), actual) {
+                violation = Some(("axis_speed", clamped));
+            }
+        }
+        if violation.is_none()
+            && movement.require_velocity_consistency
+            && new_ts > prev_ts
+            && !velocity_consistent(
+                movement.max_velocity_angle,
+                tolerance,
+                (actual.0 - prev_x, actual.1 - prev_y, actual.2 - prev_z),
+                (svx, svy, svz),
+            )
+        {
+            violation = Some(("inconsistent_velocity", (prev_x + svx * dt, prev_y + svy * dt, prev_z + svz * dt)));
+        }
+        if let Some(grid) = movement.grid.filter(|_| violation.is_none()) {
+            // 速度合法但没有落在网格点上（格子内的小步移动）
+            if !on_grid(actual, grid, movement.grid_epsilon) {
+                violation = Some(("off_grid", snap_to_grid(actual, grid)));
+            }
+        }
+        if let Some(profile) = movement.speed_profile.filter(|_| violation.is_none() && new_ts > prev_ts) {
+            let delta = (actual.0 - prev_x, actual.1 - prev_y, actual.2 - prev_z);
+            let dist = (delta.0 * delta.0 + delta.1 * delta.1 + delta.2 * delta.2).sqrt();
+            match state.speed_profiles.baseline(&uuid, profile.warmup_updates, profile.percentile) {
+                // 超出该玩家自己的速度基线：沿上报方向截断到基线允许的距离
+                Some(base) => {
+                    let allowed = base * (1.0 + profile.margin) * dt;
+                    if dist > allowed + tolerance {
+                        let k = allowed / dist;
+                        let expected = (prev_x + delta.0 * k, prev_y + delta.1 * k, prev_z + delta.2 * k);
+                        violation = Some(("speed_baseline", expected));
+                    }
+                }
+                None => state.speed_profiles.record(uuid, dist / dt, profile.warmup_updates),
+            }
+        }
+        // 可疑移动先冻结在原位观察，窗口内回到合理轨迹则不纠正
+        let held = violation.is_some() && {
+            let since = *state.suspects.entry(uuid).or_insert(now);
+            state.config.movement.suspect_hold.is_some_and(|hold| now.saturating_duration_since(since) < hold)
+        };
+        if violation.is_none() || !held {
+            state.suspects.remove(&uuid);
+        }
+        if held {
+            updated.x = existing.x;
+            updated.y = existing.y;
+            updated.z = existing.z;
+            updated.ts = existing.ts;
+        } else if let Some((reason, (ex, ey, ez))) = violation {
+            let mut corrected = apply_correction(
+                state.config.movement.correction,
+                tolerance,
+                (prev_x, prev_y, prev_z),
+                actual,
+                (ex, ey, ez),
+            );
+            // 网格世界里纠正后的位置同样要落在网格点上
+            if let Some(grid) = state.config.movement.grid {
+                corrected = snap_to_grid(corrected, grid);
+            }
+            let (cx, cy, cz) = corrected;
+            updated.x = Some(cx);
+            updated.y = Some(cy);
+            updated.z = Some(cz);
+            if reason == "non_positive_dt" {
+                // 不接受回退的时间戳，否则下一次更新的 dt 会被放大
+                updated.ts = existing.ts;
+            }
+
+            state.observer.on_violation(uuid, reason);
+            let count = state.violations.entry(uuid).or_insert(0);
+            *count += 1;
+            if state.config.quarantine_after.is_some_and(|limit| *count >= limit) {
+                println!("Quarantined {} after {} violations", existing.username, count);
+                state.quarantined.insert(uuid);
+            }
+            let nonce = issue_correction_nonce(&mut state.pending_correction, uuid);
+            out.push((
+                src,
+                ServerMessage::Correction {
+                    reason: reason.to_string(),
+                    nonce,
+                    corrected: CorrectedState {
+                        uuid,
+                        username: existing.username.clone(),
+                        x: Some(cx),
+                        y: Some(cy),
+                        z: Some(cz),
+                        vx: Some(svx),
+                        vy: Some(svy),
+                        vz: Some(svz),
+                        ts: updated.ts,
+                    },
+                    message: state.message_for(&uuid, MessageKey::InvalidMovement, &[]),
+                    seq,
+                },
+            ));
+        }
+    }
+
+    if let Some(audit) = &state.audit {
+        for (_, msg) in &out {
+            if let ServerMessage::Correction { reason, corrected, .. } = msg {
+                audit.record(AuditRecord {
+                    ts: now_millis(),
+                    uuid,
+                    username: existing.username.clone(),
+                    reason: reason.clone(),
+                    claimed: (update.x, update.y, update.z),
+                    corrected: (corrected.x, corrected.y, corrected.z),
+                    dt_ms: update.ts.zip(existing.ts).map(|(new, prev)| new.saturating_sub(prev)),
+                });
+            }
+        }
+    }
+
+    if let (Some(x), Some(y), Some(z), Some(ts)) = (updated.x, updated.y, updated.z, updated.ts) {
+        state.history.record(uuid, ts, (x, y, z));
+    }
+    state.observer.on_update(&updated);
+    state.world.players.insert(uuid, updated);
+    state.world_dirty = true;
+    out
+}
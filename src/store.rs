@@ -0,0 +1,265 @@
+//! 身份存储：记录所有见过的 UUID 及其用户名（以及客户端自带 ID 的映射、移出内存时的最后位置）
+//!
+//! 服务器只通过 `IdentityStore` 访问存储，持久化方式（JSON 文件、内存、
+//! SQLite）与协议处理解耦，处理逻辑的测试可以使用不落盘的 `InMemoryStore`。
+
+use crate::UuidStorage;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// 一条身份记录
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerRecord {
+    pub uuid: Uuid,
+    pub username: String,
+}
+
+/// 身份存储后端
+pub trait IdentityStore: Send {
+    fn get(&self, uuid: &Uuid) -> Option<PlayerRecord>;
+    /// 添加或覆盖一条记录
+    fn put(&mut self, record: PlayerRecord);
+    /// 删除所有记录（包括客户端 ID 映射）
+    fn clear(&mut self);
+    /// 所有客户端自带 ID -> UUID 的映射
+    fn client_ids(&self) -> HashMap<String, Uuid>;
+    /// 记录客户端自带 ID 对应的 UUID
+    fn put_client_id(&mut self, client_id: String, uuid: Uuid);
+    /// 玩家被移出内存时保存的最后位置
+    fn last_position(&self, uuid: &Uuid) -> Option<(f64, f64, f64)>;
+    /// 保存玩家的最后位置
+    fn put_position(&mut self, uuid: Uuid, position: (f64, f64, f64));
+    fn contains(&self, uuid: &Uuid) -> bool {
+        self.get(uuid).is_some()
+    }
+    /// 把尚未写出的修改持久化（默认不做任何事）
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl fmt::Debug for dyn IdentityStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("IdentityStore")
+    }
+}
+
+/// 仅保存在内存中的存储（测试用）
+#[derive(Debug, Default)]
+pub struct InMemoryStore {
+    records: HashMap<Uuid, String>,
+    client_ids: HashMap<String, Uuid>,
+    positions: HashMap<Uuid, (f64, f64, f64)>,
+}
+
+impl InMemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl IdentityStore for InMemoryStore {
+    fn get(&self, uuid: &Uuid) -> Option<PlayerRecord> {
+        self.records.get(uuid).map(|username| PlayerRecord {
+            uuid: *uuid,
+            username: username.clone(),
+        })
+    }
+
+    fn put(&mut self, record: PlayerRecord) {
+        self.records.insert(record.uuid, record.username);
+    }
+
+    fn clear(&mut self) {
+        self.records.clear();
+        self.client_ids.clear();
+        self.positions.clear();
+    }
+
+    fn client_ids(&self) -> HashMap<String, Uuid> {
+        self.client_ids.clone()
+    }
+
+    fn put_client_id(&mut self, client_id: String, uuid: Uuid) {
+        self.client_ids.insert(client_id, uuid);
+    }
+
+    fn last_position(&self, uuid: &Uuid) -> Option<(f64, f64, f64)> {
+        self.positions.get(uuid).copied()
+    }
+
+    fn put_position(&mut self, uuid: Uuid, position: (f64, f64, f64)) {
+        self.positions.insert(uuid, position);
+    }
+}
+
+/// JSON 文件存储（`UuidStorage` 的文件格式），`flush` 时整体写回
+#[derive(Debug)]
+pub struct FileStore {
+    path: PathBuf,
+    storage: UuidStorage,
+}
+
+impl FileStore {
+    /// 打开存储文件；文件不存在或无法解析时从空存储开始
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let storage = UuidStorage::load_from_file(&path.to_string_lossy())?;
+        Ok(FileStore { path, storage })
+    }
+}
+
+impl IdentityStore for FileStore {
+    fn get(&self, uuid: &Uuid) -> Option<PlayerRecord> {
+        self.storage.get_username(uuid).map(|username| PlayerRecord { uuid: *uuid, username })
+    }
+
+    fn put(&mut self, record: PlayerRecord) {
+        self.storage.add_uuid(record.uuid, record.username);
+    }
+
+    fn clear(&mut self) {
+        self.storage.uuids.clear();
+        self.storage.client_ids.clear();
+        self.storage.positions.clear();
+    }
+
+    fn client_ids(&self) -> HashMap<String, Uuid> {
+        self.storage.client_ids.clone()
+    }
+
+    fn put_client_id(&mut self, client_id: String, uuid: Uuid) {
+        self.storage.client_ids.insert(client_id, uuid);
+    }
+
+    fn last_position(&self, uuid: &Uuid) -> Option<(f64, f64, f64)> {
+        self.storage.positions.get(uuid).copied()
+    }
+
+    fn put_position(&mut self, uuid: Uuid, position: (f64, f64, f64)) {
+        self.storage.positions.insert(uuid, position);
+    }
+
+    fn contains(&self, uuid: &Uuid) -> bool {
+        self.storage.contains_uuid(uuid)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.storage.save_to_file(&self.path.to_string_lossy())
+    }
+}
+
+/// SQLite 存储（需要启用 `sqlite` feature），每次 `put` 立即写入
+#[cfg(feature = "sqlite")]
+pub struct SqliteStore {
+    conn: rusqlite::Connection,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+    /// 打开（或创建）数据库文件；传入 ":memory:" 使用内存数据库
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        let conn = rusqlite::Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS identities (uuid TEXT PRIMARY KEY, username TEXT NOT NULL)",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS client_ids (client_id TEXT PRIMARY KEY, uuid TEXT NOT NULL)",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS positions (uuid TEXT PRIMARY KEY, x REAL NOT NULL, y REAL NOT NULL, z REAL NOT NULL)",
+            [],
+        )?;
+        Ok(SqliteStore { conn })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl IdentityStore for SqliteStore {
+    fn get(&self, uuid: &Uuid) -> Option<PlayerRecord> {
+        self.conn
+            .query_row(
+                "SELECT username FROM identities WHERE uuid = ?1",
+                [uuid.to_string()],
+                |row| row.get::<_, String>(0),
+            )
+            .ok()
+            .map(|username| PlayerRecord { uuid: *uuid, username })
+    }
+
+    fn put(&mut self, record: PlayerRecord) {
+        let result = self.conn.execute(
+            "INSERT INTO identities (uuid, username) VALUES (?1, ?2)
+             ON CONFLICT(uuid) DO UPDATE SET username = excluded.username",
+            [record.uuid.to_string(), record.username],
+        );
+        if let Err(e) = result {
+            eprintln!("保存身份记录失败: {}", e);
+        }
+    }
+
+    fn clear(&mut self) {
+        let result = self
+            .conn
+            .execute("DELETE FROM identities", [])
+            .and_then(|_| self.conn.execute("DELETE FROM client_ids", []))
+            .and_then(|_| self.conn.execute("DELETE FROM positions", []));
+        if let Err(e) = result {
+            eprintln!("清空身份记录失败: {}", e);
+        }
+    }
+
+    fn client_ids(&self) -> HashMap<String, Uuid> {
+        let rows = self.conn.prepare("SELECT client_id, uuid FROM client_ids").and_then(|mut stmt| {
+            stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+                .collect::<rusqlite::Result<Vec<_>>>()
+        });
+        match rows {
+            Ok(rows) => rows
+                .into_iter()
+                .filter_map(|(client_id, uuid)| Uuid::parse_str(&uuid).ok().map(|uuid| (client_id, uuid)))
+                .collect(),
+            Err(e) => {
+                eprintln!("读取客户端 ID 映射失败: {}", e);
+                HashMap::new()
+            }
+        }
+    }
+
+    fn put_client_id(&mut self, client_id: String, uuid: Uuid) {
+        let result = self.conn.execute(
+            "INSERT INTO client_ids (client_id, uuid) VALUES (?1, ?2)
+             ON CONFLICT(client_id) DO UPDATE SET uuid = excluded.uuid",
+            [client_id, uuid.to_string()],
+        );
+        if let Err(e) = result {
+            eprintln!("保存客户端 ID 映射失败: {}", e);
+        }
+    }
+
+    fn last_position(&self, uuid: &Uuid) -> Option<(f64, f64, f64)> {
+        self.conn
+            .query_row(
+                "SELECT x, y, z FROM positions WHERE uuid = ?1",
+                [uuid.to_string()],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .ok()
+    }
+
+    fn put_position(&mut self, uuid: Uuid, (x, y, z): (f64, f64, f64)) {
+        let result = self.conn.execute(
+            "INSERT INTO positions (uuid, x, y, z) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(uuid) DO UPDATE SET x = excluded.x, y = excluded.y, z = excluded.z",
+            rusqlite::params![uuid.to_string(), x, y, z],
+        );
+        if let Err(e) = result {
+            eprintln!("保存玩家位置失败: {}", e);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! 离线扫描的调度工具
+//!
+//! 扫描线程不再固定每 5 秒轮询一次，而是计算“下一个玩家何时会超时”，
+//! 精确休眠到那一刻（不超过上限）；没有需要跟踪的玩家时阻塞在条件变量上，
+//! 直到有新的注册/更新唤醒它。
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// 时钟抽象，便于在测试中注入可控时间
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// 系统时钟
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// 手动推进的时钟（测试用）
+pub struct ManualClock {
+    now: Mutex<Instant>,
+}
+
+impl ManualClock {
+    pub fn new(start: Instant) -> Self {
+        ManualClock {
+            now: Mutex::new(start),
+        }
+    }
+
+    /// 时间前进 `d`
+    pub fn advance(&self, d: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now += d;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// 找出刚刚超时（且尚未通知过）的玩家，并记入 `notified`
+///
+/// 已通知但重新活跃的玩家会从 `notified` 中移除，下次超时时会再次被通知。
+pub fn collect_expired(
+    last_seen: &HashMap<Uuid, Instant>,
+    notified: &mut HashSet<Uuid>,
+    now: Instant,
+    timeout: Duration,
+) -> Vec<Uuid> {
+    collect_past_deadline(&deadlines_from(last_seen, timeout), notified, now)
+}
+
+/// 计算距离下一个玩家超时还需等待多久（不超过 `max_delay`）
+///
+/// 返回 `None` 表示当前没有需要跟踪的在线玩家，调用方可以阻塞等待唤醒。
+pub fn next_sweep_delay(
+    last_seen: &HashMap<Uuid, Instant>,
+    notified: &HashSet<Uuid>,
+    now: Instant,
+    timeout: Duration,
+    max_delay: Duration,
+) -> Option<Duration> {
+    next_deadline_delay(&deadlines_from(last_seen, timeout), notified, now, max_delay)
+}
+
+fn deadlines_from(last_seen: &HashMap<Uuid, Instant>, timeout: Duration) -> HashMap<Uuid, Instant> {
+    last_seen.iter().map(|(uuid, &t)| (*uuid, t + timeout)).collect()
+}
+
+/// 与 `collect_expired` 相同，但直接给出每个玩家的离线时刻
+pub fn collect_past_deadline(
+    deadlines: &HashMap<Uuid, Instant>,
+    notified: &mut HashSet<Uuid>,
+    now: Instant,
+) -> Vec<Uuid> {
+    notified.retain(|uuid| deadlines.get(uuid).is_some_and(|&d| now >= d));
+
+    let mut expired = Vec::new();
+    for (uuid, &d) in deadlines.iter() {
+        if now >= d && notified.insert(*uuid) {
+            expired.push((d, *uuid));
+        }
+    }
+    // 按离线时刻排序，同一时刻按 uuid，通知顺序不受 HashMap 遍历顺序影响
+    expired.sort();
+    expired.into_iter().map(|(_, uuid)| uuid).collect()
+}
+
+/// 与 `next_sweep_delay` 相同，但直接给出每个玩家的离线时刻
+pub fn next_deadline_delay(
+    deadlines: &HashMap<Uuid, Instant>,
+    notified: &HashSet<Uuid>,
+    now: Instant,
+    max_delay: Duration,
+) -> Option<Duration> {
+    deadlines
+        .iter()
+        .filter(|(uuid, _)| !notified.contains(uuid))
+        .map(|(_, &d)| d.saturating_duration_since(now))
+        .min()
+        .map(|d| d.min(max_delay))
+}
+
+/// 扫描线程的唤醒信号
+pub struct SweepSignal {
+    pending: Mutex<bool>,
+    cv: Condvar,
+}
+
+impl SweepSignal {
+    pub fn new() -> Self {
+        SweepSignal {
+            pending: Mutex::new(false),
+            cv: Condvar::new(),
+        }
+    }
+
+    /// 唤醒扫描线程（若它尚未进入等待，下次 `wait` 会立即返回）
+    pub fn notify(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        *pending = true;
+        self.cv.notify_one();
+    }
+
+    /// 阻塞直到收到 `notify`
+    pub fn wait(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        while !*pending {
+            pending = self.cv.wait(pending).unwrap();
+        }
+        *pending = false;
+    }
+}
+
+impl Default for SweepSignal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,480 @@
+//! 传输层：UDP 之外可选的 TCP / WebSocket 监听
+//!
+//! TCP 上每条 JSON 消息前加 4 字节大端长度前缀；WebSocket 每个文本帧一条消息
+//! （见 `websocket` 模块）。所有传输的消息都交给同一个 `handle_message` 处理，
+//! 回复和广播按 `ClientConn` 发回对应的连接。
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+
+/// TCP 单帧最大长度
+pub const MAX_TCP_FRAME_LEN: usize = 64 * 1024;
+
+/// 客户端连接标识
+///
+/// UDP 与 TCP 的端口空间相互独立，同一个 `ip:port` 可能同时出现在两边，
+/// 所以地址需要带上传输类型才能唯一确定一个客户端。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ClientConn {
+    Udp(SocketAddr),
+    /// 以 TCP 连接的对端地址标识
+    Tcp(SocketAddr),
+    /// 以 WebSocket 连接的对端地址标识
+    Ws(SocketAddr),
+}
+
+impl ClientConn {
+    /// 对端地址
+    pub fn addr(&self) -> SocketAddr {
+        match self {
+            ClientConn::Udp(addr) | ClientConn::Tcp(addr) | ClientConn::Ws(addr) => *addr,
+        }
+    }
+}
+
+impl From<SocketAddr> for ClientConn {
+    fn from(addr: SocketAddr) -> Self {
+        ClientConn::Udp(addr)
+    }
+}
+
+impl fmt::Display for ClientConn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientConn::Udp(addr) => write!(f, "udp://{}", addr),
+            ClientConn::Tcp(addr) => write!(f, "tcp://{}", addr),
+            ClientConn::Ws(addr) => write!(f, "ws://{}", addr),
+        }
+    }
+}
+
+/// 服务器监听的传输
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+    /// 需要启用 `websocket` feature
+    WebSocket(SocketAddr),
+}
+
+/// TCP / WebSocket 同时打开的连接数上限
+///
+/// 每个连接占用一个线程，所有监听共享同一个上限；克隆后共享同一个计数。
+#[derive(Debug, Clone)]
+pub struct ConnectionLimit {
+    open: Arc<AtomicUsize>,
+    limit: usize,
+}
+
+impl ConnectionLimit {
+    pub fn new(limit: usize) -> Self {
+        ConnectionLimit {
+            open: Arc::new(AtomicUsize::new(0)),
+            limit,
+        }
+    }
+
+    /// 占用一个连接名额，连接结束时丢弃返回值归还；已满时返回 None
+    pub fn try_acquire(&self) -> Option<ConnectionSlot> {
+        self.open
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| (open < self.limit).then_some(open + 1))
+            .ok()
+            .map(|_| ConnectionSlot(self.open.clone()))
+    }
+
+    /// 当前打开的连接数
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+}
+
+/// 一个已占用的连接名额，丢弃时归还
+#[derive(Debug)]
+pub struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 写出一帧（4 字节大端长度 + payload）
+pub fn write_frame<W: Write>(w: &mut W, payload: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+    w.write_all(&len.to_be_bytes())?;
+    w.write_all(payload)?;
+    w.flush()
+}
+
+/// 读取一帧；长度超过 `max_len` 时返回 `InvalidData`
+pub fn read_frame<R: Read>(r: &mut R, max_len: usize) -> io::Result<Vec<u8>> {
+    let mut len_buf = [0u8; 4];
+    r.read_exact(&mut len_buf)?;
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes exceeds limit {}", len, max_len),
+        ));
+    }
+    let mut payload = vec![0u8; len];
+    r.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// 在同一地址上绑定 `count` 个 UDP socket（每个由单独的线程接收）
+///
+/// 启用 `reuseport` feature 的 Unix 平台上用 SO_REUSEPORT 绑定多个独立 socket，
+/// 由内核把数据包分散到各个 socket；其他情况下只绑定一个，其余是它的克隆。
+pub fn bind_udp_sockets(addr: SocketAddr, count: usize) -> io::Result<Vec<UdpSocket>> {
+    let count = count.max(1);
+    #[cfg(all(unix, feature = "reuseport"))]
+    if count > 1 {
+        use socket2::{Domain, Protocol, Socket, Type};
+        let bind = |addr: SocketAddr| -> io::Result<UdpSocket> {
+            let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+            socket.set_reuse_port(true)?;
+            socket.bind(&addr.into())?;
+            Ok(socket.into())
+        };
+        let first = bind(addr)?;
+        // 端口为 0 时其余 socket 绑定到第一个实际分配的端口
+        let bound = first.local_addr()?;
+        let mut sockets = vec![first];
+        for _ in 1..count {
+            sockets.push(bind(bound)?);
+        }
+        return Ok(sockets);
+    }
+    let first = UdpSocket::bind(addr)?;
+    let mut sockets = Vec::with_capacity(count);
+    for _ in 1..count {
+        sockets.push(first.try_clone()?);
+    }
+    sockets.insert(0, first);
+    Ok(sockets)
+}
+
+/// 出站消息能否在拥塞时丢弃
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// 世界广播的后续分片：下一次广播会带来更新的状态，可以丢弃
+    Droppable,
+    /// 新一轮广播的第一条：可以丢弃，且启用 latest-wins 时替换队列中尚未发送的旧广播
+    Latest,
+    /// 回复、纠正、通知等：不能丢弃
+    Reliable,
+}
+
+/// 单个客户端的有界发送队列
+///
+/// 队列满时丢弃最早的可丢弃消息；没有可丢弃的消息时，新的可丢弃消息直接丢弃，
+/// 可靠消息则照常入队（可以超出容量）。
+#[derive(Debug)]
+pub struct SendQueue {
+    capacity: usize,
+    /// 新一轮广播到来时丢弃尚未发送的旧广播
+    latest_wins: bool,
+    items: VecDeque<(Delivery, Vec<u8>)>,
+    dropped: u64,
+}
+
+impl SendQueue {
+    pub fn new(capacity: usize) -> Self {
+        SendQueue {
+            capacity,
+            latest_wins: false,
+            items: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    /// 启用 latest-wins：落后的客户端只收到最新的广播
+    pub fn with_latest_wins(mut self) -> Self {
+        self.latest_wins = true;
+        self
+    }
+
+    /// 入队，返回该消息是否被接受
+    pub fn push(&mut self, payload: Vec<u8>, delivery: Delivery) -> bool {
+        if self.latest_wins && delivery == Delivery::Latest {
+            let before = self.items.len();
+            self.items.retain(|(d, _)| *d == Delivery::Reliable);
+            self.dropped += (before - self.items.len()) as u64;
+        }
+        if self.items.len() >= self.capacity {
+            match self.items.iter().position(|(d, _)| *d != Delivery::Reliable) {
+                Some(oldest) => {
+                    self.items.remove(oldest);
+                    self.dropped += 1;
+                }
+                None if delivery != Delivery::Reliable => {
+                    self.dropped += 1;
+                    return false;
+                }
+                None => {}
+            }
+        }
+        self.items.push_back((delivery, payload));
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        self.items.pop_front().map(|(_, payload)| payload)
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// 累计丢弃的消息数
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+/// 所有客户端的发送队列，由单独的线程发送
+struct SendQueues {
+    capacity: usize,
+    latest_wins: bool,
+    pending: Mutex<HashMap<ClientConn, SendQueue>>,
+    ready: Condvar,
+}
+
+/// 出站发送器：按 `ClientConn` 把数据发到 UDP socket 或对应的 TCP / WebSocket 连接
+pub struct Outbound {
+    udp: Option<UdpSocket>,
+    /// 每个 TCP 连接单独加锁：整帧写出不被其他发送方打断，慢连接也不会挡住其他连接
+    tcp: Mutex<HashMap<SocketAddr, Arc<Mutex<TcpStream>>>>,
+    /// WebSocket 连接由各自的线程读写，这里只保存投递队列
+    ws: Mutex<HashMap<SocketAddr, Sender<Vec<u8>>>>,
+    /// 启用时 `deliver` 只入队，由 `start_drain` 启动的线程发送
+    queues: Option<SendQueues>,
+    #[cfg(feature = "chaos")]
+    chaos: Option<crate::chaos::Chaos>,
+    /// 被故障注入延迟的消息，由 `start_drain` 启动的单个线程按到期时间发送
+    #[cfg(feature = "chaos")]
+    delayed: crate::chaos::DelayQueue,
+    /// `close` 之后 `start_drain` 启动的线程退出
+    closed: AtomicBool,
+}
+
+impl Outbound {
+    pub fn new(udp: Option<UdpSocket>) -> Self {
+        Outbound {
+            udp,
+            tcp: Mutex::new(HashMap::new()),
+            ws: Mutex::new(HashMap::new()),
+            queues: None,
+            #[cfg(feature = "chaos")]
+            chaos: None,
+            #[cfg(feature = "chaos")]
+            delayed: crate::chaos::DelayQueue::new(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// 为每个客户端启用容量为 `capacity` 的发送队列（需要再调用 `start_drain`）
+    ///
+    /// `latest_wins` 见 `SendQueue::with_latest_wins`。
+    pub fn with_send_queue(mut self, capacity: usize, latest_wins: bool) -> Self {
+        self.queues = Some(SendQueues {
+            capacity,
+            latest_wins,
+            pending: Mutex::new(HashMap::new()),
+            ready: Condvar::new(),
+        });
+        self
+    }
+
+    /// 启动发送线程：轮流从每个客户端的队列取一条消息发送（未启用发送队列时不做任何事）
+    ///
+    /// 启用故障注入时另启动一个线程发送被延迟的消息。返回启动的线程，`close` 之后它们退出。
+    pub fn start_drain(self: &Arc<Self>) -> Vec<JoinHandle<()>> {
+        let mut handles = Vec::new();
+        #[cfg(feature = "chaos")]
+        if self.chaos.is_some() {
+            let outbound = self.clone();
+            handles.push(std::thread::spawn(move || {
+                while let Some(due) = outbound.delayed.wait_due() {
+                    for (conn, payload) in due {
+                        let _ = outbound.send(&conn, &payload);
+                    }
+                }
+            }));
+        }
+        if self.queues.is_none() {
+            return handles;
+        }
+        let outbound = self.clone();
+        handles.push(std::thread::spawn(move || loop {
+            let Some(queues) = &outbound.queues else {
+                return;
+            };
+            let batch: Vec<(ClientConn, Vec<u8>)> = {
+                let mut pending = queues.pending.lock().unwrap();
+                loop {
+                    if outbound.closed.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    if pending.values().any(|q| !q.is_empty()) {
+                        break;
+                    }
+                    pending = queues.ready.wait(pending).unwrap();
+                }
+                pending
+                    .iter_mut()
+                    .filter_map(|(conn, q)| q.pop().map(|payload| (*conn, payload)))
+                    .collect()
+            };
+            for (conn, payload) in batch {
+                let _ = outbound.transmit(&conn, &payload);
+            }
+        }));
+        handles
+    }
+
+    /// 让 `start_drain` 启动的线程退出（队列中尚未发送的消息被丢弃）
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        #[cfg(feature = "chaos")]
+        self.delayed.close();
+        if let Some(queues) = &self.queues {
+            // 持锁通知，避免发送线程在检查标志和进入等待之间错过唤醒
+            let _pending = queues.pending.lock().unwrap();
+            queues.ready.notify_all();
+        }
+    }
+
+    /// 客户端发送队列中尚未发送的消息数
+    pub fn queued(&self, conn: &ClientConn) -> usize {
+        self.queues
+            .as_ref()
+            .and_then(|q| q.pending.lock().unwrap().get(conn).map(|q| q.len()))
+            .unwrap_or(0)
+    }
+
+    /// 对所有经由 `deliver` 的发送启用故障注入（被延迟的消息需要调用 `start_drain` 才会发出）
+    #[cfg(feature = "chaos")]
+    pub fn with_chaos(mut self, chaos: crate::chaos::Chaos) -> Self {
+        self.chaos = Some(chaos);
+        self
+    }
+
+    /// 发送一条可靠消息（见 `deliver_as`）
+    pub fn deliver(self: &Arc<Self>, conn: &ClientConn, payload: &[u8]) -> io::Result<()> {
+        self.deliver_as(conn, payload, Delivery::Reliable)
+    }
+
+    /// 发送一条消息：启用发送队列时入队，否则立即发送
+    pub fn deliver_as(self: &Arc<Self>, conn: &ClientConn, payload: &[u8], delivery: Delivery) -> io::Result<()> {
+        let Some(queues) = &self.queues else {
+            return self.transmit(conn, payload);
+        };
+        let mut pending = queues.pending.lock().unwrap();
+        let queue = pending
+            .entry(*conn)
+            .or_insert_with(|| {
+                let queue = SendQueue::new(queues.capacity);
+                if queues.latest_wins {
+                    queue.with_latest_wins()
+                } else {
+                    queue
+                }
+            });
+        if queue.push(payload.to_vec(), delivery) {
+            queues.ready.notify_one();
+        }
+        Ok(())
+    }
+
+    /// 立即发送，启用故障注入时可能被丢弃或延迟（未启用 `chaos` feature 时等同于 `send`）
+    fn transmit(self: &Arc<Self>, conn: &ClientConn, payload: &[u8]) -> io::Result<()> {
+        #[cfg(feature = "chaos")]
+        if let Some(chaos) = &self.chaos {
+            match chaos.decide() {
+                None => return Ok(()),
+                Some(delay) if !delay.is_zero() => {
+                    self.delayed.push(delay, *conn, payload.to_vec());
+                    return Ok(());
+                }
+                Some(_) => {}
+            }
+        }
+        self.send(conn, payload)
+    }
+
+    /// 登记一个 WebSocket 连接的投递队列
+    pub fn add_ws(&self, peer: SocketAddr, queue: Sender<Vec<u8>>) {
+        self.ws.lock().unwrap().insert(peer, queue);
+    }
+
+    /// 移除已断开的 WebSocket 连接
+    pub fn remove_ws(&self, peer: &SocketAddr) {
+        self.ws.lock().unwrap().remove(peer);
+    }
+
+    /// 登记一个 TCP 连接（用于写回）
+    pub fn add_tcp(&self, peer: SocketAddr, stream: TcpStream) {
+        self.tcp.lock().unwrap().insert(peer, Arc::new(Mutex::new(stream)));
+    }
+
+    /// 移除已断开的 TCP 连接
+    pub fn remove_tcp(&self, peer: &SocketAddr) {
+        self.tcp.lock().unwrap().remove(peer);
+    }
+
+    /// 发送一条消息；目标连接不存在时返回 `NotConnected`
+    ///
+    /// TCP 写出失败（包括写超时）时帧可能只写了一半，该连接随即被断开并移除。
+    pub fn send(&self, conn: &ClientConn, payload: &[u8]) -> io::Result<()> {
+        match conn {
+            ClientConn::Udp(addr) => match &self.udp {
+                Some(socket) => socket.send_to(payload, addr).map(|_| ()),
+                None => Err(io::Error::new(io::ErrorKind::NotConnected, "udp disabled")),
+            },
+            ClientConn::Tcp(peer) => {
+                // 只在取出连接时持有连接表的锁，写出期间不阻塞发往其他连接的消息
+                let stream = self
+                    .tcp
+                    .lock()
+                    .unwrap()
+                    .get(peer)
+                    .cloned()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "tcp closed"))?;
+                let mut stream = stream.lock().unwrap();
+                let result = write_frame(&mut *stream, payload);
+                if let Err(e) = &result {
+                    eprintln!("tcp write to {} failed, dropping connection: {}", peer, e);
+                    let _ = stream.shutdown(std::net::Shutdown::Both);
+                    self.remove_tcp(peer);
+                }
+                result
+            }
+            ClientConn::Ws(peer) => {
+                let ws = self.ws.lock().unwrap();
+                let queue = ws
+                    .get(peer)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "websocket closed"))?;
+                queue
+                    .send(payload.to_vec())
+                    .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "websocket closed"))
+            }
+        }
+    }
+}
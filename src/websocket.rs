@@ -0,0 +1,113 @@
+//! WebSocket 网关（需要启用 `websocket` feature）
+//!
+//! 每个文本帧是一条与 UDP 相同格式的 JSON 消息。每个连接一个线程（连接数受
+//! `ConnectionLimit` 限制）：读操作带短超时，超时间隙把 `Outbound` 投递过来的消息写回浏览器。
+
+use crate::transport::{ClientConn, ConnectionLimit, Outbound};
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tungstenite::Message;
+
+/// 读超时，决定出站消息的最大排队延迟
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// 收到一条消息时的回调
+pub type OnMessage = Arc<dyn Fn(ClientConn, &[u8]) + Send + Sync>;
+
+/// 接受 WebSocket 连接，直到监听出错或 `shutdown` 被置位；连接数达到 `limit` 时新连接直接关闭
+pub fn run_listener(
+    listener: TcpListener,
+    outbound: Arc<Outbound>,
+    on_message: OnMessage,
+    limit: ConnectionLimit,
+    shutdown: Arc<AtomicBool>,
+) {
+    for stream in listener.incoming() {
+        if shutdown.load(Ordering::Relaxed) {
+            break;
+        }
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("websocket accept error: {}", e);
+                continue;
+            }
+        };
+        let Some(slot) = limit.try_acquire() else {
+            eprintln!(
+                "Rejected WebSocket connection from {:?}: connection limit reached ({})",
+                stream.peer_addr().ok(),
+                limit.limit()
+            );
+            continue;
+        };
+        let outbound = outbound.clone();
+        let on_message = on_message.clone();
+        thread::spawn(move || {
+            serve_conn(stream, &outbound, &on_message);
+            drop(slot);
+        });
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+fn serve_conn(stream: TcpStream, outbound: &Outbound, on_message: &OnMessage) {
+    let Ok(peer) = stream.peer_addr() else {
+        return;
+    };
+    let mut ws = match tungstenite::accept(stream) {
+        Ok(ws) => ws,
+        Err(e) => {
+            eprintln!("websocket handshake failed for {}: {}", peer, e);
+            return;
+        }
+    };
+    if let Err(e) = ws.get_ref().set_read_timeout(Some(POLL_INTERVAL)) {
+        eprintln!("websocket setup failed for {}: {}", peer, e);
+        return;
+    }
+
+    let (tx, rx) = mpsc::channel::<Vec<u8>>();
+    outbound.add_ws(peer, tx);
+    let conn = ClientConn::Ws(peer);
+    println!("WebSocket client connected: {}", peer);
+
+    'conn: loop {
+        match ws.read() {
+            Ok(Message::Text(text)) => on_message(conn, text.as_bytes()),
+            Ok(Message::Binary(bytes)) => on_message(conn, &bytes),
+            Ok(Message::Close(_)) => break,
+            // ping/pong 由 tungstenite 自动处理
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(ref e)) if is_timeout(e) => {}
+            Err(tungstenite::Error::ConnectionClosed) => break,
+            Err(e) => {
+                eprintln!("websocket read error from {}: {}", peer, e);
+                break;
+            }
+        }
+
+        while let Ok(payload) = rx.try_recv() {
+            // JSON 编码走文本帧，二进制编码（如 MessagePack）走二进制帧
+            let msg = match String::from_utf8(payload) {
+                Ok(text) => Message::Text(text),
+                Err(e) => Message::Binary(e.into_bytes()),
+            };
+            if let Err(e) = ws.send(msg) {
+                eprintln!("websocket write error to {}: {}", peer, e);
+                break 'conn;
+            }
+        }
+    }
+
+    outbound.remove_ws(&peer);
+    println!("WebSocket client disconnected: {}", peer);
+}
@@ -1,27 +1,39 @@
-use backend_demo::{generate_unique_name, validate_movement, PlayerState, WorldState};
-use std::collections::HashMap;
+use backend_demo::anticheat::ActionCooldowns;
+use backend_demo::auth::{AuthClaims, AuthError, Authenticator, NoAuth, SharedSecretAuth};
+use backend_demo::codec::{dump_payload, Codec, CompactJson, PrettyJson};
+use backend_demo::config::{ActionPolicy, MovementConfig, RoomConfig, ServerConfig, SpeedProfileConfig, UsernamePolicy};
+use backend_demo::history::StateHistory;
+use backend_demo::i18n::{MessageCatalog, MessageKey};
+use backend_demo::ids::{SeededGenerator, UuidGenerator};
+use backend_demo::jitter::JitterBuffer;
+use backend_demo::metrics::{render_prometheus, LatencyHistogram, Metrics, QueueGauge, WorkerGauge};
+use backend_demo::pool::WorkerPool;
+use backend_demo::observer::{NoopObserver, ServerObserver};
+use backend_demo::protocol::{BroadcastProjection, CorrectedState, FieldError, PlayerUpdate, ServerMessage};
+use backend_demo::store::{FileStore, IdentityStore, InMemoryStore, PlayerRecord};
+use backend_demo::rtt::RttTracker;
+use backend_demo::runtime::{start_server, ServerHandle};
+use backend_demo::server::{handle_message, handle_message_instrumented, HandlerError, Outgoing, ServerState};
+use backend_demo::transport::{
+    bind_udp_sockets, read_frame, write_frame, ClientConn, ConnectionLimit, Delivery, Outbound, SendQueue, Transport,
+};
+use backend_demo::sweep::{collect_expired, collect_past_deadline, next_sweep_delay, Clock, ManualClock, SweepSignal};
+use backend_demo::{
+    acknowledges_correction, apply_correction, clamp_axes, frame, generate_unique_name, generate_unique_name_with,
+    issue_correction_nonce, now_millis, resolve_collisions, round_player, snap_to_grid, validate_movement, velocity_consistent,
+    step, CorrectionStrategy, Entity, PhysicsMode, PlayerOrder, PlayerState, SuffixStrategy, UuidStorage, WorldState, DEFAULT_MAX_NAME_SUFFIX,
+    MAX_CORRECTION_NONCE, SNAPSHOT_VERSION,
+};
+use std::collections::{HashMap, HashSet};
 use uuid::Uuid;
 use std::fs;
-use std::net::UdpSocket;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use serde_json::{json, Value};
 
 fn empty_player(username: &str) -> PlayerState {
-    PlayerState {
-        uuid: Uuid::new_v4(),
-        username: username.to_string(),
-        x: None,
-        y: None,
-        z: None,
-        ts: None,
-        rx: None,
-        ry: None,
-        rz: None,
-        vx: None,
-        vy: None,
-        vz: None,
-        action: None,
-    }
+    PlayerState::new(Uuid::new_v4(), username)
 }
 
 // ============================================================================
@@ -76,6 +88,37 @@ fn test_generate_unique_name_different_prefixes() {
     assert_eq!(name_beta, "beta_2");
 }
 
+#[test]
+fn test_generate_unique_name_custom_max_suffix() {
+    let mut world: HashMap<Uuid, PlayerState> = HashMap::new();
+    world.insert(Uuid::new_v4(), empty_player("cap_1"));
+    world.insert(Uuid::new_v4(), empty_player("cap_2"));
+    assert_eq!(generate_unique_name_with(&world, "cap", 3, SuffixStrategy::Sequential), "cap_fallback");
+    assert_eq!(generate_unique_name_with(&world, "cap", 4, SuffixStrategy::Sequential), "cap_3");
+}
+
+#[test]
+fn test_generate_unique_name_random_in_range() {
+    let mut world: HashMap<Uuid, PlayerState> = HashMap::new();
+    world.insert(Uuid::new_v4(), empty_player("dice_1"));
+    let name = generate_unique_name_with(&world, "dice", 100, SuffixStrategy::RandomInRange);
+    let suffix: u32 = name.strip_prefix("dice_").unwrap().parse().unwrap();
+    assert!((2..100).contains(&suffix));
+}
+
+#[test]
+fn test_generate_unique_name_random_token_nearly_full_world() {
+    let mut world: HashMap<Uuid, PlayerState> = HashMap::new();
+    for i in 1..10000 {
+        let key = format!("bar_{}", i);
+        world.insert(Uuid::new_v4(), empty_player(&key));
+    }
+    let name = generate_unique_name_with(&world, "bar", DEFAULT_MAX_NAME_SUFFIX, SuffixStrategy::RandomToken);
+    assert!(name.starts_with("bar_"));
+    assert_ne!(name, "bar_fallback");
+    assert!(!world.values().any(|p| p.username == name));
+}
+
 #[test]
 fn test_generate_unique_name_special_characters() {
     let mut world: HashMap<Uuid, PlayerState> = HashMap::new();
@@ -179,6 +222,510 @@ fn test_validate_movement_cheating_teleport() {
     assert_eq!(result.corrected_z.unwrap(), 0.0);
 }
 
+#[test]
+fn test_apply_correction_strategies() {
+    // 静止的玩家从原点"移动"到 (10,0,0)：期望位置是原点，容差 0.5
+    let prev = (0.0, 0.0, 0.0);
+    let actual = (10.0, 0.0, 0.0);
+    let expected = (0.0, 0.0, 0.0);
+    assert_eq!(apply_correction(CorrectionStrategy::Snap, 0.5, prev, actual, expected), expected);
+    // Nudge 0.5：落在上报位置和期望位置的正中间
+    assert_eq!(apply_correction(CorrectionStrategy::Nudge(0.5), 0.5, prev, actual, expected), (5.0, 0.0, 0.0));
+    // ClampVelocity：沿上报方向截断到允许的距离
+    assert_eq!(apply_correction(CorrectionStrategy::ClampVelocity, 0.5, prev, actual, expected), (0.5, 0.0, 0.0));
+}
+
+#[test]
+fn test_handle_update_nudge_correction() {
+    let config = ServerConfig {
+        movement: MovementConfig {
+            correction: CorrectionStrategy::Nudge(0.5),
+            ..MovementConfig::default()
+        },
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "nudged");
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "vx": 1.0, "ts": 1000})).unwrap();
+    // 期望 x=1，上报 x=9
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 9.0, "y": 0.0, "z": 0.0, "vx": 1.0, "ts": 2000})).unwrap();
+    let corrected = out.iter().find_map(|(_, m)| match m {
+        ServerMessage::Correction { corrected, .. } => Some(corrected.clone()),
+        _ => None,
+    });
+    assert_eq!(corrected.and_then(|c| c.x), Some(5.0));
+    assert_eq!(state.world.players[&uuid].x, Some(5.0));
+}
+
+#[test]
+fn test_velocity_consistent() {
+    // 同向、夹角 45 度以内、原地抖动都算一致
+    assert!(velocity_consistent(90.0, 0.5, (2.0, 0.0, 0.0), (2.0, 0.0, 0.0)));
+    assert!(velocity_consistent(90.0, 0.5, (2.0, 1.0, 0.0), (1.0, 0.0, 0.0)));
+    assert!(velocity_consistent(90.0, 0.5, (0.3, 0.0, 0.0), (-1.0, 0.0, 0.0)));
+    // 反向、上报零速度却在移动
+    assert!(!velocity_consistent(90.0, 0.5, (2.0, 0.0, 0.0), (-2.0, 0.0, 0.0)));
+    assert!(!velocity_consistent(90.0, 0.5, (2.0, 0.0, 0.0), (0.0, 0.0, 0.0)));
+    // 更严格的阈值
+    assert!(!velocity_consistent(30.0, 0.5, (2.0, 2.0, 0.0), (1.0, 0.0, 0.0)));
+}
+
+#[test]
+fn test_handle_update_flags_inconsistent_velocity() {
+    let config = ServerConfig {
+        movement: MovementConfig {
+            require_velocity_consistency: true,
+            ..MovementConfig::default()
+        },
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "backwards");
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "vx": -2.0, "ts": 1000})).unwrap();
+    // 位移长度在允许范围内，但方向与上报速度相反
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 2.0, "y": 0.0, "z": 0.0, "vx": -2.0, "ts": 2000})).unwrap();
+    assert_eq!(correction_for(&out, src).map(|(reason, _)| reason), Some("inconsistent_velocity".to_string()));
+    assert_eq!(state.world.players[&uuid].x, Some(-2.0));
+
+    // 未开启时同样的移动不会被标记
+    let mut state = new_state();
+    let uuid = register(&mut state, src, "backwards");
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "vx": -2.0, "ts": 1000})).unwrap();
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 2.0, "y": 0.0, "z": 0.0, "vx": -2.0, "ts": 2000})).unwrap();
+    assert_eq!(correction_for(&out, src), None);
+}
+
+#[test]
+fn test_clamp_axes() {
+    let caps = [None, Some(1.0), None];
+    assert_eq!(clamp_axes(caps, 0.5, 1.0, (0.0, 0.0, 0.0), (9.0, 1.2, -9.0)), None);
+    assert_eq!(clamp_axes(caps, 0.5, 1.0, (0.0, 0.0, 0.0), (9.0, -3.0, -9.0)), Some((9.0, -1.0, -9.0)));
+    assert_eq!(clamp_axes([None; 3], 0.5, 1.0, (0.0, 0.0, 0.0), (90.0, 90.0, 90.0)), None);
+}
+
+#[test]
+fn test_handle_update_per_axis_speed_cap() {
+    let config = ServerConfig {
+        movement: MovementConfig {
+            max_speed_y: Some(1.0),
+            ..MovementConfig::default()
+        },
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "grounded");
+    let corrected = |out: &Outgoing| {
+        out.iter().find_map(|(_, m)| match m {
+            ServerMessage::Correction { reason, corrected, .. } => Some((reason.clone(), (corrected.x, corrected.y, corrected.z))),
+            _ => None,
+        })
+    };
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "ts": 1000})).unwrap();
+    // 上报的竖直速度超过上限：期望位移按上限计算，只有 Y 被拉回
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 4.0, "y": 3.0, "z": 2.0, "vx": 4.0, "vy": 3.0, "vz": 2.0, "ts": 2000})).unwrap();
+    assert_eq!(corrected(&out), Some(("invalid_movement".to_string(), (Some(4.0), Some(1.0), Some(2.0)))));
+    let (_, nonce) = correction_for(&out, src).unwrap();
+
+    // 总位移在允许范围内，但竖直位移单独超限
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 4.0, "y": 4.0, "z": 2.0, "vx": 10.0, "ts": 3000, "ack": nonce})).unwrap();
+    assert_eq!(corrected(&out), Some(("axis_speed".to_string(), (Some(4.0), Some(2.0), Some(2.0)))));
+    let player = &state.world.players[&uuid];
+    assert_eq!((player.x, player.y, player.z), (Some(4.0), Some(2.0), Some(2.0)));
+}
+
+#[test]
+fn test_clock_skew_corrected_before_validation() {
+    let skew = 300_000;
+    // 恢复时采用客户端声明的位置，ts 由服务器写入（服务器时间线）；随后的一次更新是否被纠正
+    let first_move_corrected = |correct_clock_skew: bool, x: f64| {
+        let config = ServerConfig {
+            correct_clock_skew,
+            ..ServerConfig::default()
+        };
+        let mut state = new_state().with_config(config);
+        let src = client_addr(40001);
+        let client_now = || now_millis() + skew;
+        let out = handle(&mut state, src, json!({"type": "register", "username": "fast_clock", "client_ts": client_now()})).unwrap();
+        let uuid = match &out[0].1 {
+            ServerMessage::Registered { uuid, .. } => *uuid,
+            other => panic!("unexpected reply: {:?}", other),
+        };
+        let resume = json!({"type": "register", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "client_ts": client_now()});
+        handle(&mut state, src, resume).unwrap();
+        let update = json!({"type": "update", "uuid": uuid, "x": x, "y": 0.0, "z": 0.0, "vx": 5.0, "ts": client_now() + 200});
+        let corrected = correction_for(&handle(&mut state, src, update).unwrap(), src).is_some();
+        (corrected, state.clock_skew.get(&uuid).copied())
+    };
+
+    // 换算后 dt 约为 0.2 秒：正常移动通过，瞬移被纠正
+    let (corrected, estimated) = first_move_corrected(true, 1.0);
+    assert!(!corrected);
+    let estimated = estimated.unwrap();
+    assert!((estimated - i128::from(skew)).abs() < 1_000, "estimated skew {}", estimated);
+    assert!(first_move_corrected(true, 100.0).0);
+
+    // 不换算时 dt 多出 300 秒，超出校验范围，瞬移被误放行
+    assert_eq!(first_move_corrected(false, 100.0), (false, None));
+}
+
+#[test]
+fn test_snap_to_grid() {
+    assert_eq!(snap_to_grid((1.4, -0.6, 2.5), 1.0), (1.0, -1.0, 3.0));
+    assert_eq!(snap_to_grid((0.74, 0.0, 0.26), 0.5), (0.5, 0.0, 0.5));
+    assert_eq!(snap_to_grid((0.3, 0.3, 0.3), 0.0), (0.3, 0.3, 0.3));
+}
+
+#[test]
+fn test_handle_update_off_grid_position_snapped() {
+    let config = ServerConfig {
+        movement: MovementConfig {
+            grid: Some(1.0),
+            ..MovementConfig::default()
+        },
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "tiler");
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "ts": 1000})).unwrap();
+
+    // 对齐网格的移动照常通过
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 1.0, "y": 0.0, "z": 0.0, "vx": 5.0, "ts": 2000})).unwrap();
+    assert_eq!(correction_for(&out, src), None);
+
+    // 速度合法但落在格子中间：拉回最近的网格点
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 2.4, "y": 0.0, "z": 0.0, "vx": 5.0, "ts": 3000})).unwrap();
+    assert_eq!(correction_for(&out, src).map(|(reason, _)| reason), Some("off_grid".to_string()));
+    let player = &state.world.players[&uuid];
+    assert_eq!((player.x, player.y, player.z), (Some(2.0), Some(0.0), Some(0.0)));
+}
+
+#[test]
+fn test_speed_profile_learns_fast_baseline_and_flags_spike() {
+    let config = ServerConfig {
+        movement: MovementConfig {
+            speed_profile: Some(SpeedProfileConfig {
+                warmup_updates: 5,
+                ..SpeedProfileConfig::default()
+            }),
+            ..MovementConfig::default()
+        },
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "rider");
+    let mut x = 0.0;
+    let mut ts = 1000;
+    let mut step = |state: &mut ServerState, speed: f64| {
+        // 每 100ms 按上报的速度移动，速度与位移一致，常规校验都能通过
+        x += speed * 0.1;
+        ts += 100;
+        let update = json!({"type": "update", "uuid": uuid, "x": x, "y": 0.0, "z": 0.0, "vx": speed, "ts": ts});
+        correction_for(&handle(state, src, update).unwrap(), src).map(|(reason, _)| reason)
+    };
+
+    // 骑乘的玩家一直以 30 m/s 移动：预热后的基线就是 30 m/s
+    for _ in 0..8 {
+        assert_eq!(step(&mut state, 30.0), None);
+    }
+    assert_eq!(state.speed_profiles.baseline(&uuid, 5, 0.95), Some(30.0));
+
+    // 突然 10 倍速
+    assert_eq!(step(&mut state, 300.0), Some("speed_baseline".to_string()));
+    let player = &state.world.players[&uuid];
+    assert!(player.x.unwrap() < x);
+}
+
+#[test]
+fn test_max_delta_per_update_rejects_huge_jump_regardless_of_dt() {
+    let config = ServerConfig {
+        movement: MovementConfig {
+            max_delta_per_update: 50.0,
+            ..MovementConfig::default()
+        },
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "jumper");
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "ts": 1000})).unwrap();
+
+    // 10 秒、1000 m/s：速度校验本身会放行这次位移
+    let jump = json!({"type": "update", "uuid": uuid, "x": 10000.0, "y": 0.0, "z": 0.0, "vx": 1000.0, "ts": 11000});
+    let out = handle(&mut state, src, jump).unwrap();
+    assert_eq!(correction_for(&out, src).map(|(reason, _)| reason), Some("max_delta".to_string()));
+    assert_eq!(state.world.players[&uuid].x, Some(0.0));
+
+    // 默认不限制
+    let mut state = new_state();
+    let uuid = register(&mut state, src, "jumper");
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "ts": 1000})).unwrap();
+    let jump = json!({"type": "update", "uuid": uuid, "x": 10000.0, "y": 0.0, "z": 0.0, "vx": 1000.0, "ts": 11000});
+    assert_eq!(correction_for(&handle(&mut state, src, jump).unwrap(), src), None);
+}
+
+#[test]
+fn test_respawn_to_spawn_point_skips_validation_once() {
+    let config = ServerConfig {
+        movement: MovementConfig {
+            spawn_points: vec![(200.0, 0.0, 0.0)],
+            spawn_radius: 2.0,
+            ..MovementConfig::default()
+        },
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "phoenix");
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "ts": 1000})).unwrap();
+
+    let respawn = json!({"type": "update", "uuid": uuid, "x": 201.0, "y": 0.0, "z": 0.0, "ts": 1100, "action": "respawn"});
+    let out = handle(&mut state, src, respawn).unwrap();
+    assert_eq!(correction_for(&out, src), None);
+    assert_eq!(state.world.players[&uuid].x, Some(201.0));
+
+    // 豁免只针对这一次更新，之后的跳跃照常校验
+    let jump = json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "ts": 1200});
+    assert!(correction_for(&handle(&mut state, src, jump).unwrap(), src).is_some());
+
+    // 不在重生点的“重生”仍然被拒绝
+    let mut state = new_state().with_config(state.config.clone());
+    let uuid = register(&mut state, src, "phoenix");
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "ts": 1000})).unwrap();
+    let fake = json!({"type": "update", "uuid": uuid, "x": -500.0, "y": 0.0, "z": 0.0, "ts": 1100, "action": "respawn"});
+    let out = handle(&mut state, src, fake).unwrap();
+    assert!(correction_for(&out, src).is_some());
+    assert_eq!(state.world.players[&uuid].x, Some(0.0));
+}
+
+#[test]
+fn test_repeated_zero_dt_flagged_as_timestamp_manipulation() {
+    let config = ServerConfig {
+        movement: MovementConfig {
+            non_positive_dt_allowance: Some(2),
+            ..MovementConfig::default()
+        },
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "frozen_clock");
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "ts": 1000})).unwrap();
+
+    // 同一个 ts 连续上报：前两次算时钟抖动，第三次被拉回上一个合法位置
+    let stalled = |x: f64| json!({"type": "update", "uuid": uuid, "x": x, "y": 0.0, "z": 0.0, "ts": 1000});
+    assert_eq!(correction_for(&handle(&mut state, src, stalled(0.5)).unwrap(), src), None);
+    assert_eq!(correction_for(&handle(&mut state, src, stalled(1.0)).unwrap(), src), None);
+    let out = handle(&mut state, src, stalled(500.0)).unwrap();
+    let (reason, nonce) = correction_for(&out, src).unwrap();
+    assert_eq!(reason, "non_positive_dt");
+    let player = &state.world.players[&uuid];
+    assert_eq!((player.x, player.ts), (Some(1.0), Some(1000)));
+    assert_eq!(state.bad_dt[&uuid], 3);
+
+    // 时间戳恢复前进后计数清零
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 1.0, "y": 0.0, "z": 0.0, "ts": 1100, "ack": nonce})).unwrap();
+    assert_eq!(correction_for(&out, src), None);
+    assert!(!state.bad_dt.contains_key(&uuid));
+}
+
+#[test]
+fn test_suspect_hold_forgives_one_frame_spike_but_corrects_sustained_teleport() {
+    let config = ServerConfig {
+        movement: MovementConfig {
+            suspect_hold: Some(Duration::from_millis(200)),
+            ..MovementConfig::default()
+        },
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let t0 = Instant::now();
+    let at = |ms| t0 + Duration::from_millis(ms);
+    let (lagger, cheater) = (client_addr(40001), client_addr(40002));
+    let lagger_uuid = register(&mut state, lagger, "lagger");
+    let cheater_uuid = register(&mut state, cheater, "cheater");
+    let update = |uuid: Uuid, x: f64, ts: u64| json!({"type": "update", "uuid": uuid, "x": x, "y": 0.0, "z": 0.0, "vx": 5.0, "ts": ts});
+    for uuid in [lagger_uuid, cheater_uuid] {
+        let src = state.conn_of(&uuid).unwrap();
+        handle_at(&mut state, src, update(uuid, 0.0, 1000), at(0)).unwrap();
+    }
+
+    // 单帧尖峰：先冻结在原位，下一帧回到合理轨迹后直接放行
+    let out = handle_at(&mut state, lagger, update(lagger_uuid, 100.0, 1050), at(50)).unwrap();
+    assert_eq!(correction_for(&out, lagger), None);
+    assert_eq!(state.world.players[&lagger_uuid].x, Some(0.0));
+    let out = handle_at(&mut state, lagger, update(lagger_uuid, 0.5, 1100), at(100)).unwrap();
+    assert_eq!(correction_for(&out, lagger), None);
+    assert_eq!(state.world.players[&lagger_uuid].x, Some(0.5));
+    assert!(!state.suspects.contains_key(&lagger_uuid));
+
+    // 持续传送：窗口内仍冻结，窗口过后纠正
+    let out = handle_at(&mut state, cheater, update(cheater_uuid, 100.0, 1050), at(50)).unwrap();
+    assert_eq!(correction_for(&out, cheater), None);
+    let out = handle_at(&mut state, cheater, update(cheater_uuid, 100.5, 1100), at(100)).unwrap();
+    assert_eq!(correction_for(&out, cheater), None);
+    assert_eq!(state.world.players[&cheater_uuid].x, Some(0.0));
+    let out = handle_at(&mut state, cheater, update(cheater_uuid, 101.0, 1300), at(300)).unwrap();
+    assert_eq!(correction_for(&out, cheater).map(|(reason, _)| reason), Some("invalid_movement".to_string()));
+    assert!(state.world.players[&cheater_uuid].x.unwrap() < 100.0);
+}
+
+#[test]
+fn test_correction_and_broadcast_carry_input_seq() {
+    let mut state = new_state();
+    let src = client_addr(40001);
+    let other = client_addr(40002);
+    let uuid = register(&mut state, src, "predictor");
+    register(&mut state, other, "observer");
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "ts": 1000, "seq": 7})).unwrap();
+    let world_seq = |out: &Outgoing, dst: ClientConn| {
+        out.iter().find_map(|(addr, m)| match m {
+            ServerMessage::World { seq, .. } if *addr == dst => Some(*seq),
+            _ => None,
+        })
+    };
+    assert_eq!(world_seq(&out, src), Some(Some(7)));
+    // 其他客户端没有上报过序号
+    assert_eq!(world_seq(&out, other), Some(None));
+
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 50.0, "y": 0.0, "z": 0.0, "ts": 1100, "seq": 8})).unwrap();
+    let seq = out.iter().find_map(|(_, m)| match m {
+        ServerMessage::Correction { seq, .. } => Some(*seq),
+        _ => None,
+    });
+    assert_eq!(seq, Some(Some(8)));
+    assert_eq!(world_seq(&out, src), Some(Some(8)));
+}
+
+#[test]
+fn test_batch_update_validates_each_entity() {
+    let mut state = new_state();
+    let src = client_addr(40001);
+    let honest = register(&mut state, src, "squad-1");
+    let cheater = register(&mut state, src, "squad-2");
+    let stranger = register(&mut state, client_addr(40002), "stranger");
+    let batch = |updates: Value| json!({"type": "batch_update", "updates": updates});
+    handle(&mut state, src, batch(json!([
+        {"uuid": honest, "x": 0.0, "y": 0.0, "z": 0.0, "ts": 1000},
+        {"uuid": cheater, "x": 0.0, "y": 0.0, "z": 0.0, "ts": 1000},
+    ])))
+    .unwrap();
+
+    let out = handle(&mut state, src, batch(json!([
+        {"uuid": honest, "x": 0.1, "y": 0.0, "z": 0.0, "ts": 1100},
+        {"uuid": cheater, "x": 50.0, "y": 0.0, "z": 0.0, "ts": 1100},
+        {"uuid": stranger, "x": 1.0},
+    ])))
+    .unwrap();
+    match &out[0] {
+        (addr, ServerMessage::BatchResult { results }) if *addr == src => {
+            assert_eq!(results.len(), 3);
+            assert_eq!((results[0].uuid, results[0].corrected, &results[0].error), (Some(honest), false, &None));
+            assert_eq!((results[1].uuid, results[1].corrected), (Some(cheater), true));
+            assert_eq!(results[2].error.as_deref(), Some("unauthorized"));
+        }
+        other => panic!("unexpected reply: {:?}", other),
+    }
+    let corrections: Vec<_> = out
+        .iter()
+        .filter_map(|(_, m)| match m {
+            ServerMessage::Correction { corrected, .. } => Some(corrected.uuid),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(corrections, vec![cheater]);
+    assert_eq!(state.world.players[&honest].x, Some(0.1));
+    assert_eq!(state.world.players[&stranger].x, None);
+    // 全部应用后每个客户端只收到一次广播
+    let broadcasts = out.iter().filter(|(addr, m)| *addr == src && matches!(m, ServerMessage::World { .. })).count();
+    assert_eq!(broadcasts, 1);
+}
+
+#[test]
+fn test_register_protocol_version_negotiation() {
+    let config = ServerConfig {
+        min_protocol_version: 2,
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let old = client_addr(40001);
+    let out = handle(&mut state, old, json!({"type": "register", "username": "legacy", "protocol_version": 1})).unwrap();
+    assert_eq!(out, vec![(old, ServerMessage::IncompatibleVersion { server: 2, min_supported: 2 })]);
+    assert!(state.username_map.is_empty());
+
+    let src = client_addr(40002);
+    let out = handle(&mut state, src, json!({"type": "register", "username": "modern", "protocol_version": 2})).unwrap();
+    assert!(matches!(&out[0].1, ServerMessage::Registered { protocol_version: 2, .. }));
+    let uuid = state.username_map["modern"];
+    assert_eq!(state.clients[&uuid].protocol_version, Some(2));
+}
+
+#[test]
+fn test_seq_omitted_for_old_protocol_clients() {
+    let mut state = new_state();
+    let src = client_addr(40001);
+    handle(&mut state, src, json!({"type": "register", "username": "legacy", "protocol_version": 1})).unwrap();
+    let uuid = state.username_map["legacy"];
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "seq": 3})).unwrap();
+    assert!(out.iter().any(|(_, m)| matches!(m, ServerMessage::World { seq: None, .. })));
+    assert_eq!(state.last_seq[&uuid], 3);
+}
+
+#[test]
+fn test_correction_written_to_audit_log() {
+    use backend_demo::audit::{AuditLog, AuditRecord};
+    let path = std::env::temp_dir().join(format!("audit_{}.jsonl", Uuid::new_v4()));
+    let mut state = new_state().with_audit_log(AuditLog::open(&path).unwrap());
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "audited");
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "ts": 1000})).unwrap();
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 50.0, "y": 0.0, "z": 0.0, "ts": 1250})).unwrap();
+    // 丢弃状态时等待写线程写完
+    drop(state);
+
+    let content = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).ok();
+    let records: Vec<AuditRecord> = content.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    assert_eq!(records.len(), 1);
+    let record = &records[0];
+    assert_eq!(record.uuid, uuid);
+    assert_eq!(record.username, "audited");
+    assert_eq!(record.reason, "invalid_movement");
+    assert_eq!(record.claimed, (Some(50.0), Some(0.0), Some(0.0)));
+    assert_eq!(record.corrected, (Some(0.0), Some(0.0), Some(0.0)));
+    assert_eq!(record.dt_ms, Some(250));
+    assert!(record.ts > 0);
+}
+
+#[test]
+fn test_audit_log_honors_rfc3339_timestamps() {
+    use backend_demo::audit::{AuditLog, AuditRecord};
+    let path = std::env::temp_dir().join(format!("audit_{}.jsonl", Uuid::new_v4()));
+    let log = AuditLog::open_with_rfc3339(&path, true).unwrap();
+    log.record(AuditRecord {
+        ts: 1_700_000_000_123,
+        uuid: Uuid::new_v4(),
+        username: "audited".to_string(),
+        reason: "invalid_movement".to_string(),
+        claimed: (Some(50.0), None, None),
+        corrected: (Some(0.0), None, None),
+        dt_ms: None,
+    });
+    drop(log);
+
+    let content = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).ok();
+    let line: Value = serde_json::from_str(content.trim()).unwrap();
+    assert_eq!(line["ts"], "2023-11-14T22:13:20.123Z");
+    // 读回时两种格式都接受
+    let record: AuditRecord = serde_json::from_str(content.trim()).unwrap();
+    assert_eq!(record.ts, 1_700_000_000_123);
+}
+
 #[test]
 fn test_validate_movement_tolerance_boundary() {
     // 测试容差边界：恰好在容差内
@@ -326,6 +873,67 @@ fn test_validate_movement_negative_coordinates() {
     assert!(result.is_valid);
 }
 
+// ============================================================================
+// 纠正确认（nonce）测试
+// ============================================================================
+
+#[test]
+fn test_correction_nonce_ignored_then_acknowledged() {
+    let uuid = Uuid::new_v4();
+    let mut pending: HashMap<Uuid, u64> = HashMap::new();
+
+    // 没有待确认的纠正时，任何 update 都被信任
+    assert!(acknowledges_correction(&mut pending, &uuid, None));
+
+    // 瞬移触发纠正，服务器发放 nonce
+    let result = validate_movement(0.0, 0.0, 0.0, 0, 100.0, 0.0, 0.0, 1000, 10.0, 0.0, 0.0);
+    assert!(!result.is_valid);
+    let nonce = issue_correction_nonce(&mut pending, uuid);
+
+    // 客户端无视纠正（不回显 nonce）→ 再次被纠正，nonce 保持不变
+    assert!(!acknowledges_correction(&mut pending, &uuid, None));
+    assert!(!acknowledges_correction(&mut pending, &uuid, Some(nonce.wrapping_add(1))));
+    assert_eq!(pending.get(&uuid), Some(&nonce));
+
+    // 客户端确认后恢复正常校验
+    assert!(acknowledges_correction(&mut pending, &uuid, Some(nonce)));
+    assert!(!pending.contains_key(&uuid));
+    let result = validate_movement(10.0, 0.0, 0.0, 1000, 20.0, 0.0, 0.0, 2000, 10.0, 0.0, 0.0);
+    assert!(result.is_valid);
+    assert!(acknowledges_correction(&mut pending, &uuid, None));
+}
+
+#[test]
+fn test_correction_nonce_is_per_player() {
+    let alice = Uuid::new_v4();
+    let bob = Uuid::new_v4();
+    let mut pending: HashMap<Uuid, u64> = HashMap::new();
+    let nonce = issue_correction_nonce(&mut pending, alice);
+
+    // bob 不受 alice 的待确认纠正影响，也不能替 alice 确认
+    assert!(acknowledges_correction(&mut pending, &bob, Some(nonce)));
+    assert!(!acknowledges_correction(&mut pending, &alice, None));
+}
+
+#[test]
+fn test_correction_nonce_survives_javascript_numbers() {
+    let uuid = Uuid::new_v4();
+    let mut pending: HashMap<Uuid, u64> = HashMap::new();
+    // 超过 2^53 的值经过 f64 后会变
+    let unsafe_nonce = (1u64 << 53) + 1;
+    assert_ne!(unsafe_nonce as f64 as u64, unsafe_nonce);
+
+    for _ in 0..1000 {
+        let nonce = issue_correction_nonce(&mut pending, uuid);
+        assert!(nonce <= MAX_CORRECTION_NONCE);
+        // 浏览器客户端把 nonce 当作 Number 解析再原样回显
+        let echoed: f64 = serde_json::from_str(&serde_json::to_string(&nonce).unwrap()).unwrap();
+        assert_eq!(echoed as u64, nonce);
+    }
+    let nonce = pending[&uuid];
+    assert!(acknowledges_correction(&mut pending, &uuid, Some(nonce as f64 as u64)));
+}
+
 // ============================================================================
 // PlayerState 和 WorldState 测试
 // ============================================================================
@@ -347,6 +955,7 @@ fn test_player_state_serialization() {
         vy: Some(0.0),
         vz: Some(-5.2),
         action: Some("firing".to_string()),
+        extra: None,
     };
 
     let json = serde_json::to_string(&player).unwrap();
@@ -376,6 +985,7 @@ fn test_player_state_partial_fields() {
         vy: None,
         vz: None,
         action: None,
+        extra: None,
     };
 
     let json = serde_json::to_string(&player).unwrap();
@@ -390,6 +1000,7 @@ fn test_player_state_partial_fields() {
 fn test_world_state_multiple_players() {
     let mut world = WorldState {
         players: HashMap::new(),
+        entities: HashMap::new(),
     };
 
     let uuid1 = Uuid::new_v4();
@@ -411,6 +1022,7 @@ fn test_world_state_multiple_players() {
             vy: None,
             vz: None,
             action: None,
+            extra: None,
         },
     );
 
@@ -430,6 +1042,7 @@ fn test_world_state_multiple_players() {
             vy: None,
             vz: None,
             action: None,
+            extra: None,
         },
     );
 
@@ -438,6 +1051,130 @@ fn test_world_state_multiple_players() {
     assert!(world.players.contains_key(&uuid2));
 }
 
+#[test]
+fn test_player_state_new_is_empty() {
+    let uuid = Uuid::new_v4();
+    let player = PlayerState::new(uuid, "builder");
+    assert_eq!(player.uuid, uuid);
+    assert_eq!(player.username, "builder");
+    assert!(player.x.is_none() && player.y.is_none() && player.z.is_none());
+    assert!(player.rx.is_none() && player.vx.is_none());
+    assert!(player.ts.is_none());
+    assert!(player.action.is_none());
+}
+
+#[test]
+fn test_player_state_builder_setters() {
+    let player = PlayerState::new(Uuid::new_v4(), "builder")
+        .with_position(1.0, 2.0, 3.0)
+        .with_rotation(0.0, 90.0, 0.0)
+        .with_velocity(4.0, 5.0, 6.0)
+        .with_ts(1000)
+        .with_action("firing");
+    assert_eq!((player.x, player.y, player.z), (Some(1.0), Some(2.0), Some(3.0)));
+    assert_eq!(player.ry, Some(90.0));
+    assert_eq!((player.vx, player.vy, player.vz), (Some(4.0), Some(5.0), Some(6.0)));
+    assert_eq!(player.ts, Some(1000));
+    assert_eq!(player.action.as_deref(), Some("firing"));
+}
+
+#[test]
+fn test_player_update_minimal_json() {
+    let uuid = Uuid::new_v4();
+    let raw = json!({"type": "update", "uuid": uuid, "x": 1.0}).to_string();
+    let update: PlayerUpdate = serde_json::from_str(&raw).expect("minimal update should parse");
+    assert_eq!(update.uuid, uuid);
+    assert_eq!(update.x, Some(1.0));
+    assert!(update.y.is_none() && update.z.is_none());
+    assert!(update.ts.is_none());
+    assert!(update.vx.is_none());
+    assert!(update.action.is_none());
+}
+
+#[test]
+fn test_player_update_full_json() {
+    let uuid = Uuid::new_v4();
+    let raw = json!({
+        "type": "update", "uuid": uuid,
+        "x": 1.0, "y": 2.0, "z": 3.0, "ts": 1704556800000u64,
+        "rx": 0.0, "ry": 45.0, "rz": 0.0,
+        "vx": 1.5, "vy": 0.0, "vz": -1.5,
+        "action": "firing"
+    })
+    .to_string();
+    let update: PlayerUpdate = serde_json::from_str(&raw).unwrap();
+    assert_eq!(update.ts, Some(1704556800000));
+    assert_eq!(update.ry, Some(45.0));
+    assert_eq!(update.vz, Some(-1.5));
+    assert_eq!(update.action.as_deref(), Some("firing"));
+}
+
+#[test]
+fn test_player_update_requires_uuid() {
+    let raw = json!({"type": "update", "x": 1.0}).to_string();
+    assert!(serde_json::from_str::<PlayerUpdate>(&raw).is_err());
+}
+
+#[test]
+fn test_player_state_apply_full_update() {
+    let uuid = Uuid::new_v4();
+    let mut player = PlayerState::new(uuid, "merger");
+    let update = PlayerUpdate {
+        uuid,
+        x: Some(1.0),
+        y: Some(2.0),
+        z: Some(3.0),
+        ts: Some(1000),
+        rx: Some(0.0),
+        ry: Some(90.0),
+        rz: Some(0.0),
+        vx: Some(1.0),
+        vy: Some(0.0),
+        vz: Some(-1.0),
+        action: Some("jump".to_string()),
+        extra: None,
+    };
+    assert!(player.apply_update(&update));
+    let expected = PlayerState::new(uuid, "merger")
+        .with_position(1.0, 2.0, 3.0)
+        .with_ts(1000)
+        .with_rotation(0.0, 90.0, 0.0)
+        .with_velocity(1.0, 0.0, -1.0)
+        .with_action("jump");
+    assert_eq!(player, expected);
+}
+
+#[test]
+fn test_player_state_apply_single_field_update() {
+    let uuid = Uuid::new_v4();
+    let mut player = PlayerState::new(uuid, "merger").with_position(1.0, 2.0, 3.0).with_ts(1000);
+    let update = PlayerUpdate {
+        uuid,
+        x: Some(5.0),
+        ..PlayerUpdate::default()
+    };
+    assert!(player.apply_update(&update));
+    assert_eq!((player.x, player.y, player.z), (Some(5.0), Some(2.0), Some(3.0)));
+    assert_eq!(player.ts, Some(1000));
+}
+
+#[test]
+fn test_player_state_apply_noop_update() {
+    let uuid = Uuid::new_v4();
+    let mut player = PlayerState::new(uuid, "merger").with_position(1.0, 2.0, 3.0);
+    let before = player.clone();
+    // 没有字段 / 字段与当前值相同都不算变化
+    assert!(!player.apply_update(&PlayerUpdate { uuid, ..PlayerUpdate::default() }));
+    let same = PlayerUpdate {
+        uuid,
+        x: Some(1.0),
+        y: Some(2.0),
+        ..PlayerUpdate::default()
+    };
+    assert!(!player.apply_update(&same));
+    assert_eq!(player, before);
+}
+
 // ============================================================================
 // 边界情况和极限值测试
 // ============================================================================
@@ -502,6 +1239,7 @@ fn test_movement_validation_boundary_just_under_limit() {
 fn test_world_state_serialization() {
     let mut world = WorldState {
         players: HashMap::new(),
+        entities: HashMap::new(),
     };
     
     let uuid1 = Uuid::new_v4();
@@ -528,6 +1266,7 @@ fn test_world_state_file_persistence() {
     // 创建世界状态
     let mut world = WorldState {
         players: HashMap::new(),
+        entities: HashMap::new(),
     };
     let uuid = Uuid::new_v4();
     world.players.insert(uuid, empty_player("persistent_player"));
@@ -548,6 +1287,129 @@ fn test_world_state_file_persistence() {
     let _ = fs::remove_file(test_file);
 }
 
+#[test]
+fn test_in_memory_identity_store() {
+    let mut store = InMemoryStore::new();
+    let uuid = Uuid::new_v4();
+    assert!(!store.contains(&uuid));
+    store.put(PlayerRecord { uuid, username: "alice".to_string() });
+    store.put(PlayerRecord { uuid, username: "alice2".to_string() });
+    assert!(store.contains(&uuid));
+    assert_eq!(store.get(&uuid).unwrap().username, "alice2");
+    assert!(store.get(&Uuid::new_v4()).is_none());
+}
+
+#[test]
+fn test_file_identity_store_flush_and_reopen() {
+    let test_file = std::env::temp_dir().join(format!("identity_store_{}.json", Uuid::new_v4()));
+    let uuid = Uuid::new_v4();
+    {
+        let mut store = FileStore::open(&test_file).unwrap();
+        store.put(PlayerRecord { uuid, username: "saved".to_string() });
+        store.flush().unwrap();
+    }
+    let store = FileStore::open(&test_file).unwrap();
+    assert_eq!(store.get(&uuid).unwrap().username, "saved");
+    let _ = fs::remove_file(&test_file);
+}
+
+#[test]
+fn test_v1_uuid_storage_migrates_to_current_schema() {
+    let test_file = std::env::temp_dir().join(format!("uuid_storage_{}.json", Uuid::new_v4()));
+    let path = test_file.to_string_lossy().to_string();
+    let uuid = Uuid::new_v4();
+    // v1：没有 version 字段，只有 UUID -> 用户名
+    fs::write(&path, json!({"uuids": {uuid.to_string(): "veteran"}}).to_string()).unwrap();
+
+    let storage = UuidStorage::load_from_file(&path).unwrap();
+    assert_eq!(storage.get_username(&uuid).as_deref(), Some("veteran"));
+    assert!(storage.client_ids.is_empty());
+    assert!(storage.positions.is_empty());
+
+    // 写回时带上当前版本，再次读取不变
+    storage.save_to_file(&path).unwrap();
+    let saved: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(saved["version"], json!(SNAPSHOT_VERSION));
+    assert_eq!(UuidStorage::load_from_file(&path).unwrap().get_username(&uuid).as_deref(), Some("veteran"));
+
+    // 更新版本写入的文件明确报错，而不是当作空存储
+    fs::write(&path, json!({"version": SNAPSHOT_VERSION + 1, "uuids": {}}).to_string()).unwrap();
+    let err = UuidStorage::load_from_file(&path).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    assert_eq!(WorldState::load_from_file(&path).unwrap_err().kind(), std::io::ErrorKind::Unsupported);
+    let _ = fs::remove_file(&test_file);
+}
+
+#[test]
+fn test_client_id_maps_to_same_uuid_across_restart() {
+    let test_file = std::env::temp_dir().join(format!("identity_store_{}.json", Uuid::new_v4()));
+    let register_account = json!({"type": "register", "username": "account", "client_id": "acct-42"});
+    let uuid = {
+        let mut state = ServerState::new(WorldState { players: HashMap::new(), entities: HashMap::new() }, Box::new(FileStore::open(&test_file).unwrap()));
+        handle(&mut state, client_addr(40001), register_account.clone()).unwrap();
+        let uuid = state.client_id_map["acct-42"];
+        // 同一 client_id 再次注册得到同一个 UUID
+        let out = handle(&mut state, client_addr(40001), register_account.clone()).unwrap();
+        assert!(matches!(&out[0].1, ServerMessage::Registered { uuid: u, resumed: true, .. } if *u == uuid));
+        state.storage.flush().unwrap();
+        uuid
+    };
+
+    // 模拟重启：世界为空，只从身份存储恢复
+    let mut state = ServerState::new(WorldState { players: HashMap::new(), entities: HashMap::new() }, Box::new(FileStore::open(&test_file).unwrap()));
+    let out = handle(&mut state, client_addr(40002), register_account).unwrap();
+    assert!(matches!(&out[0].1, ServerMessage::Registered { uuid: u, username, resumed: true, .. } if *u == uuid && username == "account"));
+    // client_id 与出示的 uuid 不一致时拒绝
+    let other = Uuid::new_v4();
+    let result = handle(&mut state, client_addr(40002), json!({"type": "register", "uuid": other, "client_id": "acct-42"}));
+    assert_eq!(result, Err(HandlerError::Unauthorized(other)));
+    let _ = fs::remove_file(&test_file);
+}
+
+#[test]
+fn test_checkpoint_writes_only_when_dirty() {
+    let path = std::env::temp_dir().join(format!("world_state_{}.json", Uuid::new_v4()));
+    let path = path.to_string_lossy().to_string();
+    let mut state = new_state();
+    assert!(!state.world_dirty);
+    // 世界未修改：跳过写入
+    assert!(!state.checkpoint(&path).unwrap());
+    assert!(!std::path::Path::new(&path).exists());
+
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "saver");
+    assert!(state.world_dirty);
+    assert!(state.checkpoint(&path).unwrap());
+    assert!(!state.world_dirty);
+    assert!(fs::read_to_string(&path).unwrap().contains("saver"));
+
+    // 只读查询不会标记修改
+    handle(&mut state, src, json!({"type": "whoami", "uuid": uuid})).unwrap();
+    assert!(!state.checkpoint(&path).unwrap());
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 1.0, "y": 0.0, "z": 0.0, "ts": 1000})).unwrap();
+    assert!(state.world_dirty);
+    assert!(state.checkpoint(&path).unwrap());
+    let _ = fs::remove_file(&path);
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn test_sqlite_identity_store() {
+    let mut store = backend_demo::store::SqliteStore::open(":memory:").unwrap();
+    let uuid = Uuid::new_v4();
+    store.put(PlayerRecord { uuid, username: "row".to_string() });
+    store.put(PlayerRecord { uuid, username: "row2".to_string() });
+    assert_eq!(store.get(&uuid).unwrap().username, "row2");
+    assert!(!store.contains(&Uuid::new_v4()));
+    store.put_client_id("acct".to_string(), uuid);
+    assert_eq!(store.client_ids().get("acct"), Some(&uuid));
+    store.put_position(uuid, (1.0, 2.0, 3.0));
+    assert_eq!(store.last_position(&uuid), Some((1.0, 2.0, 3.0)));
+    store.clear();
+    assert!(store.client_ids().is_empty());
+    assert_eq!(store.last_position(&uuid), None);
+}
+
 // ============================================================================
 // 在线状态判断测试（基于 last_seen）
 // ============================================================================
@@ -616,6 +1478,7 @@ fn test_filter_online_players() {
 fn test_player_resume_from_world() {
     let mut world = WorldState {
         players: HashMap::new(),
+        entities: HashMap::new(),
     };
     
     let uuid = Uuid::new_v4();
@@ -669,35 +1532,3537 @@ fn test_online_check_performance() {
 }
 
 // ============================================================================
-// UUID 恢复逻辑集成测试
+// 数据包帧（CRC32）测试
 // ============================================================================
 
-/// 辅助函数：创建测试用的 UDP socket 并发送消息
-fn send_and_receive(message: Value, timeout_secs: u64) -> Result<Value, String> {
-    let socket = UdpSocket::bind("127.0.0.1:0").map_err(|e| format!("Bind failed: {}", e))?;
-    socket
-        .set_read_timeout(Some(Duration::from_secs(timeout_secs)))
-        .map_err(|e| format!("Set timeout failed: {}", e))?;
+#[test]
+fn test_frame_crc32_known_value() {
+    // 标准测试向量 "123456789" -> 0xCBF43926
+    assert_eq!(frame::crc32(b"123456789"), 0xCBF4_3926);
+}
 
-    let server_addr = "127.0.0.1:8888";
-    let msg_str = message.to_string();
-    socket
-        .send_to(msg_str.as_bytes(), server_addr)
-        .map_err(|e| format!("Send failed: {}", e))?;
+#[test]
+fn test_frame_roundtrip() {
+    let payload = br#"{"type":"update","x":1.0}"#;
+    let encoded = frame::encode(payload);
+    assert_eq!(encoded[0], frame::MAGIC);
+    assert_eq!(encoded.len(), payload.len() + frame::HEADER_LEN);
+    assert_eq!(frame::decode(&encoded).unwrap(), &payload[..]);
+}
 
-    let mut buf = [0u8; 4096];
-    match socket.recv_from(&mut buf) {
-        Ok((n, _)) => {
-            let response = String::from_utf8_lossy(&buf[..n]);
-            serde_json::from_str(&response).map_err(|e| format!("Parse failed: {}", e))
-        }
+#[test]
+fn test_frame_bit_flip_rejected() {
+    let payload = br#"{"type":"update","x":1.0}"#;
+    let mut encoded = frame::encode(payload);
+    // 翻转 payload 中的一个比特
+    let last = encoded.len() - 3;
+    encoded[last] ^= 0x01;
+    assert!(matches!(
+        frame::decode(&encoded),
+        Err(frame::FrameError::ChecksumMismatch { .. })
+    ));
+}
+
+#[test]
+fn test_server_counts_dropped_corrupted_frames() {
+    let server = TestServer::start();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut encoded = frame::encode(br#"{"type":"ping"}"#);
+    let last = encoded.len() - 3;
+    encoded[last] ^= 0x01;
+    socket.send_to(&encoded, server.addr()).unwrap();
+    socket.send_to(&encoded, server.addr()).unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while server.metrics().frames_dropped.get() < 2 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let metrics = server.metrics();
+    assert_eq!(metrics.frames_dropped.get(), 2);
+    assert!(render_prometheus(&metrics, 0).contains("game_frames_dropped_total 2"));
+}
+
+#[test]
+fn test_frame_plain_json_passthrough() {
+    // 不带帧头的纯 JSON 应原样放行
+    let plain = br#"{"type":"register","username":"a"}"#;
+    assert_eq!(frame::decode(plain).unwrap(), &plain[..]);
+}
+
+#[test]
+fn test_frame_truncated_header() {
+    let bytes = [frame::MAGIC, 0x00, 0x01];
+    assert_eq!(frame::decode(&bytes), Err(frame::FrameError::Truncated));
+}
+
+// ============================================================================
+// 自适应离线扫描测试
+// ============================================================================
+
+#[test]
+fn test_next_sweep_delay_until_earliest_expiry() {
+    let clock = ManualClock::new(Instant::now());
+    let timeout = Duration::from_secs(60);
+    let max = Duration::from_secs(30);
+    let mut last_seen: HashMap<Uuid, Instant> = HashMap::new();
+    let notified: HashSet<Uuid> = HashSet::new();
+
+    last_seen.insert(Uuid::new_v4(), clock.now());
+    clock.advance(Duration::from_secs(45));
+    last_seen.insert(Uuid::new_v4(), clock.now());
+
+    // 第一个玩家 15 秒后超时
+    let delay = next_sweep_delay(&last_seen, &notified, clock.now(), timeout, max);
+    assert_eq!(delay, Some(Duration::from_secs(15)));
+}
+
+#[test]
+fn test_next_sweep_delay_bounded_by_max() {
+    let now = Instant::now();
+    let mut last_seen: HashMap<Uuid, Instant> = HashMap::new();
+    last_seen.insert(Uuid::new_v4(), now);
+    let delay = next_sweep_delay(
+        &last_seen,
+        &HashSet::new(),
+        now,
+        Duration::from_secs(60),
+        Duration::from_secs(5),
+    );
+    assert_eq!(delay, Some(Duration::from_secs(5)));
+}
+
+#[test]
+fn test_next_sweep_delay_idle_world() {
+    let now = Instant::now();
+    let delay = next_sweep_delay(
+        &HashMap::new(),
+        &HashSet::new(),
+        now,
+        Duration::from_secs(60),
+        Duration::from_secs(5),
+    );
+    assert_eq!(delay, None); // 空世界：阻塞等待唤醒
+}
+
+#[test]
+fn test_collect_expired_notifies_once() {
+    let clock = ManualClock::new(Instant::now());
+    let timeout = Duration::from_secs(60);
+    let uuid = Uuid::new_v4();
+    let mut last_seen: HashMap<Uuid, Instant> = HashMap::new();
+    let mut notified: HashSet<Uuid> = HashSet::new();
+    last_seen.insert(uuid, clock.now());
+
+    clock.advance(Duration::from_secs(59));
+    assert!(collect_expired(&last_seen, &mut notified, clock.now(), timeout).is_empty());
+
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(collect_expired(&last_seen, &mut notified, clock.now(), timeout), vec![uuid]);
+
+    // 已通知过的玩家不再重复通知，也不再参与休眠计算
+    clock.advance(Duration::from_secs(10));
+    assert!(collect_expired(&last_seen, &mut notified, clock.now(), timeout).is_empty());
+    assert_eq!(
+        next_sweep_delay(&last_seen, &notified, clock.now(), timeout, Duration::from_secs(5)),
+        None
+    );
+
+    // 重新活跃后再次超时会再次通知
+    last_seen.insert(uuid, clock.now());
+    assert!(collect_expired(&last_seen, &mut notified, clock.now(), timeout).is_empty());
+    clock.advance(timeout);
+    assert_eq!(collect_expired(&last_seen, &mut notified, clock.now(), timeout), vec![uuid]);
+}
+
+#[test]
+fn test_sweep_signal_wakes_waiter() {
+    let signal = std::sync::Arc::new(SweepSignal::new());
+    let waiter = {
+        let signal = signal.clone();
+        std::thread::spawn(move || signal.wait())
+    };
+    signal.notify();
+    waiter.join().unwrap();
+
+    // 提前 notify 的信号不会丢失
+    signal.notify();
+    signal.wait();
+}
+
+// ============================================================================
+// handle_message 测试（不需要启动服务器）
+// ============================================================================
+
+fn client_addr(port: u16) -> ClientConn {
+    ClientConn::Udp(SocketAddr::from(([127, 0, 0, 1], port)))
+}
+
+fn new_state() -> ServerState {
+    ServerState::new(
+        WorldState {
+            players: HashMap::new(),
+            entities: HashMap::new(),
+        },
+        Box::new(InMemoryStore::new()),
+    )
+}
+
+fn handle(state: &mut ServerState, src: ClientConn, msg: Value) -> Result<Outgoing, HandlerError> {
+    handle_at(state, src, msg, Instant::now())
+}
+
+fn handle_at(
+    state: &mut ServerState,
+    src: ClientConn,
+    msg: Value,
+    now: Instant,
+) -> Result<Outgoing, HandlerError> {
+    handle_message(state, src, msg.to_string().as_bytes(), now)
+}
+
+/// 注册一个新玩家并返回其 UUID
+fn register(state: &mut ServerState, src: ClientConn, username: &str) -> Uuid {
+    let out = handle(state, src, json!({"type": "register", "username": username})).unwrap();
+    match &out[0] {
+        (addr, ServerMessage::Registered { uuid, .. }) if *addr == src => *uuid,
+        other => panic!("unexpected reply: {:?}", other),
+    }
+}
+
+/// 取出发给 `dst` 的纠正消息（如有）
+fn correction_for(out: &Outgoing, dst: ClientConn) -> Option<(String, u64)> {
+    out.iter().find_map(|(addr, msg)| match msg {
+        ServerMessage::Correction { reason, nonce, .. } if *addr == dst => {
+            Some((reason.clone(), *nonce))
+        }
+        _ => None,
+    })
+}
+
+#[test]
+fn test_handle_register_new_player() {
+    let mut state = new_state();
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "pilot");
+    assert_eq!(state.world.players.get(&uuid).unwrap().username, "pilot");
+    assert_eq!(state.conn_of(&uuid), Some(src));
+    assert_eq!(state.username_map.get("pilot"), Some(&uuid));
+}
+
+#[test]
+fn test_handle_register_retries_uuid_collision() {
+    let mut probe = SeededGenerator::new(7);
+    let taken = probe.next_uuid();
+    let fresh = probe.next_uuid();
+
+    let mut world = WorldState { players: HashMap::new(), entities: HashMap::new() };
+    world.players.insert(taken, PlayerState::new(taken, "veteran"));
+    let mut state = ServerState::new(world, Box::new(InMemoryStore::new()))
+        .with_uuid_generator(Box::new(SeededGenerator::new(7)));
+
+    // 第一次生成的 UUID 与已有玩家冲突，应重新生成
+    let uuid = register(&mut state, client_addr(40001), "rookie");
+    assert_eq!(uuid, fresh);
+    assert_eq!(state.world.players[&fresh].username, "rookie");
+    assert_eq!(state.world.players[&taken].username, "veteran");
+}
+
+#[test]
+fn test_handle_register_reply_wire_format() {
+    // 新建注册的回复不应包含 state/resumed 字段（与旧协议一致）
+    let msg = ServerMessage::Registered {
+        uuid: Uuid::nil(),
+        username: "pilot".to_string(),
+        state: None,
+        resumed: false,
+        resume_token: None,
+        server_ts: 0,
+        protocol_version: 2,
+    };
+    let v: Value = serde_json::to_value(&msg).unwrap();
+    assert_eq!(v["action"], "registered");
+    assert!(v.get("state").is_none());
+    assert!(v.get("resumed").is_none());
+}
+
+#[test]
+fn test_handle_register_name_conflict() {
+    let mut state = new_state();
+    register(&mut state, client_addr(40001), "pilot");
+    let out = handle(&mut state, client_addr(40002), json!({"type": "register", "username": "pilot"})).unwrap();
+    assert_eq!(
+        out,
+        vec![(
+            client_addr(40002),
+            ServerMessage::NameConflict {
+                suggested: "pilot_1".to_string(),
+                message: "Username is taken, try \"pilot_1\".".to_string(),
+            }
+        )]
+    );
+}
+
+#[test]
+fn test_username_policy_on_conflicting_register() {
+    let conflicting = |policy| {
+        let mut state = new_state().with_config(ServerConfig { username_policy: policy, ..ServerConfig::default() });
+        let first = register(&mut state, client_addr(40001), "pilot");
+        let out = handle(&mut state, client_addr(40002), json!({"type": "register", "username": "pilot"})).unwrap();
+        // 先注册的玩家总是保留名字
+        assert_eq!(state.username_map["pilot"], first);
+        (state, out.into_iter().next().unwrap().1)
+    };
+
+    let (state, reply) = conflicting(UsernamePolicy::Suggest);
+    assert!(matches!(reply, ServerMessage::NameConflict { suggested, .. } if suggested == "pilot_1"));
+    assert_eq!(state.world.players.len(), 1);
+
+    let (state, reply) = conflicting(UsernamePolicy::Reject);
+    assert_eq!(
+        reply,
+        ServerMessage::NameTaken {
+            username: "pilot".to_string(),
+            message: "Username \"pilot\" is taken.".to_string(),
+        }
+    );
+    assert_eq!(state.world.players.len(), 1);
+
+    let (state, reply) = conflicting(UsernamePolicy::AutoRename);
+    let ServerMessage::Registered { uuid, username, .. } = reply else {
+        panic!("unexpected reply: {:?}", reply);
+    };
+    assert_eq!(username, "pilot_1");
+    assert_eq!(state.username_map["pilot_1"], uuid);
+    assert_eq!(state.world.players[&uuid].username, "pilot_1");
+}
+
+#[test]
+fn test_handle_register_duplicate_from_same_addr_is_idempotent() {
+    let mut state = new_state();
+    let src = client_addr(40001);
+    let t0 = Instant::now();
+    let msg = json!({"type": "register", "username": "retransmit"});
+    let first = handle_at(&mut state, src, msg.clone(), t0).unwrap();
+    let second = handle_at(&mut state, src, msg.clone(), t0 + Duration::from_millis(200)).unwrap();
+    let uuid_of = |out: &Outgoing| match &out[0].1 {
+        ServerMessage::Registered { uuid, .. } => *uuid,
+        other => panic!("unexpected reply: {:?}", other),
+    };
+    assert_eq!(uuid_of(&first), uuid_of(&second));
+    assert_eq!(state.world.players.len(), 1);
+
+    // 窗口之后同名注册按普通的名字冲突处理
+    let late = handle_at(&mut state, src, msg, t0 + Duration::from_secs(3)).unwrap();
+    assert!(matches!(late[0].1, ServerMessage::NameConflict { .. }));
+    assert_eq!(state.world.players.len(), 1);
+}
+
+#[test]
+fn test_eviction_is_opt_in() {
+    assert_eq!(ServerConfig::default().evict_after, None);
+    let mut state = new_state();
+    let t0 = Instant::now();
+    handle_at(&mut state, client_addr(40001), json!({"type": "register", "username": "idler"}), t0).unwrap();
+    // 默认配置下离线再久也保留在内存中
+    assert!(state.evict_offline(t0 + Duration::from_secs(24 * 3600)).is_empty());
+    assert!(state.username_map.contains_key("idler"));
+}
+
+#[test]
+fn test_evict_offline_keeps_player_resumable_from_storage() {
+    let config = ServerConfig {
+        online_timeout: Duration::from_secs(60),
+        evict_after: Some(Duration::from_secs(600)),
+        action_cooldowns: HashMap::from([("jump".to_string(), Duration::from_secs(1))]),
+        settle_updates: 5,
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let t0 = Instant::now();
+    let src = client_addr(40001);
+    let out = handle_at(&mut state, src, json!({"type": "register", "username": "wanderer"}), t0).unwrap();
+    let uuid = match &out[0].1 {
+        ServerMessage::Registered { uuid, .. } => *uuid,
+        other => panic!("unexpected reply: {:?}", other),
+    };
+    handle_at(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "action": "jump"}), t0).unwrap();
+    state.quarantined.insert(uuid);
+    assert!(state.action_cooldowns.contains(&uuid));
+    assert!(state.settling.contains(&uuid));
+
+    // 离线但未到移除阈值
+    assert!(state.evict_offline(t0 + Duration::from_secs(300)).is_empty());
+    assert!(state.world.players.contains_key(&uuid));
+
+    assert_eq!(state.evict_offline(t0 + Duration::from_secs(660)), vec![uuid]);
+    assert!(state.world.players.is_empty());
+    assert!(!state.username_map.contains_key("wanderer"));
+    assert!(state.storage.contains(&uuid));
+    // 按玩家记录的反作弊状态一并清除
+    assert!(!state.action_cooldowns.contains(&uuid));
+    assert!(!state.settling.contains(&uuid));
+    assert!(!state.quarantined.contains(&uuid));
+
+    let later = t0 + Duration::from_secs(700);
+    let out = handle_at(&mut state, client_addr(40002), json!({"type": "register", "uuid": uuid}), later).unwrap();
+    assert!(matches!(
+        &out[0].1,
+        ServerMessage::Registered { uuid: u, username, resumed: true, .. } if *u == uuid && username == "wanderer"
+    ));
+    assert_eq!(state.username_map.get("wanderer"), Some(&uuid));
+    assert!(state.is_online(&uuid, later));
+}
+
+#[test]
+fn test_resume_from_storage_restores_position_or_default_spawn() {
+    let config = ServerConfig {
+        online_timeout: Duration::from_secs(60),
+        evict_after: Some(Duration::from_secs(600)),
+        default_spawn: Some((10.0, 0.0, -10.0)),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let t0 = Instant::now();
+    let (mover, idler) = (client_addr(40001), client_addr(40002));
+    handle_at(&mut state, mover, json!({"type": "register", "username": "mover"}), t0).unwrap();
+    handle_at(&mut state, idler, json!({"type": "register", "username": "idler"}), t0).unwrap();
+    let (mover_uuid, idler_uuid) = (state.username_map["mover"], state.username_map["idler"]);
+    handle_at(&mut state, mover, json!({"type": "update", "uuid": mover_uuid, "x": 3.0, "y": 1.0, "z": 4.0}), t0).unwrap();
+
+    let later = t0 + Duration::from_secs(700);
+    assert_eq!(state.evict_offline(later).len(), 2);
+    assert_eq!(state.storage.last_position(&mover_uuid), Some((3.0, 1.0, 4.0)));
+
+    let position_after_resume = |state: &mut ServerState, conn, uuid: Uuid| {
+        let out = handle_at(state, conn, json!({"type": "register", "uuid": uuid}), later).unwrap();
+        match &out[0].1 {
+            ServerMessage::Registered { state: Some(p), resumed: true, .. } => (p.x, p.y, p.z),
+            other => panic!("unexpected reply: {:?}", other),
+        }
+    };
+    assert_eq!(position_after_resume(&mut state, mover, mover_uuid), (Some(3.0), Some(1.0), Some(4.0)));
+    // 没有保存过位置：放到默认出生点
+    assert_eq!(position_after_resume(&mut state, idler, idler_uuid), (Some(10.0), Some(0.0), Some(-10.0)));
+}
+
+#[test]
+fn test_registrations_per_ip_limited() {
+    let config = ServerConfig {
+        max_registrations_per_ip: Some(3),
+        online_timeout: Duration::from_secs(60),
+        evict_after: Some(Duration::from_secs(600)),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let t0 = Instant::now();
+    for i in 0..3 {
+        let msg = json!({"type": "register", "username": format!("bot_{}", i)});
+        let out = handle_at(&mut state, client_addr(40001 + i), msg, t0).unwrap();
+        assert!(matches!(out[0].1, ServerMessage::Registered { .. }));
+    }
+    let out = handle_at(&mut state, client_addr(40004), json!({"type": "register", "username": "bot_3"}), t0).unwrap();
+    assert_eq!(out, vec![(client_addr(40004), ServerMessage::TooManyFromAddress { limit: 3 })]);
+    assert!(!state.username_map.contains_key("bot_3"));
+
+    // 其他 IP 不受影响
+    let other = ClientConn::Udp(SocketAddr::from(([10, 0, 0, 2], 40001)));
+    let out = handle_at(&mut state, other, json!({"type": "register", "username": "bot_3"}), t0).unwrap();
+    assert!(matches!(out[0].1, ServerMessage::Registered { .. }));
+
+    // 玩家被移除后名额释放
+    let later = t0 + Duration::from_secs(700);
+    state.evict_offline(later);
+    assert!(state.per_ip_count.is_empty());
+    let out = handle_at(&mut state, client_addr(40004), json!({"type": "register", "username": "bot_4"}), later).unwrap();
+    assert!(matches!(out[0].1, ServerMessage::Registered { .. }));
+}
+
+#[test]
+fn test_resume_counts_against_registrations_per_ip() {
+    let config = ServerConfig {
+        max_registrations_per_ip: Some(2),
+        admin_secret: Some("s3cret".to_string()),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let admin = ClientConn::Udp(SocketAddr::from(([10, 0, 0, 9], 40099)));
+    let first: Vec<Uuid> = (0..2).map(|i| register(&mut state, client_addr(40001 + i), &format!("bot_{}", i))).collect();
+    for uuid in &first {
+        handle(&mut state, admin, json!({"type": "kick", "secret": "s3cret", "uuid": uuid})).unwrap();
+    }
+    assert!(state.per_ip_count.is_empty());
+    let second: Vec<Uuid> = (2..4).map(|i| register(&mut state, client_addr(40001 + i), &format!("bot_{}", i))).collect();
+
+    // 被踢下线的玩家不能绕过上限从同一 IP 恢复
+    let out = handle(&mut state, client_addr(40010), json!({"type": "register", "uuid": first[0]})).unwrap();
+    assert_eq!(out, vec![(client_addr(40010), ServerMessage::TooManyFromAddress { limit: 2 })]);
+    assert_eq!(state.conn_of(&first[0]), None);
+
+    // 已绑定在该 IP 上的玩家换端口恢复不受影响
+    let out = handle(&mut state, client_addr(40011), json!({"type": "register", "uuid": second[0]})).unwrap();
+    assert!(matches!(out[0].1, ServerMessage::Registered { resumed: true, .. }));
+    assert_eq!(state.per_ip_count[&client_addr(40011).addr().ip()], 2);
+}
+
+#[test]
+fn test_resume_over_ip_limit_does_not_restore_evicted_player() {
+    let config = ServerConfig {
+        max_registrations_per_ip: Some(2),
+        online_timeout: Duration::from_secs(60),
+        evict_after: Some(Duration::from_secs(600)),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let t0 = Instant::now();
+    handle_at(&mut state, client_addr(40001), json!({"type": "register", "username": "bot_0"}), t0).unwrap();
+    let uuid = state.username_map["bot_0"];
+    let later = t0 + Duration::from_secs(700);
+    assert_eq!(state.evict_offline(later), vec![uuid]);
+    for i in 1..3 {
+        let msg = json!({"type": "register", "username": format!("bot_{}", i)});
+        handle_at(&mut state, client_addr(40001 + i), msg, later).unwrap();
+    }
+
+    let out = handle_at(&mut state, client_addr(40010), json!({"type": "register", "uuid": uuid}), later).unwrap();
+    assert_eq!(out, vec![(client_addr(40010), ServerMessage::TooManyFromAddress { limit: 2 })]);
+    // 超出上限的恢复不把玩家放回世界
+    assert_eq!(state.world.players.len(), 2);
+    assert!(!state.world.players.contains_key(&uuid));
+    assert!(!state.username_map.contains_key("bot_0"));
+    assert_eq!(state.per_ip_count[&client_addr(40010).addr().ip()], 2);
+}
+
+/// 只接受一个固定 token 的验证器；通过时用户名改为小写（模拟账号系统规范化）
+struct StubAuth;
+
+impl Authenticator for StubAuth {
+    fn verify(&self, username: &str, token: &str) -> Result<AuthClaims, AuthError> {
+        if token != "valid-token" {
+            return Err(AuthError(format!("unknown token for {}", username)));
+        }
+        Ok(AuthClaims { username: username.to_lowercase() })
+    }
+}
+
+#[test]
+fn test_authenticator_admits_valid_token_and_rejects_others() {
+    let config = ServerConfig {
+        authenticator: Arc::new(StubAuth),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+
+    let out = handle(&mut state, src, json!({"type": "register", "username": "Mallory", "token": "forged"})).unwrap();
+    assert_eq!(out, vec![(src, ServerMessage::AuthFailed { reason: "unknown token for Mallory".to_string() })]);
+    let out = handle(&mut state, src, json!({"type": "register", "username": "Mallory"})).unwrap();
+    assert!(matches!(out[0].1, ServerMessage::AuthFailed { .. }));
+    assert!(state.world.players.is_empty());
+    assert!(state.clients.is_empty());
+
+    let out = handle(&mut state, src, json!({"type": "register", "username": "Alice", "token": "valid-token"})).unwrap();
+    let ServerMessage::Registered { uuid, ref username, .. } = out[0].1 else {
+        panic!("unexpected reply: {:?}", out[0].1);
+    };
+    assert_eq!(username, "alice");
+
+    // 恢复会话同样需要通过验证
+    let out = handle(&mut state, client_addr(40002), json!({"type": "register", "uuid": uuid, "token": "forged"})).unwrap();
+    assert!(matches!(out[0].1, ServerMessage::AuthFailed { .. }));
+    let out = handle(&mut state, src, json!({"type": "register", "uuid": uuid, "token": "valid-token"})).unwrap();
+    assert!(matches!(out[0].1, ServerMessage::Registered { resumed: true, .. }));
+}
+
+#[test]
+fn test_failed_auth_does_not_restore_evicted_player() {
+    let config = ServerConfig {
+        authenticator: Arc::new(StubAuth),
+        online_timeout: Duration::from_secs(60),
+        evict_after: Some(Duration::from_secs(600)),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let t0 = Instant::now();
+    let msg = json!({"type": "register", "username": "alice", "token": "valid-token"});
+    handle_at(&mut state, client_addr(40001), msg, t0).unwrap();
+    let uuid = state.username_map["alice"];
+    let later = t0 + Duration::from_secs(700);
+    assert_eq!(state.evict_offline(later), vec![uuid]);
+    state.world_dirty = false;
+
+    let out = handle_at(&mut state, client_addr(40002), json!({"type": "register", "uuid": uuid, "token": "forged"}), later).unwrap();
+    assert!(matches!(out[0].1, ServerMessage::AuthFailed { .. }));
+    // 验证失败的恢复不把玩家放回世界，也不占用用户名
+    assert!(state.world.players.is_empty());
+    assert!(state.username_map.is_empty());
+    assert!(!state.world_dirty);
+}
+
+#[test]
+fn test_shared_secret_auth() {
+    let auth = SharedSecretAuth::new("hunter2");
+    assert_eq!(auth.verify("bob", "hunter2"), Ok(AuthClaims { username: "bob".to_string() }));
+    assert!(auth.verify("bob", "hunter3").is_err());
+    assert!(auth.verify("bob", "hunter22").is_err());
+    assert_eq!(auth.verify("bob", ""), Err(AuthError("token required".to_string())));
+    assert_eq!(NoAuth.verify("anyone", ""), Ok(AuthClaims { username: "anyone".to_string() }));
+}
+
+#[test]
+fn test_room_cap_rejects_overflow_player() {
+    let config = ServerConfig {
+        rooms: RoomConfig {
+            max_players: Some(2),
+            ..RoomConfig::default()
+        },
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let t0 = Instant::now();
+    let join = |name: &str| json!({"type": "register", "username": name, "room": "arena"});
+    let first = handle_at(&mut state, client_addr(40001), join("p1"), t0).unwrap();
+    handle_at(&mut state, client_addr(40002), join("p2"), t0).unwrap();
+    let out = handle_at(&mut state, client_addr(40003), join("p3"), t0).unwrap();
+    assert_eq!(
+        out,
+        vec![(client_addr(40003), ServerMessage::RoomFull { room: "arena".to_string(), limit: 2 })]
+    );
+    assert!(!state.username_map.contains_key("p3"));
+    assert_eq!(state.room_occupancy("arena", None, t0), 2);
+
+    // 已在房间中的玩家恢复会话不占额外名额
+    let ServerMessage::Registered { uuid, .. } = first[0].1 else {
+        panic!("unexpected reply: {:?}", first[0].1);
+    };
+    let resume = json!({"type": "register", "uuid": uuid, "room": "arena"});
+    let out = handle_at(&mut state, client_addr(40001), resume, t0).unwrap();
+    assert!(matches!(out[0].1, ServerMessage::Registered { resumed: true, .. }));
+
+    // 其他房间不受影响
+    let out = handle_at(&mut state, client_addr(40003), json!({"type": "register", "username": "p3", "room": "lobby"}), t0).unwrap();
+    assert!(matches!(out[0].1, ServerMessage::Registered { .. }));
+}
+
+#[test]
+fn test_room_full_resume_does_not_restore_evicted_player() {
+    let config = ServerConfig {
+        online_timeout: Duration::from_secs(60),
+        evict_after: Some(Duration::from_secs(600)),
+        rooms: RoomConfig {
+            max_players: Some(2),
+            ..RoomConfig::default()
+        },
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let t0 = Instant::now();
+    let join = |name: &str| json!({"type": "register", "username": name, "room": "arena"});
+    handle_at(&mut state, client_addr(40001), join("p1"), t0).unwrap();
+    let uuid = state.username_map["p1"];
+    let later = t0 + Duration::from_secs(700);
+    handle_at(&mut state, client_addr(40002), join("p2"), later).unwrap();
+    handle_at(&mut state, client_addr(40003), join("p3"), later).unwrap();
+    assert_eq!(state.evict_offline(later), vec![uuid]);
+
+    let resume = json!({"type": "register", "uuid": uuid, "room": "arena"});
+    let out = handle_at(&mut state, client_addr(40001), resume, later).unwrap();
+    assert_eq!(
+        out,
+        vec![(client_addr(40001), ServerMessage::RoomFull { room: "arena".to_string(), limit: 2 })]
+    );
+    // 房间已满时被恢复的玩家不进入世界
+    assert_eq!(state.world.players.len(), 2);
+    assert!(!state.world.players.contains_key(&uuid));
+    assert!(!state.username_map.contains_key("p1"));
+}
+
+#[test]
+fn test_empty_room_reaped_after_idle_ttl() {
+    let config = ServerConfig {
+        online_timeout: Duration::from_secs(10),
+        evict_after: None,
+        rooms: RoomConfig {
+            idle_ttl: Duration::from_secs(30),
+            ..RoomConfig::default()
+        },
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let t0 = Instant::now();
+    let out = handle_at(&mut state, client_addr(40001), json!({"type": "register", "username": "gone", "room": "empty"}), t0).unwrap();
+    let ServerMessage::Registered { uuid: gone, .. } = out[0].1 else {
+        panic!("unexpected reply: {:?}", out[0].1);
+    };
+    let out = handle_at(&mut state, client_addr(40002), json!({"type": "register", "username": "stay", "room": "busy"}), t0).unwrap();
+    let ServerMessage::Registered { uuid: stay, .. } = out[0].1 else {
+        panic!("unexpected reply: {:?}", out[0].1);
+    };
+    let update = json!({"type": "update", "uuid": stay, "x": 0.0, "y": 0.0, "z": 0.0});
+    handle_at(&mut state, client_addr(40002), update, t0 + Duration::from_secs(35)).unwrap();
+
+    // 空闲未满 TTL 时保留
+    let mut notified = HashSet::new();
+    state.prune_offline(&mut notified, t0 + Duration::from_secs(20));
+    assert!(state.rooms.contains_key("empty"));
+
+    state.prune_offline(&mut notified, t0 + Duration::from_secs(40));
+    assert!(!state.rooms.contains_key("empty"));
+    assert!(!state.player_rooms.contains_key(&gone));
+    assert!(state.rooms["busy"].members.contains(&stay));
+    assert_eq!(state.player_rooms.get(&stay).map(String::as_str), Some("busy"));
+}
+
+#[test]
+fn test_mark_offline_twice_broadcasts_once() {
+    let mut state = new_state();
+    let t0 = Instant::now();
+    let (leaver, watcher) = (client_addr(40001), client_addr(40002));
+    let out = handle_at(&mut state, leaver, json!({"type": "register", "username": "leaver"}), t0).unwrap();
+    let ServerMessage::Registered { uuid, .. } = out[0].1 else {
+        panic!("unexpected reply: {:?}", out[0].1);
+    };
+    handle_at(&mut state, watcher, json!({"type": "register", "username": "watcher"}), t0).unwrap();
+
+    let first = state.mark_offline(&uuid, "disconnect", t0);
+    assert_eq!(first, vec![(watcher, ServerMessage::PlayerOffline { uuid })]);
+    assert!(!state.is_online(&uuid, t0));
+    assert!(state.mark_offline(&uuid, "disconnect", t0).is_empty());
+
+    // 扫描线程随后也不会再广播一次
+    let mut notified = HashSet::new();
+    let out = state.prune_offline(&mut notified, t0 + Duration::from_millis(1));
+    assert!(out.iter().all(|(_, m)| !matches!(m, ServerMessage::PlayerOffline { uuid: u } | ServerMessage::Offline { uuid: u, .. } if *u == uuid)));
+
+    // 移除同样容忍重复调用和不存在的玩家
+    assert!(state.remove_player(&uuid).is_some());
+    assert!(state.remove_player(&uuid).is_none());
+    assert!(state.mark_offline(&uuid, "disconnect", t0).is_empty());
+    assert!(state.mark_offline(&Uuid::new_v4(), "disconnect", t0).is_empty());
+}
+
+#[test]
+fn test_evicted_player_name_reusable() {
+    let config = ServerConfig {
+        online_timeout: Duration::from_secs(60),
+        evict_after: Some(Duration::from_secs(600)),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let t0 = Instant::now();
+    handle_at(&mut state, client_addr(40001), json!({"type": "register", "username": "nomad"}), t0).unwrap();
+    let gone = state.username_map["nomad"];
+    let later = t0 + Duration::from_secs(660);
+    handle_at(&mut state, client_addr(40002), json!({"type": "register", "username": "nomad_1"}), later).unwrap();
+
+    state.evict_offline(later);
+    assert!(!state.username_map.contains_key("nomad"));
+    assert_eq!(state.suggest_name("nomad"), "nomad_2");
+
+    let out = handle_at(&mut state, client_addr(40003), json!({"type": "register", "username": "nomad"}), later).unwrap();
+    assert!(matches!(&out[0].1, ServerMessage::Registered { username, .. } if username == "nomad"));
+    assert_ne!(state.username_map["nomad"], gone);
+    // 建议名与冲突检查使用同一来源
+    let out = handle_at(&mut state, client_addr(40004), json!({"type": "register", "username": "nomad"}), later).unwrap();
+    assert!(matches!(&out[0].1, ServerMessage::NameConflict { suggested, .. } if suggested == "nomad_2"));
+}
+
+#[test]
+fn test_offline_player_name_reserved_for_grace_period() {
+    let config = ServerConfig {
+        online_timeout: Duration::from_secs(60),
+        name_reservation: Some(Duration::from_secs(30)),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let t0 = Instant::now();
+    let at = |secs| t0 + Duration::from_secs(secs);
+    let owner = client_addr(40001);
+    let stranger = client_addr(40002);
+    handle_at(&mut state, owner, json!({"type": "register", "username": "celebrity"}), t0).unwrap();
+    let uuid = state.username_map["celebrity"];
+
+    // 离线后保留期内：其他人注册同名被拒绝
+    let out = handle_at(&mut state, stranger, json!({"type": "register", "username": "celebrity"}), at(70)).unwrap();
+    assert!(matches!(&out[0].1, ServerMessage::NameConflict { .. }));
+    assert_eq!(state.reservations["celebrity"].0, uuid);
+
+    // 原玩家在保留期内恢复会话，取回名字
+    let out = handle_at(&mut state, owner, json!({"type": "register", "uuid": uuid}), at(80)).unwrap();
+    assert!(matches!(&out[0].1, ServerMessage::Registered { username, resumed: true, .. } if username == "celebrity"));
+    assert!(state.reservations.is_empty());
+
+    // 再次离线且保留期已过：名字释放给其他人
+    let out = handle_at(&mut state, stranger, json!({"type": "register", "username": "celebrity"}), at(80 + 60 + 31)).unwrap();
+    assert!(matches!(&out[0].1, ServerMessage::Registered { username, .. } if username == "celebrity"));
+    assert_ne!(state.username_map["celebrity"], uuid);
+
+    // 原玩家之后再恢复时改用建议名
+    let out = handle_at(&mut state, owner, json!({"type": "register", "uuid": uuid}), at(200)).unwrap();
+    match &out[0].1 {
+        ServerMessage::Registered { username, resumed: true, .. } => assert_ne!(username, "celebrity"),
+        other => panic!("unexpected reply: {:?}", other),
+    }
+    assert_eq!(state.storage.get(&uuid).unwrap().username, state.world.players[&uuid].username);
+}
+
+#[test]
+fn test_handle_register_rejects_mistyped_fields() {
+    let mut state = new_state();
+    let src = client_addr(40001);
+    let err = handle(&mut state, src, json!({"type": "register", "username": 123})).unwrap_err();
+    assert_eq!(err, HandlerError::Malformed(FieldError { field: "username", expected: "string" }));
+    assert_eq!(
+        err.to_reply(),
+        ServerMessage::MalformedRequest {
+            field: "username".to_string(),
+            expected: "string".to_string(),
+        }
+    );
+
+    let err = handle(&mut state, src, json!({"type": "register", "uuid": 42})).unwrap_err();
+    assert_eq!(err, HandlerError::Malformed(FieldError { field: "uuid", expected: "uuid string" }));
+    let err = handle(&mut state, src, json!({"type": "register", "uuid": "not-a-uuid", "username": "x"})).unwrap_err();
+    assert_eq!(err, HandlerError::Malformed(FieldError { field: "uuid", expected: "uuid string" }));
+    assert!(state.world.players.is_empty());
+
+    // null 与缺失等价
+    let out = handle(&mut state, src, json!({"type": "register", "uuid": null, "username": "ok"})).unwrap();
+    assert!(matches!(out[0].1, ServerMessage::Registered { .. }));
+}
+
+fn keepalive_state() -> ServerState {
+    let config = ServerConfig {
+        online_timeout: Duration::from_secs(60),
+        keepalive_timeout: Some(Duration::from_secs(10)),
+        ..ServerConfig::default()
+    };
+    new_state().with_config(config)
+}
+
+#[test]
+fn test_keepalive_pinging_idle_player_stays_online() {
+    let mut state = keepalive_state();
+    let t0 = Instant::now();
+    let src = client_addr(40001);
+    let out = handle_at(&mut state, src, json!({"type": "register", "username": "idler"}), t0).unwrap();
+    let uuid = match &out[0].1 {
+        ServerMessage::Registered { uuid, .. } => *uuid,
+        other => panic!("unexpected reply: {:?}", other),
+    };
+    // 只 ping 不移动：保活期内一直在线
+    for secs in [8, 16, 24, 32, 40, 48, 56] {
+        handle_at(&mut state, src, json!({"type": "ping", "uuid": uuid}), t0 + Duration::from_secs(secs)).unwrap();
+    }
+    assert!(state.is_online(&uuid, t0 + Duration::from_secs(59)));
+    // 不活动超时仍然生效
+    assert!(!state.is_online(&uuid, t0 + Duration::from_secs(60)));
+    let out = state.expire_inactive(&mut HashSet::new(), t0 + Duration::from_secs(60));
+    assert!(matches!(&out[0].1, ServerMessage::Offline { reason, .. } if reason == "inactivity"));
+}
+
+#[test]
+fn test_keepalive_silent_player_leaves_broadcast_without_sweep() {
+    let mut state = keepalive_state();
+    let t0 = Instant::now();
+    let (quiet, chatty) = (client_addr(40001), client_addr(40002));
+    let quiet_uuid = register(&mut state, quiet, "quiet");
+    let chatty_uuid = register(&mut state, chatty, "chatty");
+    handle_at(&mut state, quiet, json!({"type": "update", "uuid": quiet_uuid, "x": 1.0}), t0).unwrap();
+    handle_at(&mut state, chatty, json!({"type": "update", "uuid": chatty_uuid, "x": 2.0}), t0).unwrap();
+    let broadcast_players = |state: &ServerState, now| {
+        state.broadcast(now).into_iter().find_map(|(conn, m)| match m {
+            ServerMessage::World { players, .. } if conn == chatty => Some(players.into_map()),
+            _ => None,
+        })
+    };
+
+    let t9 = t0 + Duration::from_secs(9);
+    handle_at(&mut state, chatty, json!({"type": "ping", "uuid": chatty_uuid}), t9).unwrap();
+    assert!(state.is_online(&quiet_uuid, t9));
+
+    // 不经过扫描，超过保活窗口后即离线，广播里也没有该玩家
+    let t10 = t0 + Duration::from_secs(10);
+    assert!(!state.is_online(&quiet_uuid, t10));
+    assert!(state.is_online(&chatty_uuid, t10));
+    let players = broadcast_players(&state, t10).unwrap();
+    assert!(players.contains_key(&chatty_uuid));
+    assert!(!players.contains_key(&quiet_uuid));
+}
+
+#[test]
+fn test_keepalive_silent_player_dropped_early() {
+    let mut state = keepalive_state();
+    let t0 = Instant::now();
+    let src = client_addr(40001);
+    let out = handle_at(&mut state, src, json!({"type": "register", "username": "ghost"}), t0).unwrap();
+    let uuid = match &out[0].1 {
+        ServerMessage::Registered { uuid, .. } => *uuid,
+        other => panic!("unexpected reply: {:?}", other),
+    };
+    handle_at(&mut state, src, json!({"type": "ping", "uuid": uuid}), t0 + Duration::from_secs(5)).unwrap();
+    assert!(state.is_online(&uuid, t0 + Duration::from_secs(14)));
+    assert!(!state.is_online(&uuid, t0 + Duration::from_secs(15)));
+
+    let mut notified = HashSet::new();
+    assert!(state.expire_inactive(&mut notified, t0 + Duration::from_secs(14)).is_empty());
+    let out = state.expire_inactive(&mut notified, t0 + Duration::from_secs(15));
+    assert!(matches!(&out[0].1, ServerMessage::Offline { reason, .. } if reason == "keepalive"));
+    // 只通知一次
+    assert!(state.expire_inactive(&mut notified, t0 + Duration::from_secs(20)).is_empty());
+}
+
+#[test]
+fn test_prune_offline_skips_player_refreshed_after_detection() {
+    let clock = ManualClock::new(Instant::now());
+    let mut state = new_state();
+    let (stale, refreshed) = (client_addr(40001), client_addr(40002));
+    handle_at(&mut state, stale, json!({"type": "register", "username": "stale"}), clock.now()).unwrap();
+    handle_at(&mut state, refreshed, json!({"type": "register", "username": "refreshed"}), clock.now()).unwrap();
+    let (stale_uuid, refreshed_uuid) = (state.username_map["stale"], state.username_map["refreshed"]);
+
+    clock.advance(Duration::from_secs(61));
+    let candidates: HashSet<Uuid> = state
+        .offline_deadlines()
+        .into_iter()
+        .filter(|(_, d)| clock.now() >= *d)
+        .map(|(uuid, _)| uuid)
+        .collect();
+    assert_eq!(candidates, HashSet::from([stale_uuid, refreshed_uuid]));
+
+    // 判定之后、处理之前有一个玩家发来了更新
+    handle_at(&mut state, refreshed, json!({"type": "update", "uuid": refreshed_uuid, "x": 1.0}), clock.now()).unwrap();
+    let mut notified = HashSet::new();
+    let out = state.prune_offline(&mut notified, clock.now());
+    let offlined: Vec<Uuid> = out
+        .iter()
+        .filter_map(|(_, m)| match m {
+            ServerMessage::Offline { uuid, .. } => Some(*uuid),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(offlined, vec![stale_uuid]);
+    assert_eq!(notified, HashSet::from([stale_uuid]));
+    assert!(state.is_online(&refreshed_uuid, clock.now()));
+}
+
+#[test]
+fn test_inactivity_warning_sent_once_before_offline() {
+    let config = ServerConfig {
+        inactivity_warning: Some(Duration::from_secs(10)),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let t0 = Instant::now();
+    handle_at(&mut state, src, json!({"type": "register", "username": "idle"}), t0).unwrap();
+    let mut notified = HashSet::new();
+
+    assert!(state.prune_offline(&mut notified, t0 + Duration::from_secs(45)).is_empty());
+    assert_eq!(state.next_warning_delay(t0 + Duration::from_secs(45)), Some(Duration::from_secs(5)));
+    let out = state.prune_offline(&mut notified, t0 + Duration::from_millis(51_500));
+    assert_eq!(out, vec![(src, ServerMessage::InactivityWarning { seconds_remaining: 9 })]);
+    // 同一段不活动期内不再提醒
+    assert!(state.prune_offline(&mut notified, t0 + Duration::from_secs(55)).is_empty());
+    assert_eq!(state.next_warning_delay(t0 + Duration::from_secs(55)), None);
+    let out = state.prune_offline(&mut notified, t0 + Duration::from_secs(60));
+    assert_eq!(out.len(), 1);
+    assert!(matches!(out[0].1, ServerMessage::Offline { .. }));
+}
+
+#[test]
+fn test_collect_past_deadline_orders_by_deadline() {
+    let now = Instant::now();
+    let (a, b, c) = (Uuid::from_u128(3), Uuid::from_u128(1), Uuid::from_u128(2));
+    let deadlines = HashMap::from([(a, now - Duration::from_secs(5)), (b, now), (c, now)]);
+    assert_eq!(collect_past_deadline(&deadlines, &mut HashSet::new(), now), vec![a, b, c]);
+}
+
+#[test]
+fn test_inactivity_offline_broadcasts_player_offline() {
+    let mut state = new_state();
+    let t0 = Instant::now();
+    let idler = client_addr(40001);
+    let active = client_addr(40002);
+    handle_at(&mut state, idler, json!({"type": "register", "username": "idler"}), t0).unwrap();
+    let idle_uuid = state.username_map["idler"];
+    let later = t0 + Duration::from_secs(50);
+    handle_at(&mut state, active, json!({"type": "register", "username": "active"}), later).unwrap();
+
+    let out = state.expire_inactive(&mut HashSet::new(), t0 + Duration::from_secs(60));
+    let to_active: Vec<_> = out.iter().filter(|(conn, _)| *conn == active).map(|(_, m)| m.clone()).collect();
+    assert_eq!(to_active, vec![ServerMessage::PlayerOffline { uuid: idle_uuid }]);
+    // 离线的玩家本人只收到 Offline
+    assert!(out
+        .iter()
+        .filter(|(conn, _)| *conn == idler)
+        .all(|(_, m)| matches!(m, ServerMessage::Offline { .. })));
+}
+
+#[test]
+fn test_offline_message_uses_client_locale() {
+    let mut state = new_state();
+    let t0 = Instant::now();
+    let zh = client_addr(40001);
+    let out = handle_at(&mut state, zh, json!({"type": "register", "username": "小明", "locale": "zh"}), t0).unwrap();
+    assert!(matches!(out[0].1, ServerMessage::Registered { .. }));
+    let en = client_addr(40002);
+    register(&mut state, en, "bob");
+
+    let out = state.expire_inactive(&mut HashSet::new(), t0 + Duration::from_secs(61));
+    let message_to = |dst: ClientConn| {
+        out.iter()
+            .find_map(|(conn, m)| match m {
+                ServerMessage::Offline { message, .. } if *conn == dst => Some(message.clone()),
+                _ => None,
+            })
+            .unwrap()
+    };
+    assert_eq!(message_to(zh), "60 秒内没有活动，已离线。使用相同 UUID 重新加入即可恢复。");
+    assert!(message_to(en).starts_with("No activity for 60 seconds"));
+}
+
+#[test]
+fn test_message_catalog_fallback_chain() {
+    let catalog = MessageCatalog::default();
+    let zh = catalog.render("zh-TW", MessageKey::InvalidMovement, &[]);
+    assert_eq!(zh, catalog.render("zh", MessageKey::InvalidMovement, &[]));
+    let fr = catalog.render("fr", MessageKey::InvalidMovement, &[]);
+    assert_eq!(fr, catalog.render("en", MessageKey::InvalidMovement, &[]));
+}
+
+#[test]
+fn test_handle_register_resume() {
+    let mut state = new_state();
+    let uuid = register(&mut state, client_addr(40001), "pilot");
+    let out = handle(&mut state, client_addr(40003), json!({"type": "register", "uuid": uuid})).unwrap();
+    assert!(matches!(&out[0].1, ServerMessage::Registered { resumed: true, .. }));
+    assert_eq!(state.conn_of(&uuid), Some(client_addr(40003)));
+}
+
+#[test]
+fn test_handle_register_resume_with_client_position() {
+    let mut state = new_state();
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "returner");
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 1.0, "y": 0.0, "z": 1.0})).unwrap();
+
+    // 离记录位置不远：采用客户端的位置
+    let out = handle(&mut state, src, json!({"type": "register", "uuid": uuid, "x": 4.0, "y": 0.0, "z": 5.0})).unwrap();
+    match &out[0].1 {
+        ServerMessage::Registered { state: Some(p), .. } => assert_eq!((p.x, p.z), (Some(4.0), Some(5.0))),
+        other => panic!("unexpected reply: {:?}", other),
+    }
+    assert_eq!(state.world.players[&uuid].x, Some(4.0));
+
+    // 瞬移距离：忽略，保留服务器的位置
+    let out = handle(&mut state, src, json!({"type": "register", "uuid": uuid, "x": 500.0, "y": 0.0, "z": 5.0})).unwrap();
+    match &out[0].1 {
+        ServerMessage::Registered { state: Some(p), .. } => assert_eq!(p.x, Some(4.0)),
+        other => panic!("unexpected reply: {:?}", other),
+    }
+    assert_eq!(state.world.players[&uuid].x, Some(4.0));
+
+    // 从身份存储恢复的玩家没有位置，直接采用
+    let evicted = register(&mut state, client_addr(40002), "sleeper");
+    state.world.players.remove(&evicted);
+    state.username_map.remove("sleeper");
+    handle(&mut state, client_addr(40002), json!({"type": "register", "uuid": evicted, "x": 7.0, "y": 1.0, "z": 2.0})).unwrap();
+    let restored = &state.world.players[&evicted];
+    assert_eq!((restored.x, restored.y, restored.z), (Some(7.0), Some(1.0), Some(2.0)));
+}
+
+/// 取出注册回复中的 resume_token
+fn resume_token_of(out: &Outgoing) -> String {
+    match &out[0].1 {
+        ServerMessage::Registered { resume_token: Some(token), .. } => token.clone(),
+        other => panic!("unexpected reply: {:?}", other),
+    }
+}
+
+#[test]
+fn test_handle_register_rebind_from_new_address() {
+    let config = ServerConfig {
+        require_resume_token: true,
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let old = client_addr(40001);
+    let t0 = Instant::now();
+    let out = handle_at(&mut state, old, json!({"type": "register", "username": "roamer"}), t0).unwrap();
+    let token = resume_token_of(&out);
+    let uuid = state.username_map["roamer"];
+    handle_at(&mut state, old, json!({"type": "update", "uuid": uuid, "x": 3.0, "y": 4.0, "z": 5.0}), t0).unwrap();
+
+    // 换网后的新地址：没有凭证或凭证错误都被拒绝
+    let new = client_addr(40009);
+    let t1 = t0 + Duration::from_secs(1);
+    let result = handle_at(&mut state, new, json!({"type": "register", "uuid": uuid}), t1);
+    assert_eq!(result, Err(HandlerError::Unauthorized(uuid)));
+    let result = handle_at(&mut state, new, json!({"type": "register", "uuid": uuid, "resume_token": "nope"}), t1);
+    assert_eq!(result, Err(HandlerError::Unauthorized(uuid)));
+    assert_eq!(state.clients[&uuid].conn, old);
+
+    let out = handle_at(&mut state, new, json!({"type": "register", "uuid": uuid, "resume_token": token}), t1).unwrap();
+    match &out[0].1 {
+        ServerMessage::Registered { resumed: true, state: Some(p), .. } => assert_eq!(p.x, Some(3.0)),
+        other => panic!("unexpected reply: {:?}", other),
+    }
+    assert_eq!(state.clients[&uuid].conn, new);
+    assert!(state.is_online(&uuid, t1));
+    assert_eq!(state.world.players[&uuid].x, Some(3.0));
+
+    // 旧地址不再收到广播，也不能再发更新
+    let out = handle_at(&mut state, new, json!({"type": "update", "uuid": uuid, "x": 3.5, "y": 4.0, "z": 5.0}), t1).unwrap();
+    assert!(out.iter().all(|(conn, _)| *conn != old));
+    let result = handle_at(&mut state, old, json!({"type": "update", "uuid": uuid, "x": 9.0}), t1);
+    assert_eq!(result, Err(HandlerError::Unauthorized(uuid)));
+}
+
+#[test]
+fn test_rejected_resume_token_does_not_restore_evicted_player() {
+    let config = ServerConfig {
+        online_timeout: Duration::from_secs(60),
+        evict_after: Some(Duration::from_secs(600)),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let t0 = Instant::now();
+    handle_at(&mut state, client_addr(40001), json!({"type": "register", "username": "roamer"}), t0).unwrap();
+    let uuid = state.username_map["roamer"];
+    let later = t0 + Duration::from_secs(700);
+    assert_eq!(state.evict_offline(later), vec![uuid]);
+    // 凭证仍有记录时，错误的凭证必须在玩家放回世界之前被拒绝
+    state.resume_tokens.insert(uuid, "expected".to_string());
+
+    let msg = json!({"type": "register", "uuid": uuid, "resume_token": "nope"});
+    let result = handle_at(&mut state, client_addr(40009), msg, later);
+    assert_eq!(result, Err(HandlerError::Unauthorized(uuid)));
+    assert!(state.world.players.is_empty());
+    assert!(state.username_map.is_empty());
+}
+
+#[test]
+fn test_handle_register_resume_sends_snapshot_after_registered() {
+    let mut state = new_state();
+    let other = client_addr(40002);
+    let uuid = register(&mut state, client_addr(40001), "pilot");
+    let other_uuid = register(&mut state, other, "wingman");
+
+    let resumer = client_addr(40003);
+    let out = handle(&mut state, resumer, json!({"type": "register", "uuid": uuid})).unwrap();
+
+    // 顺序：先 registered，再发给恢复者本人的完整快照
+    assert!(matches!(&out[0], (addr, ServerMessage::Registered { resumed: true, .. }) if *addr == resumer));
+    match &out[1] {
+        (addr, ServerMessage::World { players, .. }) if *addr == resumer => {
+            assert!(players.contains_key(&uuid));
+            assert!(players.contains_key(&other_uuid));
+        }
+        other => panic!("expected snapshot for resumer, got {:?}", other),
+    }
+    // 恢复者只收到一份快照，其他客户端照常收到广播
+    let to_resumer = out.iter().filter(|(addr, _)| *addr == resumer).count();
+    assert_eq!(to_resumer, 2);
+    assert!(out
+        .iter()
+        .any(|(addr, msg)| *addr == other && matches!(msg, ServerMessage::World { .. })));
+}
+
+#[test]
+fn test_handle_update_partial_keeps_other_fields() {
+    let mut state = new_state();
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "partial");
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 2.0, "z": 3.0, "ry": 90.0, "ts": 1000, "action": "jump"})).unwrap();
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.5, "ts": 1100})).unwrap();
+
+    let player = &state.world.players[&uuid];
+    assert_eq!((player.x, player.y, player.z), (Some(0.5), Some(2.0), Some(3.0)));
+    assert_eq!(player.ry, Some(90.0));
+    // 动作不沿用上一次更新
+    assert_eq!(player.action, None);
+}
+
+#[test]
+fn test_movement_tolerance_scales_with_rtt() {
+    let movement = MovementConfig {
+        rtt_factor: 5.0,
+        ..MovementConfig::default()
+    };
+    assert_eq!(movement.effective_tolerance(None), 0.5);
+    let slow = movement.effective_tolerance(Some(Duration::from_millis(200)));
+    let fast = movement.effective_tolerance(Some(Duration::from_millis(20)));
+    assert!((slow - 1.5).abs() < 1e-9);
+    assert!(slow > fast);
+    // 超过 max_rtt 的上报按上限计算
+    assert_eq!(movement.effective_tolerance(Some(Duration::from_secs(30))), 5.5);
+}
+
+#[test]
+fn test_rtt_tracker_smooths_echoed_samples() {
+    let uuid = Uuid::from_u128(1);
+    let mut tracker = RttTracker::new();
+    // 没有发出过的时间戳不计入
+    assert_eq!(tracker.on_recv(uuid, 1000, 1100), None);
+
+    tracker.on_send(uuid, 1000);
+    assert_eq!(tracker.on_recv(uuid, 1000, 1080), Some(Duration::from_millis(80)));
+    // 同一时间戳只能回显一次
+    assert_eq!(tracker.on_recv(uuid, 1000, 1500), None);
+
+    // 7/8 * 80 + 1/8 * 160 = 90，再 7/8 * 90 + 1/8 * 10 = 80
+    tracker.on_send(uuid, 2000);
+    tracker.on_send(uuid, 3000);
+    assert_eq!(tracker.on_recv(uuid, 2000, 2160), Some(Duration::from_millis(90)));
+    assert_eq!(tracker.on_recv(uuid, 3000, 3010), Some(Duration::from_millis(80)));
+    assert_eq!(tracker.get(&uuid), Some(Duration::from_millis(80)));
+
+    // 回显较新的时间戳时，更早未回显的视为丢失
+    tracker.on_send(uuid, 4000);
+    tracker.on_send(uuid, 5000);
+    assert!(tracker.on_recv(uuid, 5000, 5080).is_some());
+    assert_eq!(tracker.on_recv(uuid, 4000, 5100), None);
+}
+
+#[test]
+fn test_echoed_pong_ts_measures_rtt_for_get_and_broadcast() {
+    let config = ServerConfig {
+        broadcast_rtt: true,
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "pinger");
+    let out = handle(&mut state, src, json!({"type": "ping", "uuid": uuid})).unwrap();
+    let ServerMessage::Pong { server_ts, .. } = out[0].1 else {
+        panic!("unexpected reply: {:?}", out[0].1);
+    };
+    assert_eq!(state.rtt.get(&uuid), None);
+
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "echo_ts": server_ts})).unwrap();
+    let rtt_ms = state.rtt.get(&uuid).map(|rtt| rtt.as_millis() as u64);
+    assert!(rtt_ms.is_some());
+    assert!(out.iter().any(|(_, m)| matches!(m, ServerMessage::World { rtt_ms: r, .. } if r.get(&uuid).copied() == rtt_ms)));
+    let out = handle(&mut state, src, json!({"type": "get", "uuid": uuid})).unwrap();
+    assert!(matches!(&out[0].1, ServerMessage::PlayerInfo { rtt_ms: r, .. } if *r == rtt_ms));
+}
+
+#[test]
+fn test_handle_update_rtt_scaled_tolerance() {
+    let config = ServerConfig {
+        movement: MovementConfig {
+            rtt_factor: 5.0,
+            ..MovementConfig::default()
+        },
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let (laggy_src, cheater_src) = (client_addr(40001), client_addr(40002));
+    let laggy = register(&mut state, laggy_src, "laggy");
+    let cheater = register(&mut state, cheater_src, "cheater");
+    // 高延迟玩家回显了 200ms 前发给它的时间戳
+    let sent = now_millis() - 200;
+    state.rtt.on_send(laggy, sent);
+    handle(&mut state, laggy_src, json!({"type": "ping", "uuid": laggy, "echo_ts": sent})).unwrap();
+    assert!(state.rtt.get(&laggy).is_some_and(|rtt| rtt >= Duration::from_millis(200)));
+    // 客户端自报的 rtt_ms 不计入，伪造也换不来更大的容差
+    handle(&mut state, cheater_src, json!({"type": "ping", "uuid": cheater, "rtt_ms": 1000})).unwrap();
+    assert_eq!(state.rtt.get(&cheater), None);
+
+    for (src, uuid) in [(laggy_src, laggy), (cheater_src, cheater)] {
+        handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "vx": 0.0, "ts": 1000})).unwrap();
+    }
+    // 静止却移动了 1.2 米：高延迟玩家的容差 1.5 米内，伪造 RTT 的玩家只有基础容差 0.5 米
+    let jump = |uuid: Uuid| json!({"type": "update", "uuid": uuid, "x": 1.2, "y": 0.0, "z": 0.0, "vx": 0.0, "ts": 1100});
+    let out = handle(&mut state, laggy_src, jump(laggy)).unwrap();
+    assert_eq!(correction_for(&out, laggy_src), None);
+    let out = handle(&mut state, cheater_src, jump(cheater)).unwrap();
+    assert_eq!(correction_for(&out, cheater_src).map(|(reason, _)| reason), Some("invalid_movement".to_string()));
+}
+
+#[test]
+fn test_player_extra_round_trips() {
+    let player = PlayerState::new(Uuid::new_v4(), "decorated").with_extra(json!({"team": "red", "skin": 7}));
+    let json = serde_json::to_string(&player).unwrap();
+    let deserialized: PlayerState = serde_json::from_str(&json).unwrap();
+    assert_eq!(deserialized.extra, Some(json!({"team": "red", "skin": 7})));
+    // 没有附加数据时不输出该字段
+    let plain = serde_json::to_value(PlayerState::new(Uuid::new_v4(), "plain")).unwrap();
+    assert!(plain.get("extra").is_none());
+}
+
+#[test]
+fn test_handle_update_extra_broadcast_and_size_cap() {
+    let config = ServerConfig {
+        max_extra_bytes: 32,
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "decorated");
+
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "extra": {"team": "red"}})).unwrap();
+    let broadcast_extra = out.iter().find_map(|(_, m)| match m {
+        ServerMessage::World { players, .. } => players[&uuid].extra.clone(),
+        _ => None,
+    });
+    assert_eq!(broadcast_extra, Some(json!({"team": "red"})));
+
+    let oversized = json!({"type": "update", "uuid": uuid, "extra": {"bio": "x".repeat(64)}});
+    assert_eq!(handle(&mut state, src, oversized), Err(HandlerError::InvalidField("extra")));
+    assert_eq!(state.world.players[&uuid].extra, Some(json!({"team": "red"})));
+
+    // 不带 extra 的更新保留原有附加数据
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 1.0})).unwrap();
+    assert_eq!(state.world.players[&uuid].extra, Some(json!({"team": "red"})));
+}
+
+#[test]
+fn test_rejected_update_resends_authoritative_state() {
+    let config = ServerConfig {
+        max_extra_bytes: 32,
+        rebroadcast_authoritative_on_reject: true,
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "steady");
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 1.0, "y": 2.0, "z": 3.0, "ts": 1000})).unwrap();
+
+    let oversized = json!({"type": "update", "uuid": uuid, "x": 1.5, "y": 2.0, "z": 3.0, "extra": {"bio": "x".repeat(64)}});
+    let out = handle(&mut state, src, oversized).unwrap();
+    assert_eq!(out.len(), 2);
+    assert_eq!(out[0], (src, HandlerError::InvalidField("extra").to_reply()));
+    match &out[1] {
+        (dst, ServerMessage::PlayerInfo { player, online: true, .. }) if *dst == src => {
+            assert_eq!((player.x, player.y, player.z), (Some(1.0), Some(2.0), Some(3.0)));
+        }
+        other => panic!("unexpected reply: {:?}", other),
+    }
+
+    // 不存在的玩家没有权威状态可发，照常返回错误
+    let ghost = Uuid::new_v4();
+    let msg = json!({"type": "update", "uuid": ghost, "x": 0.0});
+    assert_eq!(handle(&mut state, src, msg), Err(HandlerError::UnknownPlayer(ghost)));
+}
+
+#[test]
+fn test_broadcast_projection_omits_unselected_fields() {
+    let config = ServerConfig {
+        broadcast_projection: Some(BroadcastProjection::position_only()),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "sprinter");
+    let msg = json!({"type": "update", "uuid": uuid, "x": 1.0, "y": 2.0, "z": 3.0, "vx": 4.0, "action": "jump", "ts": 1000});
+    let out = handle(&mut state, src, msg).unwrap();
+    let world = out.iter().find(|(_, m)| matches!(m, ServerMessage::World { .. })).unwrap();
+    let wire: Value = serde_json::from_slice(&CompactJson.encode(&world.1)).unwrap();
+    let player = wire["players"][uuid.to_string()].as_object().unwrap();
+    let mut keys: Vec<&str> = player.keys().map(|k| k.as_str()).collect();
+    keys.sort();
+    assert_eq!(keys, vec!["rx", "ry", "rz", "username", "x", "y", "z"]);
+
+    // 客户端仍能解析裁剪后的广播，uuid 从键补回
+    match serde_json::from_value::<ServerMessage>(wire).unwrap() {
+        ServerMessage::World { players, .. } => {
+            assert_eq!(players[&uuid].uuid, uuid);
+            assert_eq!(players[&uuid].x, Some(1.0));
+            assert_eq!(players[&uuid].vx, None);
+        }
+        other => panic!("unexpected message: {:?}", other),
+    }
+}
+
+#[test]
+fn test_broadcast_excludes_own_entry_when_self_disabled() {
+    let config = ServerConfig {
+        include_self_in_broadcast: false,
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let (a, b) = (client_addr(40001), client_addr(40002));
+    let uuid_a = register(&mut state, a, "alpha");
+    let uuid_b = register(&mut state, b, "bravo");
+    let out = handle(&mut state, a, json!({"type": "update", "uuid": uuid_a, "x": 1.0, "y": 0.0, "z": 0.0, "ts": 1000})).unwrap();
+    let world_for = |dst: ClientConn| {
+        out.iter()
+            .find_map(|(addr, m)| match m {
+                ServerMessage::World { players, .. } if *addr == dst => Some(players.clone().into_map()),
+                _ => None,
+            })
+            .unwrap()
+    };
+    let seen_by_a = world_for(a);
+    assert!(!seen_by_a.contains_key(&uuid_a));
+    assert!(seen_by_a.contains_key(&uuid_b));
+    let seen_by_b = world_for(b);
+    assert!(seen_by_b.contains_key(&uuid_a));
+    assert!(!seen_by_b.contains_key(&uuid_b));
+}
+
+#[test]
+fn test_projectile_broadcast_until_ttl_expires() {
+    let mut state = new_state();
+    let src = client_addr(40001);
+    let shooter = register(&mut state, src, "shooter");
+    let spawned_at = now_millis();
+    let expires_at = spawned_at + 500;
+    let arrow = state.spawn_entity(Entity::Projectile {
+        owner: Some(shooter),
+        position: (0.0, 1.0, 0.0),
+        velocity: (20.0, 0.0, 0.0),
+        spawned_at,
+        expires_at: Some(expires_at),
+    });
+    let pickup = state.spawn_entity(Entity::Pickup { item: "arrow_bundle".to_string(), position: (5.0, 0.0, 5.0), expires_at: None });
+    let entities_seen = |state: &ServerState| {
+        state
+            .broadcast(Instant::now())
+            .into_iter()
+            .find_map(|(conn, m)| match m {
+                ServerMessage::World { entities, .. } if conn == src => Some(entities),
+                _ => None,
+            })
+            .unwrap()
+    };
+
+    let seen = entities_seen(&state);
+    assert_eq!(seen.len(), 2);
+    assert!(matches!(seen[&arrow], Entity::Projectile { owner: Some(o), .. } if o == shooter));
+    let wire: Value = serde_json::to_value(&seen[&arrow]).unwrap();
+    assert_eq!(wire["type"], "projectile");
+
+    // 未到期前保留，到期后从世界和广播中移除；没有到期时间的物品不受影响
+    assert!(state.expire_entities(expires_at - 1).is_empty());
+    assert_eq!(state.expire_entities(expires_at), vec![arrow]);
+    let seen = entities_seen(&state);
+    assert!(!seen.contains_key(&arrow));
+    assert!(seen.contains_key(&pickup));
+    assert_eq!(state.world.next_entity_expiry(), None);
+}
+
+#[test]
+fn test_stable_broadcast_order() {
+    let config = ServerConfig {
+        stable_broadcast_order: Some(PlayerOrder::ByUsername),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    for (i, name) in ["delta", "alpha", "echo", "charlie", "bravo"].iter().enumerate() {
+        register(&mut state, client_addr(40001 + i as u16), name);
+    }
+    let now = Instant::now();
+    let encode = |state: &ServerState| {
+        let msgs = state.world_messages(&state.snapshot(now), 0, None);
+        CompactJson.encode(&msgs[0])
+    };
+    let first = encode(&state);
+    assert_eq!(first, encode(&state));
+
+    // 输出顺序与 to_sorted_vec 一致
+    let text = String::from_utf8(first).unwrap();
+    let positions: Vec<usize> = state
+        .world
+        .to_sorted_vec(PlayerOrder::ByUsername)
+        .iter()
+        .map(|p| text.find(&p.uuid.to_string()).unwrap())
+        .collect();
+    assert!(positions.windows(2).all(|w| w[0] < w[1]));
+    let names: Vec<&str> = state
+        .world
+        .to_sorted_vec(PlayerOrder::ByUsername)
+        .iter()
+        .map(|p| p.username.as_str())
+        .collect();
+    assert_eq!(names, ["alpha", "bravo", "charlie", "delta", "echo"]);
+}
+
+#[test]
+fn test_broadcast_chunks_per_client_mtu() {
+    let mut state = new_state();
+    let small = client_addr(40001);
+    let large = client_addr(40002);
+    handle(&mut state, small, json!({"type": "register", "username": "small", "mtu": 300})).unwrap();
+    handle(&mut state, large, json!({"type": "register", "username": "large", "mtu": 4000})).unwrap();
+    for i in 0..8 {
+        register(&mut state, client_addr(40100 + i), &format!("filler{}", i));
+    }
+
+    let out = state.broadcast(Instant::now());
+    let chunks_for = |dst: ClientConn| -> Vec<&ServerMessage> {
+        out.iter().filter(|(conn, _)| *conn == dst).map(|(_, m)| m).collect()
+    };
+    let (small_chunks, large_chunks) = (chunks_for(small), chunks_for(large));
+    assert!(small_chunks.len() > large_chunks.len());
+    assert_eq!(large_chunks.len(), 1);
+    assert!(matches!(large_chunks[0], ServerMessage::World { chunk: None, .. }));
+
+    let mut seen = HashSet::new();
+    for (i, msg) in small_chunks.iter().enumerate() {
+        assert!(CompactJson.encode(msg).len() <= 300);
+        match msg {
+            ServerMessage::World { players, chunk, .. } => {
+                assert_eq!(*chunk, Some((i as u32, small_chunks.len() as u32)));
+                seen.extend(players.keys().copied());
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+    assert_eq!(seen.len(), 10);
+}
+
+#[test]
+fn test_register_rejects_zero_mtu() {
+    let mut state = new_state();
+    let msg = json!({"type": "register", "username": "tiny", "mtu": 0});
+    let err = handle(&mut state, client_addr(40001), msg).unwrap_err();
+    assert_eq!(err, HandlerError::Malformed(FieldError { field: "mtu", expected: "positive integer" }));
+}
+
+#[test]
+fn test_handle_update_broadcasts_to_all_clients() {
+    let mut state = new_state();
+    let a = client_addr(40001);
+    let b = client_addr(40002);
+    let uuid_a = register(&mut state, a, "alpha");
+    register(&mut state, b, "beta");
+
+    let out = handle(&mut state, a, json!({"type": "update", "uuid": uuid_a, "x": 1.0, "y": 2.0, "z": 3.0, "ts": 1000})).unwrap();
+    let recipients: HashSet<ClientConn> = out
+        .iter()
+        .filter(|(_, m)| matches!(m, ServerMessage::World { .. }))
+        .map(|(addr, _)| *addr)
+        .collect();
+    assert_eq!(recipients, HashSet::from([a, b]));
+    assert_eq!(state.world.players.get(&uuid_a).unwrap().x, Some(1.0));
+}
+
+#[test]
+fn test_handle_update_correction_requires_ack() {
+    let mut state = new_state();
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "cheater");
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "ts": 0})).unwrap();
+
+    // 瞬移 → 纠正到 (10,0,0)
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 100.0, "y": 0.0, "z": 0.0, "ts": 1000, "vx": 10.0})).unwrap();
+    let (reason, nonce) = correction_for(&out, src).expect("should be corrected");
+    assert_eq!(reason, "invalid_movement");
+    assert_eq!(state.world.players.get(&uuid).unwrap().x, Some(10.0));
+
+    // 无视纠正继续按自己的位置上报 → 再次被纠正，位置不变
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 110.0, "y": 0.0, "z": 0.0, "ts": 2000, "vx": 10.0})).unwrap();
+    assert_eq!(correction_for(&out, src), Some(("unacknowledged_correction".to_string(), nonce)));
+    assert_eq!(state.world.players.get(&uuid).unwrap().x, Some(10.0));
+
+    // 确认纠正后正常移动被接受
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 20.0, "y": 0.0, "z": 0.0, "ts": 2000, "vx": 10.0, "ack": nonce})).unwrap();
+    assert!(correction_for(&out, src).is_none());
+    assert_eq!(state.world.players.get(&uuid).unwrap().x, Some(20.0));
+}
+
+#[test]
+fn test_handle_whoami_registered() {
+    let mut state = new_state();
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "pilot");
+
+    // 从另一个地址查询，不应重新绑定地址
+    let query = client_addr(40009);
+    let out = handle(&mut state, query, json!({"type": "whoami", "uuid": uuid})).unwrap();
+    assert_eq!(
+        out,
+        vec![(
+            query,
+            ServerMessage::Identity {
+                uuid,
+                username: "pilot".to_string(),
+                online: true,
+                from_storage: false,
+            }
+        )]
+    );
+    assert_eq!(state.conn_of(&uuid), Some(src));
+}
+
+#[test]
+fn test_handle_whoami_from_storage() {
+    let uuid = Uuid::new_v4();
+    let mut storage = InMemoryStore::new();
+    storage.put(PlayerRecord { uuid, username: "stored".to_string() });
+    let mut state = ServerState::new(WorldState { players: HashMap::new(), entities: HashMap::new() }, Box::new(storage));
+
+    let out = handle(&mut state, client_addr(40001), json!({"type": "whoami", "uuid": uuid})).unwrap();
+    assert!(matches!(
+        &out[0].1,
+        ServerMessage::Identity { online: false, from_storage: true, username, .. } if username == "stored"
+    ));
+    assert!(state.world.players.is_empty());
+    assert!(state.last_seen.is_empty());
+}
+
+#[test]
+fn test_handle_whoami_unknown() {
+    let mut state = new_state();
+    let uuid = Uuid::new_v4();
+    let out = handle(&mut state, client_addr(40001), json!({"type": "whoami", "uuid": uuid})).unwrap();
+    assert!(matches!(&out[0].1, ServerMessage::UuidNotFound { uuid: u, .. } if *u == uuid));
+    assert!(state.clients.is_empty());
+}
+
+#[test]
+fn test_handle_get_by_uuid() {
+    let mut state = new_state();
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "target");
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 1.0, "y": 2.0, "z": 3.0, "ts": 1000})).unwrap();
+
+    // 从其他地址查询，不会重新绑定连接
+    let asker = client_addr(40002);
+    let out = handle(&mut state, asker, json!({"type": "get", "uuid": uuid})).unwrap();
+    match &out[..] {
+        [(dst, ServerMessage::PlayerInfo { player, online: true, .. })] => {
+            assert_eq!(*dst, asker);
+            assert_eq!(player.username, "target");
+            assert_eq!((player.x, player.y, player.z), (Some(1.0), Some(2.0), Some(3.0)));
+        }
+        other => panic!("unexpected reply: {:?}", other),
+    }
+    assert_eq!(state.conn_of(&uuid), Some(src));
+
+    let missing = Uuid::new_v4();
+    let out = handle(&mut state, asker, json!({"type": "get", "uuid": missing})).unwrap();
+    assert!(matches!(&out[0].1, ServerMessage::UuidNotFound { uuid: u, .. } if *u == missing));
+}
+
+#[test]
+fn test_handle_get_by_username() {
+    let mut state = new_state();
+    let uuid = register(&mut state, client_addr(40001), "target");
+    let asker = client_addr(40002);
+    let before = state.last_seen.clone();
+
+    let out = handle(&mut state, asker, json!({"type": "get", "username": "target"})).unwrap();
+    assert!(matches!(&out[0].1, ServerMessage::PlayerInfo { player, .. } if player.uuid == uuid));
+    assert_eq!(state.last_seen, before);
+
+    let missing = handle(&mut state, asker, json!({"type": "get", "username": "nobody"}));
+    assert_eq!(missing, Err(HandlerError::UnknownUsername("nobody".to_string())));
+    let empty = handle(&mut state, asker, json!({"type": "get"}));
+    assert_eq!(empty, Err(HandlerError::InvalidField("username")));
+}
+
+#[test]
+fn test_action_cooldowns_try_use() {
+    let mut cooldowns = ActionCooldowns::new();
+    let uuid = Uuid::new_v4();
+    let other = Uuid::new_v4();
+    let t0 = Instant::now();
+    let cd = Duration::from_millis(100);
+
+    assert!(cooldowns.try_use(uuid, "firing", cd, t0));
+    assert!(!cooldowns.try_use(uuid, "firing", cd, t0 + Duration::from_millis(10)));
+    // 不同动作、不同玩家互不影响
+    assert!(cooldowns.try_use(uuid, "jump", cd, t0 + Duration::from_millis(10)));
+    assert!(cooldowns.try_use(other, "firing", cd, t0 + Duration::from_millis(10)));
+    // 冷却结束后可以再次使用
+    assert!(cooldowns.try_use(uuid, "firing", cd, t0 + Duration::from_millis(100)));
+}
+
+#[test]
+fn test_handle_update_action_cooldown_suppresses_repeat() {
+    let mut config = ServerConfig::default();
+    config
+        .action_cooldowns
+        .insert("firing".to_string(), Duration::from_millis(100));
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "gunner");
+    let t0 = Instant::now();
+
+    handle_at(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 1.0, "action": "firing"}), t0).unwrap();
+    assert_eq!(state.world.players[&uuid].action.as_deref(), Some("firing"));
+
+    // 10ms 后再次开火：动作被丢弃，但位置照常更新
+    handle_at(
+        &mut state,
+        src,
+        json!({"type": "update", "uuid": uuid, "x": 2.0, "action": "firing"}),
+        t0 + Duration::from_millis(10),
+    )
+    .unwrap();
+    assert_eq!(state.world.players[&uuid].action, None);
+    assert_eq!(state.world.players[&uuid].x, Some(2.0));
+}
+
+fn whitelist(policy: ActionPolicy) -> ServerConfig {
+    ServerConfig {
+        allowed_actions: Some(["idle", "walk", "run", "fire", "jump"].iter().map(|s| s.to_string()).collect()),
+        action_policy: policy,
+        ..ServerConfig::default()
+    }
+}
+
+#[test]
+fn test_handle_update_action_whitelist_clears_unknown() {
+    let mut state = new_state().with_config(whitelist(ActionPolicy::Clear));
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "actor");
+
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 1.0, "action": "jump"})).unwrap();
+    assert_eq!(state.world.players[&uuid].action.as_deref(), Some("jump"));
+
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 2.0, "action": "<script>"})).unwrap();
+    assert_eq!(state.world.players[&uuid].action, None);
+    assert_eq!(state.world.players[&uuid].x, Some(2.0));
+}
+
+#[test]
+fn test_handle_update_action_whitelist_rejects_unknown() {
+    let mut state = new_state().with_config(whitelist(ActionPolicy::Reject));
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "actor");
+    let result = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 2.0, "action": "dance"}));
+    assert_eq!(result, Err(HandlerError::InvalidField("action")));
+    assert_eq!(state.world.players[&uuid].x, None);
+}
+
+#[test]
+fn test_accepted_action_broadcast_separately_from_snapshot() {
+    let config = ServerConfig {
+        broadcast_actions: true,
+        ..whitelist(ActionPolicy::Clear)
+    };
+    let mut state = new_state().with_config(config);
+    let (shooter, watcher) = (client_addr(40001), client_addr(40002));
+    let uuid = register(&mut state, shooter, "shooter");
+    register(&mut state, watcher, "watcher");
+    let actions = |out: &Outgoing| -> Vec<(ClientConn, ServerMessage)> {
+        out.iter().filter(|(_, m)| matches!(m, ServerMessage::PlayerAction { .. })).cloned().collect()
+    };
+
+    let out = handle(&mut state, shooter, json!({"type": "update", "uuid": uuid, "x": 1.0, "action": "fire", "ts": 1000})).unwrap();
+    let expected = ServerMessage::PlayerAction { uuid, action: "fire".to_string(), ts: 1000 };
+    assert_eq!(actions(&out), vec![(watcher, expected)]);
+    assert!(out.iter().any(|(conn, m)| *conn == watcher && matches!(m, ServerMessage::World { .. })));
+
+    // idle 和不在白名单中的动作不单独通知
+    for action in ["idle", "dance"] {
+        let out = handle(&mut state, shooter, json!({"type": "update", "uuid": uuid, "x": 1.0, "action": action})).unwrap();
+        assert!(actions(&out).is_empty(), "{} was broadcast", action);
+    }
+}
+
+#[test]
+fn test_round_player() {
+    let player = PlayerState::new(Uuid::new_v4(), "p")
+        .with_position(1.23456, -7.891, 0.005)
+        .with_velocity(0.123, 0.0, 0.0)
+        .with_ts(1234);
+    let rounded = round_player(&player, 2);
+    assert_eq!(rounded.x, Some(1.23));
+    assert_eq!(rounded.y, Some(-7.89));
+    assert_eq!(rounded.vx, Some(0.12));
+    assert_eq!(rounded.ts, Some(1234));
+    assert_eq!(rounded.rx, None);
+}
+
+#[test]
+fn test_broadcast_coord_precision_only_on_wire() {
+    let config = ServerConfig {
+        coord_precision: Some(2),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "precise");
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 1.23456, "y": 0.0, "z": 0.0})).unwrap();
+
+    let (_, world) = out
+        .iter()
+        .find(|(_, m)| matches!(m, ServerMessage::World { .. }))
+        .unwrap();
+    let wire = serde_json::to_string(world).unwrap();
+    assert!(wire.contains("\"x\":1.23,"), "{}", wire);
+    assert!(!wire.contains("1.23456"));
+    // 权威状态保持原始精度
+    assert_eq!(state.world.players[&uuid].x, Some(1.23456));
+}
+
+#[test]
+fn test_handle_update_settling_grace_skips_validation() {
+    let config = ServerConfig {
+        settle_updates: 2,
+        settle_period: Duration::from_secs(2),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let t0 = Instant::now();
+    let uuid = register(&mut state, src, "newcomer");
+    let spawned = state.world.players[&uuid].clone().with_position(0.0, 0.0, 0.0).with_ts(0);
+    state.world.players.insert(uuid, spawned);
+
+    // 宽限期内的大跳跃被接受并记录
+    let jump = json!({"type": "update", "uuid": uuid, "x": 100.0, "y": 0.0, "z": 0.0, "ts": 1000, "vx": 0.0});
+    let out = handle_at(&mut state, src, jump, t0 + Duration::from_millis(10)).unwrap();
+    assert!(correction_for(&out, src).is_none());
+    assert_eq!(state.world.players[&uuid].x, Some(100.0));
+
+    // 宽限期（次数与时长）都过去之后照常校验
+    handle_at(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 100.0, "y": 0.0, "z": 0.0, "ts": 2000}), t0 + Duration::from_millis(20)).unwrap();
+    let cheat = json!({"type": "update", "uuid": uuid, "x": 500.0, "y": 0.0, "z": 0.0, "ts": 3000, "vx": 0.0});
+    let out = handle_at(&mut state, src, cheat, t0 + Duration::from_secs(3)).unwrap();
+    assert!(correction_for(&out, src).is_some());
+}
+
+#[test]
+fn test_tcp_frame_roundtrip() {
+    let payload = br#"{"type":"register","username":"tcp"}"#;
+    let mut wire: Vec<u8> = Vec::new();
+    write_frame(&mut wire, payload).unwrap();
+    write_frame(&mut wire, b"{}").unwrap();
+    assert_eq!(&wire[..4], &(payload.len() as u32).to_be_bytes());
+
+    let mut reader = std::io::Cursor::new(wire);
+    assert_eq!(read_frame(&mut reader, 1024).unwrap(), payload);
+    assert_eq!(read_frame(&mut reader, 1024).unwrap(), b"{}");
+    // 读完后是 EOF
+    assert_eq!(
+        read_frame(&mut reader, 1024).unwrap_err().kind(),
+        std::io::ErrorKind::UnexpectedEof
+    );
+}
+
+#[test]
+fn test_tcp_frame_too_large() {
+    let mut wire: Vec<u8> = Vec::new();
+    write_frame(&mut wire, &[b'x'; 100]).unwrap();
+    let err = read_frame(&mut std::io::Cursor::new(wire), 10).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_default_transports_udp_only() {
+    let config = ServerConfig::default();
+    assert_eq!(
+        config.transports,
+        vec![Transport::Udp(SocketAddr::from(([127, 0, 0, 1], 8888)))]
+    );
+}
+
+#[test]
+fn test_handle_tcp_client_is_distinct_from_udp() {
+    let mut state = new_state();
+    let addr = SocketAddr::from(([127, 0, 0, 1], 40001));
+    let tcp = ClientConn::Tcp(addr);
+    let uuid = register(&mut state, tcp, "tcp_pilot");
+    assert_eq!(state.conn_of(&uuid), Some(tcp));
+
+    // TCP 客户端的更新被接受，广播发回 TCP 连接
+    let out = handle(&mut state, tcp, json!({"type": "update", "uuid": uuid, "x": 1.0})).unwrap();
+    assert!(out.iter().any(|(conn, m)| *conn == tcp && matches!(m, ServerMessage::World { .. })));
+
+    // 同一 ip:port 的 UDP 来源不是同一个客户端
+    let result = handle(&mut state, ClientConn::Udp(addr), json!({"type": "update", "uuid": uuid, "x": 2.0}));
+    assert_eq!(result, Err(HandlerError::Unauthorized(uuid)));
+}
+
+#[test]
+fn test_outbound_websocket_queue() {
+    let outbound = Outbound::new(None);
+    let peer = SocketAddr::from(([127, 0, 0, 1], 40002));
+    let ws = ClientConn::Ws(peer);
+    assert_eq!(ws.to_string(), "ws://127.0.0.1:40002");
+
+    // 未登记的连接
+    assert!(outbound.send(&ws, b"{}").is_err());
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    outbound.add_ws(peer, tx);
+    outbound.send(&ws, br#"{"action":"world"}"#).unwrap();
+    assert_eq!(rx.try_recv().unwrap(), br#"{"action":"world"}"#.to_vec());
+
+    outbound.remove_ws(&peer);
+    assert!(outbound.send(&ws, b"{}").is_err());
+}
+
+#[test]
+fn test_metrics_record_handler_results() {
+    let mut state = new_state();
+    let src = client_addr(40001);
+    let mut metrics = Metrics::new();
+    let payload = json!({"type": "register", "username": "counted"}).to_string();
+    metrics.record(&handle_message(&mut state, src, payload.as_bytes(), Instant::now()));
+    metrics.record(&handle_message(&mut state, src, b"not json", Instant::now()));
+    assert_eq!(metrics.messages, 2);
+    assert_eq!(metrics.errors, 1);
+    assert_eq!(metrics.corrections, 0);
+}
+
+#[test]
+fn test_instrumented_handler_records_latency() {
+    let mut state = new_state();
+    let src = client_addr(40001);
+    for _ in 0..3 {
+        handle_message_instrumented(&mut state, src, br#"{"type": "ping"}"#, Instant::now()).unwrap();
+    }
+    let latency = &state.metrics.handler_latency;
+    assert_eq!(latency.count(), 3);
+    assert_eq!(latency.bucket_counts().iter().sum::<u64>(), 3);
+    assert!(latency.quantile(0.99).is_some());
+    assert_eq!(state.metrics.messages, 3);
+
+    let text = render_prometheus(&state.metrics, 0);
+    assert!(text.contains("# TYPE game_handler_seconds histogram"));
+    assert!(text.contains("game_handler_seconds_bucket{le=\"+Inf\"} 3"));
+    assert!(text.contains("game_handler_seconds_count 3"));
+}
+
+#[test]
+fn test_worker_pool_bounds_busy_threads_under_flood() {
+    let gauge = WorkerGauge::new();
+    let pool = std::sync::Arc::new(WorkerPool::new(2, 256, gauge.clone(), QueueGauge::new()));
+    let (done_tx, done_rx) = std::sync::mpsc::channel();
+    let senders: Vec<_> = (0..4)
+        .map(|_| {
+            let pool = pool.clone();
+            let done_tx = done_tx.clone();
+            std::thread::spawn(move || {
+                for _ in 0..50 {
+                    let done_tx = done_tx.clone();
+                    pool.execute(move || {
+                        std::thread::sleep(Duration::from_micros(200));
+                        let _ = done_tx.send(());
+                    });
+                }
+            })
+        })
+        .collect();
+    for sender in senders {
+        sender.join().unwrap();
+    }
+    for _ in 0..200 {
+        done_rx.recv_timeout(Duration::from_secs(5)).expect("job never ran");
+    }
+    assert_eq!(pool.workers(), 2);
+    assert!(gauge.peak() >= 1 && gauge.peak() <= 2, "peak workers {}", gauge.peak());
+
+    let metrics = Metrics { workers: gauge, ..Metrics::new() };
+    assert!(render_prometheus(&metrics, 0).contains("game_workers_active "));
+}
+
+#[test]
+fn test_ingest_queue_drops_newest_when_full() {
+    let queue = QueueGauge::new();
+    let pool = WorkerPool::new(1, 2, WorkerGauge::new(), queue.clone());
+    // 唯一的工作线程被第一个任务占住
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let (started_tx, started_rx) = std::sync::mpsc::channel();
+    assert!(pool.execute(move || {
+        let _ = started_tx.send(());
+        let _ = release_rx.recv();
+    }));
+    started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    let (done_tx, done_rx) = std::sync::mpsc::channel();
+    let accepted: Vec<bool> = (0..5)
+        .map(|i| {
+            let done_tx = done_tx.clone();
+            pool.execute(move || {
+                let _ = done_tx.send(i);
+            })
+        })
+        .collect();
+    assert_eq!(accepted, vec![true, true, false, false, false]);
+    assert_eq!(queue.dropped(), 3);
+    assert_eq!((queue.depth(), queue.peak()), (2, 2));
+
+    // 放行后排队的任务照常执行，之后的新任务也能进入
+    release_tx.send(()).unwrap();
+    let ran: Vec<i32> = (0..2).map(|_| done_rx.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+    assert_eq!(ran, vec![0, 1]);
+    assert!(pool.execute(move || {
+        let _ = done_tx.send(9);
+    }));
+    assert_eq!(done_rx.recv_timeout(Duration::from_secs(5)).unwrap(), 9);
+    assert_eq!(queue.depth(), 0);
+}
+
+#[test]
+fn test_server_responsive_with_tiny_ingest_queue() {
+    let server = TestServer::with_config(ServerConfig {
+        max_workers: 1,
+        ingest_capacity: 1,
+        ..ServerConfig::default()
+    });
+    let flood = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let ping = json!({"type": "ping"}).to_string();
+    for _ in 0..500 {
+        let _ = flood.send_to(ping.as_bytes(), server.addr());
+    }
+    let reply = (0..5).find_map(|_| send_and_receive(server.addr(), json!({"type": "ping"}), 1).ok());
+    assert_eq!(reply.unwrap()["action"], "pong");
+}
+
+#[test]
+fn test_latency_histogram_quantiles() {
+    let hist = LatencyHistogram::new();
+    assert_eq!(hist.quantile(0.5), None);
+    for _ in 0..98 {
+        hist.record(Duration::from_micros(80));
+    }
+    hist.record(Duration::from_millis(3));
+    hist.record(Duration::from_secs(1));
+    assert_eq!(hist.quantile(0.5), Some(Duration::from_micros(100)));
+    assert_eq!(hist.quantile(0.99), Some(Duration::from_millis(5)));
+    assert_eq!(hist.quantile(1.0), Some(Duration::MAX));
+}
+
+#[test]
+fn test_render_prometheus() {
+    let metrics = Metrics {
+        messages: 42,
+        errors: 1,
+        corrections: 3,
+        broadcasts: 7,
+        ..Metrics::new()
+    };
+    let text = render_prometheus(&metrics, 5);
+    for name in ["game_messages_total", "game_corrections_total", "game_players_online"] {
+        assert!(text.contains(&format!("# HELP {} ", name)), "{}", text);
+    }
+    assert!(text.contains("# TYPE game_messages_total counter"));
+    assert!(text.contains("# TYPE game_players_online gauge"));
+
+    // 每个样本行都是 "<名称> <数值>"
+    let samples: HashMap<&str, f64> = text
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| {
+            let (name, value) = line.split_once(' ').unwrap();
+            (name, value.parse().unwrap())
+        })
+        .collect();
+    assert_eq!(samples["game_messages_total"], 42.0);
+    assert_eq!(samples["game_corrections_total"], 3.0);
+    assert_eq!(samples["game_players_online"], 5.0);
+}
+
+#[test]
+fn test_bind_udp_sockets_share_one_port() {
+    let sockets = bind_udp_sockets(SocketAddr::from(([127, 0, 0, 1], 0)), 3).unwrap();
+    assert_eq!(sockets.len(), 3);
+    let port = sockets[0].local_addr().unwrap().port();
+    assert!(sockets.iter().all(|s| s.local_addr().unwrap().port() == port));
+    assert_ne!(port, 0);
+    assert_eq!(bind_udp_sockets(SocketAddr::from(([127, 0, 0, 1], 0)), 0).unwrap().len(), 1);
+}
+
+#[test]
+fn test_send_queue_overflow_drops_old_broadcasts_keeps_reliable() {
+    let mut queue = SendQueue::new(3);
+    assert!(queue.push(b"world1".to_vec(), Delivery::Droppable));
+    assert!(queue.push(b"correction".to_vec(), Delivery::Reliable));
+    assert!(queue.push(b"world2".to_vec(), Delivery::Droppable));
+    // 队列已满：依次挤掉最早的广播，可靠消息保留
+    assert!(queue.push(b"world3".to_vec(), Delivery::Droppable));
+    assert!(queue.push(b"world4".to_vec(), Delivery::Droppable));
+    assert_eq!(queue.len(), 3);
+    assert_eq!(queue.dropped(), 2);
+
+    let drained: Vec<Vec<u8>> = std::iter::from_fn(|| queue.pop()).collect();
+    assert_eq!(drained, vec![b"correction".to_vec(), b"world3".to_vec(), b"world4".to_vec()]);
+}
+
+#[test]
+fn test_send_queue_never_drops_reliable() {
+    let mut queue = SendQueue::new(2);
+    assert!(queue.push(b"a".to_vec(), Delivery::Reliable));
+    assert!(queue.push(b"b".to_vec(), Delivery::Reliable));
+    // 没有可丢弃的消息时，新的广播被丢弃，可靠消息超出容量也照常入队
+    assert!(!queue.push(b"world".to_vec(), Delivery::Droppable));
+    assert!(queue.push(b"c".to_vec(), Delivery::Reliable));
+    assert_eq!(queue.len(), 3);
+    assert_eq!(queue.dropped(), 1);
+}
+
+#[test]
+fn test_send_queue_latest_wins_replaces_unsent_broadcast() {
+    let mut queue = SendQueue::new(8).with_latest_wins();
+    queue.push(b"v1".to_vec(), Delivery::Latest);
+    queue.push(b"correction".to_vec(), Delivery::Reliable);
+    // v1 尚未发送时 v2 到达：只发送 v2，纠正保留原顺序
+    queue.push(b"v2".to_vec(), Delivery::Latest);
+    // 同一轮广播的后续分片不会替换第一片
+    queue.push(b"v2-part2".to_vec(), Delivery::Droppable);
+    assert_eq!(queue.dropped(), 1);
+    let drained: Vec<Vec<u8>> = std::iter::from_fn(|| queue.pop()).collect();
+    assert_eq!(drained, vec![b"correction".to_vec(), b"v2".to_vec(), b"v2-part2".to_vec()]);
+
+    // 未启用时广播照常排队
+    let mut queue = SendQueue::new(8);
+    queue.push(b"v1".to_vec(), Delivery::Latest);
+    queue.push(b"v2".to_vec(), Delivery::Latest);
+    assert_eq!(queue.len(), 2);
+}
+
+#[test]
+fn test_outbound_drops_stalled_tcp_peer_after_write_timeout() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    // 对端连上后从不读取
+    let _stalled = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, peer) = listener.accept().unwrap();
+    stream.set_write_timeout(Some(Duration::from_millis(50))).unwrap();
+    let outbound = Outbound::new(None);
+    outbound.add_tcp(peer, stream);
+    let conn = ClientConn::Tcp(peer);
+
+    // 填满内核缓冲区后写超时，连接被移除而不是一直阻塞
+    let chunk = vec![b'x'; 60 * 1024];
+    let started = Instant::now();
+    let failed = (0..2000).find_map(|_| outbound.send(&conn, &chunk).err());
+    assert!(failed.is_some(), "send never timed out");
+    assert!(started.elapsed() < Duration::from_secs(10));
+    assert_eq!(outbound.send(&conn, b"{}").unwrap_err().kind(), std::io::ErrorKind::NotConnected);
+}
+
+#[test]
+fn test_outbound_drop_stale_broadcasts() {
+    let outbound = std::sync::Arc::new(Outbound::new(None).with_send_queue(4, true));
+    let peer = SocketAddr::from(([127, 0, 0, 1], 40005));
+    let ws = ClientConn::Ws(peer);
+    let (tx, rx) = std::sync::mpsc::channel();
+    outbound.add_ws(peer, tx);
+    outbound.deliver_as(&ws, b"v1", Delivery::Latest).unwrap();
+    outbound.deliver_as(&ws, b"v2", Delivery::Latest).unwrap();
+    assert_eq!(outbound.queued(&ws), 1);
+
+    outbound.start_drain();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(2)).unwrap(), b"v2".to_vec());
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+}
+
+#[test]
+fn test_outbound_close_stops_drain_thread() {
+    let outbound = std::sync::Arc::new(Outbound::new(None).with_send_queue(4, false));
+    let handles = outbound.start_drain();
+    assert_eq!(handles.len(), 1);
+    outbound.close();
+    let (done_tx, done_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let _ = done_tx.send(());
+    });
+    done_rx.recv_timeout(Duration::from_secs(2)).expect("drain thread did not exit");
+    // 发送线程持有的引用已释放（UDP socket 随之关闭）
+    assert_eq!(std::sync::Arc::strong_count(&outbound), 1);
+}
+
+#[test]
+fn test_server_stop_joins_drain_thread() {
+    let server = TestServer::with_config(ServerConfig {
+        send_queue_capacity: Some(8),
+        ..ServerConfig::default()
+    });
+    let reply = send_and_receive(server.addr(), json!({"type": "ping"}), 1).unwrap();
+    assert_eq!(reply["action"], "pong");
+    let (done_tx, done_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        server.stop();
+        let _ = done_tx.send(());
+    });
+    done_rx.recv_timeout(Duration::from_secs(5)).expect("stop did not return");
+}
+
+#[test]
+fn test_outbound_send_queue_drains_in_order() {
+    let outbound = std::sync::Arc::new(Outbound::new(None).with_send_queue(4, false));
+    let peer = SocketAddr::from(([127, 0, 0, 1], 40004));
+    let ws = ClientConn::Ws(peer);
+    let (tx, rx) = std::sync::mpsc::channel();
+    outbound.add_ws(peer, tx);
+
+    // 发送线程启动前消息只入队
+    outbound.deliver_as(&ws, b"w1", Delivery::Droppable).unwrap();
+    outbound.deliver(&ws, b"reply").unwrap();
+    assert_eq!(outbound.queued(&ws), 2);
+    assert!(rx.try_recv().is_err());
+
+    outbound.start_drain();
+    let received: Vec<Vec<u8>> = (0..2).map(|_| rx.recv_timeout(Duration::from_secs(2)).unwrap()).collect();
+    assert_eq!(received, vec![b"w1".to_vec(), b"reply".to_vec()]);
+    assert_eq!(outbound.queued(&ws), 0);
+}
+
+#[cfg(feature = "chaos")]
+#[test]
+fn test_chaos_is_deterministic_for_seed() {
+    use backend_demo::chaos::Chaos;
+    use backend_demo::config::ChaosConfig;
+    let config = ChaosConfig {
+        drop_probability: 0.5,
+        min_latency: Duration::from_millis(5),
+        max_latency: Duration::from_millis(20),
+        seed: 7,
+    };
+    let a: Vec<_> = (0..200).map({ let c = Chaos::new(&config); move |_| c.decide() }).collect();
+    let b: Vec<_> = (0..200).map({ let c = Chaos::new(&config); move |_| c.decide() }).collect();
+    assert_eq!(a, b);
+
+    let delivered: Vec<_> = a.iter().flatten().collect();
+    assert!(delivered.len() > 60 && delivered.len() < 140);
+    assert!(delivered.iter().all(|d| **d >= config.min_latency && **d <= config.max_latency));
+}
+
+#[cfg(feature = "chaos")]
+#[test]
+fn test_outbound_deliver_drops_with_chaos() {
+    use backend_demo::chaos::Chaos;
+    use backend_demo::config::ChaosConfig;
+    let config = ChaosConfig {
+        drop_probability: 0.5,
+        seed: 42,
+        ..ChaosConfig::default()
+    };
+    let outbound = std::sync::Arc::new(Outbound::new(None).with_chaos(Chaos::new(&config)));
+    let peer = SocketAddr::from(([127, 0, 0, 1], 40003));
+    let (tx, rx) = std::sync::mpsc::channel();
+    outbound.add_ws(peer, tx);
+    for i in 0..100u8 {
+        outbound.deliver(&ClientConn::Ws(peer), &[i]).unwrap();
+    }
+
+    // 同一种子的注入器给出同样的丢包序列
+    let twin = Chaos::new(&config);
+    let expected: Vec<Vec<u8>> = (0..100u8).filter(|_| twin.decide().is_some()).map(|i| vec![i]).collect();
+    let received: Vec<Vec<u8>> = rx.try_iter().collect();
+    assert_eq!(received, expected);
+    assert!(received.len() < 100);
+}
+
+#[cfg(feature = "chaos")]
+#[test]
+fn test_chaos_delayed_sends_share_one_timer_thread() {
+    use backend_demo::chaos::Chaos;
+    use backend_demo::config::ChaosConfig;
+    let config = ChaosConfig {
+        min_latency: Duration::from_millis(30),
+        max_latency: Duration::from_millis(30),
+        ..ChaosConfig::default()
+    };
+    let outbound = std::sync::Arc::new(Outbound::new(None).with_chaos(Chaos::new(&config)));
+    let peer = SocketAddr::from(([127, 0, 0, 1], 40006));
+    let (tx, rx) = std::sync::mpsc::channel();
+    outbound.add_ws(peer, tx);
+    for i in 0..3u8 {
+        outbound.deliver(&ClientConn::Ws(peer), &[i]).unwrap();
+    }
+    // 延迟的消息只排队，由 start_drain 启动的线程到期后按顺序发出
+    std::thread::sleep(Duration::from_millis(60));
+    assert!(rx.try_recv().is_err());
+
+    let handles = outbound.start_drain();
+    assert_eq!(handles.len(), 1);
+    let received: Vec<Vec<u8>> = (0..3).map(|_| rx.recv_timeout(Duration::from_secs(2)).unwrap()).collect();
+    assert_eq!(received, vec![vec![0], vec![1], vec![2]]);
+
+    // 关闭后计时线程退出，尚未到期的消息被丢弃
+    outbound.deliver(&ClientConn::Ws(peer), &[3]).unwrap();
+    outbound.close();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(std::sync::Arc::strong_count(&outbound), 1);
+    assert!(rx.recv_timeout(Duration::from_millis(60)).is_err());
+}
+
+#[test]
+fn test_connection_limit_caps_open_connections() {
+    let limit = ConnectionLimit::new(2);
+    let first = limit.try_acquire().unwrap();
+    let second = limit.clone().try_acquire().unwrap();
+    assert_eq!(limit.open(), 2);
+    assert!(limit.try_acquire().is_none());
+
+    // 连接结束后名额归还
+    drop(first);
+    assert_eq!(limit.open(), 1);
+    let third = limit.try_acquire();
+    assert!(third.is_some());
+    drop((second, third));
+    assert_eq!(limit.open(), 0);
+}
+
+/// 取出发给 `dst` 的世界广播中的 server_ts
+fn broadcast_ts(out: &Outgoing, dst: ClientConn) -> u64 {
+    out.iter()
+        .find_map(|(conn, m)| match m {
+            ServerMessage::World { server_ts, .. } if *conn == dst => Some(*server_ts),
+            _ => None,
+        })
+        .expect("no broadcast for destination")
+}
+
+#[test]
+fn test_broadcast_server_ts_non_decreasing() {
+    let mut state = new_state();
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "clocked");
+    let first = broadcast_ts(&state.broadcast(Instant::now()), src);
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 1.0})).unwrap();
+    let second = broadcast_ts(&out, src);
+    assert!(first > 0);
+    assert!(second >= first);
+}
+
+#[test]
+fn test_handle_ping_echoes_client_ts() {
+    let mut state = new_state();
+    let src = client_addr(40001);
+    let before = backend_demo::now_millis();
+    let out = handle(&mut state, src, json!({"type": "ping", "client_ts": 12345})).unwrap();
+    match &out[..] {
+        [(conn, ServerMessage::Pong { client_ts, server_ts })] if *conn == src => {
+            assert_eq!(*client_ts, Some(12345));
+            assert!(*server_ts >= before);
+        }
+        other => panic!("unexpected reply: {:?}", other),
+    }
+}
+
+#[test]
+fn test_step_advances_by_velocity() {
+    let uuid = Uuid::new_v4();
+    let idle = Uuid::new_v4();
+    let mut world = WorldState { players: HashMap::new(), entities: HashMap::new() };
+    world.players.insert(uuid, PlayerState::new(uuid, "mover").with_position(1.0, 0.0, 0.0).with_velocity(2.0, 0.0, -1.0));
+    world.players.insert(idle, PlayerState::new(idle, "idle"));
+    for _ in 0..4 {
+        step(&mut world, Duration::from_millis(250));
+    }
+    let p = &world.players[&uuid];
+    assert!((p.x.unwrap() - 3.0).abs() < 1e-9);
+    assert!((p.z.unwrap() + 1.0).abs() < 1e-9);
+    assert_eq!(world.players[&idle].x, None);
+}
+
+#[test]
+fn test_resolve_collisions_separates_coincident_players() {
+    let (a, b, far) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+    let mut world = WorldState { players: HashMap::new(), entities: HashMap::new() };
+    world.players.insert(a, PlayerState::new(a, "a").with_position(5.0, 0.0, 5.0));
+    world.players.insert(b, PlayerState::new(b, "b").with_position(5.0, 0.0, 5.0));
+    world.players.insert(far, PlayerState::new(far, "far").with_position(50.0, 0.0, 0.0));
+
+    assert_eq!(resolve_collisions(&mut world, 0.5), vec![a, b]);
+    let (pa, pb) = (&world.players[&a], &world.players[&b]);
+    assert!((pb.x.unwrap() - pa.x.unwrap() - 1.0).abs() < 1e-9);
+    assert_eq!((pa.x, pb.x), (Some(4.5), Some(5.5)));
+    assert_eq!(world.players[&far].x, Some(50.0));
+    // 已经分开后不再移动
+    assert!(resolve_collisions(&mut world, 0.5).is_empty());
+}
+
+#[test]
+fn test_handle_update_pushes_overlapping_players_apart() {
+    let config = ServerConfig {
+        collision_radius: Some(1.0),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let (src_a, src_b) = (client_addr(40001), client_addr(40002));
+    let a = register(&mut state, src_a, "blocker");
+    let b = register(&mut state, src_b, "pusher");
+    handle(&mut state, src_a, json!({"type": "update", "uuid": a, "x": 0.0, "y": 0.0, "z": 0.0})).unwrap();
+    handle(&mut state, src_b, json!({"type": "update", "uuid": b, "x": 1.0, "y": 0.0, "z": 0.0})).unwrap();
+    let (pa, pb) = (&state.world.players[&a], &state.world.players[&b]);
+    assert!((pb.x.unwrap() - pa.x.unwrap() - 2.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_server_authoritative_ignores_client_position() {
+    let config = ServerConfig {
+        physics_mode: PhysicsMode::ServerAuthoritative,
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let t0 = Instant::now();
+    let out = handle_at(&mut state, src, json!({"type": "register", "username": "sim"}), t0).unwrap();
+    let uuid = match &out[0].1 {
+        ServerMessage::Registered { uuid, .. } => *uuid,
+        other => panic!("unexpected reply: {:?}", other),
+    };
+    // 客户端声称在 x=100：位置被忽略，只接受速度
+    let out = handle_at(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 100.0, "vx": 2.0, "vy": 0.0, "vz": 0.0}), t0).unwrap();
+    assert_eq!(correction_for(&out, src), None);
+    assert_eq!(state.world.players[&uuid].x, None);
+
+    for _ in 0..5 {
+        state.step_physics(Duration::from_millis(100), t0);
+    }
+    let p = &state.world.players[&uuid];
+    assert!((p.x.unwrap() - 1.0).abs() < 1e-9);
+    assert_eq!(p.vx, Some(2.0));
+}
+
+#[test]
+fn test_state_history_interpolates_between_samples() {
+    let mut history = StateHistory::new(Duration::from_secs(1));
+    let uuid = Uuid::new_v4();
+    history.record(uuid, 1000, (0.0, 0.0, 0.0));
+    history.record(uuid, 1100, (10.0, 0.0, -2.0));
+    assert_eq!(history.position_at(&uuid, 1050), Some((5.0, 0.0, -1.0)));
+    assert_eq!(history.position_at(&uuid, 1100), Some((10.0, 0.0, -2.0)));
+    // 超出记录范围
+    assert_eq!(history.position_at(&uuid, 1200), None);
+    assert_eq!(history.position_at(&Uuid::new_v4(), 1050), None);
+}
+
+#[test]
+fn test_state_history_drops_samples_outside_window() {
+    let mut history = StateHistory::new(Duration::from_secs(1));
+    let uuid = Uuid::new_v4();
+    history.record(uuid, 1000, (0.0, 0.0, 0.0));
+    history.record(uuid, 1500, (1.0, 0.0, 0.0));
+    history.record(uuid, 2600, (2.0, 0.0, 0.0));
+    assert_eq!(history.position_at(&uuid, 1200), None);
+    assert!(history.position_at(&uuid, 2000).is_some());
+}
+
+#[test]
+fn test_handle_update_records_history() {
+    let mut state = new_state();
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "target");
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "ts": 1000})).unwrap();
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.4, "y": 0.0, "z": 0.0, "ts": 1200})).unwrap();
+    let (x, _, _) = state.position_at(&uuid, 1100).unwrap();
+    assert!((x - 0.2).abs() < 1e-9);
+}
+
+#[test]
+fn test_handle_discover_reports_server_info() {
+    let config = ServerConfig {
+        server_name: "lan-party".to_string(),
+        discovery: true,
+        max_players: Some(16),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let t0 = Instant::now();
+    let a = client_addr(40001);
+    let later = t0 + Duration::from_secs(120);
+    handle_at(&mut state, client_addr(40002), json!({"type": "register", "username": "b"}), t0).unwrap();
+    handle_at(&mut state, a, json!({"type": "register", "username": "a"}), later).unwrap();
+
+    // 只统计在线玩家
+    let probe = client_addr(50000);
+    let out = handle_at(&mut state, probe, json!({"type": "discover"}), later).unwrap();
+    assert_eq!(
+        out,
+        vec![(
+            probe,
+            ServerMessage::ServerInfo {
+                name: "lan-party".to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                player_count: 1,
+                max_players: Some(16),
+            }
+        )]
+    );
+}
+
+#[test]
+fn test_handle_discover_disabled_by_default() {
+    let mut state = new_state();
+    let result = handle(&mut state, client_addr(50000), json!({"type": "discover"}));
+    assert_eq!(result, Err(HandlerError::UnknownType("discover".to_string())));
+}
+
+#[test]
+fn test_handle_error_invalid_utf8() {
+    let mut state = new_state();
+    let result = handle_message(&mut state, client_addr(40001), &[0xff, 0xfe, 0x00], Instant::now());
+    assert_eq!(result, Err(HandlerError::InvalidUtf8));
+}
+
+#[test]
+fn test_decode_inbound_strict_utf8() {
+    use backend_demo::codec::InboundError;
+    assert_eq!(CompactJson.decode_inbound(&[b'{', 0xff, b'}']), Err(InboundError::InvalidUtf8));
+    assert!(matches!(CompactJson.decode_inbound(b"{oops"), Err(InboundError::Malformed(_))));
+    assert_eq!(CompactJson.decode_inbound(br#"{"type":"ping"}"#), Ok(json!({"type": "ping"})));
+}
+
+#[test]
+fn test_invalid_utf8_datagram_gets_error_reply() {
+    let server = TestServer::start();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    socket.send_to(&[b'{', 0xff, 0xfe, b'}'], server.addr()).unwrap();
+    let mut buf = [0u8; 1024];
+    let (n, _) = socket.recv_from(&mut buf).expect("invalid utf-8 should not be dropped silently");
+    let reply: Value = serde_json::from_slice(&buf[..n]).unwrap();
+    assert_eq!(reply["action"], "error");
+    assert_eq!(reply["error"], "invalid_utf8");
+}
+
+#[test]
+fn test_handle_error_malformed_json() {
+    let mut state = new_state();
+    let result = handle_message(&mut state, client_addr(40001), b"{not json", Instant::now());
+    assert!(matches!(result, Err(HandlerError::MalformedJson(_))));
+}
+
+#[test]
+fn test_rfc3339_timestamps_round_trip_to_same_millis() {
+    let ts = 1_760_000_000_123u64;
+    let msg = ServerMessage::PlayerInfo {
+        player: PlayerState::new(Uuid::new_v4(), "clock").with_ts(u128::from(ts)),
+        online: true,
+        rtt_ms: None,
+    };
+    let parse = |v: &Value| chrono::DateTime::parse_from_rfc3339(v.as_str().unwrap()).unwrap().timestamp_millis() as u64;
+
+    let codec = ServerConfig { rfc3339_timestamps: true, ..ServerConfig::default() }.codec();
+    let bytes = codec.encode(&msg);
+    let wire: Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(parse(&wire["player"]["ts"]), ts);
+    assert_eq!(codec.decode(&bytes).unwrap(), msg);
+    let pong = codec.encode(&ServerMessage::Pong { client_ts: Some(5), server_ts: ts });
+    let wire: Value = serde_json::from_slice(&pong).unwrap();
+    assert_eq!(parse(&wire["server_ts"]), ts);
+    assert_eq!(wire["client_ts"], 5);
+
+    // 默认仍是毫秒数
+    let wire: Value = serde_json::from_slice(&ServerConfig::default().codec().encode(&msg)).unwrap();
+    assert_eq!(wire["player"]["ts"], ts);
+}
+
+#[test]
+fn test_dump_payload_escapes_and_truncates() {
+    assert_eq!(dump_payload(b"{\"type\":1}", 256), "{\\\"type\\\":1}");
+    assert_eq!(dump_payload(&[b'{', 0xff, 0x00, b'}'], 256), "{\\xff\\x00}");
+    assert_eq!(dump_payload(b"abcdef", 3), "abc...(+3 bytes)");
+    assert_eq!(dump_payload(b"", 3), "");
+}
+
+/// 收集 debug 日志（logger 在整个测试进程内只能安装一次）
+struct CapturedLogs(std::sync::Mutex<Vec<String>>);
+
+impl log::Log for CapturedLogs {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Debug
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static CAPTURED_LOGS: CapturedLogs = CapturedLogs(std::sync::Mutex::new(Vec::new()));
+
+#[test]
+fn test_unparseable_packet_logged_at_debug_with_length() {
+    let _ = log::set_logger(&CAPTURED_LOGS);
+    log::set_max_level(log::LevelFilter::Debug);
+    let mut state = new_state();
+    let src = client_addr(40777);
+    let payload = b"\x93\xa4type\xa4ping";
+    let result = handle_message(&mut state, src, payload, Instant::now());
+    assert!(matches!(result, Err(HandlerError::InvalidUtf8)));
+
+    let logs = CAPTURED_LOGS.0.lock().unwrap();
+    let record = logs.iter().find(|line| line.contains(&src.to_string())).expect("no debug record for the packet");
+    assert!(record.contains(&format!("({} bytes)", payload.len())));
+    assert!(record.contains("\\x93\\xa4type"));
+}
+
+#[test]
+fn test_handle_error_missing_type() {
+    let mut state = new_state();
+    let result = handle(&mut state, client_addr(40001), json!({"username": "x"}));
+    assert_eq!(result, Err(HandlerError::MissingType));
+}
+
+#[test]
+fn test_handle_error_unknown_type() {
+    let mut state = new_state();
+    let result = handle(&mut state, client_addr(40001), json!({"type": "frobnicate"}));
+    assert_eq!(result, Err(HandlerError::UnknownType("frobnicate".to_string())));
+}
+
+#[test]
+fn test_handle_error_invalid_field() {
+    let mut state = new_state();
+    let result = handle(&mut state, client_addr(40001), json!({"type": "update", "uuid": "not-a-uuid"}));
+    assert_eq!(result, Err(HandlerError::InvalidField("uuid")));
+}
+
+#[test]
+fn test_handle_error_unknown_player() {
+    let mut state = new_state();
+    let uuid = Uuid::new_v4();
+    let result = handle(&mut state, client_addr(40001), json!({"type": "update", "uuid": uuid, "x": 1.0}));
+    assert_eq!(result, Err(HandlerError::UnknownPlayer(uuid)));
+}
+
+#[test]
+fn test_handle_error_unauthorized() {
+    let mut state = new_state();
+    let uuid = register(&mut state, client_addr(40001), "victim");
+    // 另一个地址冒用广播中看到的 UUID
+    let result = handle(&mut state, client_addr(40002), json!({"type": "update", "uuid": uuid, "x": 999.0}));
+    assert_eq!(result, Err(HandlerError::Unauthorized(uuid)));
+    assert_eq!(state.world.players.get(&uuid).unwrap().x, None);
+}
+
+#[test]
+fn test_spectator_follow_receives_players_within_radius() {
+    let config = ServerConfig {
+        admin_secret: Some("s3cret".to_string()),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let mut uuids = Vec::new();
+    for (i, (name, x)) in [("leader", 0.0), ("near", 5.0), ("far", 50.0)].into_iter().enumerate() {
+        let src = client_addr(40001 + i as u16);
+        let uuid = register(&mut state, src, name);
+        handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": x, "y": 0.0, "z": 0.0})).unwrap();
+        uuids.push(uuid);
+    }
+    let (leader, near, far) = (uuids[0], uuids[1], uuids[2]);
+    let spectator = client_addr(40100);
+    let visible = |out: &Outgoing| {
+        let mut seen: Vec<Uuid> = out
+            .iter()
+            .filter(|(conn, _)| *conn == spectator)
+            .flat_map(|(_, m)| match m {
+                ServerMessage::World { players, .. } => players.keys().copied().collect::<Vec<_>>(),
+                _ => Vec::new(),
+            })
+            .collect();
+        seen.sort();
+        seen
+    };
+    let sorted = |mut v: Vec<Uuid>| {
+        v.sort();
+        v
+    };
+
+    let out = handle(&mut state, spectator, json!({"type": "spectate", "follow": leader, "radius": 10.0})).unwrap();
+    assert_eq!(visible(&out), sorted(vec![leader, near]));
+
+    // 视野跟着被跟随的玩家移动
+    let msg = json!({"type": "teleport", "secret": "s3cret", "uuid": leader, "x": 45.0, "y": 0.0, "z": 0.0});
+    let out = handle(&mut state, client_addr(40099), msg).unwrap();
+    assert_eq!(visible(&out), sorted(vec![leader, far]));
+
+    // 固定中心的视野
+    let out = handle(&mut state, spectator, json!({"type": "spectate", "center": [0.0, 0.0, 0.0], "radius": 6.0})).unwrap();
+    assert_eq!(visible(&out), vec![near]);
+    assert_eq!(
+        handle(&mut state, spectator, json!({"type": "spectate", "follow": leader, "radius": -1.0})),
+        Err(HandlerError::InvalidField("radius"))
+    );
+}
+
+#[test]
+fn test_kick_carries_configured_retry_after() {
+    let config = ServerConfig {
+        admin_secret: Some("s3cret".to_string()),
+        kick_retry_after: Duration::from_secs(300),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let (griefer, bystander, admin) = (client_addr(40001), client_addr(40002), client_addr(40099));
+    let uuid = register(&mut state, griefer, "griefer");
+    let other = register(&mut state, bystander, "bystander");
+
+    let msg = json!({"type": "kick", "secret": "s3cret", "uuid": uuid, "reason": "griefing"});
+    let out = handle(&mut state, admin, msg).unwrap();
+    let notice = ServerMessage::Kicked { uuid, reason: "griefing".to_string(), retry_after_secs: 300 };
+    assert!(out.contains(&(griefer, notice.clone())));
+    assert!(out.contains(&(admin, notice)));
+    assert!(out.contains(&(bystander, ServerMessage::PlayerOffline { uuid })));
+    let wire: Value = serde_json::from_slice(&CompactJson.encode(&out[0].1)).unwrap();
+    assert_eq!(wire["retry_after_secs"], 300);
+
+    // 被踢的玩家立即离线、不再收到广播
+    assert!(!state.is_online(&uuid, Instant::now()));
+    let out = handle(&mut state, bystander, json!({"type": "update", "uuid": other, "x": 1.0, "y": 0.0, "z": 0.0})).unwrap();
+    assert!(out.iter().all(|(conn, _)| *conn != griefer));
+}
+
+#[test]
+fn test_import_world_adds_players_to_broadcast() {
+    let config = ServerConfig {
+        admin_secret: Some("s3cret".to_string()),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let (watcher, admin) = (client_addr(40001), client_addr(40099));
+    let watcher_uuid = register(&mut state, watcher, "watcher");
+    let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+    let world = json!({"players": {
+        a.to_string(): {"uuid": a, "username": "alpha", "x": 1.0, "y": 0.0, "z": 0.0},
+        b.to_string(): {"uuid": b, "username": "beta", "x": -4.0, "y": 2.0, "z": 0.5},
+    }});
+
+    // 无法解析的世界整体拒绝
+    let bad = json!({"type": "import", "secret": "s3cret", "world": {"players": [1, 2]}});
+    assert!(matches!(handle(&mut state, admin, bad), Err(HandlerError::Malformed(f)) if f.field == "world"));
+    let clash = json!({"type": "import", "secret": "s3cret", "world": {"players": {
+        a.to_string(): {"uuid": a, "username": "watcher"},
+    }}});
+    assert!(matches!(handle(&mut state, admin, clash), Err(HandlerError::Malformed(_))));
+    assert_eq!(state.world.players.len(), 1);
+
+    let msg = json!({"type": "import", "secret": "s3cret", "world": world.clone()});
+    let out = handle(&mut state, admin, msg).unwrap();
+    assert_eq!(out[0], (admin, ServerMessage::WorldImported { players: 2, removed: 0 }));
+    let players = out
+        .into_iter()
+        .find_map(|(conn, m)| match m {
+            ServerMessage::World { players, .. } if conn == watcher => Some(players.into_map()),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(players.len(), 3);
+    assert_eq!(players[&b].x, Some(-4.0));
+    assert_eq!(state.username_map["alpha"], a);
+
+    // replace 移除不在导入世界中的玩家
+    let msg = json!({"type": "import", "secret": "s3cret", "mode": "replace", "world": world});
+    let out = handle(&mut state, admin, msg).unwrap();
+    assert_eq!(out[0], (admin, ServerMessage::WorldImported { players: 2, removed: 1 }));
+    assert!(!state.world.players.contains_key(&watcher_uuid));
+    assert!(!state.username_map.contains_key("watcher"));
+}
+
+#[test]
+fn test_import_rejects_players_keyed_by_another_uuid() {
+    let config = ServerConfig {
+        admin_secret: Some("s3cret".to_string()),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+    let msg = json!({"type": "import", "secret": "s3cret", "world": {"players": {
+        a.to_string(): {"uuid": b, "username": "alpha"},
+    }}});
+    let result = handle(&mut state, client_addr(40099), msg);
+    assert!(matches!(result, Err(HandlerError::Malformed(f)) if f.field == "world"));
+    assert!(state.world.players.is_empty());
+    assert!(state.username_map.is_empty());
+}
+
+#[test]
+fn test_import_resets_per_player_state() {
+    let config = ServerConfig {
+        admin_secret: Some("s3cret".to_string()),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "mover");
+    state.pending_correction.insert(uuid, 7);
+    state.rtt.record(uuid, Duration::from_millis(300));
+    state.quarantined.insert(uuid);
+
+    let msg = json!({"type": "import", "secret": "s3cret", "world": {"players": {
+        uuid.to_string(): {"uuid": uuid, "username": "mover", "x": 50.0, "y": 0.0, "z": 0.0},
+    }}});
+    handle(&mut state, client_addr(40099), msg).unwrap();
+    // 导入的位置重新开始校验：旧的纠正、RTT 和隔离状态不再适用
+    assert!(!state.pending_correction.contains_key(&uuid));
+    assert_eq!(state.rtt.get(&uuid), None);
+    assert!(!state.quarantined.contains(&uuid));
+    assert_eq!(state.conn_of(&uuid), Some(src));
+}
+
+#[test]
+fn test_import_replace_kicks_connected_players() {
+    let config = ServerConfig {
+        admin_secret: Some("s3cret".to_string()),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let (watcher, admin) = (client_addr(40001), client_addr(40099));
+    let watcher_uuid = register(&mut state, watcher, "watcher");
+    let a = Uuid::from_u128(1);
+    let msg = json!({"type": "import", "secret": "s3cret", "mode": "replace", "world": {"players": {
+        a.to_string(): {"uuid": a, "username": "alpha"},
+    }}});
+    let out = handle(&mut state, admin, msg).unwrap();
+    assert_eq!(out[0], (admin, ServerMessage::WorldImported { players: 1, removed: 1 }));
+    assert!(out.contains(&(
+        watcher,
+        ServerMessage::Kicked {
+            uuid: watcher_uuid,
+            reason: "world_replaced".to_string(),
+            retry_after_secs: state.config.kick_retry_after.as_secs(),
+        }
+    )));
+
+    // 连接不再绑定到已移除的玩家
+    assert_eq!(state.conn_of(&watcher_uuid), None);
+    assert!(!state.registered_by_conn.contains_key(&watcher));
+    let result = handle(&mut state, watcher, json!({"type": "update", "uuid": watcher_uuid, "x": 1.0}));
+    assert_eq!(result, Err(HandlerError::UnknownPlayer(watcher_uuid)));
+}
+
+#[test]
+fn test_config_query_exposes_prediction_params_but_not_secret() {
+    let config = ServerConfig {
+        admin_secret: Some("s3cret".to_string()),
+        movement: MovementConfig {
+            tolerance: 0.75,
+            max_speed_y: Some(8.0),
+            grid: Some(0.5),
+            ..MovementConfig::default()
+        },
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let out = handle(&mut state, src, json!({"type": "config"})).unwrap();
+    assert_eq!(out.len(), 1);
+    assert!(matches!(
+        out[0],
+        (dst, ServerMessage::ServerConfigInfo { tolerance, max_speed_x: None, max_speed_y: Some(8.0), grid: Some(0.5), .. })
+            if dst == src && tolerance == 0.75
+    ));
+    let wire = String::from_utf8(CompactJson.encode(&out[0].1)).unwrap();
+    assert!(wire.contains("\"action\":\"server_config_info\""));
+    assert!(!wire.contains("s3cret"));
+    assert!(!wire.contains("secret"));
+}
+
+#[test]
+fn test_handle_teleport_requires_secret() {
+    let config = ServerConfig {
+        admin_secret: Some("s3cret".to_string()),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let uuid = register(&mut state, client_addr(40001), "stuck");
+    let msg = json!({"type": "teleport", "secret": "guess", "uuid": uuid, "x": 1.0, "y": 2.0, "z": 3.0});
+    assert_eq!(handle(&mut state, client_addr(40002), msg), Err(HandlerError::Forbidden));
+
+    // 未配置密钥时管理消息一律拒绝
+    let mut state = new_state();
+    let uuid = register(&mut state, client_addr(40001), "stuck");
+    let msg = json!({"type": "teleport", "uuid": uuid, "x": 1.0, "y": 2.0, "z": 3.0});
+    assert_eq!(handle(&mut state, client_addr(40002), msg), Err(HandlerError::Forbidden));
+}
+
+#[test]
+fn test_handle_teleport_skips_next_movement_check() {
+    let config = ServerConfig {
+        admin_secret: Some("s3cret".to_string()),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "traveller");
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "ts": 1000})).unwrap();
+
+    let out = handle(
+        &mut state,
+        client_addr(40099),
+        json!({"type": "teleport", "secret": "s3cret", "uuid": uuid, "x": 500.0, "y": 0.0, "z": 0.0}),
+    )
+    .unwrap();
+    assert!(out.iter().any(|(conn, m)| *conn == src && matches!(m, ServerMessage::World { .. })));
+    let player = &state.world.players[&uuid];
+    assert_eq!(player.x, Some(500.0));
+    assert!(player.ts.unwrap() > 1000);
+
+    // 玩家随后照常上报新位置：不应被当作作弊纠正
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 500.5, "y": 0.0, "z": 0.0, "ts": 1100})).unwrap();
+    assert_eq!(correction_for(&out, src), None);
+    assert_eq!(state.world.players[&uuid].x, Some(500.5));
+
+    // 豁免只生效一次
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 900.0, "y": 0.0, "z": 0.0, "ts": 1200})).unwrap();
+    assert!(correction_for(&out, src).is_some());
+}
+
+#[test]
+fn test_trusted_client_skips_movement_check() {
+    let config = ServerConfig {
+        admin_secret: Some("s3cret".to_string()),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let (bot_src, player_src) = (client_addr(40001), client_addr(40002));
+    let bot = register(&mut state, bot_src, "cinematic");
+    let player = register(&mut state, player_src, "regular");
+
+    let out = handle(&mut state, client_addr(40099), json!({"type": "trust", "secret": "s3cret", "uuid": bot})).unwrap();
+    assert_eq!(out, vec![(client_addr(40099), ServerMessage::TrustChanged { uuid: bot, trusted: true })]);
+
+    for (src, uuid) in [(bot_src, bot), (player_src, player)] {
+        handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "ts": 1000})).unwrap();
+    }
+    // 同样的瞬移：受信任的被接受，普通玩家被纠正
+    let out = handle(&mut state, bot_src, json!({"type": "update", "uuid": bot, "x": 500.0, "y": 0.0, "z": 0.0, "ts": 1100})).unwrap();
+    assert_eq!(correction_for(&out, bot_src), None);
+    assert_eq!(state.world.players[&bot].x, Some(500.0));
+    let out = handle(&mut state, player_src, json!({"type": "update", "uuid": player, "x": 500.0, "y": 0.0, "z": 0.0, "ts": 1100})).unwrap();
+    assert!(correction_for(&out, player_src).is_some());
+    assert_eq!(state.world.players[&player].x, Some(0.0));
+
+    // 撤销后恢复校验
+    handle(&mut state, client_addr(40099), json!({"type": "trust", "secret": "s3cret", "uuid": bot, "trusted": false})).unwrap();
+    let out = handle(&mut state, bot_src, json!({"type": "update", "uuid": bot, "x": 900.0, "y": 0.0, "z": 0.0, "ts": 1200})).unwrap();
+    assert!(correction_for(&out, bot_src).is_some());
+}
+
+#[test]
+fn test_trusted_clients_from_config() {
+    let mut state = new_state();
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "replay");
+    let config = ServerConfig {
+        trusted_clients: HashSet::from([uuid]),
+        ..ServerConfig::default()
+    };
+    let mut state = state.with_config(config);
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "ts": 1000})).unwrap();
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 500.0, "y": 0.0, "z": 0.0, "ts": 1100})).unwrap();
+    assert_eq!(correction_for(&out, src), None);
+    // 管理消息仍然需要密钥
+    let msg = json!({"type": "trust", "uuid": uuid, "trusted": false});
+    assert_eq!(handle(&mut state, src, msg), Err(HandlerError::Forbidden));
+}
+
+#[test]
+fn test_quarantined_player_broadcast_position_freezes() {
+    let config = ServerConfig {
+        admin_secret: Some("s3cret".to_string()),
+        quarantine_after: Some(2),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "suspect");
+    handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "ts": 1000})).unwrap();
+
+    // 两次违规后被隔离
+    let mut nonce = None;
+    for ts in [1100, 1200] {
+        let mut msg = json!({"type": "update", "uuid": uuid, "x": 100.0, "y": 0.0, "z": 0.0, "ts": ts});
+        if let Some(n) = nonce {
+            msg["ack"] = json!(n);
+        }
+        let out = handle(&mut state, src, msg).unwrap();
+        nonce = correction_for(&out, src).map(|(_, n)| n);
+        assert!(nonce.is_some());
+    }
+    assert!(state.quarantined.contains(&uuid));
+
+    // 之后的移动不再纠正，广播中的位置停在最后的合法位置
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 300.0, "y": 0.0, "z": 0.0, "vx": 5.0, "ts": 1300})).unwrap();
+    assert_eq!(correction_for(&out, src), None);
+    let broadcast = out.iter().find_map(|(_, m)| match m {
+        ServerMessage::World { players, .. } => Some(players[&uuid].clone()),
+        _ => None,
+    });
+    let broadcast = broadcast.unwrap();
+    assert_eq!(broadcast.x, Some(0.0));
+    assert_eq!(broadcast.vx, Some(0.0));
+    assert_eq!(state.shadow[&uuid].x, Some(300.0));
+
+    // 管理员可以查看影子状态并解除隔离
+    let admin = client_addr(40099);
+    let out = handle(&mut state, admin, json!({"type": "quarantine", "secret": "s3cret", "uuid": uuid})).unwrap();
+    match &out[0].1 {
+        ServerMessage::Quarantine { quarantined, violations, claimed, .. } => {
+            assert!(*quarantined);
+            assert_eq!(*violations, 2);
+            assert_eq!(claimed.as_ref().and_then(|p| p.x), Some(300.0));
+        }
+        other => panic!("unexpected reply {:?}", other),
+    }
+    handle(&mut state, admin, json!({"type": "quarantine", "secret": "s3cret", "uuid": uuid, "release": true})).unwrap();
+    let out = handle(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.2, "y": 0.0, "z": 0.0, "ts": 1400})).unwrap();
+    assert_eq!(correction_for(&out, src), None);
+    assert_eq!(state.world.players[&uuid].x, Some(0.2));
+}
+
+#[test]
+fn test_handle_reset_clears_world_and_notifies_clients() {
+    let config = ServerConfig {
+        admin_secret: Some("s3cret".to_string()),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let (a, b) = (client_addr(40001), client_addr(40002));
+    let alice = register(&mut state, a, "alice");
+    register(&mut state, b, "bob");
+    let watcher = client_addr(40003);
+    handle(&mut state, watcher, json!({"type": "spectate", "follow": alice, "radius": 10.0})).unwrap();
+
+    let denied = handle(&mut state, a, json!({"type": "reset", "secret": "guess"}));
+    assert_eq!(denied, Err(HandlerError::Forbidden));
+    assert_eq!(state.world.players.len(), 2);
+
+    let out = handle(&mut state, a, json!({"type": "reset", "secret": "s3cret"})).unwrap();
+    assert_eq!(out.len(), 3);
+    assert!(state.spectators.is_empty());
+    for dst in [a, b, watcher] {
+        assert!(out.contains(&(dst, ServerMessage::WorldReset { storage_cleared: false })));
+    }
+    assert!(state.world.players.is_empty());
+    assert!(state.clients.is_empty());
+    assert!(state.username_map.is_empty());
+    assert!(state.last_seen.is_empty());
+    // 默认保留身份记录
+    assert!(state.storage.contains(&alice));
+}
+
+#[test]
+fn test_handle_reset_can_clear_storage() {
+    let config = ServerConfig {
+        admin_secret: Some("s3cret".to_string()),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "alice");
+
+    let msg = json!({"type": "reset", "secret": "s3cret", "clear_storage": true});
+    let out = handle(&mut state, src, msg).unwrap();
+    assert_eq!(out, vec![(src, ServerMessage::WorldReset { storage_cleared: true })]);
+    assert!(!state.storage.contains(&uuid));
+    assert_eq!(state.online_players(Instant::now()).len(), 0);
+}
+
+fn update_at_ts(ts: u128) -> PlayerUpdate {
+    PlayerUpdate {
+        ts: Some(ts),
+        ..PlayerUpdate::default()
+    }
+}
+
+#[test]
+fn test_jitter_buffer_reorders_by_ts() {
+    let mut buffer = JitterBuffer::new(Duration::from_millis(30), 4);
+    let t0 = Instant::now();
+    buffer.push(update_at_ts(200), t0);
+    buffer.push(update_at_ts(100), t0 + Duration::from_millis(5));
+
+    assert!(buffer.drain_ready(t0 + Duration::from_millis(10)).is_empty());
+    let ready: Vec<_> = buffer
+        .drain_ready(t0 + Duration::from_millis(40))
+        .into_iter()
+        .map(|u| u.ts)
+        .collect();
+    assert_eq!(ready, vec![Some(100), Some(200)]);
+    assert!(buffer.is_empty());
+}
+
+#[test]
+fn test_jitter_buffer_depth_forces_release() {
+    let mut buffer = JitterBuffer::new(Duration::from_secs(1), 2);
+    let t0 = Instant::now();
+    buffer.push(update_at_ts(30), t0);
+    buffer.push(update_at_ts(10), t0);
+    buffer.push(update_at_ts(20), t0);
+    let ready: Vec<_> = buffer.drain_ready(t0).into_iter().map(|u| u.ts).collect();
+    assert_eq!(ready, vec![Some(10)]);
+}
+
+#[test]
+fn test_handle_update_jitter_window_applies_in_ts_order() {
+    let config = ServerConfig {
+        jitter_window: Duration::from_millis(30),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "jittery");
+    let t0 = Instant::now();
+
+    // ts=1100 的更新先于 ts=1050 到达
+    let out = handle_at(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 1.0, "y": 0.0, "z": 0.0, "ts": 1100}), t0).unwrap();
+    assert!(out.is_empty());
+    handle_at(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.5, "y": 0.0, "z": 0.0, "ts": 1050}), t0).unwrap();
+    assert_eq!(state.world.players[&uuid].x, None);
+
+    let out = state.flush_jitter(t0 + Duration::from_millis(30));
+    assert_eq!(correction_for(&out, src), None);
+    assert_eq!(state.world.players[&uuid].x, Some(1.0));
+    assert_eq!(state.world.players[&uuid].ts, Some(1100));
+}
+
+#[test]
+fn test_handle_update_coalesces_updates_within_tick() {
+    let config = ServerConfig {
+        coalesce_interval: Duration::from_millis(50),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "bursty");
+    let t0 = Instant::now();
+    handle_at(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "vx": 3.0, "ts": 1000}), t0).unwrap();
+    state.flush_coalesced(t0);
+
+    for (x, ts) in [(0.3, 1100), (0.6, 1200), (0.9, 1300)] {
+        let msg = json!({"type": "update", "uuid": uuid, "x": x, "y": 0.0, "z": 0.0, "vx": 3.0, "ts": ts});
+        assert!(handle_at(&mut state, src, msg, t0).unwrap().is_empty());
+    }
+    assert_eq!(state.world.players[&uuid].x, Some(0.0));
+
+    let out = state.flush_coalesced(t0 + Duration::from_millis(50));
+    // 只校验一次（相对上一次应用的状态）并只广播一次
+    assert_eq!(out.len(), 1);
+    assert!(matches!(out[0].1, ServerMessage::World { .. }));
+    assert_eq!(state.world.players[&uuid].x, Some(0.9));
+    assert_eq!(state.world.players[&uuid].ts, Some(1300));
+    assert!(state.flush_coalesced(t0 + Duration::from_millis(100)).is_empty());
+}
+
+#[test]
+fn test_handle_update_coalescing_keeps_newest_ts() {
+    let config = ServerConfig {
+        coalesce_interval: Duration::from_millis(50),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "reordered");
+    let t0 = Instant::now();
+    handle_at(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 2.0, "y": 0.0, "z": 0.0, "ts": 1200}), t0).unwrap();
+    handle_at(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 1.0, "y": 0.0, "z": 0.0, "ts": 1100}), t0).unwrap();
+
+    state.flush_coalesced(t0);
+    assert_eq!(state.world.players[&uuid].x, Some(2.0));
+    assert_eq!(state.world.players[&uuid].ts, Some(1200));
+}
+
+#[test]
+fn test_tick_drains_queued_inputs_and_validates_them() {
+    let config = ServerConfig {
+        tick_hz: Some(20),
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    let uuid = register(&mut state, src, "ticked");
+    let t0 = Instant::now();
+    let dt = state.config.tick_interval().unwrap();
+    assert_eq!(dt, Duration::from_millis(50));
+
+    let msg = json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "ts": 1000});
+    assert!(handle_at(&mut state, src, msg, t0).unwrap().is_empty());
+    let msg = json!({"type": "update", "uuid": uuid, "x": 0.1, "y": 0.0, "z": 0.0, "ts": 1100});
+    assert!(handle_at(&mut state, src, msg, t0).unwrap().is_empty());
+    assert_eq!(state.world.players[&uuid].x, None);
+
+    // 两条输入按到达顺序应用，只广播一次
+    let out = state.tick(dt, t0);
+    assert_eq!(out.len(), 1);
+    assert!(matches!(out[0].1, ServerMessage::World { .. }));
+    assert_eq!(state.world.players[&uuid].x, Some(0.1));
+    assert!(state.tick(dt, t0 + dt).is_empty());
+
+    // 移动校验在周期内进行
+    let msg = json!({"type": "update", "uuid": uuid, "x": 500.0, "y": 0.0, "z": 0.0, "ts": 1200});
+    handle_at(&mut state, src, msg, t0 + dt).unwrap();
+    let out = state.tick(dt, t0 + dt * 2);
+    assert!(correction_for(&out, src).is_some());
+    assert_eq!(state.world.players[&uuid].x, Some(0.1));
+}
+
+/// 按顺序记录回调的观察者
+#[derive(Default)]
+struct RecordingObserver {
+    events: std::sync::Mutex<Vec<String>>,
+}
+
+impl ServerObserver for RecordingObserver {
+    fn on_join(&self, _uuid: Uuid, username: &str, _conn: ClientConn, resumed: bool) {
+        self.events.lock().unwrap().push(format!("join:{}:{}", username, resumed));
+    }
+    fn on_update(&self, player: &PlayerState) {
+        self.events.lock().unwrap().push(format!("update:{}", player.username));
+    }
+    fn on_violation(&self, _uuid: Uuid, reason: &str) {
+        self.events.lock().unwrap().push(format!("violation:{}", reason));
+    }
+    fn on_leave(&self, _uuid: Uuid, username: &str, reason: &str) {
+        self.events.lock().unwrap().push(format!("leave:{}:{}", username, reason));
+    }
+}
+
+#[test]
+fn test_observer_register_update_leave() {
+    let observer = std::sync::Arc::new(RecordingObserver::default());
+    let mut state = new_state().with_observer(observer.clone());
+    let src = client_addr(40001);
+    let t0 = Instant::now();
+
+    let uuid = register(&mut state, src, "watched");
+    handle_at(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 0.0, "y": 0.0, "z": 0.0, "ts": 1000}), t0).unwrap();
+    handle_at(&mut state, src, json!({"type": "update", "uuid": uuid, "x": 900.0, "y": 0.0, "z": 0.0, "ts": 1100}), t0).unwrap();
+
+    let mut notified = HashSet::new();
+    let out = state.expire_inactive(&mut notified, t0 + Duration::from_secs(61));
+    assert!(matches!(out.as_slice(), [(conn, ServerMessage::Offline { .. })] if *conn == src));
+    // 已通知过的玩家不会重复触发
+    assert!(state.expire_inactive(&mut notified, t0 + Duration::from_secs(62)).is_empty());
+
+    assert_eq!(
+        *observer.events.lock().unwrap(),
+        vec![
+            "join:watched:false",
+            "update:watched",
+            "violation:invalid_movement",
+            "update:watched",
+            "leave:watched:inactivity",
+        ]
+    );
+}
+
+#[test]
+fn test_handle_error_payload_too_large() {
+    let config = ServerConfig {
+        max_recv_bytes: 64,
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let chat = "x".repeat(100);
+    let payload = json!({"type": "register", "username": chat}).to_string();
+    let result = handle_message(&mut state, client_addr(40001), payload.as_bytes(), Instant::now());
+    let err = result.unwrap_err();
+    assert_eq!(err, HandlerError::PayloadTooLarge { size: payload.len(), limit: 64 });
+    assert_eq!(err.to_reply(), ServerMessage::PayloadTooLarge { size: payload.len(), limit: 64 });
+    assert!(state.world.players.is_empty());
+}
+
+#[test]
+fn test_handler_error_reply() {
+    let reply = HandlerError::UnknownPlayer(Uuid::nil()).to_reply();
+    match reply {
+        ServerMessage::Error { error, message } => {
+            assert_eq!(error, "unknown_player");
+            assert!(message.contains(&Uuid::nil().to_string()));
+        }
+        other => panic!("unexpected reply: {:?}", other),
+    }
+}
+
+#[test]
+fn test_unknown_type_reply_lists_supported_types() {
+    let mut state = new_state();
+    let err = handle(&mut state, client_addr(40001), json!({"type": "frobnicate"})).unwrap_err();
+    match err.to_reply() {
+        ServerMessage::UnknownType { received, supported } => {
+            assert_eq!(received.as_deref(), Some("frobnicate"));
+            for t in ["register", "update", "ping", "get", "whoami"] {
+                assert!(supported.iter().any(|s| s == t), "missing {}", t);
+            }
+        }
+        other => panic!("unexpected reply: {:?}", other),
+    }
+
+    // 缺少 type 时同样回复，不带 received
+    let err = handle(&mut state, client_addr(40001), json!({"username": "x"})).unwrap_err();
+    let v = serde_json::to_value(err.to_reply()).unwrap();
+    assert_eq!(v["action"], "unknown_type");
+    assert!(v.get("received").is_none());
+    assert!(v["supported"].as_array().is_some_and(|a| !a.is_empty()));
+}
+
+// ============================================================================
+// 消息编解码测试
+// ============================================================================
+
+fn sample_correction() -> ServerMessage {
+    ServerMessage::Correction {
+        reason: "invalid_movement".to_string(),
+        nonce: 42,
+        corrected: CorrectedState {
+            uuid: Uuid::new_v4(),
+            username: "runner".to_string(),
+            x: Some(1.5),
+            y: Some(0.0),
+            z: None,
+            vx: Some(0.25),
+            vy: None,
+            vz: None,
+            ts: Some(1_700_000_000_000),
+        },
+        message: "Movement rejected".to_string(),
+        seq: Some(17),
+    }
+}
+
+#[test]
+fn test_codec_compact_json_roundtrip() {
+    let msg = sample_correction();
+    let bytes = CompactJson.encode(&msg);
+    assert!(!bytes.contains(&b'\n'));
+    assert_eq!(CompactJson.decode(&bytes).unwrap(), msg);
+}
+
+#[test]
+fn test_codec_pretty_json_roundtrip() {
+    let msg = sample_correction();
+    let bytes = PrettyJson.encode(&msg);
+    assert!(bytes.contains(&b'\n'));
+    assert_eq!(PrettyJson.decode(&bytes).unwrap(), msg);
+}
+
+#[cfg(feature = "msgpack")]
+#[test]
+fn test_codec_msgpack_roundtrip() {
+    use backend_demo::codec::MessagePack;
+    let msg = sample_correction();
+    let bytes = MessagePack.encode(&msg);
+    assert_eq!(MessagePack.decode(&bytes).unwrap(), msg);
+}
+
+#[cfg(feature = "msgpack")]
+#[test]
+fn test_msgpack_inbound_skips_utf8_check() {
+    use backend_demo::codec::MessagePack;
+    let config = ServerConfig {
+        wire_format: backend_demo::codec::WireFormat::MessagePack,
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let payload = rmp_serde::to_vec_named(&json!({"type": "register", "username": "binary"})).unwrap();
+    assert_eq!(payload[0] & 0xf0, 0x80);
+    let out = handle_message(&mut state, client_addr(40001), &payload, Instant::now()).unwrap();
+    assert!(matches!(out[0].1, ServerMessage::Registered { .. }));
+    // JSON 数据包在二进制模式下仍然可用
+    assert_eq!(MessagePack.decode_inbound(br#"{"type":"ping"}"#), Ok(json!({"type": "ping"})));
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn test_compressed_broadcast_roundtrip() {
+    use backend_demo::codec::COMPRESSED_MARKER;
+    let config = ServerConfig {
+        compress_broadcasts: true,
+        compress_threshold_bytes: 256,
+        ..ServerConfig::default()
+    };
+    let mut state = new_state().with_config(config);
+    let src = client_addr(40001);
+    for i in 0..20 {
+        let uuid = register(&mut state, client_addr(40100 + i), &format!("crowd{}", i));
+        handle(&mut state, client_addr(40100 + i), json!({"type": "update", "uuid": uuid, "x": i as f64, "y": 0.0, "z": 0.0})).unwrap();
+    }
+    register(&mut state, src, "viewer");
+    let codec = state.config.codec();
+
+    let (_, world) = state.broadcast(Instant::now()).into_iter().find(|(conn, _)| *conn == src).unwrap();
+    let plain = CompactJson.encode(&world);
+    let bytes = codec.encode(&world);
+    assert_eq!(bytes[0], COMPRESSED_MARKER);
+    assert!(bytes.len() < plain.len());
+    let decoded = codec.decode(&bytes).unwrap();
+    match (&decoded, &world) {
+        (ServerMessage::World { players: a, .. }, ServerMessage::World { players: b, .. }) => {
+            assert_eq!(a.len(), 21);
+            assert_eq!(a, b);
+        }
+        other => panic!("unexpected messages {:?}", other),
+    }
+
+    // 小消息和非广播消息不压缩
+    let msg = sample_correction();
+    assert_eq!(codec.encode(&msg), CompactJson.encode(&msg));
+    assert_eq!(codec.decode(&codec.encode(&msg)).unwrap(), msg);
+}
+
+// ============================================================================
+// UUID 恢复逻辑集成测试
+// ============================================================================
+
+/// 进程内测试服务器：UDP 绑定在临时端口上，使用内存存储，不读写世界状态文件
+struct TestServer {
+    handle: Option<ServerHandle>,
+    addr: SocketAddr,
+}
+
+impl TestServer {
+    fn start() -> Self {
+        Self::with_config(ServerConfig::default())
+    }
+
+    fn with_config(config: ServerConfig) -> Self {
+        let config = ServerConfig {
+            transports: vec![Transport::Udp(SocketAddr::from(([127, 0, 0, 1], 0)))],
+            world_path: None,
+            ..config
+        };
+        let handle = start_server(config, Box::new(InMemoryStore::new()), std::sync::Arc::new(NoopObserver))
+            .expect("test server failed to start");
+        let addr = handle.udp_addr().unwrap();
+        TestServer { handle: Some(handle), addr }
+    }
+
+    fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn metrics(&self) -> Metrics {
+        self.handle.as_ref().unwrap().metrics()
+    }
+
+    fn stop(mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.stop();
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.stop();
+        }
+    }
+}
+
+#[test]
+fn test_test_server_stop_releases_port() {
+    let server = TestServer::start();
+    let addr = server.addr();
+    assert_ne!(addr.port(), 0);
+    assert!(send_and_receive(addr, json!({"type": "ping"}), 2).is_ok());
+    server.stop();
+    // 接收循环已退出，端口可以重新绑定
+    assert!(UdpSocket::bind(addr).is_ok());
+}
+
+#[test]
+fn test_idle_udp_loop_wakes_for_packets_and_shutdown() {
+    let server = TestServer::with_config(ServerConfig {
+        udp_recv_timeout: Duration::from_millis(20),
+        ..ServerConfig::default()
+    });
+    // 空闲一段时间后的第一个数据包照常处理
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(send_and_receive(server.addr(), json!({"type": "ping"}), 2).is_ok());
+    let started = Instant::now();
+    server.stop();
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn test_stop_notifies_connected_clients() {
+    let server = TestServer::with_config(ServerConfig {
+        shutdown_reconnect_after: Duration::from_secs(3),
+        ..ServerConfig::default()
+    });
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    socket.send_to(json!({"type": "register", "username": "stayer"}).to_string().as_bytes(), server.addr()).unwrap();
+    let mut buf = [0u8; 4096];
+    let (n, _) = socket.recv_from(&mut buf).unwrap();
+    let reply: Value = serde_json::from_slice(&buf[..n]).unwrap();
+    assert_eq!(reply["action"], "registered");
+
+    server.stop();
+    // 停机前的广播可能还在路上，找到停机通知为止
+    let notice = std::iter::from_fn(|| {
+        let (n, _) = socket.recv_from(&mut buf).ok()?;
+        serde_json::from_slice::<Value>(&buf[..n]).ok()
+    })
+    .find(|msg| msg["action"] == "server_shutdown")
+        .expect("no shutdown notice");
+    assert_eq!(notice["reason"], "shutdown");
+    assert_eq!(notice["retry_after_secs"], 3);
+}
+
+/// `clients` 个客户端在 `duration` 内不停向 `server` 发送 ping，返回服务器回复的 pong 总数
+fn ping_throughput(server: &TestServer, clients: usize, duration: Duration) -> usize {
+    let addr = server.addr();
+    let workers: Vec<_> = (0..clients)
+        .map(|_| {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+            let sender = socket.try_clone().unwrap();
+            std::thread::spawn(move || {
+                let ping = json!({"type": "ping"}).to_string();
+                let deadline = Instant::now() + duration;
+                while Instant::now() < deadline {
+                    let _ = sender.send_to(ping.as_bytes(), addr);
+                    std::thread::sleep(Duration::from_micros(200));
+                }
+            });
+            std::thread::spawn(move || {
+                let mut buf = [0u8; 512];
+                let mut received = 0;
+                while socket.recv_from(&mut buf).is_ok() {
+                    received += 1;
+                }
+                received
+            })
+        })
+        .collect();
+    workers.into_iter().map(|w| w.join().unwrap()).sum()
+}
+
+#[test]
+#[ignore] // 性能基准：cargo test --release --features reuseport -- --ignored bench_udp --nocapture
+fn bench_udp_socket_sharding() {
+    let duration = Duration::from_secs(3);
+    let sharded_config = ServerConfig {
+        udp_sockets: 4,
+        ..ServerConfig::default()
+    };
+    let single = ping_throughput(&TestServer::start(), 8, duration);
+    let sharded = ping_throughput(&TestServer::with_config(sharded_config), 8, duration);
+    let pps = |n: usize| n as f64 / duration.as_secs_f64();
+    println!("1 socket:  {:.0} packets/sec", pps(single));
+    println!("4 sockets: {:.0} packets/sec", pps(sharded));
+    assert!(single > 0 && sharded > 0);
+}
+
+/// 辅助函数：创建测试用的 UDP socket 并发送消息
+fn send_and_receive(server_addr: SocketAddr, message: Value, timeout_secs: u64) -> Result<Value, String> {
+    let socket = UdpSocket::bind("127.0.0.1:0").map_err(|e| format!("Bind failed: {}", e))?;
+    socket
+        .set_read_timeout(Some(Duration::from_secs(timeout_secs)))
+        .map_err(|e| format!("Set timeout failed: {}", e))?;
+
+    let msg_str = message.to_string();
+    socket
+        .send_to(msg_str.as_bytes(), server_addr)
+        .map_err(|e| format!("Send failed: {}", e))?;
+
+    let mut buf = [0u8; 4096];
+    match socket.recv_from(&mut buf) {
+        Ok((n, _)) => {
+            let response = std::str::from_utf8(&buf[..n]).map_err(|e| format!("Invalid utf-8: {}", e))?;
+            serde_json::from_str(response).map_err(|e| format!("Parse failed: {}", e))
+        }
         Err(e) => Err(format!("Receive failed: {}", e)),
     }
 }
 
 #[test]
-#[ignore] // 需要运行服务器才能测试
 fn test_uuid_not_found() {
+    let server = TestServer::start();
     // 测试：提供一个不存在的 UUID，不提供用户名
     let fake_uuid = "00000000-0000-0000-0000-000000000001";
     let request = json!({
@@ -705,7 +5070,7 @@ fn test_uuid_not_found() {
         "uuid": fake_uuid
     });
 
-    match send_and_receive(request, 2) {
+    match send_and_receive(server.addr(), request, 2) {
         Ok(response) => {
             assert_eq!(
                 response.get("action").and_then(|v| v.as_str()),
@@ -723,14 +5088,14 @@ fn test_uuid_not_found() {
 }
 
 #[test]
-#[ignore] // 需要运行服务器才能测试
 fn test_username_required() {
+    let server = TestServer::start();
     // 测试：既不提供 UUID 也不提供用户名
     let request = json!({
         "type": "register"
     });
 
-    match send_and_receive(request, 2) {
+    match send_and_receive(server.addr(), request, 2) {
         Ok(response) => {
             assert_eq!(
                 response.get("action").and_then(|v| v.as_str()),
@@ -743,8 +5108,8 @@ fn test_username_required() {
 }
 
 #[test]
-#[ignore] // 需要运行服务器才能测试
 fn test_normal_registration() {
+    let server = TestServer::start();
     // 测试：正常注册（提供用户名）
     let username = format!("test_user_{}", std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
@@ -756,7 +5121,7 @@ fn test_normal_registration() {
         "username": username
     });
 
-    match send_and_receive(request, 2) {
+    match send_and_receive(server.addr(), request, 2) {
         Ok(response) => {
             assert_eq!(
                 response.get("action").and_then(|v| v.as_str()),
@@ -778,8 +5143,8 @@ fn test_normal_registration() {
 }
 
 #[test]
-#[ignore] // 需要运行服务器才能测试
 fn test_valid_uuid_resume() {
+    let server = TestServer::start();
     // 测试：先注册，然后使用有效的 UUID 恢复
     let username = format!("resume_test_{}", std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
@@ -792,7 +5157,7 @@ fn test_valid_uuid_resume() {
         "username": username
     });
 
-    let uuid = match send_and_receive(register_request, 2) {
+    let uuid = match send_and_receive(server.addr(), register_request, 2) {
         Ok(response) => {
             response.get("uuid")
                 .and_then(|v| v.as_str())
@@ -808,7 +5173,7 @@ fn test_valid_uuid_resume() {
         "uuid": uuid
     });
 
-    match send_and_receive(resume_request, 2) {
+    match send_and_receive(server.addr(), resume_request, 2) {
         Ok(response) => {
             assert_eq!(
                 response.get("action").and_then(|v| v.as_str()),
@@ -831,30 +5196,31 @@ fn test_valid_uuid_resume() {
 }
 
 #[test]
-#[ignore] // 需要运行服务器才能测试
 fn test_malformed_uuid() {
+    let server = TestServer::start();
     // 测试：提供格式错误的 UUID
     let request = json!({
         "type": "register",
         "uuid": "this-is-not-a-valid-uuid"
     });
 
-    match send_and_receive(request, 2) {
+    match send_and_receive(server.addr(), request, 2) {
         Ok(response) => {
-            // 格式错误的 UUID 会被解析失败，服务器会要求提供用户名
+            // 格式错误的 UUID 按字段类型错误回复
             assert_eq!(
                 response.get("action").and_then(|v| v.as_str()),
-                Some("username_required"),
-                "服务器应该返回 username_required（因为 UUID 解析失败）"
+                Some("malformed_request"),
+                "服务器应该返回 malformed_request（因为 UUID 解析失败）"
             );
+            assert_eq!(response.get("field").and_then(|v| v.as_str()), Some("uuid"));
         }
         Err(e) => panic!("测试失败: {}", e),
     }
 }
 
 #[test]
-#[ignore] // 需要运行服务器才能测试
 fn test_uuid_with_username_invalid_uuid() {
+    let server = TestServer::start();
     // 测试：同时提供 UUID 和用户名，但 UUID 不存在
     // 服务器应该优先检查 UUID，返回 uuid_not_found
     let fake_uuid = "11111111-1111-1111-1111-111111111111";
@@ -864,7 +5230,7 @@ fn test_uuid_with_username_invalid_uuid() {
         "username": "should_not_be_used"
     });
 
-    match send_and_receive(request, 2) {
+    match send_and_receive(server.addr(), request, 2) {
         Ok(response) => {
             assert_eq!(
                 response.get("action").and_then(|v| v.as_str()),